@@ -0,0 +1,278 @@
+use chrono::{DateTime, Duration, FixedOffset, LocalResult, NaiveDate, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Daily window a webhook accepts deliveries in, given as `active_hours` of a webhook table.
+///
+/// Deliveries outside the window are queued until it opens. `end` may be `24:00`, and may be
+/// earlier than `start` for windows spanning midnight.
+///
+/// ```toml
+/// active_hours = { tz = "Asia/Seoul", start = "08:00", end = "24:00" }
+/// ```
+///
+/// `tz` is an IANA time zone name, or a UTC offset such as `+09:00` or `UTC`. Windows follow
+/// daylight saving time of named zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "ActiveHoursRepr", into = "ActiveHoursRepr")]
+pub struct ActiveHours {
+    zone: Zone,
+    /// Minutes after local midnight.
+    start: u32,
+    end: u32,
+}
+
+/// Time zone of a daily schedule, either named or a fixed UTC offset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    Offset(FixedOffset),
+    Named(chrono_tz::Tz),
+}
+
+impl std::fmt::Display for Zone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Offset(offset) => offset.fmt(f),
+            Self::Named(tz) => f.write_str(tz.name()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActiveHoursRepr {
+    tz: String,
+    start: String,
+    end: String,
+}
+
+impl TryFrom<ActiveHoursRepr> for ActiveHours {
+    type Error = String;
+
+    fn try_from(repr: ActiveHoursRepr) -> Result<Self, Self::Error> {
+        let zone = parse_zone(&repr.tz)?;
+        let start = parse_time(&repr.start)
+            .filter(|&t| t < 24 * 60)
+            .ok_or_else(|| format!("start: expected HH:MM before 24:00, got {:?}", repr.start))?;
+        let end = parse_time(&repr.end)
+            .ok_or_else(|| format!("end: expected HH:MM up to 24:00, got {:?}", repr.end))?;
+        if start == end % (24 * 60) {
+            return Err(format!(
+                "start and end are the same time, {}; remove active_hours for all day delivery",
+                repr.start,
+            ));
+        }
+        Ok(Self { zone, start, end })
+    }
+}
+
+impl From<ActiveHours> for ActiveHoursRepr {
+    fn from(hours: ActiveHours) -> Self {
+        let format_time = |t: u32| format!("{:02}:{:02}", t / 60, t % 60);
+        Self {
+            tz: hours.zone.to_string(),
+            start: format_time(hours.start),
+            end: format_time(hours.end),
+        }
+    }
+}
+
+/// Parses an IANA time zone name such as `Asia/Seoul`, or a UTC offset such as `+09:00`, or
+/// `UTC`.
+pub fn parse_zone(tz: &str) -> Result<Zone, String> {
+    if tz.eq_ignore_ascii_case("utc") || tz == "Z" {
+        return Ok(Zone::Offset(FixedOffset::east(0)));
+    }
+    let (sign, rest) = match tz.as_bytes().first() {
+        Some(b'+') => (1, &tz[1..]),
+        Some(b'-') => (-1, &tz[1..]),
+        _ => {
+            return tz.parse().map(Zone::Named).map_err(|_| {
+                format!(
+                    "tz: expected a time zone name like Asia/Seoul or a UTC offset like +09:00, \
+                     got {:?}",
+                    tz,
+                )
+            });
+        }
+    };
+    let invalid = || format!("tz: expected a UTC offset like +09:00, got {:?}", tz);
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours = hours.parse::<i32>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<i32>().map_err(|_| invalid())?;
+    if hours > 14 || minutes >= 60 {
+        return Err(invalid());
+    }
+    Ok(Zone::Offset(FixedOffset::east(sign * (hours * 3600 + minutes * 60))))
+}
+
+/// Parses `HH:MM` into minutes after midnight, accepting up to `24:00`.
+pub fn parse_time(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.split_once(':')?;
+    let hours = hours.parse::<u32>().ok()?;
+    let minutes = minutes.parse::<u32>().ok()?;
+    let time = hours * 60 + minutes;
+    (minutes < 60 && time <= 24 * 60).then_some(time)
+}
+
+impl ActiveHours {
+    /// Returns whether `now` falls in the window.
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let minute = self.zone.local_minute(now);
+        if self.start < self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Returns when the window next opens, if `now` is outside it.
+    pub fn deferral(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if self.contains(now) {
+            return None;
+        }
+        Some(self.zone.next_local_time(self.start, now))
+    }
+}
+
+impl Zone {
+    /// Minutes after local midnight at `now`.
+    fn local_minute(&self, now: DateTime<Utc>) -> u32 {
+        fn minute<Z: TimeZone>(zone: &Z, now: DateTime<Utc>) -> u32 {
+            let local = now.with_timezone(zone);
+            local.hour() * 60 + local.minute()
+        }
+        match self {
+            Self::Offset(offset) => minute(offset, now),
+            Self::Named(tz) => minute(tz, now),
+        }
+    }
+
+    /// Returns the first time after `now` which is `minutes` after local midnight.
+    ///
+    /// Times skipped by a daylight saving transition fall on the end of the transition, and
+    /// times repeated by one on their first occurrence.
+    pub fn next_local_time(&self, minutes: u32, now: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::Offset(offset) => next_local_time(offset, minutes, now),
+            Self::Named(tz) => next_local_time(tz, minutes, now),
+        }
+    }
+}
+
+fn next_local_time<Z: TimeZone>(zone: &Z, minutes: u32, now: DateTime<Utc>) -> DateTime<Utc> {
+    let today = now.with_timezone(zone).date().naive_local();
+    (0..=2)
+        .map(|days| local_time(zone, today + Duration::days(days), minutes))
+        .find(|&time| time > now)
+        .expect("local time of the day after tomorrow is in the future")
+}
+
+/// Returns `minutes` after midnight of `date` at `zone`.
+fn local_time<Z: TimeZone>(zone: &Z, date: NaiveDate, minutes: u32) -> DateTime<Utc> {
+    let mut time = date.and_hms(0, 0, 0) + Duration::minutes(minutes.into());
+    // local times in a gap don't exist; no zone skips more than a day
+    loop {
+        match zone.from_local_datetime(&time) {
+            LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => {
+                return time.with_timezone(&Utc);
+            }
+            LocalResult::None => time += Duration::minutes(1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn hours(tz: &str, start: &str, end: &str) -> ActiveHours {
+        ActiveHours::try_from(ActiveHoursRepr {
+            tz: tz.into(),
+            start: start.into(),
+            end: end.into(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_zones() {
+        assert_eq!(parse_zone("Asia/Seoul"), Ok(Zone::Named(chrono_tz::Asia::Seoul)));
+        assert_eq!(parse_zone("+09:00"), Ok(Zone::Offset(FixedOffset::east(9 * 3600))));
+        assert_eq!(parse_zone("UTC"), Ok(Zone::Offset(FixedOffset::east(0))));
+        assert!(parse_zone("Mars/Olympus").is_err());
+        assert!(parse_zone("+15:00").is_err());
+        assert_eq!(hours("Asia/Seoul", "08:00", "24:00").zone.to_string(), "Asia/Seoul");
+    }
+
+    #[test]
+    fn named_zone_window() {
+        let hours = hours("Asia/Seoul", "08:00", "24:00");
+        // 07:59 and 08:00 KST
+        assert!(!hours.contains(utc("2022-01-10T22:59:00Z")));
+        assert!(hours.contains(utc("2022-01-10T23:00:00Z")));
+        assert_eq!(
+            hours.deferral(utc("2022-01-10T16:00:00Z")),
+            Some(utc("2022-01-10T23:00:00Z")),
+        );
+    }
+
+    #[test]
+    fn window_follows_dst() {
+        // 09:00 to 17:00 in New York is 14:00 to 22:00 UTC in winter, 13:00 to 21:00 in summer
+        let hours = hours("America/New_York", "09:00", "17:00");
+        assert!(hours.contains(utc("2022-01-10T14:00:00Z")));
+        assert!(!hours.contains(utc("2022-01-10T13:30:00Z")));
+        assert!(hours.contains(utc("2022-07-10T13:30:00Z")));
+        assert!(!hours.contains(utc("2022-07-10T21:30:00Z")));
+    }
+
+    #[test]
+    fn deferral_across_spring_forward() {
+        // DST starts on 2022-03-13 at 02:00 EST, skipping to 03:00 EDT
+        let hours = hours("America/New_York", "09:00", "17:00");
+        // 22:00 EST on the 12th opens at 09:00 EDT on the 13th
+        assert_eq!(
+            hours.deferral(utc("2022-03-13T03:00:00Z")),
+            Some(utc("2022-03-13T13:00:00Z")),
+        );
+        // 02:30 doesn't exist on the 13th, and falls on 03:00 EDT
+        let zone = parse_zone("America/New_York").unwrap();
+        assert_eq!(
+            zone.next_local_time(150, utc("2022-03-13T05:00:00Z")),
+            utc("2022-03-13T07:00:00Z"),
+        );
+    }
+
+    #[test]
+    fn deferral_across_fall_back() {
+        // DST ends on 2022-11-06 at 02:00 EDT, repeating 01:00 to 02:00 as EST
+        let hours = hours("America/New_York", "09:00", "17:00");
+        // 22:00 EDT on the 5th opens at 09:00 EST on the 6th
+        assert_eq!(
+            hours.deferral(utc("2022-11-06T02:00:00Z")),
+            Some(utc("2022-11-06T14:00:00Z")),
+        );
+        // 01:30 happens twice on the 6th; the first one is taken
+        let zone = parse_zone("America/New_York").unwrap();
+        assert_eq!(
+            zone.next_local_time(90, utc("2022-11-06T04:00:00Z")),
+            utc("2022-11-06T05:30:00Z"),
+        );
+    }
+
+    #[test]
+    fn window_spanning_midnight() {
+        let hours = hours("+09:00", "22:00", "06:00");
+        assert!(hours.contains(utc("2022-01-10T14:00:00Z")));
+        assert!(hours.contains(utc("2022-01-10T20:59:00Z")));
+        assert!(!hours.contains(utc("2022-01-10T21:00:00Z")));
+        assert_eq!(
+            hours.deferral(utc("2022-01-10T21:00:00Z")),
+            Some(utc("2022-01-11T13:00:00Z")),
+        );
+    }
+}
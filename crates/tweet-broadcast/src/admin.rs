@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use serde::Deserialize;
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+
+use tweet_fetch::{backoff::BackoffType, StreamEvent};
+
+use crate::reload::EngineConfig;
+use crate::sink::SinkFactory;
+use crate::watchdog::WatchdogConfig;
+use crate::webhook::WebhookTarget;
+
+/// Backoffs of the filtered stream longer than this in total are notified.
+const BACKOFF_NOTICE_AFTER: Duration = Duration::from_secs(60);
+
+/// Config of admin notifications, `admin/config.toml` in the cache directory.
+///
+/// ```toml
+/// [admin]
+/// webhooks = ["https://discord.com/api/webhooks/..."]
+/// cooldown_secs = 600
+///
+/// [watchdog.silence_secs]
+/// filtered_stream = 7200
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    admin: AdminMeta,
+    #[serde(default)]
+    watchdog: WatchdogConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdminMeta {
+    webhooks: Vec<WebhookTarget>,
+    /// Seconds to hold back repeated notices of the same kind, so that a flapping stream
+    /// doesn't flood the channel.
+    #[serde(default = "default_cooldown_secs")]
+    cooldown_secs: u64,
+}
+
+fn default_cooldown_secs() -> u64 {
+    600
+}
+
+impl EngineConfig for AdminConfig {
+    fn validate(&self) -> Result<()> {
+        for (idx, webhook) in self.admin.webhooks.iter().enumerate() {
+            webhook
+                .validate()
+                .map_err(|e| eyre::eyre!("admin.webhooks[{}]: {}", idx, e))?;
+        }
+        self.watchdog
+            .validate()
+            .map_err(|e| eyre::eyre!("watchdog.{}", e))?;
+        Ok(())
+    }
+
+    fn webhooks(&self) -> Vec<(String, &WebhookTarget)> {
+        self.admin
+            .webhooks
+            .iter()
+            .enumerate()
+            .map(|(idx, webhook)| (format!("admin.webhooks[{}]", idx), webhook))
+            .collect()
+    }
+}
+
+impl AdminConfig {
+    pub fn watchdog(&self) -> &WatchdogConfig {
+        &self.watchdog
+    }
+}
+
+/// Notices held back by the cooldown, by kind.
+#[derive(Debug, Default)]
+struct Cooldown {
+    last_sent: HashMap<String, (Instant, u32)>,
+}
+
+impl Cooldown {
+    /// Returns whether a notice of `key` may be sent at `now`, with the number of notices held
+    /// back since the last one sent.
+    fn check(&mut self, key: &str, period: Duration, now: Instant) -> Option<u32> {
+        match self.last_sent.get_mut(key) {
+            Some((sent_at, suppressed)) if now.duration_since(*sent_at) < period => {
+                *suppressed += 1;
+                None
+            }
+            _ => {
+                let suppressed = self
+                    .last_sent
+                    .insert(key.to_owned(), (now, 0))
+                    .map(|(_, suppressed)| suppressed)
+                    .unwrap_or(0);
+                Some(suppressed)
+            }
+        }
+    }
+}
+
+/// Sends notices about the relay itself to the admin webhooks, those of `admin/config.toml` and
+/// `--admin-webhook`.
+#[derive(Debug, Clone)]
+pub struct AdminNotifier {
+    sinks: SinkFactory,
+    config_rx: Option<watch::Receiver<Arc<AdminConfig>>>,
+    admin_webhook: Option<WebhookTarget>,
+    cooldown: Arc<Mutex<Cooldown>>,
+}
+
+impl AdminNotifier {
+    pub fn new(
+        sinks: SinkFactory,
+        config_rx: Option<watch::Receiver<Arc<AdminConfig>>>,
+        admin_webhook: Option<WebhookTarget>,
+    ) -> Self {
+        Self {
+            sinks,
+            config_rx,
+            admin_webhook,
+            cooldown: Default::default(),
+        }
+    }
+
+    fn webhooks(&self) -> Vec<WebhookTarget> {
+        let mut webhooks = self.admin_webhook.iter().cloned().collect::<Vec<_>>();
+        if let Some(config_rx) = &self.config_rx {
+            webhooks.extend(config_rx.borrow().admin.webhooks.iter().cloned());
+        }
+        webhooks
+    }
+
+    fn cooldown_period(&self) -> Duration {
+        let secs = self
+            .config_rx
+            .as_ref()
+            .map(|config_rx| config_rx.borrow().admin.cooldown_secs)
+            .unwrap_or_else(default_cooldown_secs);
+        Duration::from_secs(secs)
+    }
+
+    /// Sends `message` to every admin webhook.
+    pub async fn notify(&self, message: &str) {
+        for webhook in self.webhooks() {
+            let sink = self.sinks.build(&webhook);
+            if let Err(e) = sink.notify(message).await {
+                log::error!("Failed to notify admin webhook {}: {}", sink.name(), e);
+            }
+        }
+    }
+
+    /// Sends `message` unless a notice of the same `kind` was sent within the cooldown.
+    pub async fn notify_throttled(&self, kind: &str, message: &str) {
+        let suppressed = {
+            let mut cooldown = self.cooldown.lock().unwrap();
+            cooldown.check(kind, self.cooldown_period(), Instant::now())
+        };
+        match suppressed {
+            None => log::debug!("Admin notice held back by cooldown: {}", message),
+            Some(0) => self.notify(message).await,
+            Some(n) => {
+                let message = format!("{} ({} similar notice(s) held back)", message, n);
+                self.notify(&message).await;
+            }
+        }
+    }
+}
+
+/// Health event of the filtered stream, reported to the stream monitor.
+#[derive(Debug)]
+pub enum StreamHealthEvent {
+    Stream(StreamEvent),
+    Disconnected(String),
+}
+
+/// Sender of stream health events to the task spawned by `spawn_stream_monitor`.
+#[derive(Debug, Clone)]
+pub struct StreamMonitor {
+    tx: mpsc::UnboundedSender<StreamHealthEvent>,
+}
+
+impl StreamMonitor {
+    /// Returns an observer for `TwitterClient::make_observed_stream`.
+    pub fn observer(&self) -> impl Fn(StreamEvent) + Send + Sync + 'static {
+        let tx = self.tx.clone();
+        move |event| {
+            tx.send(StreamHealthEvent::Stream(event)).ok();
+        }
+    }
+
+    pub fn disconnected(&self, error: &dyn std::fmt::Display) {
+        self.tx
+            .send(StreamHealthEvent::Disconnected(error.to_string()))
+            .ok();
+    }
+}
+
+#[derive(Debug)]
+struct BackoffEpisode {
+    kind: BackoffType,
+    since: DateTime<Utc>,
+    notified: bool,
+}
+
+/// Spawns a task posting notices of disconnects, reconnects and long backoffs of the filtered
+/// stream to the admin webhooks.
+pub fn spawn_stream_monitor(notifier: AdminNotifier) -> StreamMonitor {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut disconnected_at = None;
+        let mut backoff = None::<BackoffEpisode>;
+        while let Some(event) = rx.recv().await {
+            let now = Utc::now();
+            match event {
+                StreamHealthEvent::Disconnected(error) => {
+                    disconnected_at.get_or_insert(now);
+                    let message = format!(
+                        "Filtered stream disconnected at <t:{}:T>: {}",
+                        now.timestamp(),
+                        error,
+                    );
+                    notifier.notify_throttled("disconnect", &message).await;
+                }
+                StreamHealthEvent::Stream(StreamEvent::Connected) => {
+                    backoff = None;
+                    if let Some(since) = disconnected_at.take() {
+                        let message = format!(
+                            "Filtered stream reconnected at <t:{}:T>, after {} second(s) down",
+                            now.timestamp(),
+                            (now - since).num_seconds(),
+                        );
+                        notifier.notify_throttled("reconnect", &message).await;
+                    }
+                }
+                StreamHealthEvent::Stream(StreamEvent::BackingOff {
+                    kind,
+                    failures,
+                    duration,
+                }) => {
+                    if !matches!(&backoff, Some(episode) if episode.kind == kind) {
+                        backoff = Some(BackoffEpisode {
+                            kind,
+                            since: now,
+                            notified: false,
+                        });
+                    }
+                    let episode = backoff.as_mut().unwrap();
+                    let waited = (now - episode.since).to_std().unwrap_or_default() + duration;
+                    if episode.notified || waited <= BACKOFF_NOTICE_AFTER {
+                        continue;
+                    }
+                    episode.notified = true;
+                    let message = format!(
+                        "Filtered stream in {} backoff since <t:{}:T>, {} failure(s) in a row, \
+                         retrying in {} second(s)",
+                        kind,
+                        episode.since.timestamp(),
+                        failures,
+                        duration.as_secs(),
+                    );
+                    notifier.notify_throttled("backoff", &message).await;
+                }
+            }
+        }
+    });
+    StreamMonitor { tx }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RecordingSinks;
+
+    const COOLDOWN: Duration = Duration::from_secs(600);
+
+    fn notices(sinks: &RecordingSinks) -> Vec<String> {
+        sinks
+            .deliveries()
+            .iter()
+            .filter_map(|delivery| delivery.notice().map(str::to_owned))
+            .collect()
+    }
+
+    #[test]
+    fn cooldown_counts_held_back_notices() {
+        let mut cooldown = Cooldown::default();
+        let start = Instant::now();
+        // kinds are held back separately
+        assert_eq!(cooldown.check("reconnect", COOLDOWN, start), Some(0));
+        let mut check = |at| cooldown.check("disconnect", COOLDOWN, at);
+        assert_eq!(check(start), Some(0));
+
+        for secs in [1, 60, 599] {
+            assert_eq!(check(start + Duration::from_secs(secs)), None, "{}s", secs);
+        }
+        let now = start + COOLDOWN;
+        assert_eq!(check(now), Some(3));
+        // the cooldown starts over from the notice sent
+        assert_eq!(check(now + COOLDOWN / 2), None);
+        assert_eq!(check(now + COOLDOWN), Some(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flapping_is_held_back() {
+        let sinks = RecordingSinks::default();
+        let webhook = "https://discord.com/api/webhooks/1/admin".parse().unwrap();
+        let notifier = AdminNotifier::new(sinks.factory(), None, Some(WebhookTarget::new(webhook)));
+
+        notifier.notify_throttled("disconnect", "down").await;
+        tokio::time::advance(Duration::from_secs(30)).await;
+        notifier.notify_throttled("disconnect", "down again").await;
+        notifier.notify_throttled("disconnect", "flapping").await;
+        assert_eq!(notices(&sinks), ["down"]);
+
+        tokio::time::advance(COOLDOWN).await;
+        notifier.notify_throttled("disconnect", "down later").await;
+        assert_eq!(
+            notices(&sinks),
+            ["down", "down later (2 similar notice(s) held back)"],
+        );
+    }
+}
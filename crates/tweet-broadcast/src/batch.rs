@@ -0,0 +1,179 @@
+//! Batching of consecutive tweets of the same author, e.g. a thread, into single messages.
+
+use serde::{Deserialize, Serialize};
+
+use tweet_discord::embed::{MAX_ACTION_ROWS, MAX_BUTTONS_PER_ROW};
+use tweet_discord::limits::{MAX_CONTENT_CHARS, MAX_EMBEDS_PER_MESSAGE, MAX_EMBED_CHARS_PER_MESSAGE};
+use tweet_discord::WebhookPayload;
+use tweet_model as model;
+
+/// Tweets sent together with `batch_threads`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadGrouping {
+    /// Consecutive tweets of the same author.
+    #[default]
+    Author,
+    /// Consecutive tweets of the same author in the same conversation.
+    Conversation,
+}
+
+impl ThreadGrouping {
+    fn continues(self, prev: &model::Tweet, tweet: &model::Tweet) -> bool {
+        // retweets are rendered as the retweeted tweet, whose author may be anyone
+        if prev.is_retweet() || tweet.is_retweet() {
+            return false;
+        }
+        let same_author = matches!(
+            (prev.author_id(), tweet.author_id()),
+            (Some(prev), Some(author)) if prev == author
+        );
+        match self {
+            Self::Author => same_author,
+            Self::Conversation => {
+                same_author
+                    && matches!(
+                        (prev.conversation_id(), tweet.conversation_id()),
+                        (Some(prev), Some(conversation)) if prev == conversation
+                    )
+            }
+        }
+    }
+}
+
+/// Splits `tweets` into runs of consecutive tweets which belong together, keeping their order.
+pub fn group_tweets<'a>(
+    tweets: &[&'a model::Tweet],
+    grouping: ThreadGrouping,
+) -> Vec<Vec<&'a model::Tweet>> {
+    let mut groups = Vec::<Vec<&model::Tweet>>::new();
+    for &tweet in tweets {
+        match groups.last_mut() {
+            Some(group) if grouping.continues(group.last().unwrap(), tweet) => group.push(tweet),
+            _ => groups.push(vec![tweet]),
+        }
+    }
+    groups
+}
+
+/// Returns whether `payload` can be sent as a single message without being split.
+fn fits_in_message(payload: &WebhookPayload) -> bool {
+    payload.embeds().len() <= MAX_EMBEDS_PER_MESSAGE
+        && payload.embed_chars() <= MAX_EMBED_CHARS_PER_MESSAGE
+        && payload.content_text().map_or(0, |content| content.chars().count()) <= MAX_CONTENT_CHARS
+        && payload.button_count() <= MAX_ACTION_ROWS * MAX_BUTTONS_PER_ROW
+}
+
+/// Packs rendered tweets into as few messages as possible, in order.
+///
+/// A tweet is never split between messages, so that its images stay in one gallery; a tweet
+/// over the limits on its own is left to be split on execution as usual.
+pub fn pack_payloads(payloads: Vec<WebhookPayload>) -> Vec<WebhookPayload> {
+    let mut messages = Vec::<WebhookPayload>::new();
+    for payload in payloads {
+        let packed = match messages.last() {
+            Some(message) => {
+                let packed = message.clone().append(payload.clone());
+                Some(packed).filter(fits_in_message)
+            }
+            None => None,
+        };
+        match packed {
+            Some(packed) => *messages.last_mut().unwrap() = packed,
+            None => messages.push(payload),
+        }
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use tweet_discord::embed::Embed;
+
+    use super::*;
+
+    fn tweet(id: &str, author: &str, conversation: &str) -> model::Tweet {
+        model::Tweet::new(id, "text")
+            .with_author_id(author)
+            .with_conversation_id(conversation)
+    }
+
+    fn group_ids(tweets: &[model::Tweet], grouping: ThreadGrouping) -> Vec<Vec<&str>> {
+        let tweets = tweets.iter().collect::<Vec<_>>();
+        group_tweets(&tweets, grouping)
+            .into_iter()
+            .map(|group| group.into_iter().map(|tweet| tweet.id()).collect())
+            .collect()
+    }
+
+    /// Payload with an embed per description.
+    fn payload(descriptions: &[usize]) -> WebhookPayload {
+        descriptions.iter().fold(WebhookPayload::new(), |payload, &chars| {
+            payload.embed(Embed::new().description("a".repeat(chars)))
+        })
+    }
+
+    fn embed_counts(messages: &[WebhookPayload]) -> Vec<usize> {
+        messages.iter().map(|message| message.embeds().len()).collect()
+    }
+
+    #[test]
+    fn groups_consecutive_tweets_of_author() {
+        let tweets = [
+            tweet("1", "a", "1"),
+            tweet("2", "a", "1"),
+            tweet("3", "a", "3"),
+            tweet("4", "b", "3"),
+            tweet("5", "a", "3"),
+        ];
+        let groups = group_ids(&tweets, ThreadGrouping::Author);
+        assert_eq!(groups, [vec!["1", "2", "3"], vec!["4"], vec!["5"]]);
+        let groups = group_ids(&tweets, ThreadGrouping::Conversation);
+        assert_eq!(groups, [vec!["1", "2"], vec!["3"], vec!["4"], vec!["5"]]);
+    }
+
+    #[test]
+    fn retweets_are_not_grouped() {
+        let retweet = tweet("2", "a", "2")
+            .with_referenced_tweet(model::TweetReferenceType::Retweeted, "100");
+        let tweets = [tweet("1", "a", "1"), retweet, tweet("3", "a", "1")];
+        let groups = group_ids(&tweets, ThreadGrouping::Author);
+        assert_eq!(groups, [vec!["1"], vec!["2"], vec!["3"]]);
+    }
+
+    #[test]
+    fn packs_up_to_embed_count() {
+        let payloads = (0..6).map(|_| payload(&[10, 10])).collect();
+        let messages = pack_payloads(payloads);
+        assert_eq!(embed_counts(&messages), [MAX_EMBEDS_PER_MESSAGE, 2]);
+    }
+
+    #[test]
+    fn packs_up_to_embed_chars() {
+        let chars = MAX_EMBED_CHARS_PER_MESSAGE / 3;
+        let payloads = (0..4).map(|_| payload(&[chars])).collect();
+        let messages = pack_payloads(payloads);
+        assert_eq!(embed_counts(&messages), [3, 1]);
+        assert_eq!(messages[0].embed_chars(), MAX_EMBED_CHARS_PER_MESSAGE);
+    }
+
+    #[test]
+    fn packs_up_to_content_length() {
+        let content = "a".repeat(MAX_CONTENT_CHARS / 2);
+        let payloads = (0..2)
+            .map(|_| WebhookPayload::new().content(content.clone()))
+            .collect();
+        // joined by a line break, one over the limit
+        assert_eq!(pack_payloads(payloads).len(), 2);
+    }
+
+    #[test]
+    fn oversized_tweet_stays_alone() {
+        let oversized = payload(&[10; MAX_EMBEDS_PER_MESSAGE + 1]);
+        let payloads = vec![payload(&[10]), oversized, payload(&[10])];
+        let messages = pack_payloads(payloads);
+        // split on execution instead
+        assert_eq!(embed_counts(&messages), [1, MAX_EMBEDS_PER_MESSAGE + 1, 1]);
+        assert!(!fits_in_message(&messages[1]));
+    }
+}
@@ -6,10 +6,50 @@ use tweet_model::{
     cache::*,
 };
 
+use crate::image::{ImageSaver, SaveImages};
+
+/// Namespaces of cached items which `collect_garbage` may delete. Heads and configs live
+/// alongside some of them but aren't JSON files, so scans skip them.
+pub const GC_NAMESPACES: &[&str] = &[
+    "tweets",
+    "users",
+    "media",
+    "stream",
+    "searches/relayed",
+    "stream/unrouted",
+    "delivered",
+];
+/// Files stat'ed or deleted at once by `collect_garbage`.
+const GC_CONCURRENCY: usize = 32;
+
+/// Parses an age such as `30d`, `12h`, `90m` or `45s`.
+pub fn parse_age(s: &str) -> Result<std::time::Duration, String> {
+    let invalid = || format!("expected an age like 30d or 12h, got `{}`", s);
+    let (number, unit) = s.split_at(s.len().saturating_sub(1));
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    let secs = match unit {
+        "d" => number * 86400,
+        "h" => number * 3600,
+        "m" => number * 60,
+        "s" => number,
+        _ => return Err(invalid()),
+    };
+    Ok(std::time::Duration::from_secs(secs))
+}
+
 #[derive(Debug, Clone)]
 pub struct FsCache {
     dir: std::path::PathBuf,
-    remote: Option<RemoteConfig>,
+    /// Remote cache config, replaced by `reload_remote`. Downloads already requested keep the
+    /// config they were signed with.
+    remote: std::sync::Arc<std::sync::RwLock<Option<std::sync::Arc<RemoteConfig>>>>,
+    remote_config_path: std::path::PathBuf,
+    /// Skips every store, for dry runs.
+    read_only: bool,
+    /// Saves photos locally, unless disabled by `--no-save-images` or `remote.toml`.
+    images: Option<ImageSaver>,
+    // remote download tasks hold read guards, so that acquiring write waits for them
+    remote_downloads: std::sync::Arc<tokio::sync::RwLock<()>>,
 }
 
 #[derive(Clone, serde::Serialize, serde::Deserialize)]
@@ -29,6 +69,23 @@ impl std::fmt::Debug for RemoteConfig {
 }
 
 impl RemoteConfig {
+    /// Reads the remote cache config at `path`, `None` if it doesn't exist.
+    async fn read(path: &std::path::Path) -> eyre::Result<Option<Self>> {
+        let buf = match tokio::fs::read(path).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let remote = toml::from_slice::<Self>(&buf)?;
+        if !matches!(remote.endpoint.scheme(), "http" | "https") {
+            eyre::bail!("endpoint: not an HTTP URL");
+        }
+        if remote.signing_key.is_empty() {
+            eyre::bail!("signing_key: must not be empty");
+        }
+        Ok(Some(remote))
+    }
+
     fn sign(&self, message: &[u8]) -> (ring::hmac::Tag, i64) {
         let now = Utc::now();
         let expires_at = now + Duration::seconds(30);
@@ -64,38 +121,182 @@ impl RemoteConfig {
 }
 
 impl FsCache {
-    pub async fn new(path: impl Into<std::path::PathBuf>, no_save_images: bool) -> Self {
+    /// Opens the cache at `path`, uploading to the remote cache of `remote_config_path` if it
+    /// exists.
+    pub async fn new(
+        path: impl Into<std::path::PathBuf>,
+        remote_config_path: &std::path::Path,
+        no_save_images: bool,
+    ) -> Self {
         let dir = path.into();
-        let remote = {
-            let config_path = dir.join("remote.toml");
-            match tokio::fs::read(config_path).await {
-                Ok(buf) => {
-                    match toml::from_slice::<RemoteConfig>(&buf) {
-                        Ok(mut remote) => {
-                            remote.no_save_images |= no_save_images;
-                            Some(remote)
-                        },
-                        Err(e) => {
-                            log::error!("Failed to read remote.toml: {}", e);
-                            None
-                        },
-                    }
-                },
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                    None
-                },
-                Err(e) => {
-                    log::error!("Failed to read remote.toml: {}", e);
-                    None
-                },
+        let remote = match RemoteConfig::read(remote_config_path).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                log::error!("Failed to read remote.toml: {}", e);
+                None
             }
         };
+        let no_save_images = no_save_images || matches!(&remote, Some(r) if r.no_save_images);
+        let images = if no_save_images {
+            None
+        } else {
+            Some(ImageSaver::new(dir.join("images")))
+        };
         Self {
             dir,
-            remote,
+            remote: std::sync::Arc::new(std::sync::RwLock::new(remote.map(std::sync::Arc::new))),
+            remote_config_path: remote_config_path.to_owned(),
+            read_only: false,
+            images,
+            remote_downloads: Default::default(),
+        }
+    }
+
+    /// Makes every store a no-op, so that dry runs don't advance heads or mark tweets as seen.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    fn skip_store(&self, key: String) -> BoxFuture<'_, Result<String, FsError>> {
+        log::trace!("Read only, not storing {}", key);
+        Box::pin(async { Ok(key) })
+    }
+
+    /// Reads the remote cache config again, keeping the current one if the new one is invalid.
+    ///
+    /// Only the endpoint and the signing key are applied; `no_save_images` takes effect on
+    /// restart.
+    pub async fn reload_remote(&self) {
+        match RemoteConfig::read(&self.remote_config_path).await {
+            Ok(remote) => {
+                let enabled = remote.is_some();
+                *self.remote.write().unwrap() = remote.map(std::sync::Arc::new);
+                if enabled {
+                    log::info!("Reloaded {}", self.remote_config_path.display());
+                } else {
+                    log::info!(
+                        "{} removed, remote cache disabled",
+                        self.remote_config_path.display(),
+                    );
+                }
+            }
+            Err(e) => {
+                log::error!(
+                    "Rejected new remote.toml, keeping the current one: {}: {}",
+                    self.remote_config_path.display(),
+                    e,
+                );
+            }
         }
     }
 
+    /// Waits for remote media downloads requested so far.
+    pub async fn flush_remote_downloads(&self) {
+        let _ = self.remote_downloads.write().await;
+    }
+
+    /// Counts the cached items.
+    pub async fn stats(&self) -> Result<CacheStats, std::io::Error> {
+        Ok(CacheStats {
+            tweets: self.count_entries("tweets").await?,
+            users: self.count_entries("users").await?,
+            media: self.count_entries("media").await?,
+        })
+    }
+
+    async fn count_entries(&self, dir: &str) -> Result<usize, std::io::Error> {
+        let mut entries = match tokio::fs::read_dir(self.subpath(dir)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut count = 0;
+        while entries.next_entry().await?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Lists the keys stored under `namespace`, e.g. `tweets`, in no particular order.
+    pub async fn scan_keys(&self, namespace: &str) -> Result<Vec<String>, std::io::Error> {
+        let mut entries = match tokio::fs::read_dir(self.subpath(namespace)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension() != Some("json".as_ref()) {
+                continue;
+            }
+            if let Some(key) = path.file_stem().and_then(|stem| stem.to_str()) {
+                keys.push(key.to_owned());
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Deletes items of `namespace` last written more than `older_than` ago, or only counts them
+    /// with `dry_run`.
+    ///
+    /// Safe to run alongside a live instance; items which disappear during the scan are skipped.
+    pub async fn collect_garbage(
+        &self,
+        namespace: &str,
+        older_than: std::time::Duration,
+        dry_run: bool,
+    ) -> Result<GcStats, std::io::Error> {
+        use futures_util::{StreamExt, TryStreamExt};
+
+        if !GC_NAMESPACES.contains(&namespace) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("refusing to collect garbage in {}", namespace),
+            ));
+        }
+        let cutoff = std::time::SystemTime::now() - older_than;
+        let keys = self.scan_keys(namespace).await?;
+        let scanned = keys.len();
+        let expired = futures_util::stream::iter(keys)
+            .map(|key| {
+                let path = self.subpath(format!("{}/{}.json", namespace, key));
+                async move {
+                    let metadata = match tokio::fs::metadata(&path).await {
+                        Ok(metadata) => metadata,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                        Err(e) => return Err(e),
+                    };
+                    if metadata.modified()? >= cutoff {
+                        return Ok(None);
+                    }
+                    if !dry_run {
+                        match tokio::fs::remove_file(&path).await {
+                            Ok(()) => {}
+                            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    Ok(Some(metadata.len()))
+                }
+            })
+            .buffer_unordered(GC_CONCURRENCY)
+            .try_filter_map(|size| async move { Ok(size) })
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(GcStats {
+            namespace: namespace.to_owned(),
+            scanned,
+            expired: expired.len(),
+            bytes: expired.iter().sum(),
+        })
+    }
+
+    /// Returns the path of the item `key` of `namespace`, e.g. to read items of old layouts.
+    pub fn item_path(&self, namespace: &str, key: &str) -> std::path::PathBuf {
+        self.subpath(format!("{}/{}.json", namespace, key))
+    }
+
     fn subpath(&self, path: impl AsRef<std::path::Path>) -> std::path::PathBuf {
         self.dir.join(path)
     }
@@ -107,6 +308,34 @@ impl FsCache {
     }
 }
 
+impl SaveImages for FsCache {
+    fn save_images<'a>(&self, media: impl IntoIterator<Item = &'a model::Media>) {
+        if self.read_only {
+            return;
+        }
+        if let Some(images) = &self.images {
+            images.save(media);
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CacheStats {
+    tweets: usize,
+    users: usize,
+    media: usize,
+}
+
+/// Result of `collect_garbage` for a namespace.
+#[derive(Debug, serde::Serialize)]
+pub struct GcStats {
+    pub namespace: String,
+    pub scanned: usize,
+    /// Items older than the cutoff, deleted unless it was a dry run.
+    pub expired: usize,
+    pub bytes: u64,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum FsError {
     #[error("HTTP error: {0}")]
@@ -149,6 +378,9 @@ macro_rules! impl_cache {
         impl StoreCache<$it> for FsCache {
             fn store(&self, item: &$it) -> BoxFuture<'_, Result<String, Self::Error>> {
                 let key = item.key().to_owned();
+                if self.read_only {
+                    return self.skip_store(key);
+                }
                 let path = self.subpath(format!(concat!($base, "/{}.json"), key));
                 let v = serde_json::to_vec(item).unwrap();
                 Box::pin(async {
@@ -168,11 +400,17 @@ macro_rules! impl_cache {
 impl_cache!(model::Tweet, "tweets", load);
 impl StoreCache<model::Tweet> for FsCache {
     fn store(&self, item: &model::Tweet) -> BoxFuture<'_, Result<String, Self::Error>> {
-        if let Some(remote) = &self.remote {
+        if self.read_only {
+            return self.skip_store(item.key().to_owned());
+        }
+        let remote = self.remote.read().unwrap().clone();
+        if let Some(remote) = remote {
             let id = item.id().to_owned();
             let remote_media_save = remote.download_tweet_media(&id);
             let client = remote.client.clone();
+            let guard = self.remote_downloads.clone().try_read_owned().ok();
             tokio::spawn(async move {
+                let _guard = guard;
                 let ret: Result<_, reqwest::Error> = async {
                     let res = client.execute(remote_media_save).await?;
                     let status = res.status();
@@ -212,6 +450,9 @@ impl StoreCache<model::Tweet> for FsCache {
 impl_cache!(model::User, "users");
 impl_cache!(model::Media, "media");
 impl_cache!(tweet_route::CacheData, "stream");
+impl_cache!(crate::search::RelayedTweet, "searches/relayed");
+impl_cache!(crate::stream::UnroutedRecord, "stream/unrouted");
+impl_cache!(crate::dedup::DeliveredMarker, "delivered");
 
 impl LoadCache<tweet_fetch::ListHead> for FsCache {
     fn load(&self, key: &str) -> BoxFuture<'_, Result<tweet_fetch::ListHead, Self::Error>> {
@@ -243,6 +484,9 @@ impl LoadCache<tweet_fetch::ListHead> for FsCache {
 impl StoreCache<tweet_fetch::ListHead> for FsCache {
     fn store(&self, item: &tweet_fetch::ListHead) -> BoxFuture<'_, Result<String, Self::Error>> {
         let key = item.key().to_owned();
+        if self.read_only {
+            return self.skip_store(key);
+        }
         let head = item.head().map(|s| s.to_owned());
         let path = self.subpath(format!("lists/{}", key));
         Box::pin(async {
@@ -289,6 +533,9 @@ impl LoadCache<tweet_fetch::UserTimelineHead> for FsCache {
 impl StoreCache<tweet_fetch::UserTimelineHead> for FsCache {
     fn store(&self, item: &tweet_fetch::UserTimelineHead) -> BoxFuture<'_, Result<String, Self::Error>> {
         let key = item.key().to_owned();
+        if self.read_only {
+            return self.skip_store(key);
+        }
         let head = item.head().map(|s| s.to_owned());
         let path = self.subpath(format!("users/{}", key));
         Box::pin(async {
@@ -304,3 +551,29 @@ impl StoreCache<tweet_fetch::UserTimelineHead> for FsCache {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn read_only_cache_writes_nothing() {
+        let dir = std::env::temp_dir()
+            .join(format!("tweet-broadcast-cache-read-only-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut cache = FsCache::new(&dir, &dir.join("remote.toml"), false).await;
+        cache.set_read_only(true);
+
+        let tweet = model::Tweet::new("10", "text");
+        assert_eq!(cache.store(&tweet).await.unwrap(), "10");
+        assert!(!LoadCache::<model::Tweet>::has(&cache, "10").await.unwrap());
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(entries, 0);
+
+        cache.set_read_only(false);
+        cache.store(&tweet).await.unwrap();
+        assert!(LoadCache::<model::Tweet>::has(&cache, "10").await.unwrap());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use tweet_model::cache::*;
+
+use crate::webhook::WebhookTarget;
+
+/// Record of a tweet delivered to a webhook, keyed by the tweet ID and a hash of the webhook URL
+/// and thread.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeliveredMarker {
+    key: String,
+    delivered_at: DateTime<Utc>,
+}
+
+impl CacheItem for DeliveredMarker {
+    fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+fn marker_key(tweet_id: &str, target: &WebhookTarget) -> String {
+    let mut destination = target.url().as_str().to_owned();
+    if let Some(thread_id) = target.thread_id() {
+        destination.push('#');
+        destination.push_str(thread_id);
+    }
+    let digest = ring::digest::digest(&ring::digest::SHA256, destination.as_bytes());
+    let hash = digest.as_ref()[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    format!("{}-{}", tweet_id, hash)
+}
+
+/// Keeps engines from delivering a tweet to a webhook which already received it, e.g. from the
+/// filtered stream and a list watching the same account.
+///
+/// Deliveries are recorded as `DeliveredMarker`s in the cache. A disabled guard lets every
+/// delivery through and records nothing.
+#[derive(Debug)]
+pub struct DedupGuard<'a, Cache> {
+    cache: &'a Cache,
+    enabled: bool,
+}
+
+impl<'a, Cache> DedupGuard<'a, Cache>
+where
+    Cache: LoadCache<DeliveredMarker> + StoreCache<DeliveredMarker>,
+{
+    pub fn new(cache: &'a Cache, enabled: bool) -> Self {
+        Self { cache, enabled }
+    }
+
+    /// Returns whether `tweet_id` was already delivered to `target`.
+    ///
+    /// Lookup errors are logged and treated as not delivered.
+    pub async fn is_delivered(&self, tweet_id: &str, target: &WebhookTarget) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let key = marker_key(tweet_id, target);
+        match LoadCache::<DeliveredMarker>::has(self.cache, &key).await {
+            Ok(true) => {
+                log::debug!(
+                    "Tweet {} was already delivered to {}, skipping",
+                    tweet_id,
+                    tweet_discord::redact_url(target.url()),
+                );
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                log::error!("Failed to look up delivery of tweet {}: {}", tweet_id, e);
+                false
+            }
+        }
+    }
+
+    /// Records that `tweet_id` was delivered to `target`.
+    pub async fn mark_delivered(&self, tweet_id: &str, target: &WebhookTarget) {
+        if !self.enabled {
+            return;
+        }
+        let marker = DeliveredMarker {
+            key: marker_key(tweet_id, target),
+            delivered_at: Utc::now(),
+        };
+        if let Err(e) = self.cache.store(&marker).await {
+            log::error!("Failed to record delivery of tweet {}: {}", tweet_id, e);
+        }
+    }
+}
+
+/// Tweet IDs seen within a short window, to drop payloads which arrive twice in a row, e.g. over
+/// redundant connections during failovers or in the overlap of a backfill.
+///
+/// Holds at most `capacity` IDs, forgetting the oldest ones first.
+#[derive(Debug)]
+pub struct RecentIds {
+    ids: HashSet<String>,
+    order: VecDeque<(String, Instant)>,
+    capacity: usize,
+    window: Duration,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        Self {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+            window,
+        }
+    }
+
+    pub fn set_limits(&mut self, capacity: usize, window: Duration) {
+        self.capacity = capacity;
+        self.window = window;
+        self.expire(Instant::now());
+    }
+
+    /// Records `id` as seen now, returning whether it was already seen within the window.
+    pub fn check(&mut self, id: &str) -> bool {
+        let now = Instant::now();
+        self.expire(now);
+        if self.ids.contains(id) {
+            return true;
+        }
+        if self.capacity > 0 {
+            self.ids.insert(id.to_owned());
+            self.order.push_back((id.to_owned(), now));
+            self.expire(now);
+        }
+        false
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((id, seen_at)) = self.order.front() {
+            if self.order.len() <= self.capacity && now.duration_since(*seen_at) < self.window {
+                break;
+            }
+            self.ids.remove(id);
+            self.order.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MemoryCache;
+
+    fn webhook(thread_id: Option<&str>) -> WebhookTarget {
+        let url = "https://discord.com/api/webhooks/1/hook".parse().unwrap();
+        WebhookTarget::new(url).with_thread_id(thread_id.map(String::from))
+    }
+
+    #[tokio::test]
+    async fn deliveries_are_per_destination() {
+        let cache = MemoryCache::default();
+        let guard = DedupGuard::new(&cache, true);
+        let (channel, thread) = (webhook(None), webhook(Some("5")));
+        guard.mark_delivered("10", &channel).await;
+        assert!(guard.is_delivered("10", &channel).await);
+        // other threads of the channel and other tweets still get it
+        assert!(!guard.is_delivered("10", &thread).await);
+        assert!(!guard.is_delivered("11", &channel).await);
+    }
+
+    #[tokio::test]
+    async fn disabled_guard_records_nothing() {
+        let cache = MemoryCache::default();
+        let target = webhook(None);
+        DedupGuard::new(&cache, false).mark_delivered("10", &target).await;
+        assert!(!DedupGuard::new(&cache, true).is_delivered("10", &target).await);
+
+        DedupGuard::new(&cache, true).mark_delivered("10", &target).await;
+        assert!(!DedupGuard::new(&cache, false).is_delivered("10", &target).await);
+    }
+}
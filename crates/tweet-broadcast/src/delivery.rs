@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{Duration, NaiveDate, Utc};
+use tokio_util::sync::CancellationToken;
+
+use tweet_discord::{DeliveryCounters, WebhookExecutor};
+
+/// Seconds between taking the executor's counters into the daily rollup and writing it.
+const FLUSH_INTERVAL_SECS: u64 = 60;
+
+/// Counters of a day, by redacted destination.
+pub type DailyRollup = BTreeMap<String, DeliveryCounters>;
+
+/// Adds `counters` into `rollup`.
+fn merge_counters<'a>(
+    rollup: &mut DailyRollup,
+    counters: impl IntoIterator<Item = (&'a String, &'a DeliveryCounters)>,
+) {
+    for (destination, counters) in counters {
+        rollup.entry(destination.clone()).or_default().add(counters);
+    }
+}
+
+fn rollup_path(dir: &Path, date: NaiveDate) -> PathBuf {
+    dir.join(format!("{}.json", date.format("%Y-%m-%d")))
+}
+
+async fn read_rollup(path: &Path) -> std::io::Result<Option<DailyRollup>> {
+    match tokio::fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Daily per-destination delivery counters, rolled up from the executor into
+/// `{cache}/delivery/{date}.json`. Days are in UTC.
+#[derive(Debug)]
+pub struct DeliveryStats {
+    dir: PathBuf,
+    executor: WebhookExecutor,
+    today: Mutex<(NaiveDate, DailyRollup)>,
+}
+
+impl DeliveryStats {
+    /// Opens the rollups in `dir`, continuing today's if it exists.
+    pub async fn open(dir: PathBuf, executor: WebhookExecutor) -> Self {
+        let date = Utc::today().naive_utc();
+        let rollup = match read_rollup(&rollup_path(&dir, date)).await {
+            Ok(rollup) => rollup.unwrap_or_default(),
+            Err(e) => {
+                log::error!("Ignoring invalid delivery stats of {}: {}", date, e);
+                Default::default()
+            }
+        };
+        Self {
+            dir,
+            executor,
+            today: Mutex::new((date, rollup)),
+        }
+    }
+
+    /// Takes the executor's counters into today's rollup. Returns the rollup of the previous day
+    /// if the day changed since the last call.
+    fn absorb(&self) -> Option<(NaiveDate, DailyRollup)> {
+        let counters = self.executor.take_counters();
+        let date = Utc::today().naive_utc();
+        let mut today = self.today.lock().unwrap();
+        let mut previous = None;
+        if today.0 != date {
+            previous = Some(std::mem::replace(&mut *today, (date, Default::default())));
+        }
+        merge_counters(&mut today.1, &counters);
+        previous
+    }
+
+    async fn write(&self, date: NaiveDate, rollup: &DailyRollup) {
+        let path = rollup_path(&self.dir, date);
+        let data = serde_json::to_vec(rollup).unwrap();
+        let tmp_path = path.with_extension("tmp");
+        let ret = async {
+            tokio::fs::create_dir_all(&self.dir).await?;
+            tokio::fs::write(&tmp_path, &data).await?;
+            tokio::fs::rename(&tmp_path, &path).await
+        }
+        .await;
+        if let Err(e) = ret {
+            log::error!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+
+    /// Takes the executor's counters and writes the rollups changed by them.
+    pub async fn flush(&self) {
+        if let Some((date, rollup)) = self.absorb() {
+            self.write(date, &rollup).await;
+        }
+        let (date, rollup) = self.today.lock().unwrap().clone();
+        self.write(date, &rollup).await;
+    }
+
+    /// Returns today's counters as of the last flush, for the status endpoint.
+    pub fn today(&self) -> (NaiveDate, DailyRollup) {
+        self.today.lock().unwrap().clone()
+    }
+}
+
+/// Writes the rollups every minute, until `shutdown` is cancelled. The last counters are left
+/// for a final `flush` once deliveries stop.
+pub async fn run(stats: Arc<DeliveryStats>, shutdown: CancellationToken) {
+    let interval = std::time::Duration::from_secs(FLUSH_INTERVAL_SECS);
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = timer.tick() => {},
+        }
+        stats.flush().await;
+    }
+}
+
+/// Reads the rollups of the last `days` days in `dir`, today included, from the oldest. Days
+/// without a rollup are skipped.
+pub async fn read_days(dir: &Path, days: u32) -> std::io::Result<Vec<(NaiveDate, DailyRollup)>> {
+    let today = Utc::today().naive_utc();
+    let mut ret = Vec::new();
+    for offset in (0..days as i64).rev() {
+        let date = today - Duration::days(offset);
+        if let Some(rollup) = read_rollup(&rollup_path(dir, date)).await? {
+            ret.push((date, rollup));
+        }
+    }
+    Ok(ret)
+}
+
+/// Prints `days` as a table, with a total row per destination when there are several days.
+pub fn print_table(days: &[(NaiveDate, DailyRollup)]) {
+    if days.is_empty() {
+        println!("No deliveries recorded.");
+        return;
+    }
+    let width = days
+        .iter()
+        .flat_map(|(_, rollup)| rollup.keys())
+        .map(|destination| destination.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(11);
+    println!(
+        "{:<10}  {:<width$}  {:>8}  {:>8}  {:>12}  {:>12}",
+        "DATE", "DESTINATION", "SENT", "FAILED", "RATE_LIMITED", "BYTES",
+    );
+    let print_row = |date: &str, destination: &str, counters: &DeliveryCounters| {
+        println!(
+            "{:<10}  {:<width$}  {:>8}  {:>8}  {:>12}  {:>12}",
+            date, destination, counters.sent, counters.failed, counters.rate_limited, counters.bytes,
+        );
+    };
+    let mut total = DailyRollup::new();
+    for (date, rollup) in days {
+        let date = date.format("%Y-%m-%d").to_string();
+        for (destination, counters) in rollup {
+            print_row(&date, destination, counters);
+        }
+        merge_counters(&mut total, rollup);
+    }
+    if days.len() > 1 {
+        for (destination, counters) in &total {
+            print_row("total", destination, counters);
+        }
+    }
+}
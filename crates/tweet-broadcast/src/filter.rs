@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+use tweet_model as model;
+
+/// Content filters of a list or user timeline, given as the `filter` table of its config entry.
+///
+/// Retweets are judged by their source tweet, except for `exclude_retweets`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContentFilter {
+    exclude_retweets: bool,
+    exclude_replies: bool,
+    /// Only relays tweets with attached media.
+    require_media: bool,
+    /// Drops tweets containing any of these words, case insensitive.
+    keyword_denylist: Vec<String>,
+    /// Only relays tweets with one of these hashtags, if not empty. Leading `#` is optional.
+    hashtag_allowlist: Vec<String>,
+}
+
+impl ContentFilter {
+    pub fn with_exclude_retweets(mut self, exclude: bool) -> Self {
+        self.exclude_retweets |= exclude;
+        self
+    }
+
+    pub fn with_exclude_replies(mut self, exclude: bool) -> Self {
+        self.exclude_replies |= exclude;
+        self
+    }
+
+    /// Returns whether `tweet` should be relayed.
+    pub fn accepts(&self, tweet: &model::Tweet, includes: &model::ResponseIncludes) -> bool {
+        let source = tweet.get_retweet_source();
+        if self.exclude_retweets && source.is_some() {
+            return false;
+        }
+        let tweet = source
+            .and_then(|id| includes.get_tweet(id))
+            .unwrap_or(tweet);
+
+        if self.exclude_replies && tweet.is_reply() {
+            return false;
+        }
+        if self.require_media && !tweet.has_media() {
+            return false;
+        }
+        if !self.keyword_denylist.is_empty() {
+            let text = tweet.unescaped_text().to_lowercase();
+            if self
+                .keyword_denylist
+                .iter()
+                .any(|keyword| text.contains(&keyword.to_lowercase()))
+            {
+                return false;
+            }
+        }
+        if !self.hashtag_allowlist.is_empty() {
+            let allowed = tweet.entities().hashtags().iter().any(|hashtag| {
+                self.hashtag_allowlist.iter().any(|allowed| {
+                    allowed
+                        .trim_start_matches('#')
+                        .eq_ignore_ascii_case(hashtag.tag())
+                })
+            });
+            if !allowed {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Returns the tweets among `tweets` which pass the filter.
+    pub fn apply<'a>(
+        &self,
+        tweets: &'a [model::Tweet],
+        includes: &model::ResponseIncludes,
+    ) -> Vec<&'a model::Tweet> {
+        tweets
+            .iter()
+            .filter(|tweet| self.accepts(tweet, includes))
+            .collect()
+    }
+}
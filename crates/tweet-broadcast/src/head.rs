@@ -0,0 +1,132 @@
+use chrono::{TimeZone, Utc};
+use eyre::Result;
+
+use tweet_fetch::{ListHead, UserTimelineHead};
+use tweet_model::cache::*;
+
+use crate::cache::FsCache;
+
+/// Milliseconds since the Unix epoch at which tweet ID timestamps start.
+const TWITTER_EPOCH_MS: i64 = 1288834974657;
+
+/// Subcommands of `head`, inspecting and editing the last seen tweet IDs of engines.
+#[derive(Debug, clap::Subcommand)]
+pub enum HeadCommand {
+    /// Print the head.
+    Show(HeadTarget),
+    /// Set the head, so that the next poll fetches tweets newer than the given ID.
+    Set {
+        #[clap(flatten)]
+        target: HeadTarget,
+        /// Tweet ID to continue from.
+        value: String,
+    },
+    /// Remove the head. The next poll is treated as the first one, and relays nothing.
+    Clear(HeadTarget),
+}
+
+impl HeadCommand {
+    /// Returns whether the subcommand changes the cache.
+    pub fn writes(&self) -> bool {
+        !matches!(self, Self::Show(_))
+    }
+}
+
+/// Head to work on; exactly one has to be given.
+#[derive(Debug, clap::Args)]
+pub struct HeadTarget {
+    /// ID of a list.
+    #[clap(long, conflicts_with_all = &["user", "search"])]
+    list: Option<String>,
+    /// ID of a user.
+    #[clap(long, conflicts_with = "search")]
+    user: Option<String>,
+    /// ID of a search term.
+    #[clap(long)]
+    search: Option<String>,
+}
+
+impl HeadTarget {
+    fn name(&self) -> String {
+        match (&self.list, &self.user, &self.search) {
+            (Some(id), _, _) => format!("list {}", id),
+            (_, Some(id), _) => format!("user {}", id),
+            (_, _, Some(id)) => format!("search {}", id),
+            _ => String::from("?"),
+        }
+    }
+
+    async fn load(&self, cache: &FsCache) -> Result<Option<String>> {
+        match (&self.list, &self.user, &self.search) {
+            (Some(id), _, _) => {
+                let head: ListHead = cache.load(id).await?;
+                Ok(head.head().map(|s| s.to_owned()))
+            }
+            (_, Some(id), _) => {
+                let head: UserTimelineHead = cache.load(id).await?;
+                Ok(head.head().map(|s| s.to_owned()))
+            }
+            (_, _, Some(_)) => {
+                eyre::bail!("search heads are kept in memory and start over on restart")
+            }
+            _ => eyre::bail!("give the head with --list, --user or --search"),
+        }
+    }
+
+    async fn store(&self, cache: &FsCache, head: Option<String>) -> Result<()> {
+        match (&self.list, &self.user) {
+            (Some(id), _) => cache.store(&ListHead::new(id.clone(), head)).await?,
+            (_, Some(id)) => cache.store(&UserTimelineHead::new(id.clone(), head)).await?,
+            _ => unreachable!("heads are loaded before they're stored"),
+        };
+        Ok(())
+    }
+}
+
+/// Checks that `value` looks like a tweet ID: a positive integer not from the future.
+fn parse_tweet_id(value: &str) -> Result<String> {
+    let id = value
+        .parse::<u64>()
+        .ok()
+        .filter(|&id| id > 0)
+        .ok_or_else(|| eyre::eyre!("{:?} is not a tweet ID", value))?;
+    let created_at_ms = (id >> 22) as i64 + TWITTER_EPOCH_MS;
+    let created_at = Utc.timestamp_millis(created_at_ms);
+    if created_at > Utc::now() + chrono::Duration::minutes(1) {
+        eyre::bail!("tweet ID {} would be from {}, in the future", id, created_at);
+    }
+    Ok(id.to_string())
+}
+
+fn describe(head: &Option<String>) -> &str {
+    head.as_deref().unwrap_or("(none)")
+}
+
+/// Runs a `head` subcommand. With `dry_run`, changes are printed but not applied.
+pub async fn run(cache: &FsCache, command: &HeadCommand, dry_run: bool) -> Result<()> {
+    let (target, after) = match command {
+        HeadCommand::Show(target) => {
+            let head = target.load(cache).await?;
+            println!("{}: {}", target.name(), describe(&head));
+            return Ok(());
+        }
+        HeadCommand::Set { target, value } => (target, Some(parse_tweet_id(value)?)),
+        HeadCommand::Clear(target) => (target, None),
+    };
+
+    let before = target.load(cache).await?;
+    println!("{}: {} -> {}", target.name(), describe(&before), describe(&after));
+    if dry_run {
+        println!("Dry run, the head wasn't changed.");
+        return Ok(());
+    }
+    target.store(cache, after.clone()).await?;
+    if after.is_none() {
+        eprintln!(
+            "warning: the next poll of {} is treated as the first one; it relays nothing and \
+             only records the latest tweet",
+            target.name(),
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use tokio_util::sync::CancellationToken;
+
+use tweet_fetch::TwitterClient;
+
+use crate::queue::DeliveryQueue;
+use crate::status::{EngineActivity, EngineState, EngineSummary, StatusRegistry};
+use crate::Engine;
+
+/// Rate limit families of the Twitter API used by `engine`, as named by the fetch client.
+fn endpoint_families(engine: &str) -> &'static [&'static str] {
+    match engine.parse::<Engine>() {
+        Ok(Engine::FilteredStream) => &["stream", "tweets"],
+        Ok(Engine::List) => &["lists"],
+        Ok(Engine::User) => &["users"],
+        Ok(Engine::Search) => &["search", "tweets"],
+        Err(_) => &[],
+    }
+}
+
+/// Formats the status line of an engine, in `key=value` form.
+///
+/// Counts are since `previous`, the activity at the last status line, or since startup if there
+/// was none.
+fn format_line(
+    summary: &EngineSummary,
+    previous: Option<&EngineActivity>,
+    queue_len: Option<usize>,
+    rate_limits: &[(&str, Option<(u32, u32)>)],
+) -> String {
+    let (received, relayed) = match previous {
+        Some(previous) => (
+            summary.activity.received.saturating_sub(previous.received),
+            summary.activity.relayed.saturating_sub(previous.relayed),
+        ),
+        None => (summary.activity.received, summary.activity.relayed),
+    };
+    let mut line = format!(
+        "engine={} received={} relayed={}",
+        summary.name, received, relayed,
+    );
+    match summary.state {
+        EngineState::Starting => line.push_str(" state=starting"),
+        EngineState::Ok => line.push_str(" state=ok"),
+        EngineState::Failing(since) => {
+            write!(line, " state=failing since={}", since.to_rfc3339()).unwrap();
+        }
+    }
+    if let Some(tracker_size) = summary.tracker_size {
+        write!(line, " tracker={}", tracker_size).unwrap();
+    }
+    if let Some(queue_len) = queue_len {
+        write!(line, " queue={}", queue_len).unwrap();
+    }
+    for (family, window) in rate_limits {
+        match window {
+            Some((remaining, limit)) => {
+                write!(line, " rate_limit.{}={}/{}", family, remaining, limit).unwrap();
+            }
+            None => write!(line, " rate_limit.{}=-", family).unwrap(),
+        }
+    }
+    if let Some((at, message)) = &summary.last_error {
+        write!(line, " last_error_at={} last_error={:?}", at.to_rfc3339(), message).unwrap();
+    }
+    line
+}
+
+/// Logs a status line for each engine every `interval`, until `shutdown` is cancelled. Rate limits
+/// are read from the client of each engine in `clients`, by engine name.
+pub async fn run(
+    interval: std::time::Duration,
+    status: Arc<StatusRegistry>,
+    clients: HashMap<String, TwitterClient>,
+    queue: Option<DeliveryQueue>,
+    shutdown: CancellationToken,
+) {
+    let mut previous = BTreeMap::<String, EngineActivity>::new();
+    let mut timer = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = timer.tick() => {},
+        }
+
+        let queue_len = match &queue {
+            Some(queue) => match queue.len().await {
+                Ok(len) => Some(len),
+                Err(e) => {
+                    log::error!("Failed to read delivery queue: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let now = SystemTime::now();
+        for summary in status.engine_summaries() {
+            let budget = clients.get(&summary.name).map(|client| client.budget());
+            let rate_limits = endpoint_families(&summary.name)
+                .iter()
+                .map(|&family| (family, budget.and_then(|budget| budget.remaining(family, now))))
+                .collect::<Vec<_>>();
+            let line = format_line(&summary, previous.get(&summary.name), queue_len, &rate_limits);
+            log::info!("Status: {}", line);
+            previous.insert(summary.name, summary.activity);
+        }
+    }
+}
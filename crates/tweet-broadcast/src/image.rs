@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use tweet_model as model;
+
+/// Number of concurrent image downloads.
+const MAX_CONCURRENT_DOWNLOADS: usize = 4;
+/// Attempts of a single download before it's recorded as failed.
+const MAX_ATTEMPTS: u32 = 3;
+/// Batches a failed download is retried with before it's given up.
+const MAX_RETRY_ROUNDS: u32 = 3;
+
+/// Caches which keep media files, in addition to their metadata.
+pub trait SaveImages {
+    /// Saves the photos among `media` in the background.
+    fn save_images<'a>(&self, media: impl IntoIterator<Item = &'a model::Media>);
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImageError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] #[source] reqwest::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] #[source] std::io::Error),
+}
+
+impl ImageError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Http(e) => e.status().map(|s| s.is_server_error()).unwrap_or(true),
+            Self::Io(_) => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FailedDownload {
+    url: reqwest::Url,
+    rounds: u32,
+}
+
+/// Downloads original photos into `images/<media_key>.<ext>`.
+///
+/// Downloads which fail are retried along with the next batch.
+#[derive(Debug, Clone)]
+pub struct ImageSaver {
+    dir: PathBuf,
+    client: reqwest::Client,
+    permits: Arc<tokio::sync::Semaphore>,
+    failed: Arc<Mutex<HashMap<String, FailedDownload>>>,
+}
+
+impl ImageSaver {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            client: Default::default(),
+            permits: Arc::new(tokio::sync::Semaphore::new(MAX_CONCURRENT_DOWNLOADS)),
+            failed: Default::default(),
+        }
+    }
+
+    /// Spawns downloads of the photos among `media`, and of previously failed downloads.
+    pub fn save<'a>(&self, media: impl IntoIterator<Item = &'a model::Media>) {
+        let mut downloads = std::mem::take(&mut *self.failed.lock().unwrap());
+        for media in media {
+            if media.media_type() != model::MediaType::Photo {
+                continue;
+            }
+            if let Some(url) = media.url_orig() {
+                downloads.entry(media.key().to_owned()).or_insert(FailedDownload { url, rounds: 0 });
+            }
+        }
+
+        for (media_key, download) in downloads {
+            let saver = self.clone();
+            tokio::spawn(async move {
+                let _permit = saver.permits.acquire().await;
+                if let Err(e) = saver.download(&media_key, &download.url).await {
+                    let rounds = download.rounds + 1;
+                    if rounds >= MAX_RETRY_ROUNDS {
+                        log::warn!("Giving up saving image {}: {}", media_key, e);
+                    } else {
+                        log::debug!("Failed to save image {}, will retry: {}", media_key, e);
+                        saver.failed.lock().unwrap().insert(
+                            media_key,
+                            FailedDownload {
+                                url: download.url,
+                                rounds,
+                            },
+                        );
+                    }
+                }
+            });
+        }
+    }
+
+    /// Downloads an image unless it's already saved, retrying transient errors.
+    async fn download(&self, media_key: &str, url: &reqwest::Url) -> Result<(), ImageError> {
+        let path = self.dir.join(format!("{}.{}", media_key, extension(url)));
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Ok(());
+        }
+
+        let mut attempt = 1;
+        let data = loop {
+            let ret = async {
+                let res = self.client.get(url.clone()).send().await?.error_for_status()?;
+                Ok::<_, ImageError>(res.bytes().await?)
+            }
+            .await;
+            match ret {
+                Ok(data) => break data,
+                Err(e) if attempt < MAX_ATTEMPTS && e.is_retryable() => {
+                    tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        // write to a temporary file first, so that partial files are never seen as saved
+        let tmp_path = self.dir.join(format!(".{}.tmp", media_key));
+        tokio::fs::write(&tmp_path, &data).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        log::debug!("Saved image {}", path.display());
+        Ok(())
+    }
+}
+
+fn extension(url: &reqwest::Url) -> &str {
+    url.path_segments()
+        .and_then(|mut s| s.next_back())
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, ext)| ext)
+        .filter(|ext| !ext.is_empty())
+        .unwrap_or("jpg")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    const FIXTURE: &[u8] = b"\x89PNG fixture";
+
+    /// HTTP server on localhost answering every request with `FIXTURE` and `status`.
+    struct FixtureServer {
+        addr: std::net::SocketAddr,
+        status: Arc<AtomicU16>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    impl FixtureServer {
+        fn start() -> Self {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let status = Arc::new(AtomicU16::new(200));
+            let requests = Arc::new(AtomicUsize::new(0));
+            std::thread::spawn({
+                let (status, requests) = (status.clone(), requests.clone());
+                move || {
+                    for stream in listener.incoming() {
+                        let mut stream = stream.unwrap();
+                        let mut request = Vec::new();
+                        let mut buf = [0u8; 1024];
+                        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                            let len = stream.read(&mut buf).unwrap();
+                            if len == 0 {
+                                break;
+                            }
+                            request.extend_from_slice(&buf[..len]);
+                        }
+                        requests.fetch_add(1, Ordering::SeqCst);
+                        let head = format!(
+                            "HTTP/1.1 {} Fixture\r\nConnection: close\r\n\
+                             Content-Length: {}\r\n\r\n",
+                            status.load(Ordering::SeqCst),
+                            FIXTURE.len(),
+                        );
+                        stream.write_all(head.as_bytes()).unwrap();
+                        stream.write_all(FIXTURE).unwrap();
+                    }
+                }
+            });
+            Self {
+                addr,
+                status,
+                requests,
+            }
+        }
+
+        fn url(&self, name: &str) -> reqwest::Url {
+            format!("http://{}/media/{}", self.addr, name).parse().unwrap()
+        }
+
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("tweet-broadcast-images-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn file_names(dir: &std::path::Path) -> Vec<String> {
+        let mut names = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Waits for background downloads until `done` holds.
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        for _ in 0..500 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out waiting for downloads");
+    }
+
+    #[tokio::test]
+    async fn existing_images_are_skipped() {
+        let server = FixtureServer::start();
+        let dir = temp_dir("existing");
+        std::fs::write(dir.join("3_1.png"), b"saved").unwrap();
+        let saver = ImageSaver::new(&dir);
+        saver.download("3_1", &server.url("3_1.png")).await.unwrap();
+        assert_eq!(server.requests(), 0);
+        assert_eq!(std::fs::read(dir.join("3_1.png")).unwrap(), b"saved");
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn images_are_renamed_into_place() {
+        let server = FixtureServer::start();
+        let dir = temp_dir("rename");
+        let saver = ImageSaver::new(&dir);
+        saver.download("3_1", &server.url("3_1.png?name=orig")).await.unwrap();
+        assert_eq!(server.requests(), 1);
+        // no temporary file left behind
+        assert_eq!(file_names(&dir), ["3_1.png"]);
+        assert_eq!(std::fs::read(dir.join("3_1.png")).unwrap(), FIXTURE);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_are_retried_next_batch() {
+        let server = FixtureServer::start();
+        let dir = temp_dir("retry");
+        let saver = ImageSaver::new(&dir);
+        let photo = model::Media::new("3_1", model::MediaType::Photo, 1, 1)
+            .with_url(server.url("3_1.png"));
+
+        // not retried within the batch, as client errors aren't transient
+        server.status.store(404, Ordering::SeqCst);
+        saver.save([&photo]);
+        wait_until(|| saver.failed.lock().unwrap().contains_key("3_1")).await;
+        assert_eq!(server.requests(), 1);
+        assert!(file_names(&dir).is_empty());
+
+        server.status.store(200, Ordering::SeqCst);
+        saver.save(std::iter::empty());
+        wait_until(|| dir.join("3_1.png").exists()).await;
+        assert_eq!(server.requests(), 2);
+        assert!(saver.failed.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,183 +1,275 @@
 use std::collections::HashMap;
-use std::path::Path;
 
 use eyre::Result;
+use futures_util::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 
 use tweet_fetch::{ListHead, TwitterClient};
-use tweet_model::{
-    self as model,
-    cache::*,
-};
+use tweet_model::{self as model, cache::*, trace::Span};
+
+use crate::dedup::DeliveredMarker;
+use crate::image::SaveImages;
+use crate::filter::ContentFilter;
+use crate::poll::{self, PollResult, SourceMeta, TweetSource};
+use crate::reload::EngineConfig;
+use crate::router::RouterPool;
+use crate::settings::{self, PollSettings};
+use crate::sink::SinkFactory;
+use crate::webhook::WebhookTarget;
+use crate::Engine;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ListMeta {
     #[serde(default)]
     cache_tweets: bool,
-    webhooks: Vec<reqwest::Url>,
+    #[serde(default)]
+    filter: ContentFilter,
+    /// Only relays tweets scoring at least this much, if given.
+    score_threshold: Option<f64>,
+    /// Routes tweets with the route scripts instead of relaying them to `webhooks`, which are
+    /// used for tweets failing to route and for catch-up messages.
+    #[serde(default)]
+    use_router: bool,
+    /// Passed to the route scripts as the tag of the matching rule.
+    router_tag: Option<String>,
+    webhooks: Vec<WebhookTarget>,
 }
 
 impl ListMeta {
-    pub fn webhooks(&self) -> &[reqwest::Url] {
+    /// Returns whether `tweet` scores high enough to be relayed.
+    ///
+    /// Tweets which can't be scored are relayed.
+    fn meets_threshold(
+        &self,
+        scorer: &dyn tweet_route::Scorer,
+        tweet: &model::Tweet,
+        includes: &model::ResponseIncludes,
+    ) -> bool {
+        let threshold = match self.score_threshold {
+            Some(threshold) => threshold,
+            None => return true,
+        };
+        match tweet_route::score_tweet(scorer, tweet, includes) {
+            Some(score) if score < threshold => {
+                log::debug!(
+                    "Tweet {}: score {:.4} below threshold {}",
+                    tweet.id(),
+                    score,
+                    threshold,
+                );
+                false
+            }
+            Some(_) => true,
+            None => {
+                log::warn!(
+                    "Tweet {}: author or metrics missing, relaying without score",
+                    tweet.id(),
+                );
+                true
+            }
+        }
+    }
+}
+
+impl SourceMeta for ListMeta {
+    fn webhooks(&self) -> &[WebhookTarget] {
         &self.webhooks
     }
+
+    fn filter<'a>(
+        &self,
+        scorer: &dyn tweet_route::Scorer,
+        tweets: &'a [model::Tweet],
+        includes: &model::ResponseIncludes,
+    ) -> Vec<&'a model::Tweet> {
+        let span = model::span!("filter", tweets = tweets.len());
+        let _guard = span.enter();
+        self.filter
+            .apply(tweets, includes)
+            .into_iter()
+            .filter(|tweet| self.meets_threshold(scorer, tweet, includes))
+            .collect()
+    }
+
+    fn cache_tweets(&self) -> bool {
+        self.cache_tweets
+    }
+
+    fn save_images(&self) -> bool {
+        true
+    }
+
+    fn router_tags(&self) -> Option<Vec<String>> {
+        self.use_router
+            .then(|| self.router_tag.iter().cloned().collect())
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ListsConfig {
+    #[serde(default)]
+    settings: PollSettings,
     lists: HashMap<String, ListMeta>,
 }
 
-impl ListsConfig {
-    pub async fn from_config(config: impl AsRef<Path>) -> Result<Self> {
-        let data = tokio::fs::read(config).await?;
-        let config = toml::from_slice::<ListsConfig>(&data)?;
-        Ok(config)
+impl EngineConfig for ListsConfig {
+    fn validate(&self) -> Result<()> {
+        settings::LIST_POLL
+            .check_key("settings.poll_interval_secs", self.settings.poll_interval_secs)?;
+        for (id, meta) in &self.lists {
+            if id.parse::<u64>().is_err() {
+                eyre::bail!("lists.{}: list ID is not numeric", id);
+            }
+            for (idx, webhook) in meta.webhooks.iter().enumerate() {
+                webhook
+                    .validate()
+                    .map_err(|e| eyre::eyre!("lists.{}.webhooks[{}]: {}", id, idx, e))?;
+            }
+            if let Some(threshold) = meta.score_threshold {
+                if !threshold.is_finite() || threshold <= 0.0 {
+                    eyre::bail!(
+                        "lists.{}.score_threshold: must be a positive number, got {}",
+                        id,
+                        threshold,
+                    );
+                }
+            }
+            if meta.router_tag.is_some() && !meta.use_router {
+                eyre::bail!("lists.{}.router_tag: set without use_router", id);
+            }
+        }
+        Ok(())
+    }
+
+    fn webhooks(&self) -> Vec<(String, &WebhookTarget)> {
+        self.lists
+            .iter()
+            .flat_map(|(id, meta)| {
+                meta.webhooks.iter().enumerate().map(move |(idx, webhook)| {
+                    (format!("lists.{}.webhooks[{}]", id, idx), webhook)
+                })
+            })
+            .collect()
     }
 }
 
 impl ListsConfig {
+    pub fn settings(&self) -> &PollSettings {
+        &self.settings
+    }
+
     pub fn lists(&self) -> impl Iterator<Item = (&String, &ListMeta)> {
         self.lists.iter()
     }
 }
 
-async fn send_first_time_webhook(
-    client: &reqwest::Client,
-    webhook_url: &reqwest::Url,
-    list_id: &str,
-) -> Result<()> {
-    let message = format!("List `{}` initialized", list_id,);
-    let payload = serde_json::json!({
-        "username": "tweet-broadcast",
-        "content": message,
-    });
-
-    tweet_discord::execute_webhook(client, webhook_url, &payload).await?;
-    Ok(())
-}
+impl TweetSource for ListHead {
+    type Meta = ListMeta;
 
-async fn send_catchup_webhook(
-    client: &reqwest::Client,
-    webhook_url: &reqwest::Url,
-    list_id: &str,
-    tweet_count: usize,
-) -> Result<()> {
-    let message = format!(
-        "Skipping {} tweet{} of list `{}` during list catch-up",
-        tweet_count,
-        if tweet_count == 1 { "" } else { "s" },
-        list_id,
-    );
-    let payload = serde_json::json!({
-        "username": "tweet-broadcast",
-        "content": message,
-    });
-
-    tweet_discord::execute_webhook(client, webhook_url, &payload).await?;
-    Ok(())
+    const ENGINE: Engine = Engine::List;
+    const KIND: &'static str = "list";
+    const NAME: &'static str = "list";
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn span(id: &str) -> Span {
+        model::span!("list_poll", engine = "list", list_id = %id)
+    }
+
+    fn is_new(&self) -> bool {
+        self.head().is_none()
+    }
+
+    fn poll<'a>(
+        &'a mut self,
+        client: &'a TwitterClient,
+        catchup: bool,
+    ) -> BoxFuture<'a, Result<PollResult, tweet_fetch::Error>> {
+        Box::pin(self.load_and_update(client, catchup))
+    }
 }
 
-pub async fn run_list_once<Cache: LoadCache<ListHead> + StoreCache<ListHead> + StoreCache<model::Tweet>>(
+pub async fn run_list_once<Cache: LoadCache<ListHead> + StoreCache<ListHead> + StoreCache<model::Tweet> + LoadCache<DeliveredMarker> + StoreCache<DeliveredMarker> + SaveImages>(
     client: &TwitterClient,
+    sinks: &SinkFactory,
+    router: &RouterPool,
     config: &ListsConfig,
     catchup: bool,
+    interval: std::time::Duration,
     cache: &Cache,
-) {
-    use futures_util::{StreamExt, TryStreamExt};
-
-    let webhook_client = reqwest::Client::builder().build().unwrap();
-
-    let stream = futures_util::stream::FuturesUnordered::new();
-    for (id, meta) in config.lists() {
-        let webhook_client = &webhook_client;
-        let fut = async move {
-            let ret = async {
-                let mut head = cache.load(id).await?;
-                let first_time = head.head().is_none();
-                let tweets = head.load_and_update(client, catchup).await?;
-                cache.store(&head).await?;
-                Ok::<_, eyre::Error>((tweets, first_time))
-            }
-            .await;
-            let (tweets, first_time) = match ret {
-                Ok(tweets) => tweets,
-                Err(e) => {
-                    log::error!("List fetch for {} failed: {}", id, e);
-                    let mut event = sentry::event_from_error(AsRef::<dyn std::error::Error + 'static>::as_ref(&e));
-                    event.tags.insert(String::from("id"), id.into());
-                    sentry::capture_event(event);
-                    return;
-                }
-            };
-            let model::ResponseItem {
-                data: tweets,
-                includes,
-                ..
-            } = &tweets;
-
-            let cache_fut = futures_util::stream::FuturesUnordered::new();
-            if meta.cache_tweets {
-                for tweet in tweets {
-                    cache_fut.push(async move {
-                        cache.store(tweet).await?;
-                        Ok::<_, eyre::Error>(())
-                    });
-                }
-            }
+) -> Result<()> {
+    poll::run_source_once::<ListHead, _>(
+        client,
+        sinks,
+        Some(router),
+        config.lists(),
+        catchup,
+        interval,
+        cache,
+    )
+    .await
+}
 
-            let webhooks_fut = futures_util::stream::FuturesUnordered::new();
-            for webhook in meta.webhooks() {
-                let webhook_client = &webhook_client;
-                webhooks_fut.push(async move {
-                    if catchup && tweets.len() > 5 {
-                        send_catchup_webhook(
-                            webhook_client,
-                            webhook,
-                            id,
-                            tweets.len(),
-                        )
-                        .await?;
-                    } else if first_time {
-                        send_first_time_webhook(webhook_client, webhook, id).await?;
-                    } else {
-                        for tweet in tweets {
-                            tweet_discord::send_webhook(
-                                webhook_client,
-                                webhook,
-                                tweet,
-                                includes,
-                            )
-                            .await?;
-                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                        }
-                    }
-                    Ok::<_, eyre::Error>(())
-                });
-            }
+#[cfg(test)]
+mod tests {
+    use model::score::ScoreInput;
 
-            let (cache_ret, webhooks_ret) = futures_util::join!(
-                cache_fut.try_collect::<()>(),
-                webhooks_fut.try_collect::<()>(),
-            );
-            if let Err(e) = cache_ret {
-                log::error!("Failed to cache tweets: {}", e);
-                let mut event = sentry::event_from_error(AsRef::<dyn std::error::Error + 'static>::as_ref(&e));
-                event.tags.insert(String::from("list_id"), id.into());
-                sentry::capture_event(event);
-                return;
-            }
-            if let Err(e) = webhooks_ret {
-                log::error!("Failed to send webhook for {}: {}", id, e);
-                let mut event = sentry::event_from_error(AsRef::<dyn std::error::Error + 'static>::as_ref(&e));
-                event.tags.insert(String::from("id"), id.into());
-                sentry::capture_event(event);
-                return;
-            }
+    use super::*;
+
+    /// Scores tweets by their likes, to pick which of them meet the threshold.
+    #[derive(Debug)]
+    struct LikeScorer;
+
+    impl tweet_route::Scorer for LikeScorer {
+        fn score(&self, input: &ScoreInput<'_>) -> f64 {
+            input.tweet_metrics.like_count as f64
+        }
+    }
 
-            log::debug!("List fetch for {} successful", id);
+    fn tweet(id: &str, author_id: &str, likes: u64) -> model::Tweet {
+        let metrics = model::TweetPublicMetrics {
+            reply_count: 0,
+            retweet_count: 0,
+            quote_count: 0,
+            like_count: likes,
         };
-        stream.push(fut);
+        model::Tweet::new(id, "text")
+            .with_author_id(author_id)
+            .with_created_at(chrono::Utc::now())
+            .with_metrics(metrics)
+    }
+
+    #[test]
+    fn threshold_uses_the_given_scorer() {
+        let meta = toml::from_str::<ListMeta>("score_threshold = 5.0\nwebhooks = []").unwrap();
+        let mut includes = model::ResponseIncludes::default();
+        let author_metrics = model::UserPublicMetrics {
+            followers_count: 0,
+            following_count: 0,
+            tweet_count: 0,
+            listed_count: 0,
+        };
+        includes.push_user(model::User::new("1", "Author", "author").with_metrics(author_metrics));
+        let tweets = [
+            tweet("10", "1", 4),
+            tweet("11", "1", 5),
+            tweet("12", "1", 100),
+            // the author is missing, so it can't be scored
+            tweet("13", "2", 0),
+        ];
+
+        let ids = |relayed: Vec<&model::Tweet>| {
+            relayed
+                .into_iter()
+                .map(|tweet| tweet.id().to_owned())
+                .collect::<Vec<_>>()
+        };
+        let relayed = meta.filter(&LikeScorer, &tweets, &includes);
+        assert_eq!(ids(relayed), ["11", "12", "13"]);
+
+        let meta = toml::from_str::<ListMeta>("webhooks = []").unwrap();
+        let relayed = meta.filter(&LikeScorer, &tweets, &includes);
+        assert_eq!(ids(relayed).len(), tweets.len());
     }
-    stream.collect::<()>().await;
 }
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error("cache directory is in use by {}", describe_owner(.0))]
+    Held(Option<u32>),
+    #[error("I/O error: {0}")]
+    Io(#[from] #[source] std::io::Error),
+}
+
+fn describe_owner(pid: &Option<u32>) -> String {
+    match pid {
+        Some(pid) => format!("process {}", pid),
+        None => String::from("another process"),
+    }
+}
+
+/// Lock file in the cache directory, keeping other instances from writing to the same cache.
+///
+/// The lock is an exclusive `flock` on the file, released by the kernel when the owner exits,
+/// so locks of crashed instances need no takeover. It works across containers sharing the
+/// volume, where every instance may run as PID 1. The file holds the PID of the owner for error
+/// messages only, and stays in place when the lock is dropped, as removing it would let two
+/// instances lock different files.
+#[derive(Debug)]
+pub struct CacheLock {
+    _file: File,
+}
+
+impl CacheLock {
+    pub fn acquire(cache_dir: &Path) -> Result<Self, LockError> {
+        let path = cache_dir.join("lock");
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // the owner's PID must stay readable until the lock is ours
+            .truncate(false)
+            .open(&path)?;
+        // SAFETY: `file` owns the descriptor for the duration of the call
+        let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if ret != 0 {
+            let e = std::io::Error::last_os_error();
+            if e.kind() == std::io::ErrorKind::WouldBlock {
+                let mut owner = String::new();
+                file.read_to_string(&mut owner).ok();
+                return Err(LockError::Held(owner.trim().parse().ok()));
+            }
+            return Err(e.into());
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("tweet-broadcast-lock-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn acquire_writes_pid() {
+        let dir = temp_dir("acquire");
+        let lock = CacheLock::acquire(&dir).unwrap();
+        let owner = std::fs::read_to_string(dir.join("lock")).unwrap();
+        assert_eq!(owner.trim(), std::process::id().to_string());
+        drop(lock);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn contended_lock_is_held() {
+        let dir = temp_dir("contention");
+        let lock = CacheLock::acquire(&dir).unwrap();
+        // locks are per open file, so the same process can't lock twice either
+        match CacheLock::acquire(&dir) {
+            Err(LockError::Held(Some(pid))) => assert_eq!(pid, std::process::id()),
+            ret => panic!("expected the lock to be held, got {:?}", ret),
+        }
+        drop(lock);
+        CacheLock::acquire(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn stale_lock_is_taken_over() {
+        let dir = temp_dir("stale");
+        // left behind by an instance which exited, or one in another container with our PID
+        std::fs::write(dir.join("lock"), format!("{}\n", std::process::id())).unwrap();
+        let _lock = CacheLock::acquire(&dir).unwrap();
+        std::fs::write(dir.join("lock"), "garbage").unwrap();
+        assert!(matches!(CacheLock::acquire(&dir), Err(LockError::Held(None))));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
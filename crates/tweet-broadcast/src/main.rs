@@ -1,19 +1,56 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 use clap::Parser;
+use sentry::SentryFutureExt;
 use tokio::signal::unix as unix_signal;
+use tokio_util::sync::CancellationToken;
 
 use tweet_fetch::TwitterClient;
+use tweet_model::trace::TraceExt;
 use tweet_route::Router;
 
+mod active_hours;
+mod admin;
+mod batch;
 mod cache;
+mod dedup;
+mod delivery;
+mod filter;
+mod head;
+mod heartbeat;
 mod image;
 mod list;
+mod lock;
+mod metrics;
+mod migrate;
+mod parse;
+mod poll;
+mod queue;
+mod reload;
+mod router;
+mod replay;
+mod report;
+mod rules;
 mod search;
+mod settings;
+mod sink;
+mod status;
+mod summary;
 mod stream;
+mod supervisor;
+mod tokens;
+#[cfg(test)]
+mod testing;
 mod user;
+mod validate;
+mod watchdog;
+mod webhook;
 
-#[derive(Debug, PartialEq, Eq, Hash, strum::EnumString, strum::Display)]
+/// How long engines are given to finish in-flight deliveries on shutdown.
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, strum::EnumString, strum::Display)]
 #[strum(serialize_all = "snake_case")]
 enum Engine {
     FilteredStream,
@@ -25,48 +62,478 @@ enum Engine {
 #[derive(Debug, Parser)]
 #[clap(version)]
 struct Args {
-    #[clap(short, long, env = "TWITTER_CACHE", default_value = "./.tweets")]
+    #[clap(flatten)]
+    common: CommonArgs,
+    #[clap(flatten)]
+    run: RunArgs,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Flags shared by every subcommand.
+#[derive(Debug, clap::Args)]
+struct CommonArgs {
+    #[clap(short, long, env = "TWITTER_CACHE", default_value = "./.tweets", global = true)]
     cache: std::path::PathBuf,
-    #[clap(long, env = "TWITTER_NO_SAVE_IMAGES")]
+    /// Directory of configs and route scripts, which may be read-only. Configs missing from it
+    /// are read from the cache directory.
+    #[clap(long, env = "TWEET_CONFIG_DIR", global = true)]
+    config_dir: Option<std::path::PathBuf>,
+    #[clap(long, env = "TWITTER_NO_SAVE_IMAGES", global = true)]
     no_save_images: bool,
-    #[clap(short, long = "engine")]
+    /// Fetch and route as usual, but only log deliveries and don't write to the cache.
+    #[clap(long, env = "TWITTER_DRY_RUN", global = true)]
+    dry_run: bool,
+    /// Run even if another instance is using the cache directory.
+    #[clap(long, env = "TWITTER_ALLOW_SHARED_CACHE", global = true)]
+    allow_shared_cache: bool,
+    #[clap(short, long = "engine", global = true)]
     engines: Vec<Engine>,
+    /// Route scripts for the filtered stream, run in order with their routes concatenated.
+    /// Relative paths are resolved against the config directory if given.
+    #[clap(long = "route-script", default_value = "route.js", global = true)]
+    route_scripts: Vec<std::path::PathBuf>,
+    /// Tag attached to every Sentry event, as `key=value`. Can be given multiple times.
+    #[clap(long = "sentry-tag", parse(try_from_str = report::parse_tag), global = true)]
+    sentry_tags: Vec<(String, String)>,
+    /// Don't report errors of parsing API responses or cached files to Sentry.
+    #[clap(long, env = "SENTRY_DROP_PARSE_ERRORS", global = true)]
+    sentry_drop_parse_errors: bool,
+}
+
+/// Flags of `run`, also accepted without a subcommand.
+#[derive(Debug, clap::Args)]
+struct RunArgs {
+    /// Only log the Discord messages which would be deleted for deleted tweets.
+    #[clap(long, env = "TWITTER_DELETION_DRY_RUN", global = true)]
+    deletion_dry_run: bool,
+    /// Seconds between list fetches, overriding `poll_interval_secs` of the list config.
+    #[clap(long, env = "TWITTER_LIST_INTERVAL", global = true)]
+    list_interval: Option<u64>,
+    /// Seconds between user timeline fetches, overriding `poll_interval_secs` of the user config.
+    /// User timelines have their own rate limit, separate from lists.
+    #[clap(long, env = "TWITTER_USER_INTERVAL", global = true)]
+    user_interval: Option<u64>,
+    /// Seconds between search term fetches, overriding `poll_interval_secs` of the search config.
+    #[clap(long, env = "TWITTER_SEARCH_INTERVAL", global = true)]
+    search_interval: Option<u64>,
+    /// Seconds between trending tweet updates, overriding `tracker_interval_secs` of the search
+    /// config.
+    #[clap(long, env = "TWITTER_SEARCH_TRACKER_INTERVAL", global = true)]
+    search_tracker_interval: Option<u64>,
+    /// Address to serve `/healthz`, `/status` and `/metrics` on, e.g. `127.0.0.1:8080`.
+    #[clap(long, env = "TWITTER_STATUS_ADDR", global = true)]
+    status_addr: Option<std::net::SocketAddr>,
+    /// Minutes between status lines logged for each engine. 0 disables them.
+    #[clap(long, env = "TWITTER_STATUS_LOG_INTERVAL", default_value = "15", global = true)]
+    status_log_interval: u64,
+    /// Webhook notified of operational problems, e.g. route scripts which failed to reload.
+    #[clap(long, env = "TWITTER_ADMIN_WEBHOOK", global = true)]
+    admin_webhook: Option<reqwest::Url>,
+    /// Engines which deliver tweets even to webhooks which already received them from another
+    /// engine.
+    #[clap(long = "no-dedup", global = true)]
+    no_dedup: Vec<Engine>,
+    /// Route filtered stream tweets by rule tag with `stream/config.toml`, without route scripts.
+    /// This is the default if none of the route scripts exist.
+    #[clap(long, global = true)]
+    no_router: bool,
+    /// Number of threads routing tweets of the filtered stream and lists with the route scripts,
+    /// each with its own isolate.
+    #[clap(long, env = "TWITTER_ROUTER_THREADS", default_value = "1", global = true)]
+    router_threads: usize,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Run the enabled engines. This is the default.
+    Run,
+    /// Check engine configs and route scripts, without connecting to Twitter.
+    ///
+    /// Checks the configs of the given engines, or every config present if none is given.
+    ValidateConfig {
+        /// Also fetch every configured webhook to check that it exists.
+        #[clap(long)]
+        probe_webhooks: bool,
+    },
+    /// Print the number of cached tweets, users and media.
+    CacheStats,
+    /// Delete cached items last written before a cutoff. With --dry-run, only count them.
+    ///
+    /// Heads and configs are never deleted. Safe to run while another instance is running.
+    CacheGc {
+        /// Namespaces to collect, e.g. `tweets`. Every collectable namespace if not given.
+        #[clap(long = "namespace")]
+        namespaces: Vec<String>,
+        /// Delete items older than this, e.g. `30d` or `12h`.
+        #[clap(long, default_value = "30d", parse(try_from_str = cache::parse_age))]
+        older_than: std::time::Duration,
+    },
+    /// Convert routed tweet metadata of the legacy `meta/` layout, so that the tweets are not
+    /// relayed again. With --dry-run, only reports what would be converted.
+    MigrateCache {
+        /// Remove legacy files once converted. Files which fail to convert are kept.
+        #[clap(long)]
+        remove_legacy: bool,
+    },
+    /// Route cached tweets again with the current route scripts, e.g. after fixing a broken one.
+    Replay {
+        /// Replay tweets newer than this ID.
+        #[clap(long)]
+        since: u64,
+        /// Replay tweets as matching a rule with this tag, skipping tweets relayed with other
+        /// tags.
+        #[clap(long)]
+        tag: Option<String>,
+        /// Replay tweets which were already relayed.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Print the number of messages sent to each webhook per day, from the newest days.
+    DeliveryStats {
+        /// Number of days to print, today included.
+        #[clap(long, default_value = "7")]
+        days: u32,
+    },
+    /// Manage the rules of the filtered stream. With --dry-run, changes are only validated.
+    Rules {
+        #[clap(subcommand)]
+        command: rules::RulesCommand,
+        /// Print JSON instead of tables.
+        #[clap(long, global = true)]
+        json: bool,
+    },
+    /// Show or edit the last seen tweet ID of a list, user or search term. With --dry-run,
+    /// changes are only printed.
+    Head {
+        #[clap(subcommand)]
+        command: head::HeadCommand,
+    },
+    /// Parse captured API responses with the models, printing a summary of each or the error
+    /// with the path of the offending field.
+    ///
+    /// Reads JSON documents, e.g. one stream line per line, from the file or stdin.
+    Parse {
+        /// Kind of the responses: stream, list, search or tweet. Detected from each document's
+        /// shape if not given.
+        #[clap(long)]
+        kind: Option<parse::PayloadKind>,
+        /// Print the parsed models instead of summaries.
+        #[clap(long)]
+        debug: bool,
+        file: Option<std::path::PathBuf>,
+    },
+}
+
+/// Loads route scripts, run in the given order, scoring payloads with `scorer`.
+async fn load_router(
+    route_scripts: &[std::path::PathBuf],
+    scorer: Arc<dyn tweet_route::Scorer>,
+) -> eyre::Result<Router> {
+    let mut scripts = Vec::new();
+    for path in route_scripts {
+        let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+        let script = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| eyre::eyre!("{}: {}", path.display(), e))?;
+        scripts.push((name, script));
+    }
+    let scripts = scripts.iter().map(|(name, script)| (&**name, &**script));
+    let mut router = Router::with_scripts(128 * 1024 * 1024, scripts).map_err(|e| {
+        if let Some(js_error) = e.js_error() {
+            log::error!("Route script stack trace:\n{}", js_error.format_stack());
+        }
+        eyre::Report::from(e)
+    })?;
+    router.set_scorer(scorer);
+    Ok(router)
 }
 
 #[tokio::main]
 async fn main() {
     let Args {
-        cache: cache_dir,
-        no_save_images,
-        mut engines,
+        common: CommonArgs {
+            cache: cache_dir,
+            config_dir,
+            no_save_images,
+            dry_run,
+            allow_shared_cache,
+            mut engines,
+            route_scripts,
+            sentry_tags,
+            sentry_drop_parse_errors,
+        },
+        run: RunArgs {
+            deletion_dry_run,
+            list_interval,
+            user_interval,
+            search_interval,
+            search_tracker_interval,
+            status_addr,
+            status_log_interval,
+            admin_webhook,
+            no_dedup,
+            no_router,
+            router_threads,
+        },
+        command,
     } = Args::parse();
 
+    env_logger::init();
+
+    let config_dir = reload::ConfigDir::new(config_dir, cache_dir.clone());
+    let route_scripts = route_scripts
+        .iter()
+        .map(|path| config_dir.script_path(path))
+        .collect::<Vec<_>>();
+    let remote_config_path = config_dir.path("remote.toml");
+
+    let platform = v8::Platform::new(0, false).make_shared();
+    v8::V8::initialize_platform(platform);
+    v8::V8::initialize();
+
+    match command {
+        Some(Command::ValidateConfig { probe_webhooks }) => {
+            let ok =
+                validate::validate_config(&config_dir, &engines, &route_scripts, probe_webhooks)
+                    .await;
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        Some(Command::CacheStats) => {
+            let cache = cache::FsCache::new(&cache_dir, &remote_config_path, no_save_images).await;
+            match cache.stats().await {
+                Ok(stats) => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+                Err(e) => {
+                    eprintln!("Failed to read cache: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::CacheGc { namespaces, older_than }) => {
+            let cache = cache::FsCache::new(&cache_dir, &remote_config_path, no_save_images).await;
+            let namespaces = if namespaces.is_empty() {
+                cache::GC_NAMESPACES.iter().map(|ns| ns.to_string()).collect()
+            } else {
+                namespaces
+            };
+            let mut ok = true;
+            let expired_label = if dry_run { "EXPIRED" } else { "DELETED" };
+            println!("{:<18} {:>9} {:>9} {:>12}", "NAMESPACE", "SCANNED", expired_label, "BYTES");
+            for namespace in &namespaces {
+                match cache.collect_garbage(namespace, older_than, dry_run).await {
+                    Ok(stats) => println!(
+                        "{:<18} {:>9} {:>9} {:>12}",
+                        stats.namespace, stats.scanned, stats.expired, stats.bytes,
+                    ),
+                    Err(e) => {
+                        eprintln!("Failed to collect garbage in {}: {}", namespace, e);
+                        ok = false;
+                    }
+                }
+            }
+            if dry_run {
+                println!("Dry run, nothing was deleted.");
+            }
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        Some(Command::Parse { kind, debug, file }) => {
+            let input = match &file {
+                Some(path) => std::fs::read_to_string(path),
+                None => std::io::read_to_string(std::io::stdin()),
+            };
+            let input = match input {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("Failed to read input: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if parse::run(&input, kind, debug) > 0 {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::DeliveryStats { days }) => {
+            match delivery::read_days(&cache_dir.join("delivery"), days).await {
+                Ok(days) => delivery::print_table(&days),
+                Err(e) => {
+                    eprintln!("Failed to read delivery stats: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(Command::Rules { command, json }) => {
+            let tokens = tokens::TokenSet::from_env();
+            let client = match tokens.resolve(Engine::FilteredStream) {
+                Ok(token) => TwitterClient::new(token),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Err(e) = rules::run(&client, &command, json, dry_run).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::MigrateCache { remove_legacy }) => {
+            let cache = cache::FsCache::new(&cache_dir, &remote_config_path, no_save_images).await;
+            let stats = match migrate::run(&cache, remove_legacy, dry_run).await {
+                Ok(stats) => stats,
+                Err(e) => {
+                    eprintln!("Failed to migrate cache: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            for (key, reason) in &stats.skipped {
+                eprintln!("Skipped meta/{}.json: {}", key, reason);
+            }
+            println!(
+                "Scanned {}, migrated {}, already present {}, skipped {}, removed {}",
+                stats.scanned,
+                stats.migrated,
+                stats.existing,
+                stats.skipped.len(),
+                stats.removed,
+            );
+            if dry_run {
+                println!("Dry run, nothing was written.");
+            }
+            return;
+        }
+        Some(Command::Head { command }) => {
+            // a running instance would overwrite the edited head with its own
+            let _lock = if command.writes() && !dry_run && !allow_shared_cache {
+                match lock::CacheLock::acquire(&cache_dir) {
+                    Ok(lock) => Some(lock),
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to lock {}: {}. Stop the running instance first.",
+                            cache_dir.display(),
+                            e,
+                        );
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+            let cache = cache::FsCache::new(&cache_dir, &remote_config_path, no_save_images).await;
+            if let Err(e) = head::run(&cache, &command, dry_run).await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
     if engines.is_empty() {
         engines.push(Engine::FilteredStream);
         engines.push(Engine::List);
     }
     let engines = engines.into_iter().collect::<HashSet<_>>();
 
+    // engines sharing a token share a client, and so its rate limits
+    let tokens = tokens::TokenSet::from_env();
+    let mut client_set = tokens::ClientSet::default();
+    let client_engines = match &command {
+        Some(Command::Replay { .. }) => vec![Engine::FilteredStream],
+        _ => engines.iter().copied().collect(),
+    };
+    let mut clients = HashMap::new();
+    for engine in client_engines {
+        match client_set.client(&tokens, engine) {
+            Ok(client) => {
+                clients.insert(engine, client);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    log::info!(
+        "Cache directory: {}, config directory: {}",
+        cache_dir.display(),
+        config_dir.dir().display(),
+    );
     std::fs::create_dir_all(&cache_dir).expect("Invalid cache directory");
     std::fs::create_dir_all(cache_dir.join("images")).unwrap();
+    // dry runs don't write to the cache, so they can run alongside the main instance
+    let cache_lock = if dry_run || allow_shared_cache {
+        None
+    } else {
+        match lock::CacheLock::acquire(&cache_dir) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                eprintln!(
+                    "Failed to lock {}: {}. Pass --allow-shared-cache to run anyway.",
+                    cache_dir.display(),
+                    e,
+                );
+                std::process::exit(1);
+            }
+        }
+    };
 
-    let token = std::env::var("TWITTER_APP_TOKEN").expect("TWITTER_APP_TOKEN not found or invalid");
-
-    env_logger::init();
     let _sentry = sentry::init((
         std::env::var_os("SENTRY_DSN"),
-        sentry::ClientOptions {
-            release: sentry::release_name!(),
-            ..Default::default()
-        },
+        report::client_options(sentry_drop_parse_errors),
     ));
+    report::set_tags(&sentry_tags);
 
-    let cache = cache::FsCache::new(&cache_dir, no_save_images).await;
-    let client = TwitterClient::new(token);
+    let mut cache = cache::FsCache::new(&cache_dir, &remote_config_path, no_save_images).await;
+    cache.set_read_only(dry_run);
+    let status = Arc::new(status::StatusRegistry::new());
+    let scorer = settings::load_scorer(&config_dir.path("score/config.toml"))
+        .await
+        .expect("Failed to load config");
+    let mut sinks = sink::SinkFactory::new(status.clone());
+    sinks.set_dry_run(dry_run);
+    sinks.set_scorer(scorer.clone());
+    if dry_run {
+        log::info!("Dry run, deliveries are only logged and the cache is read only");
+    }
+    let shutdown = CancellationToken::new();
+    // cancelled by engines when Twitter rejects their token, to shut down with an error
+    let auth_failed = CancellationToken::new();
 
-    let platform = v8::Platform::new(0, false).make_shared();
-    v8::V8::initialize_platform(platform);
-    v8::V8::initialize();
+    if let Some(Command::Replay { since, tag, force }) = command {
+        let mut router =
+            load_router(&route_scripts, scorer).await.expect("Failed to load router");
+        let options = replay::ReplayOptions { since, tag, force };
+        let client = &clients[&Engine::FilteredStream];
+        let ret = replay::run(client, &sinks, &cache, &mut router, &options).await;
+        cache.flush_remote_downloads().await;
+        if let Err(e) = ret {
+            log::error!("Replay failed: {}", e);
+            drop(cache_lock);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if !dry_run {
+        let queue = queue::DeliveryQueue::open(cache_dir.join("queue"))
+            .await
+            .expect("Failed to open delivery queue");
+        match queue.len().await {
+            Ok(0) => {}
+            Ok(len) => log::info!("{} queued deliveries left from the previous run", len),
+            Err(e) => log::error!("Failed to read delivery queue: {}", e),
+        }
+        sinks.set_queue(queue.clone());
+        let executor = sinks.discord_executor().clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            queue.run_dispatcher(executor, shutdown).await;
+        });
+    }
 
     let mut sigterm = unix_signal::signal(unix_signal::SignalKind::terminate())
         .expect("Failed to listen SIGTERM");
@@ -74,196 +541,621 @@ async fn main() {
         unix_signal::signal(unix_signal::SignalKind::interrupt()).expect("Failed to listen SIGINT");
     let mut sigquit =
         unix_signal::signal(unix_signal::SignalKind::quit()).expect("Failed to listen SIGQUIT");
+    let mut sighup =
+        unix_signal::signal(unix_signal::SignalKind::hangup()).expect("Failed to listen SIGHUP");
+    let mut sigusr1 = unix_signal::signal(unix_signal::SignalKind::user_defined1())
+        .expect("Failed to listen SIGUSR1");
 
     let local_set = tokio::task::LocalSet::new();
 
+    let admin_config_path = config_dir.path("admin/config.toml");
+    let admin_config = if admin_config_path.exists() {
+        Some(
+            reload::ConfigHolder::<admin::AdminConfig>::load(admin_config_path)
+                .await
+                .expect("Failed to load config"),
+        )
+    } else {
+        None
+    };
+    let admin = admin::AdminNotifier::new(
+        sinks.clone(),
+        admin_config.as_ref().map(|config| config.subscribe()),
+        admin_webhook.map(webhook::WebhookTarget::new),
+    );
+
+    let mut script_reloader = None;
     let stream_handle = if engines.contains(&Engine::FilteredStream) {
         log::info!("Enabling engine {}", Engine::FilteredStream);
-        let client = client.clone();
+        status.register_stream();
+        let tag_routing = no_router || !route_scripts.iter().any(|path| path.exists());
+        let config_path = config_dir.path("stream/config.toml");
+        let route_scripts = if tag_routing {
+            log::info!(
+                "Routing filtered stream tweets by rule tag with {}, without route scripts",
+                config_path.display(),
+            );
+            Vec::new()
+        } else {
+            log::info!(
+                "Routing filtered stream tweets with route scripts {:?}",
+                route_scripts,
+            );
+            route_scripts.clone()
+        };
+        let reloader = Arc::new(stream::ScriptReloader::new(
+            route_scripts.clone(),
+            config_path,
+            tag_routing,
+            admin.clone(),
+        ));
+        script_reloader = Some(reloader.clone());
+        let monitor = admin::spawn_stream_monitor(admin.clone());
+        let status = status.clone();
+        let client = clients[&Engine::FilteredStream].clone();
+        let mut sinks = sinks.clone();
+        sinks.set_dedup(!no_dedup.contains(&Engine::FilteredStream));
         let cache = cache.clone();
-        Some(local_set.spawn_local(async move {
-            let script = tokio::fs::read_to_string("route.js").await.expect("Failed to load router");
-            let mut router = Router::new(128 * 1024 * 1024, &script).expect("Failed to load router");
-            loop {
-                if let Err(e) = stream::run_line_loop(&client, &cache, &mut router).await {
-                    log::error!("Stream error: {}", e);
+        let shutdown = shutdown.clone();
+        let auth_failed = auth_failed.clone();
+        Some(local_set.spawn_local(supervisor::supervise(Engine::FilteredStream, shutdown.clone(), move || {
+            let status = status.clone();
+            let client = client.clone();
+            let sinks = sinks.clone();
+            let cache = cache.clone();
+            let route_scripts = route_scripts.clone();
+            let reloader = reloader.clone();
+            let monitor = monitor.clone();
+            let shutdown = shutdown.clone();
+            let auth_failed = auth_failed.clone();
+            tokio::task::spawn_local(async move {
+                let scorer = sinks.scorer().clone();
+                let router =
+                    router::RouterPool::spawn("stream", router_threads, route_scripts, scorer);
+                if router.size() > 1 {
+                    log::info!("Routing stream tweets with {} router threads", router.size());
                 }
-            }
-        }))
+                router.load().await.expect("Failed to load router");
+                reloader.load_config(&router).await.expect("Failed to load config");
+                loop {
+                    let lines = client.make_observed_stream(monitor.observer());
+                    match stream::run_line_loop(lines, &sinks, &cache, &router, &shutdown, &status, &reloader).await {
+                        Ok(()) => break,
+                        Err(e) if report::is_auth_failure(e.as_ref()) => {
+                            report::auth_failure(Engine::FilteredStream, e.as_ref());
+                            status.record_stream_error(&e);
+                            auth_failed.cancel();
+                            break;
+                        },
+                        Err(e) => {
+                            log::error!("Stream error: {}", e);
+                            status.record_stream_error(&e);
+                            monitor.disconnected(&e);
+                            sinks.summary().record_error(Engine::FilteredStream);
+                        },
+                    }
+                }
+                log::info!("Stopped engine {}", Engine::FilteredStream);
+            }.bind_hub(report::engine_hub(Engine::FilteredStream)))
+        })))
     } else {
         None
     };
+    let mut search_config = None;
     let search_handle = if engines.contains(&Engine::Search) {
         log::info!("Enabling engine {}", Engine::Search);
-        let client = client.clone();
+        let config_path = config_dir.path("searches/config.toml");
+        let config_holder = reload::ConfigHolder::<search::SearchConfig>::load(config_path)
+            .await
+            .expect("Failed to load config");
+        let config_rx = config_holder.subscribe();
+        let search_settings = config_rx.borrow().settings().clone();
+        let fetch_interval = settings::SEARCH_POLL
+            .resolve(search_interval, search_settings.poll_interval_secs)
+            .expect("Invalid search interval");
+        let tracker_interval = settings::SEARCH_TRACKER
+            .resolve(search_tracker_interval, search_settings.tracker_interval_secs)
+            .expect("Invalid search tracker interval");
+        let tracker_limits = search::TrackerLimits::from_settings(&search_settings);
+        search_config = Some(config_holder);
+
+        status.register_engine(Engine::Search, tracker_interval);
+        let status = status.clone();
+        let client = clients[&Engine::Search].clone();
+        let mut sinks = sinks.clone();
+        sinks.set_dedup(!no_dedup.contains(&Engine::Search));
         let cache = cache.clone();
+        let shutdown = shutdown.clone();
+        let auth_failed = auth_failed.clone();
+
+        Some(tokio::spawn(supervisor::supervise(Engine::Search, shutdown.clone(), move || {
+            let status = status.clone();
+            let client = client.clone();
+            let sinks = sinks.clone();
+            let cache = cache.clone();
+            let config_rx = config_rx.clone();
+            let shutdown = shutdown.clone();
+            let auth_failed = auth_failed.clone();
+            tokio::spawn(async move {
+                let mut tracker = search::TrendingContext::new();
+                tracker.set_limits(tracker_limits);
+                tracker.set_scorer(sinks.scorer().clone());
+                // tracker updates give way to fetches when the lookup budget runs low
+                let tracker_client = client.with_priority(tweet_fetch::Priority::Low);
+
+                log::info!("Initializing search terms");
+                let config = config_rx.borrow().clone();
+                let mut heads = std::collections::HashMap::new();
+                let mut schedule = search::TermSchedule::new();
+                for term in config.terms() {
+                    let interval = term.fetch_interval_secs.map_or(fetch_interval, std::time::Duration::from_secs);
+                    schedule.mark_fetched(term.id, tokio::time::Instant::now(), interval);
+                    let mut head = tweet_fetch::SearchHead::new(term.term.to_owned(), None);
+                    head.set_max_results(term.max_results);
+                    match head.fetch(&client).await {
+                        Ok(tweet_model::ResponseItem {
+                            data: tweets,
+                            includes,
+                            ..
+                        }) => {
+                            tweet_model::metrics::increment_counter_by(
+                                tweet_model::metrics::TWEETS_RECEIVED,
+                                &[("engine", "search")],
+                                tweets.len() as u64,
+                            );
+                            status.record_received(Engine::Search, tweets.len());
+                            if term.trending {
+                                for tweet in &tweets {
+                                    tracker.insert(tweet, &includes, term);
+                                }
+                            }
+                        },
+                        Err(e) if e.is_unauthorized() => {
+                            report::auth_failure(Engine::Search, &e);
+                            auth_failed.cancel();
+                            return;
+                        },
+                        Err(e) => {
+                            log::error!("Search init failed: {}", e);
+                            sinks.summary().record_error(Engine::Search);
+                            sentry::capture_error(&e);
+                            continue;
+                        },
+                    };
+                    heads.insert(term.id.to_owned(), head);
+                }
+
+                let mut tracker_timer = tokio::time::interval(tracker_interval);
+                let mut deletion_timer = tokio::time::interval_at(
+                    tokio::time::Instant::now() + fetch_interval,
+                    fetch_interval,
+                );
+
+                log::info!("Started search loop");
+                loop {
+                    // reloaded configs may add terms, which are picked up on the next tracker tick
+                    let next_fetch = schedule
+                        .next_due(&config_rx.borrow())
+                        .unwrap_or_else(|| tokio::time::Instant::now() + fetch_interval);
+                    let (fetch, check_deletions) = tokio::select! {
+                        biased;
+                        _ = shutdown.cancelled() => break,
+                        _ = tokio::time::sleep_until(next_fetch) => (true, false),
+                        _ = deletion_timer.tick() => (false, true),
+                        _ = tracker_timer.tick() => (false, false),
+                    };
+                    let config = config_rx.borrow().clone();
 
-        let config_path = cache_dir.join("searches/config.toml");
-        let config = search::SearchConfig::from_config(config_path).await.expect("Failed to load config");
-
-        Some(tokio::spawn(async move {
-            let mut tracker = search::TrendingContext::new();
-
-            log::info!("Initializing search terms");
-            let mut heads = std::collections::HashMap::new();
-            for term in config.terms() {
-                let mut head = tweet_fetch::SearchHead::new(term.term.to_owned(), None);
-                match head.fetch(&client).await {
-                    Ok(tweet_model::ResponseItem {
-                        data: tweets,
-                        includes,
-                        ..
-                    }) => {
-                        if term.trending {
-                            for tweet in &tweets {
-                                tracker.insert(tweet, &includes, term);
+                    if check_deletions {
+                        tracker.log_stats();
+                        log::trace!("Running deletion check");
+                        let ret = tracker
+                            .reconcile_deletions(&tracker_client, &sinks, &cache, deletion_dry_run)
+                            .await;
+                        if let Err(e) = ret {
+                            if report::is_auth_failure(e.as_ref()) {
+                                report::auth_failure(Engine::Search, e.as_ref());
+                                auth_failed.cancel();
+                                return;
                             }
+                            log::error!("Deletion check failed: {}", e);
+                            sinks.summary().record_error(Engine::Search);
+                            sentry::capture_error(AsRef::<dyn std::error::Error + 'static>::as_ref(&e));
                         }
-                    },
-                    Err(e) => {
-                        log::error!("Search init failed: {}", e);
-                        sentry::capture_error(&e);
                         continue;
-                    },
-                };
-                heads.insert(term.id.to_owned(), head);
-            }
+                    }
 
-            let mut timer = tokio::time::interval(std::time::Duration::from_secs(30));
-            let mut tick_count = 0;
+                    if fetch {
+                        log::trace!("Running search fetch");
 
-            log::info!("Started search loop");
-            loop {
-                timer.tick().await;
-                tick_count += 1;
+                        let now = tokio::time::Instant::now();
+                        schedule.retain(&config);
+                        for term in schedule.due_terms(&config, now) {
+                            let interval = term.fetch_interval_secs.map_or(fetch_interval, std::time::Duration::from_secs);
+                            schedule.mark_fetched(term.id, now, interval);
 
-                if tick_count % 6 == 0 {
-                    tick_count = 0;
-                    log::trace!("Running search fetch");
+                            let trending = term.trending;
+                            let head = heads
+                                .entry(term.id.to_owned())
+                                .or_insert_with(|| tweet_fetch::SearchHead::new(term.term.to_owned(), None));
+                            if head.term() != term.term {
+                                // the query changed, start over
+                                *head = tweet_fetch::SearchHead::new(term.term.to_owned(), None);
+                            }
+                            head.set_max_results(term.max_results);
 
-                    for term in config.terms() {
-                        let id = term.id.to_owned();
-                        let trending = term.trending;
-                        let head = heads.get_mut(&id).unwrap();
-
-                        match head.fetch(&client).await {
-                            Ok(tweet_model::ResponseItem {
-                                data: tweets,
-                                includes,
-                                ..
-                            }) => {
-                                if trending {
-                                    for tweet in &tweets {
-                                        tracker.insert(tweet, &includes, term);
+                            match head.fetch(&client).await {
+                                Ok(tweet_model::ResponseItem {
+                                    data: tweets,
+                                    includes,
+                                    ..
+                                }) => {
+                                    tweet_model::metrics::increment_counter_by(
+                                        tweet_model::metrics::TWEETS_RECEIVED,
+                                        &[("engine", "search")],
+                                        tweets.len() as u64,
+                                    );
+                                    status.record_received(Engine::Search, tweets.len());
+                                    if trending {
+                                        for tweet in &tweets {
+                                            tracker.insert(tweet, &includes, term);
+                                        }
                                     }
-                                }
-                            },
-                            Err(e) => {
-                                log::error!("Search failed: {}", e);
-                                sentry::capture_error(&e);
-                                continue;
-                            },
-                        };
+                                },
+                                Err(e) if e.is_unauthorized() => {
+                                    report::auth_failure(Engine::Search, &e);
+                                    auth_failed.cancel();
+                                    return;
+                                },
+                                Err(e) => {
+                                    log::error!("Search failed: {}", e);
+                                    sinks.summary().record_error(Engine::Search);
+                                    sentry::capture_error(&e);
+                                    continue;
+                                },
+                            };
+                        }
+                        continue;
                     }
-                }
 
-                log::trace!("Running tracker update");
-                if let Err(e) = tracker.run_once(&client, &cache).await {
-                    log::error!("Tracking failed: {}", e);
-                    sentry::capture_error(AsRef::<dyn std::error::Error + 'static>::as_ref(&e));
+                    log::trace!("Running tracker update");
+                    let span = tweet_model::span!("tracker_check", engine = "search");
+                    let ret = tracker
+                        .run_once(&tracker_client, &sinks, &config, &cache)
+                        .in_span(span)
+                        .await;
+                    match ret {
+                        Ok(()) => status.record_success(Engine::Search),
+                        Err(e) if report::is_auth_failure(e.as_ref()) => {
+                            report::auth_failure(Engine::Search, e.as_ref());
+                            status.record_error(Engine::Search, &e);
+                            auth_failed.cancel();
+                            return;
+                        }
+                        Err(e) => {
+                            log::error!("Tracking failed: {}", e);
+                            sentry::capture_error(AsRef::<dyn std::error::Error + 'static>::as_ref(&e));
+                            status.record_error(Engine::Search, &e);
+                            sinks.summary().record_error(Engine::Search);
+                        }
+                    }
+                    tweet_model::metrics::set_gauge(
+                        tweet_model::metrics::TRACKER_SIZE,
+                        &[],
+                        tracker.tracking_count() as f64,
+                    );
+                    let tracker_sizes = tracker.tracking_counts();
+                    for term in config.terms() {
+                        let size = tracker_sizes.get(term.id).copied().unwrap_or(0);
+                        tweet_model::metrics::set_gauge(
+                            tweet_model::metrics::TRACKER_TERM_SIZE,
+                            &[("term", term.id)],
+                            size as f64,
+                        );
+                    }
+                    status.record_tracker_sizes(tracker_sizes);
                 }
-            }
-        }))
+                log::info!("Stopped engine {}", Engine::Search);
+            }.bind_hub(report::engine_hub(Engine::Search)))
+        })))
     } else {
         None
     };
+    let mut list_config = None;
+    let mut list_router = None;
     let list_handle = if engines.contains(&Engine::List) {
         log::info!("Enabling engine {}", Engine::List);
-        let client = client.clone();
+        let config_path = config_dir.path("lists/config.toml");
+        let config_holder = reload::ConfigHolder::<list::ListsConfig>::load(config_path)
+            .await
+            .expect("Failed to load config");
+        let config_rx = config_holder.subscribe();
+        let interval = settings::LIST_POLL
+            .resolve(list_interval, config_rx.borrow().settings().poll_interval_secs)
+            .expect("Invalid list interval");
+        list_config = Some(config_holder);
+
+        // lists with use_router route tweets with the same scripts as the filtered stream
+        let router =
+            router::RouterPool::spawn("list", router_threads, route_scripts.clone(), scorer.clone());
+        if router.size() > 1 {
+            log::info!("Routing list tweets with {} router threads", router.size());
+        }
+        list_router = Some(router.clone());
+
+        status.register_engine(Engine::List, interval);
+        let status = status.clone();
+        let client = clients[&Engine::List].clone();
+        let mut sinks = sinks.clone();
+        sinks.set_dedup(!no_dedup.contains(&Engine::List));
         let cache = cache.clone();
+        let shutdown = shutdown.clone();
+        let auth_failed = auth_failed.clone();
+        Some(tokio::spawn(supervisor::supervise(Engine::List, shutdown.clone(), move || {
+            let status = status.clone();
+            let client = client.clone();
+            let sinks = sinks.clone();
+            let router = router.clone();
+            let cache = cache.clone();
+            let config_rx = config_rx.clone();
+            let shutdown = shutdown.clone();
+            let auth_failed = auth_failed.clone();
+            tokio::spawn(async move {
+                let mut timer = tokio::time::interval(interval);
+                log::info!("Started list fetch loop");
 
-        let config_path = cache_dir.join("lists/config.toml");
-        let config = list::ListsConfig::from_config(config_path).await.expect("Failed to load config");
-        Some(tokio::spawn(async move {
-            let mut timer = tokio::time::interval(std::time::Duration::from_secs(60));
-            log::info!("Started list fetch loop");
-
-            let mut catchup = true;
-            loop {
-                timer.tick().await;
-                log::debug!(
-                    "Running list fetch{}",
-                    if catchup { " (catch-up)" } else { "" }
-                );
+                let mut catchup = true;
+                loop {
+                    tokio::select! {
+                        biased;
+                        _ = shutdown.cancelled() => break,
+                        _ = timer.tick() => {},
+                    }
+                    log::debug!(
+                        "Running list fetch{}",
+                        if catchup { " (catch-up)" } else { "" }
+                    );
 
-                list::run_list_once(&client, &config, catchup, &cache).await;
-                catchup = false;
-            }
-        }))
+                    let config = config_rx.borrow().clone();
+                    match list::run_list_once(&client, &sinks, &router, &config, catchup, interval, &cache).await {
+                        Ok(()) => {
+                            status.record_success(Engine::List);
+                            if let Some(stats) = router.heap_stats().await {
+                                log::debug!("List router heap: {}", stats);
+                            }
+                        }
+                        Err(e) if report::is_auth_failure(e.as_ref()) => {
+                            report::auth_failure(Engine::List, e.as_ref());
+                            status.record_error(Engine::List, &e);
+                            auth_failed.cancel();
+                            break;
+                        }
+                        Err(e) => {
+                            status.record_error(Engine::List, &e);
+                            sinks.summary().record_error(Engine::List);
+                        }
+                    }
+                    catchup = false;
+                }
+                log::info!("Stopped engine {}", Engine::List);
+            }.bind_hub(report::engine_hub(Engine::List)))
+        })))
     } else {
         None
     };
+    let mut user_config = None;
     let user_handle = if engines.contains(&Engine::User) {
         log::info!("Enabling engine {}", Engine::User);
-        let client = client.clone();
-
-        let config_path = cache_dir.join("users/config.toml");
-        let config = user::UsersConfig::from_config(config_path).await.expect("Failed to load config");
-        Some(tokio::spawn(async move {
-            let mut timer = tokio::time::interval(std::time::Duration::from_secs(60));
-            log::info!("Started user timeline fetch loop");
-
-            let mut catchup = true;
-            loop {
-                timer.tick().await;
-                log::debug!(
-                    "Running user timeline fetch{}",
-                    if catchup { " (catch-up)" } else { "" }
-                );
+        let config_path = config_dir.path("users/config.toml");
+        let config_holder = reload::ConfigHolder::<user::UsersConfig>::load(config_path)
+            .await
+            .expect("Failed to load config");
+        let config_rx = config_holder.subscribe();
+        let interval = settings::USER_POLL
+            .resolve(user_interval, config_rx.borrow().settings().poll_interval_secs)
+            .expect("Invalid user timeline interval");
+        user_config = Some(config_holder);
 
-                user::run_list_once(&client, &config, catchup, &cache).await;
-                catchup = false;
-            }
-        }))
+        status.register_engine(Engine::User, interval);
+        let status = status.clone();
+        let client = clients[&Engine::User].clone();
+        let mut sinks = sinks.clone();
+        sinks.set_dedup(!no_dedup.contains(&Engine::User));
+        let cache = cache.clone();
+        let shutdown = shutdown.clone();
+        let auth_failed = auth_failed.clone();
+        Some(tokio::spawn(supervisor::supervise(Engine::User, shutdown.clone(), move || {
+            let status = status.clone();
+            let client = client.clone();
+            let sinks = sinks.clone();
+            let cache = cache.clone();
+            let config_rx = config_rx.clone();
+            let shutdown = shutdown.clone();
+            let auth_failed = auth_failed.clone();
+            tokio::spawn(async move {
+                let mut timer = tokio::time::interval(interval);
+                log::info!("Started user timeline fetch loop");
+
+                let mut catchup = true;
+                loop {
+                    tokio::select! {
+                        biased;
+                        _ = shutdown.cancelled() => break,
+                        _ = timer.tick() => {},
+                    }
+                    log::debug!(
+                        "Running user timeline fetch{}",
+                        if catchup { " (catch-up)" } else { "" }
+                    );
+
+                    let config = config_rx.borrow().clone();
+                    match user::run_timelines_once(&client, &sinks, &config, catchup, interval, &cache).await {
+                        Ok(()) => status.record_success(Engine::User),
+                        Err(e) if report::is_auth_failure(e.as_ref()) => {
+                            report::auth_failure(Engine::User, e.as_ref());
+                            status.record_error(Engine::User, &e);
+                            auth_failed.cancel();
+                            break;
+                        }
+                        Err(e) => {
+                            status.record_error(Engine::User, &e);
+                            sinks.summary().record_error(Engine::User);
+                        }
+                    }
+                    catchup = false;
+                }
+                log::info!("Stopped engine {}", Engine::User);
+            }.bind_hub(report::engine_hub(Engine::User)))
+        })))
     } else {
         None
     };
 
+    {
+        let engines = engines.iter().map(|engine| engine.to_string()).collect();
+        let status = status.clone();
+        let admin = admin.clone();
+        let config_rx = admin_config.as_ref().map(|config| config.subscribe());
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            watchdog::run(engines, status, admin, config_rx, shutdown).await;
+        });
+    }
+
+    if status_log_interval > 0 {
+        let interval = std::time::Duration::from_secs(status_log_interval * 60);
+        let status = status.clone();
+        let clients = clients
+            .iter()
+            .map(|(engine, client)| (engine.to_string(), client.clone()))
+            .collect();
+        let queue = sinks.queue().cloned();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            heartbeat::run(interval, status, clients, queue, shutdown).await;
+        });
+    }
+
+    let summary_config_path = config_dir.path("summary/config.toml");
+    let mut summary_config = None;
+    if dry_run {
+        log::debug!("Dry run, daily summary disabled");
+    } else if summary_config_path.exists() {
+        let config_holder =
+            reload::ConfigHolder::<summary::SummaryConfig>::load(summary_config_path)
+                .await
+                .expect("Failed to load config");
+        let config_rx = config_holder.subscribe();
+        summary_config = Some(config_holder);
+
+        let stats_path = cache_dir.join("summary/stats.json");
+        let recorder = sinks.summary().clone();
+        recorder.restore(&stats_path).await;
+        let sinks = sinks.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            summary::run(recorder, config_rx, sinks, stats_path, shutdown).await;
+        });
+    }
+
+    let delivery_stats = Arc::new(
+        delivery::DeliveryStats::open(cache_dir.join("delivery"), sinks.discord_executor().clone())
+            .await,
+    );
+    // dry runs don't send anything, nor write to the cache
+    if !dry_run {
+        tokio::spawn(delivery::run(delivery_stats.clone(), shutdown.clone()));
+    }
+
+    if let Some(addr) = status_addr {
+        let metrics: &'static metrics::PrometheusRecorder =
+            Box::leak(Box::new(metrics::PrometheusRecorder::new()));
+        tweet_model::metrics::set_recorder(metrics);
+        let status = status.clone();
+        let cache = cache.clone();
+        let delivery_stats = delivery_stats.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            let ret = status::serve(addr, status, cache, delivery_stats, metrics, shutdown).await;
+            if let Err(e) = ret {
+                log::error!("Status server failed: {}", e);
+            }
+        });
+    }
+
     let sig_handle = tokio::spawn(async move {
-        let sigterm = sigterm.recv();
-        tokio::pin!(sigterm);
-        let sigint = sigint.recv();
-        tokio::pin!(sigint);
-        let sigquit = sigquit.recv();
-        tokio::pin!(sigquit);
-
-        futures_util::future::select_all([sigterm, sigint, sigquit]).await;
-        if let Some(stream_handle) = &stream_handle {
-            stream_handle.abort();
-        }
-        if let Some(search_handle) = &search_handle {
-            search_handle.abort();
-        }
-        if let Some(list_handle) = &list_handle {
-            list_handle.abort();
-        }
-        if let Some(user_handle) = &user_handle {
-            user_handle.abort();
-        }
-        if let Some(stream_handle) = stream_handle {
-            stream_handle.await.err();
-        }
-        if let Some(search_handle) = search_handle {
-            search_handle.await.err();
-        }
-        if let Some(list_handle) = list_handle {
-            list_handle.await.err();
-        }
-        if let Some(user_handle) = user_handle {
-            user_handle.await.err();
+        let aborted = loop {
+            tokio::select! {
+                _ = sighup.recv() => {
+                    log::info!("Reloading configs");
+                    cache.reload_remote().await;
+                    if let Some(config) = &search_config {
+                        config.reload().await;
+                    }
+                    if let Some(config) = &list_config {
+                        config.reload().await;
+                    }
+                    if let Some(config) = &user_config {
+                        config.reload().await;
+                    }
+                    if let Some(config) = &summary_config {
+                        config.reload().await;
+                    }
+                    if let Some(config) = &admin_config {
+                        config.reload().await;
+                    }
+                },
+                _ = sigusr1.recv() => {
+                    if script_reloader.is_none() && list_router.is_none() {
+                        log::warn!("No engine uses route scripts, nothing to reload");
+                    } else {
+                        log::info!("Reloading route scripts");
+                    }
+                    if let Some(reloader) = &script_reloader {
+                        reloader.request();
+                    }
+                    if let Some(router) = &list_router {
+                        router.reload();
+                    }
+                },
+                _ = sigterm.recv() => break false,
+                _ = sigint.recv() => break false,
+                _ = sigquit.recv() => break false,
+                _ = auth_failed.cancelled() => break true,
+            }
+        };
+        log::info!("Shutting down, waiting for in-flight deliveries");
+        shutdown.cancel();
+
+        let mut handles = [stream_handle, search_handle, list_handle, user_handle]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let flush = async {
+            futures_util::future::join_all(handles.iter_mut()).await;
+            cache.flush_remote_downloads().await;
+        };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, flush).await.is_err() {
+            log::warn!(
+                "Engines did not stop in {} seconds, aborting",
+                SHUTDOWN_TIMEOUT.as_secs(),
+            );
+            for handle in &handles {
+                handle.abort();
+            }
         }
+        aborted
     });
 
     local_set.await;
-    sig_handle.await.ok();
+    let aborted = sig_handle.await.unwrap_or(false);
+    if !dry_run {
+        delivery_stats.flush().await;
+    }
+    drop(cache_lock);
+    if aborted {
+        // flush Sentry events before exiting
+        drop(_sentry);
+        std::process::exit(1);
+    }
 }
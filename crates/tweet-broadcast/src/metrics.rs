@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use tweet_model::metrics::{Labels, Recorder};
+
+/// Prefix of every exported metric name.
+const PREFIX: &str = "tweet_broadcast_";
+
+/// Metric series, by name and rendered label set.
+type Series<T> = BTreeMap<(&'static str, String), T>;
+
+/// Recorder keeping metrics in memory, rendered in the Prometheus text format on scrape.
+#[derive(Debug, Default)]
+pub struct PrometheusRecorder {
+    counters: Mutex<Series<u64>>,
+    gauges: Mutex<Series<f64>>,
+}
+
+impl PrometheusRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Renders every series in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        render_series(&mut out, "counter", &self.counters.lock().unwrap());
+        render_series(&mut out, "gauge", &self.gauges.lock().unwrap());
+        out
+    }
+}
+
+impl Recorder for PrometheusRecorder {
+    fn increment_counter(&self, name: &'static str, labels: Labels<'_>, value: u64) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .entry((name, format_labels(labels)))
+            .or_default() += value;
+    }
+
+    fn set_gauge(&self, name: &'static str, labels: Labels<'_>, value: f64) {
+        self.gauges
+            .lock()
+            .unwrap()
+            .insert((name, format_labels(labels)), value);
+    }
+}
+
+fn render_series<T: std::fmt::Display>(out: &mut String, ty: &str, series: &Series<T>) {
+    let mut last_name = None;
+    for ((name, labels), value) in series {
+        if last_name != Some(name) {
+            writeln!(out, "# TYPE {}{} {}", PREFIX, name, ty).unwrap();
+            last_name = Some(name);
+        }
+        writeln!(out, "{}{}{} {}", PREFIX, name, labels, value).unwrap();
+    }
+}
+
+fn format_labels(labels: Labels<'_>) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let mut labels = labels.to_vec();
+    labels.sort_unstable();
+    let labels = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tweet_model::metrics as m;
+
+    #[test]
+    fn renders_recorded_metrics() {
+        let recorder = PrometheusRecorder::new();
+        assert_eq!(recorder.render(), "");
+
+        recorder.increment_counter(m::TWEETS_RECEIVED, &[("engine", "stream")], 3);
+        recorder.increment_counter(m::TWEETS_RECEIVED, &[("engine", "list")], 1);
+        recorder.increment_counter(m::TWEETS_RECEIVED, &[("engine", "stream")], 2);
+        let labels = [("status", "2xx"), ("endpoint", "search")];
+        recorder.increment_counter(m::TWITTER_REQUESTS, &labels, 1);
+        recorder.increment_counter(m::WEBHOOKS_FAILED, &[("host", "a \"b\"\\\n")], 1);
+        recorder.set_gauge(m::TRACKER_SIZE, &[], 10.0);
+        recorder.set_gauge(m::TRACKER_SIZE, &[], 4.0);
+        recorder.set_gauge(m::ROUTER_HEAP_USED_BYTES, &[], 1.5e6);
+
+        let expected = "\
+# TYPE tweet_broadcast_tweets_received_total counter
+tweet_broadcast_tweets_received_total{engine=\"list\"} 1
+tweet_broadcast_tweets_received_total{engine=\"stream\"} 5
+# TYPE tweet_broadcast_twitter_requests_total counter
+tweet_broadcast_twitter_requests_total{endpoint=\"search\",status=\"2xx\"} 1
+# TYPE tweet_broadcast_webhooks_failed_total counter
+tweet_broadcast_webhooks_failed_total{host=\"a \\\"b\\\"\\\\\\n\"} 1
+# TYPE tweet_broadcast_router_heap_used_bytes gauge
+tweet_broadcast_router_heap_used_bytes 1500000
+# TYPE tweet_broadcast_tracker_size gauge
+tweet_broadcast_tracker_size 4
+";
+        assert_eq!(recorder.render(), expected);
+    }
+}
@@ -0,0 +1,126 @@
+//! Migration of routed tweet metadata from the legacy `meta/` layout to `CacheData`.
+//!
+//! The legacy binary stored the full route payload of each routed tweet under `meta/<id>.json`.
+//! Without migrating them, the stream engine sees every previously routed tweet as new.
+
+use eyre::Result;
+use serde_json::Value;
+
+use tweet_model::cache::*;
+use tweet_route::CacheData;
+
+use crate::cache::FsCache;
+
+/// Namespace of the legacy metadata.
+const LEGACY_NAMESPACE: &str = "meta";
+
+#[derive(Debug, Default)]
+pub struct MigrationStats {
+    pub scanned: usize,
+    pub migrated: usize,
+    /// Files whose tweet already has `CacheData`, which is kept.
+    pub existing: usize,
+    /// Files which could not be converted, with the reason.
+    pub skipped: Vec<(String, String)>,
+    pub removed: usize,
+}
+
+/// Returns the `id` of the object at `key` of `payload`, if the object is present.
+fn object_id<'a>(payload: &'a Value, key: &str) -> Result<Option<&'a str>, String> {
+    match payload.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(object) => object
+            .get("id")
+            .and_then(Value::as_str)
+            .map(Some)
+            .ok_or_else(|| format!("{}.id is missing", key)),
+    }
+}
+
+/// Converts a legacy route payload into `CacheData`, the same way `CacheData::from` does for
+/// current payloads.
+fn convert(payload: &Value) -> Result<CacheData, String> {
+    let tweet_id = object_id(payload, "tweet")?.ok_or("tweet is missing")?;
+    let author_id = match object_id(payload, "author")? {
+        Some(id) => id,
+        None => payload
+            .pointer("/tweet/author_id")
+            .and_then(Value::as_str)
+            .ok_or("author is missing")?,
+    };
+    let original_tweet_id = object_id(payload, "originalTweet")?;
+    let original_author_id = object_id(payload, "originalAuthor")?;
+    let media_keys = match payload.get("media") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(media)) => media
+            .iter()
+            .map(|media| media.get("media_key").and_then(Value::as_str))
+            .collect::<Option<Vec<_>>>()
+            .ok_or("media_key of media is missing")?,
+        Some(_) => return Err(String::from("media is not an array")),
+    };
+    let score = payload
+        .get("score")
+        .and_then(Value::as_f64)
+        .ok_or("score is missing")?;
+    let tags = match payload.get("tags") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(tags)) => tags
+            .iter()
+            .map(Value::as_str)
+            .collect::<Option<Vec<_>>>()
+            .ok_or("tags are not strings")?,
+        Some(_) => return Err(String::from("tags is not an array")),
+    };
+
+    // retweets are keyed by the retweet, and target the retweeted tweet
+    let data = serde_json::json!({
+        "tweetId": original_tweet_id.unwrap_or(tweet_id),
+        "authorId": original_author_id.unwrap_or(author_id),
+        "targetTweetId": original_tweet_id.and(Some(tweet_id)),
+        "targetAuthorId": original_author_id.and(Some(author_id)),
+        "mediaKeys": media_keys,
+        "score": score,
+        "tags": tags,
+    });
+    serde_json::from_value(data).map_err(|e| e.to_string())
+}
+
+/// Converts every legacy metadata file into `CacheData`, keeping existing `CacheData`. With
+/// `remove_legacy`, converted files are removed. With `dry_run`, nothing is written.
+pub async fn run(cache: &FsCache, remove_legacy: bool, dry_run: bool) -> Result<MigrationStats> {
+    let mut stats = MigrationStats::default();
+    let mut keys = cache.scan_keys(LEGACY_NAMESPACE).await?;
+    keys.sort_unstable_by(|a, b| tweet_model::iter::compare_ids(a, b));
+    for key in keys {
+        stats.scanned += 1;
+        let path = cache.item_path(LEGACY_NAMESPACE, &key);
+        let converted = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice::<Value>(&bytes)
+                .map_err(|e| e.to_string())
+                .and_then(|payload| convert(&payload)),
+            Err(e) => Err(e.to_string()),
+        };
+        let data = match converted {
+            Ok(data) => data,
+            Err(reason) => {
+                stats.skipped.push((key, reason));
+                continue;
+            }
+        };
+
+        if LoadCache::<CacheData>::has(cache, data.key()).await? {
+            stats.existing += 1;
+        } else {
+            if !dry_run {
+                cache.store(&data).await?;
+            }
+            stats.migrated += 1;
+        }
+        if remove_legacy && !dry_run {
+            tokio::fs::remove_file(&path).await?;
+            stats.removed += 1;
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,235 @@
+//! Parsing captured API responses with the models, to find which field broke a parse.
+
+use serde::de::DeserializeOwned;
+
+use tweet_model as model;
+
+/// Kind of a captured response, which decides the model it's parsed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::EnumString, strum::Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum PayloadKind {
+    /// Line of the filtered stream, with matching rules.
+    Stream,
+    /// Page of list tweets.
+    List,
+    /// Page of search results.
+    Search,
+    /// Single tweet lookup.
+    Tweet,
+}
+
+impl PayloadKind {
+    /// Guesses the kind of a response from its shape.
+    fn detect(value: &serde_json::Value) -> Self {
+        if value.get("matching_rules").is_some() {
+            Self::Stream
+        } else if matches!(value.get("data"), Some(serde_json::Value::Array(_))) {
+            let meta = value.get("meta");
+            let search = ["newest_id", "oldest_id"]
+                .iter()
+                .any(|key| meta.and_then(|meta| meta.get(key)).is_some());
+            if search {
+                Self::Search
+            } else {
+                Self::List
+            }
+        } else {
+            Self::Tweet
+        }
+    }
+}
+
+/// Parses `value` into `T`, describing errors with the path of the offending field.
+fn parse_value<T: DeserializeOwned>(value: serde_json::Value) -> Result<T, String> {
+    serde_path_to_error::deserialize(value).map_err(|e| format!("at {}: {}", e.path(), e.inner()))
+}
+
+fn summarize(tweet: &model::Tweet, includes: &model::ResponseIncludes) -> String {
+    let author = match tweet.author_id() {
+        Some(id) => match includes.get_user(id) {
+            Some(user) => format!("@{}", user.username()),
+            None => format!("user {} (not included)", id),
+        },
+        None => String::from("unknown author"),
+    };
+    let missing_media = tweet
+        .media_keys()
+        .iter()
+        .filter(|key| includes.get_media(key).is_none())
+        .count();
+    let mut ret = format!("tweet {} by {}, {} media", tweet.id(), author, tweet.media_keys().len());
+    if missing_media > 0 {
+        ret.push_str(&format!(" ({} not included)", missing_media));
+    }
+    ret
+}
+
+/// Parses one response, returning the lines to print.
+fn parse_document(
+    value: serde_json::Value,
+    kind: PayloadKind,
+    debug: bool,
+) -> Result<Vec<String>, String> {
+    fn output<T: std::fmt::Debug>(item: &T, debug: bool, summary: Vec<String>) -> Vec<String> {
+        if debug {
+            vec![format!("{:#?}", item)]
+        } else {
+            summary
+        }
+    }
+
+    let ret = match kind {
+        PayloadKind::Stream => {
+            let item: model::ResponseItem<model::Tweet, model::StreamMeta> = parse_value(value)?;
+            let tags = item
+                .meta
+                .matching_rules()
+                .iter()
+                .map(|rule| rule.tag())
+                .collect::<Vec<_>>();
+            let summary = summarize(&item.data, &item.includes);
+            let summary = format!("{}, rule tags {:?}", summary, tags);
+            output(&item, debug, vec![summary])
+        }
+        PayloadKind::List => {
+            let item: model::ResponseItem<Vec<model::Tweet>, model::ListMeta> =
+                parse_value(value)?;
+            let mut summary = vec![format!(
+                "{} tweet(s), result_count {}, next_token {:?}",
+                item.data.len(),
+                item.meta.result_count(),
+                item.meta.next_token(),
+            )];
+            summary.extend(item.data.iter().map(|tweet| summarize(tweet, &item.includes)));
+            output(&item, debug, summary)
+        }
+        PayloadKind::Search => {
+            let item: model::ResponseItem<Vec<model::Tweet>, model::SearchMeta> =
+                parse_value(value)?;
+            let mut summary = vec![format!(
+                "{} tweet(s), result_count {}, newest_id {:?}",
+                item.data.len(),
+                item.meta.result_count(),
+                item.meta.newest_id(),
+            )];
+            summary.extend(item.data.iter().map(|tweet| summarize(tweet, &item.includes)));
+            output(&item, debug, summary)
+        }
+        PayloadKind::Tweet => {
+            let item: model::ResponseItem<model::Tweet> = parse_value(value)?;
+            let summary = summarize(&item.data, &item.includes);
+            output(&item, debug, vec![summary])
+        }
+    };
+    Ok(ret)
+}
+
+/// Returns the line where the document after `offset` starts, skipping whitespace, as the
+/// offset points right past the previous document.
+fn line_at(input: &str, offset: usize) -> usize {
+    let skipped = input[offset..].len() - input[offset..].trim_start().len();
+    input[..offset + skipped].matches('\n').count() + 1
+}
+
+/// Parses each JSON document of `input`, usually one per line as captured from the stream, and
+/// prints a summary or the `Debug` representation of each. Returns the number of documents
+/// which failed to parse.
+///
+/// The kind of each document is detected from its shape unless `kind` is given.
+pub fn run(input: &str, kind: Option<PayloadKind>, debug: bool) -> usize {
+    let mut documents = serde_json::Deserializer::from_str(input).into_iter::<serde_json::Value>();
+    let mut parsed = 0;
+    let mut failed = 0;
+    loop {
+        let line = line_at(input, documents.byte_offset());
+        let value = match documents.next() {
+            Some(Ok(value)) => value,
+            Some(Err(e)) => {
+                // the rest of the input can't be split into documents
+                eprintln!("line {}: invalid JSON: {}", line, e);
+                failed += 1;
+                break;
+            }
+            None => break,
+        };
+
+        let kind = kind.unwrap_or_else(|| PayloadKind::detect(&value));
+        match parse_document(value, kind, debug) {
+            Ok(lines) => {
+                parsed += 1;
+                for output in lines {
+                    println!("line {} ({}): {}", line, kind, output);
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("line {} ({}): {}", line, kind, e);
+            }
+        }
+    }
+    eprintln!("Parsed {} of {} document(s)", parsed, parsed + failed);
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(json: &str, kind: PayloadKind) -> Result<Vec<String>, String> {
+        parse_document(serde_json::from_str(json).unwrap(), kind, false)
+    }
+
+    #[test]
+    fn detects_kinds() {
+        let cases = [
+            (r#"{"data":{"id":"1","text":""},"matching_rules":[]}"#, PayloadKind::Stream),
+            (r#"{"data":[],"meta":{"result_count":0}}"#, PayloadKind::List),
+            (r#"{"data":[],"meta":{"result_count":0,"newest_id":"1"}}"#, PayloadKind::Search),
+            (r#"{"data":{"id":"1","text":""}}"#, PayloadKind::Tweet),
+        ];
+        for (json, kind) in cases {
+            let value = serde_json::from_str(json).unwrap();
+            assert_eq!(PayloadKind::detect(&value), kind, "{}", json);
+        }
+    }
+
+    #[test]
+    fn summarizes_tweet() {
+        let json = r#"{
+            "data": {
+                "id": "10", "text": "hi", "author_id": "2",
+                "attachments": {"media_keys": ["3_1"]}
+            },
+            "includes": {"users": [{"id": "2", "name": "Name", "username": "name"}]}
+        }"#;
+        let summary = parse_str(json, PayloadKind::Tweet).unwrap();
+        assert_eq!(summary, ["tweet 10 by @name, 1 media (1 not included)"]);
+    }
+
+    #[test]
+    fn error_has_field_path() {
+        let json = r#"{"data":[{"id":"1","text":""},{"id":"2","text":"","created_at":"yesterday"}],
+            "meta":{"result_count":2}}"#;
+        let e = parse_str(json, PayloadKind::List).unwrap_err();
+        assert!(e.starts_with("at data[1].created_at: "), "{}", e);
+
+        let e = parse_str(r#"{"data":{"id":"1"}}"#, PayloadKind::Tweet).unwrap_err();
+        assert!(e.contains("missing field `text`"), "{}", e);
+    }
+
+    #[test]
+    fn counts_lines_of_documents() {
+        let input = "{}\n\n{}\n{\n}";
+        let mut documents =
+            serde_json::Deserializer::from_str(input).into_iter::<serde_json::Value>();
+        let mut lines = Vec::new();
+        loop {
+            let line = line_at(input, documents.byte_offset());
+            if documents.next().is_none() {
+                break;
+            }
+            lines.push(line);
+        }
+        assert_eq!(lines, [1, 3, 4]);
+    }
+}
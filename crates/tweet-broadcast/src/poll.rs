@@ -0,0 +1,972 @@
+//! Polling engines relaying new tweets of lists and user timelines.
+
+use std::time::Duration;
+
+use eyre::Result;
+use futures_util::future::BoxFuture;
+
+use tweet_fetch::{GapInfo, TwitterClient};
+use tweet_model::{
+    self as model,
+    cache::*,
+    metrics,
+    trace::{Span, TraceExt},
+};
+
+use crate::batch;
+use crate::dedup::{DedupGuard, DeliveredMarker};
+use crate::image::SaveImages;
+use crate::router::RouterPool;
+use crate::settings;
+use crate::sink::{Sink, SinkError, SinkFactory};
+use crate::webhook::WebhookTarget;
+use crate::Engine;
+
+/// Tweets fetched by a poll, with the gap before them if the source couldn't return every
+/// tweet since the last poll.
+pub type PollResult = model::ResponseItem<Vec<model::Tweet>, Option<GapInfo>>;
+
+/// Head of a polled source of tweets, which remembers the last seen tweet.
+pub trait TweetSource: CacheItem + Send + Sync + Sized {
+    /// Per-source config.
+    type Meta: SourceMeta;
+
+    const ENGINE: Engine;
+    /// Kind of the source in notifications, metrics and relay sources, e.g. `list`.
+    const KIND: &'static str;
+    /// Name of the source in logs, e.g. `user timeline`.
+    const NAME: &'static str;
+    /// Pause after each tweet delivered to a webhook.
+    const DELIVERY_INTERVAL: Option<Duration> = None;
+
+    /// Span of polling the source with ID `id`.
+    fn span(id: &str) -> Span;
+
+    /// Returns whether the source was never polled, so that there's nothing to relay yet.
+    fn is_new(&self) -> bool;
+
+    /// Fetches the tweets since the head, and moves the head to the newest one. The meta tells
+    /// the tweets which could not be fetched, if any.
+    fn poll<'a>(
+        &'a mut self,
+        client: &'a TwitterClient,
+        catchup: bool,
+    ) -> BoxFuture<'a, Result<PollResult, tweet_fetch::Error>>;
+}
+
+/// Config of a polled source.
+pub trait SourceMeta: Sync {
+    fn webhooks(&self) -> &[WebhookTarget];
+
+    /// Returns the fetched tweets to relay.
+    fn filter<'a>(
+        &self,
+        scorer: &dyn tweet_route::Scorer,
+        tweets: &'a [model::Tweet],
+        includes: &model::ResponseIncludes,
+    ) -> Vec<&'a model::Tweet>;
+
+    /// Returns whether relayed tweets are cached.
+    fn cache_tweets(&self) -> bool {
+        false
+    }
+
+    /// Returns whether media of relayed tweets are saved.
+    fn save_images(&self) -> bool {
+        false
+    }
+
+    /// Returns the tags to route tweets with, if they are routed with the route scripts.
+    fn router_tags(&self) -> Option<Vec<String>> {
+        None
+    }
+}
+
+fn as_std_error(e: &eyre::Error) -> &(dyn std::error::Error + 'static) {
+    e.as_ref()
+}
+
+/// Returns `text` with the first letter in upper case.
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+async fn send_first_time_webhook<S: TweetSource>(sink: &dyn Sink, id: &str) -> Result<()> {
+    let message = format!("{} `{}` initialized", capitalize(S::KIND), id);
+    sink.notify(&message).await?;
+    Ok(())
+}
+
+/// Describes `gap` for notices and logs.
+fn describe_gap(gap: &GapInfo) -> String {
+    let estimate = match gap.estimated_missed {
+        Some(count) => format!("about {} tweet(s)", count),
+        None => String::from("an unknown number of tweets"),
+    };
+    format!(
+        "{} between tweets {} and {}",
+        estimate, gap.from_id, gap.to_id
+    )
+}
+
+async fn send_gap_webhook<S: TweetSource>(sink: &dyn Sink, id: &str, gap: &GapInfo) -> Result<()> {
+    let message = format!(
+        "Missed {} of {} `{}`, which could not be fetched",
+        describe_gap(gap),
+        S::KIND,
+        id,
+    );
+    sink.notify(&message).await?;
+    Ok(())
+}
+
+/// Tweets linked in catch-up messages, newest first.
+const CATCHUP_LINK_COUNT: usize = 10;
+
+/// Lists links to the newest of `tweets` skipped during catch-up, one per line, followed by the
+/// number of tweets not listed.
+pub fn format_skipped_tweets(
+    tweets: &[&model::Tweet],
+    includes: &model::ResponseIncludes,
+) -> String {
+    let mut tweets = tweets.to_vec();
+    tweets.sort_unstable_by(|a, b| model::iter::compare_ids(b.id(), a.id()));
+
+    let mut lines = tweets
+        .iter()
+        .take(CATCHUP_LINK_COUNT)
+        .map(|tweet| {
+            let username = tweet
+                .author_id()
+                .and_then(|id| includes.get_user(id))
+                .map_or("i/web", |author| author.username());
+            // angle brackets suppress link previews
+            format!("<https://twitter.com/{}/status/{}>", username, tweet.id())
+        })
+        .collect::<Vec<_>>();
+    if tweets.len() > CATCHUP_LINK_COUNT {
+        lines.push(format!(
+            "\u{2026}and {} more",
+            tweets.len() - CATCHUP_LINK_COUNT
+        ));
+    }
+    lines.join("\n")
+}
+
+async fn send_catchup_webhook<S: TweetSource>(
+    sink: &dyn Sink,
+    id: &str,
+    tweets: &[&model::Tweet],
+    includes: &model::ResponseIncludes,
+) -> Result<()> {
+    let tweet_count = tweets.len();
+    let message = format!(
+        "Skipping {} tweet{} of {} `{}` during {} catch-up:\n{}",
+        tweet_count,
+        if tweet_count == 1 { "" } else { "s" },
+        S::KIND,
+        id,
+        S::NAME,
+        format_skipped_tweets(tweets, includes),
+    );
+    sink.notify(&message).await?;
+    Ok(())
+}
+
+fn log_skipped<S: TweetSource>(id: &str, tweet: &model::Tweet, e: &SinkError) {
+    log::warn!("Skipping tweet {} of {} {}: {}", tweet.id(), S::KIND, id, e);
+}
+
+/// Delivers `batch` to `sink`, as one message if the sink batches tweets, returning the tweets
+/// delivered.
+///
+/// Tweets which can't be rendered are skipped; the rest of their batch is delivered one by one.
+async fn deliver_batch<'a, S: TweetSource>(
+    sink: &dyn Sink,
+    id: &str,
+    batch: &[&'a model::Tweet],
+    includes: &model::ResponseIncludes,
+) -> Result<Vec<&'a model::Tweet>, SinkError> {
+    match sink
+        .deliver_batch(batch, includes, &Default::default())
+        .await
+    {
+        Ok(_) => Ok(batch.to_vec()),
+        Err(e) if e.is_render_failure() && batch.len() > 1 => {
+            let mut delivered = Vec::new();
+            for &tweet in batch {
+                match sink.deliver(tweet, includes, &Default::default()).await {
+                    Ok(_) => delivered.push(tweet),
+                    Err(e) if e.is_render_failure() => log_skipped::<S>(id, tweet, &e),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(delivered)
+        }
+        Err(e) if e.is_render_failure() => {
+            log_skipped::<S>(id, batch[0], &e);
+            Ok(Vec::new())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Delivers `tweets` to the routes returned by the route scripts, returning the tweets which
+/// failed to route.
+async fn route_tweets<'a, S, Cache>(
+    router: &RouterPool,
+    sinks: &SinkFactory,
+    id: &str,
+    tags: Vec<String>,
+    tweets: &[&'a model::Tweet],
+    includes: &model::ResponseIncludes,
+    cache: &Cache,
+) -> Vec<&'a model::Tweet>
+where
+    S: TweetSource,
+    Cache: LoadCache<DeliveredMarker> + StoreCache<DeliveredMarker> + SaveImages,
+{
+    let dedup = DedupGuard::new(cache, sinks.dedup_enabled());
+    // route on every member of the pool at once, but deliver in order
+    let route_results = futures_util::future::join_all(
+        tweets
+            .iter()
+            .map(|tweet| router.route(tweet, includes, tags.clone())),
+    )
+    .await;
+    let mut failed = Vec::new();
+    for (&tweet, route_result) in tweets.iter().zip(route_results) {
+        let routes = match route_result {
+            Ok(routes) => routes,
+            Err(e) => {
+                log::error!(
+                    "Failed to route tweet {} of {} {}, relaying to static webhooks: {}",
+                    tweet.id(),
+                    S::KIND,
+                    id,
+                    e,
+                );
+                let mut event = sentry::event_from_error(&e);
+                event.tags.insert(String::from("id"), id.into());
+                sentry::capture_event(event);
+                failed.push(tweet);
+                continue;
+            }
+        };
+        if routes.is_empty() {
+            log::debug!("No routes for tweet {} of {} {}", tweet.id(), S::KIND, id);
+            continue;
+        }
+        crate::stream::deliver_routes(sinks, &dedup, tweet, includes, &routes).await;
+        sinks.summary().record_relay(
+            &[format!("{}:{}", S::KIND, id)],
+            tweet,
+            includes,
+            tweet_route::score_tweet(&**sinks.scorer(), tweet, includes),
+        );
+        sinks.status().record_relayed(S::ENGINE);
+        cache.save_images(
+            tweet
+                .media_keys()
+                .iter()
+                .filter_map(|key| includes.get_media(key)),
+        );
+    }
+    failed
+}
+
+/// Polls each of `sources` once, relaying new tweets to their webhooks.
+///
+/// The first poll of a source only announces it, and catch-up polls with more than a handful of
+/// tweets list them instead of relaying each. `router` routes tweets of sources which use the
+/// route scripts.
+pub async fn run_source_once<'s, S, Cache>(
+    client: &TwitterClient,
+    sinks: &SinkFactory,
+    router: Option<&RouterPool>,
+    sources: impl IntoIterator<Item = (&'s String, &'s S::Meta)>,
+    catchup: bool,
+    interval: Duration,
+    cache: &Cache,
+) -> Result<()>
+where
+    S: TweetSource,
+    S::Meta: 's,
+    Cache: LoadCache<S>
+        + StoreCache<S>
+        + StoreCache<model::Tweet>
+        + LoadCache<DeliveredMarker>
+        + StoreCache<DeliveredMarker>
+        + SaveImages,
+{
+    use futures_util::{StreamExt, TryStreamExt};
+
+    let dedup = &DedupGuard::new(cache, sinks.dedup_enabled());
+    let permits = tokio::sync::Semaphore::new(settings::MAX_CONCURRENT_FETCHES);
+    let permits = &permits;
+    // an auth failure fails every fetch, so one of them is enough to abort the run
+    let auth_error = &std::sync::Mutex::new(None);
+    let stream = futures_util::stream::FuturesUnordered::new();
+    for (id, meta) in sources {
+        let fut = async move {
+            tokio::time::sleep(settings::stagger_offset(id, interval)).await;
+            let ret = async {
+                let _permit = permits.acquire().await?;
+                let mut head: S = cache.load(id).await?;
+                let first_time = head.is_new();
+                let tweets = head.poll(client, catchup).await?;
+                cache.store(&head).await?;
+                Ok::<_, eyre::Error>((tweets, first_time))
+            }
+            .await;
+            let (tweets, first_time) = match ret {
+                Ok(tweets) => tweets,
+                Err(e) if crate::report::is_auth_failure(e.as_ref()) => {
+                    *auth_error.lock().unwrap() = Some(e);
+                    return false;
+                }
+                Err(e) => {
+                    log::error!("{} fetch for {} failed: {}", capitalize(S::NAME), id, e);
+                    let mut event = sentry::event_from_error(as_std_error(&e));
+                    event.tags.insert(String::from("id"), id.into());
+                    sentry::capture_event(event);
+                    return false;
+                }
+            };
+            let model::ResponseItem {
+                data: tweets,
+                includes,
+                meta: gap,
+            } = &tweets;
+            if let Some(gap) = gap {
+                let message = format!(
+                    "{} fetch for {} missed {}",
+                    capitalize(S::NAME),
+                    id,
+                    describe_gap(gap),
+                );
+                log::warn!("{}", message);
+                sentry::with_scope(
+                    |scope| scope.set_tag("id", id),
+                    || sentry::capture_message(&message, sentry::Level::Warning),
+                );
+            }
+            metrics::increment_counter_by(
+                metrics::TWEETS_RECEIVED,
+                &[("engine", S::KIND)],
+                tweets.len() as u64,
+            );
+            sinks.status().record_received(S::ENGINE, tweets.len());
+            let fetched_count = tweets.len();
+            let tweets = &meta.filter(&**sinks.scorer(), tweets, includes);
+            if tweets.len() < fetched_count {
+                log::debug!(
+                    "Filtered out {} of {} tweet(s) of {} {}",
+                    fetched_count - tweets.len(),
+                    fetched_count,
+                    S::NAME,
+                    id,
+                );
+            }
+
+            let cache_fut = futures_util::stream::FuturesUnordered::new();
+            if meta.cache_tweets() {
+                for tweet in tweets {
+                    cache_fut.push(async move {
+                        cache.store(*tweet).await?;
+                        Ok::<_, eyre::Error>(())
+                    });
+                }
+            }
+
+            // catch-up and first time messages go to the static webhooks as usual
+            let catchup_skipped = catchup && tweets.len() > 5;
+            let unrouted;
+            let tweets = match (router, meta.router_tags()) {
+                (Some(router), Some(tags)) if !catchup_skipped && !first_time => {
+                    unrouted =
+                        route_tweets::<S, _>(router, sinks, id, tags, tweets, includes, cache)
+                            .await;
+                    &unrouted
+                }
+                _ => tweets,
+            };
+
+            let webhooks_fut = futures_util::stream::FuturesUnordered::new();
+            for webhook in meta.webhooks() {
+                let sink = sinks.build(webhook);
+                if !sink.is_available() {
+                    continue;
+                }
+                webhooks_fut.push(async move {
+                    let sink = &*sink;
+                    if let Some(gap) = gap {
+                        send_gap_webhook::<S>(sink, id, gap).await?;
+                    }
+                    if catchup && tweets.len() > 5 {
+                        send_catchup_webhook::<S>(sink, id, tweets, includes).await?;
+                    } else if first_time {
+                        send_first_time_webhook::<S>(sink, id).await?;
+                    } else {
+                        let mut pending = Vec::new();
+                        for &tweet in tweets {
+                            if !dedup.is_delivered(tweet.id(), webhook).await {
+                                pending.push(tweet);
+                            }
+                        }
+                        let batches = match webhook.thread_grouping() {
+                            Some(grouping) => batch::group_tweets(&pending, grouping),
+                            None => pending.into_iter().map(|tweet| vec![tweet]).collect(),
+                        };
+                        for batch in &batches {
+                            let delivered = deliver_batch::<S>(sink, id, batch, includes).await?;
+                            for tweet in &delivered {
+                                dedup.mark_delivered(tweet.id(), webhook).await;
+                                if meta.save_images() {
+                                    let media = tweet.media_keys().iter();
+                                    cache.save_images(
+                                        media.filter_map(|key| includes.get_media(key)),
+                                    );
+                                }
+                            }
+                            let interval = S::DELIVERY_INTERVAL.filter(|_| !delivered.is_empty());
+                            if let Some(interval) = interval {
+                                tokio::time::sleep(interval).await;
+                            }
+                        }
+                    }
+                    Ok::<_, eyre::Error>(())
+                });
+            }
+
+            let (cache_ret, webhooks_ret) = futures_util::join!(
+                cache_fut.try_collect::<()>(),
+                webhooks_fut.try_collect::<()>(),
+            );
+            if let Err(e) = cache_ret {
+                log::error!("Failed to cache tweets: {}", e);
+                let mut event = sentry::event_from_error(as_std_error(&e));
+                event.tags.insert(format!("{}_id", S::KIND), id.into());
+                sentry::capture_event(event);
+                return false;
+            }
+            if let Err(e) = webhooks_ret {
+                log::error!("Failed to send webhook for {}: {}", id, e);
+                let permanent = e
+                    .downcast_ref::<SinkError>()
+                    .map(|e| e.is_permanent())
+                    .unwrap_or(false);
+                if permanent {
+                    log::warn!("Skipping the webhook for the rest of the run");
+                }
+                let mut event = sentry::event_from_error(as_std_error(&e));
+                event.tags.insert(String::from("id"), id.into());
+                sentry::capture_event(event);
+                return false;
+            }
+
+            if !catchup_skipped && !first_time && !meta.webhooks().is_empty() {
+                let sources = [format!("{}:{}", S::KIND, id)];
+                for tweet in tweets {
+                    let score = tweet_route::score_tweet(&**sinks.scorer(), tweet, includes);
+                    sinks
+                        .summary()
+                        .record_relay(&sources, tweet, includes, score);
+                    sinks.status().record_relayed(S::ENGINE);
+                }
+            }
+
+            log::debug!("{} fetch for {} successful", capitalize(S::NAME), id);
+            true
+        };
+        stream.push(fut.in_span(S::span(id)));
+    }
+    let results = stream.collect::<Vec<_>>().await;
+    if let Some(e) = auth_error.lock().unwrap().take() {
+        return Err(e);
+    }
+    let failed = results.iter().filter(|&&ok| !ok).count();
+    if failed > 0 {
+        eyre::bail!("{} of {} {}(s) failed", failed, results.len(), S::NAME);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::testing::{Delivery, MemoryCache, RecordingSinks};
+
+    /// Source which returns the tweets queued in its head.
+    #[derive(Debug, Default, Serialize, Deserialize)]
+    struct MockSource {
+        key: String,
+        polled: bool,
+        pending: Vec<model::Tweet>,
+        gap: bool,
+        fail: bool,
+        /// Status to reject the credentials with.
+        unauthorized: Option<u16>,
+    }
+
+    impl CacheItem for MockSource {
+        fn key(&self) -> &str {
+            &self.key
+        }
+    }
+
+    struct MockMeta {
+        webhooks: Vec<WebhookTarget>,
+    }
+
+    impl SourceMeta for MockMeta {
+        fn webhooks(&self) -> &[WebhookTarget] {
+            &self.webhooks
+        }
+
+        fn filter<'a>(
+            &self,
+            _scorer: &dyn tweet_route::Scorer,
+            tweets: &'a [model::Tweet],
+            _includes: &model::ResponseIncludes,
+        ) -> Vec<&'a model::Tweet> {
+            tweets.iter().collect()
+        }
+    }
+
+    impl TweetSource for MockSource {
+        type Meta = MockMeta;
+
+        const ENGINE: Engine = Engine::List;
+        const KIND: &'static str = "mock";
+        const NAME: &'static str = "mock source";
+
+        fn span(_id: &str) -> Span {
+            Span::none()
+        }
+
+        fn is_new(&self) -> bool {
+            !self.polled
+        }
+
+        fn poll<'a>(
+            &'a mut self,
+            _client: &'a TwitterClient,
+            _catchup: bool,
+        ) -> BoxFuture<'a, Result<PollResult, tweet_fetch::Error>> {
+            if let Some(status) = self.unauthorized {
+                let body = String::new();
+                return Box::pin(
+                    async move { Err(tweet_fetch::Error::Unauthorized { status, body }) },
+                );
+            }
+            if self.fail {
+                return Box::pin(async { Err(tweet_fetch::Error::StreamClosed) });
+            }
+            self.polled = true;
+            let tweets = std::mem::take(&mut self.pending);
+            let gap = match tweets.last() {
+                Some(oldest) if self.gap => Some(GapInfo {
+                    from_id: String::from("1"),
+                    to_id: oldest.id().to_owned(),
+                    estimated_missed: Some(3),
+                }),
+                _ => None,
+            };
+            self.gap = false;
+            let ret = model::ResponseItem {
+                data: tweets,
+                includes: Default::default(),
+                meta: gap,
+            };
+            Box::pin(async { Ok(ret) })
+        }
+    }
+
+    fn tweets(ids: &[&str]) -> Vec<model::Tweet> {
+        ids.iter()
+            .map(|id| model::Tweet::new(*id, "text"))
+            .collect()
+    }
+
+    fn webhook(name: &str) -> WebhookTarget {
+        let url = format!("https://discord.com/api/webhooks/1/{}", name);
+        WebhookTarget::new(url.parse().unwrap())
+    }
+
+    /// Queues `source` in the cache, keeping whether it was polled.
+    async fn queue(cache: &MemoryCache, source: MockSource) {
+        let head = LoadCache::<MockSource>::load(cache, &source.key).await;
+        let polled = matches!(head, Ok(head) if head.polled);
+        let source = MockSource { polled, ..source };
+        cache.store(&source).await.unwrap();
+    }
+
+    fn pending(key: &str, ids: &[&str]) -> MockSource {
+        MockSource {
+            key: key.to_owned(),
+            pending: tweets(ids),
+            ..Default::default()
+        }
+    }
+
+    async fn run(
+        sinks: &RecordingSinks,
+        sources: &[(String, MockMeta)],
+        catchup: bool,
+        cache: &MemoryCache,
+    ) -> Result<()> {
+        run_with(&sinks.factory(), sources, catchup, cache).await
+    }
+
+    async fn run_with(
+        factory: &SinkFactory,
+        sources: &[(String, MockMeta)],
+        catchup: bool,
+        cache: &MemoryCache,
+    ) -> Result<()> {
+        let client = TwitterClient::new("token");
+        let sources = sources.iter().map(|(id, meta)| (id, meta));
+        run_source_once::<MockSource, _>(
+            &client,
+            factory,
+            None,
+            sources,
+            catchup,
+            Duration::ZERO,
+            cache,
+        )
+        .await
+    }
+
+    fn source(id: &str, webhooks: &[&str]) -> (String, MockMeta) {
+        let webhooks = webhooks.iter().map(|name| webhook(name)).collect();
+        (id.to_owned(), MockMeta { webhooks })
+    }
+
+    #[tokio::test]
+    async fn first_poll_announces_source() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"])];
+        queue(&cache, pending("a", &["10", "11"])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        let notices = sinks.deliveries();
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0].notice(), Some("Mock `a` initialized"));
+    }
+
+    #[tokio::test]
+    async fn relays_new_tweets_once_per_webhook() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["one", "two"])];
+        queue(&cache, pending("a", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        queue(&cache, pending("a", &["10", "11"])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        let deliveries = sinks.deliveries();
+        for name in ["one", "two"] {
+            let ids = deliveries
+                .iter()
+                .filter(|delivery| {
+                    matches!(delivery, Delivery::Tweet { target, .. } if target.ends_with(name))
+                })
+                .filter_map(Delivery::tweet_id)
+                .collect::<Vec<_>>();
+            assert_eq!(ids, ["10", "11"]);
+        }
+
+        // fetched again, e.g. by an overlapping page
+        sinks.clear();
+        queue(&cache, pending("a", &["11", "12"])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        assert_eq!(sinks.tweet_ids(), ["12", "12"]);
+    }
+
+    #[tokio::test]
+    async fn catchup_lists_skipped_tweets() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"])];
+        queue(&cache, pending("a", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        queue(&cache, pending("a", &["10", "11", "12", "13", "14", "15"])).await;
+        run(&sinks, &sources, true, &cache).await.unwrap();
+        let deliveries = sinks.deliveries();
+        assert_eq!(deliveries.len(), 1);
+        let notice = deliveries[0].notice().unwrap();
+        assert!(notice.starts_with("Skipping 6 tweets of mock `a` during mock source catch-up:\n"));
+        assert!(notice.contains("/status/15>"), "{}", notice);
+
+        // few enough tweets are relayed as usual
+        sinks.clear();
+        queue(&cache, pending("a", &["16"])).await;
+        run(&sinks, &sources, true, &cache).await.unwrap();
+        assert_eq!(sinks.tweet_ids(), ["16"]);
+    }
+
+    #[tokio::test]
+    async fn gap_is_announced_before_tweets() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"])];
+        queue(&cache, pending("a", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        let source = MockSource {
+            gap: true,
+            ..pending("a", &["10", "11"])
+        };
+        queue(&cache, source).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        let deliveries = sinks.deliveries();
+        assert_eq!(
+            deliveries[0].notice(),
+            Some(
+                "Missed about 3 tweet(s) between tweets 1 and 11 of mock `a`, \
+                 which could not be fetched"
+            ),
+        );
+        assert_eq!(sinks.tweet_ids(), ["10", "11"]);
+    }
+
+    #[tokio::test]
+    async fn failed_fetch_fails_only_its_source() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"]), source("b", &["other"])];
+        queue(&cache, pending("a", &[])).await;
+        queue(&cache, pending("b", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        let source = MockSource {
+            fail: true,
+            ..pending("a", &["10"])
+        };
+        queue(&cache, source).await;
+        queue(&cache, pending("b", &["20"])).await;
+        let e = run(&sinks, &sources, false, &cache).await.unwrap_err();
+        assert_eq!(e.to_string(), "1 of 2 mock source(s) failed");
+        assert_eq!(sinks.tweet_ids(), ["20"]);
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_retried_next_run() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"]), source("b", &["other"])];
+        queue(&cache, pending("a", &[])).await;
+        queue(&cache, pending("b", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        queue(&cache, pending("a", &["10"])).await;
+        sinks.fail_next(1);
+        let attempts = sinks.attempts();
+        let e = run(&sinks, &sources, false, &cache).await.unwrap_err();
+        assert_eq!(e.to_string(), "1 of 2 mock source(s) failed");
+        assert!(sinks.deliveries().is_empty());
+        assert_eq!(sinks.attempts(), attempts + 1);
+
+        queue(&cache, pending("a", &["10"])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        assert_eq!(sinks.tweet_ids(), ["10"]);
+    }
+
+    #[tokio::test]
+    async fn auth_failure_aborts_run() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"]), source("b", &["other"])];
+        // rejected on the first poll
+        let source = MockSource {
+            unauthorized: Some(401),
+            ..pending("a", &[])
+        };
+        queue(&cache, source).await;
+        queue(&cache, pending("b", &[])).await;
+        let e = run(&sinks, &sources, false, &cache).await.unwrap_err();
+        assert!(crate::report::is_auth_failure(e.as_ref()), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn token_revoked_mid_run() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"])];
+        queue(&cache, pending("a", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        queue(&cache, pending("a", &["10"])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        let source = MockSource {
+            unauthorized: Some(403),
+            ..pending("a", &["11"])
+        };
+        queue(&cache, source).await;
+        let e = run(&sinks, &sources, false, &cache).await.unwrap_err();
+        assert!(crate::report::is_auth_failure(e.as_ref()), "{}", e);
+        assert!(sinks.deliveries().is_empty());
+
+        // relayed once the token is fixed
+        queue(&cache, pending("a", &["11"])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        assert_eq!(sinks.tweet_ids(), ["11"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fetches_are_staggered_over_interval() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [
+            source("a", &["hook"]),
+            source("b", &["hook"]),
+            source("c", &["hook"]),
+        ];
+        for (id, _) in &sources {
+            queue(&cache, pending(id, &[])).await;
+        }
+        let interval = Duration::from_secs(60);
+        let client = TwitterClient::new("token");
+        let start = tokio::time::Instant::now();
+        run_source_once::<MockSource, _>(
+            &client,
+            &sinks.factory(),
+            None,
+            sources.iter().map(|(id, meta)| (id, meta)),
+            false,
+            interval,
+            &cache,
+        )
+        .await
+        .unwrap();
+
+        // each source waits its own offset, so the run lasts as long as the longest of them
+        let offsets = sources
+            .iter()
+            .map(|(id, _)| settings::stagger_offset(id, interval));
+        assert_eq!(start.elapsed(), offsets.max().unwrap());
+        assert!(start.elapsed() < interval / 2);
+        assert_eq!(sinks.deliveries().len(), sources.len());
+    }
+
+    #[tokio::test]
+    async fn dry_run_stores_and_sends_nothing() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"])];
+        queue(&cache, pending("a", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        queue(&cache, pending("a", &["10", "11"])).await;
+        let attempts = sinks.attempts();
+        let mut factory = sinks.factory();
+        factory.set_dry_run(true);
+        cache.set_read_only(true);
+        run_with(&factory, &sources, false, &cache).await.unwrap();
+        assert!(sinks.deliveries().is_empty());
+        assert_eq!(sinks.attempts(), attempts);
+        let tweet = LoadCache::<model::Tweet>::has(&cache, "10").await.unwrap();
+        assert!(!tweet);
+        let head = LoadCache::<MockSource>::load(&cache, "a").await.unwrap();
+        assert_eq!(head.pending.len(), 2);
+
+        // the tweets weren't marked as relayed
+        cache.set_read_only(false);
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        assert_eq!(sinks.tweet_ids(), ["10", "11"]);
+    }
+
+    /// Delivers `id` like the filtered stream does, to the route `name`.
+    async fn stream_deliver(sinks: &SinkFactory, cache: &MemoryCache, id: &str, name: &str) {
+        let dedup = DedupGuard::new(cache, sinks.dedup_enabled());
+        let tweet = model::Tweet::new(id, "text");
+        let route = tweet_route::RouteResultItem {
+            origin: String::from("test"),
+            url: webhook(name).url().clone(),
+            thread_id: None,
+            payload: serde_json::json!({ "content": "routed" }),
+            render: None,
+        };
+        let includes = Default::default();
+        crate::stream::deliver_routes(sinks, &dedup, &tweet, &includes, &[route]).await;
+    }
+
+    /// Returns the number of tweets sent to the webhook `name`, raw or rendered.
+    fn sent_to(sinks: &RecordingSinks, name: &str) -> usize {
+        let target = webhook(name).url().to_string();
+        sinks
+            .deliveries()
+            .iter()
+            .filter(|delivery| match delivery {
+                Delivery::Tweet { target: to, .. } | Delivery::Raw { target: to } => *to == target,
+                Delivery::Notice { .. } => false,
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn engines_deliver_shared_tweets_once() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let factory = sinks.factory();
+        let sources = [source("a", &["hook", "other"])];
+        queue(&cache, pending("a", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        // relayed by the stream first, then fetched from the list
+        stream_deliver(&factory, &cache, "10", "hook").await;
+        queue(&cache, pending("a", &["10"])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        assert_eq!((sent_to(&sinks, "hook"), sent_to(&sinks, "other")), (1, 1));
+
+        // and the other way around
+        sinks.clear();
+        queue(&cache, pending("a", &["11"])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        stream_deliver(&factory, &cache, "11", "hook").await;
+        assert_eq!((sent_to(&sinks, "hook"), sent_to(&sinks, "other")), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn dedup_can_be_disabled_per_engine() {
+        let cache = MemoryCache::default();
+        let sinks = RecordingSinks::default();
+        let sources = [source("a", &["hook"])];
+        queue(&cache, pending("a", &[])).await;
+        run(&sinks, &sources, false, &cache).await.unwrap();
+        sinks.clear();
+
+        // the list engine ignores deliveries of the stream, which still records its own
+        let stream = sinks.factory();
+        let mut list = sinks.factory();
+        list.set_dedup(false);
+        stream_deliver(&stream, &cache, "10", "hook").await;
+        queue(&cache, pending("a", &["10"])).await;
+        run_with(&list, &sources, false, &cache).await.unwrap();
+        assert_eq!(sent_to(&sinks, "hook"), 2);
+
+        // deliveries of the list aren't recorded, so the stream doesn't skip them
+        sinks.clear();
+        queue(&cache, pending("a", &["11"])).await;
+        run_with(&list, &sources, false, &cache).await.unwrap();
+        stream_deliver(&stream, &cache, "11", "hook").await;
+        assert_eq!(sent_to(&sinks, "hook"), 2);
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+use tweet_model::metrics;
+
+use crate::webhook::WebhookTarget;
+
+/// Deliveries still failing after this long are dropped.
+const MAX_AGE_HOURS: i64 = 24;
+/// Delay before the first retry, doubling on each failure.
+const INITIAL_RETRY_DELAY_SECS: i64 = 30;
+/// Upper bound of the retry delay.
+const MAX_RETRY_DELAY_SECS: i64 = 3600;
+/// Deferred deliveries sent to a target when its active hours start. Older ones are replaced by
+/// a notice with their count.
+const MAX_DEFERRED_PER_TARGET: usize = 10;
+/// How often the queue is checked when nothing is due.
+const IDLE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct QueuedDelivery {
+    target: WebhookTarget,
+    payload: serde_json::Value,
+    attempts: u32,
+    enqueued_at: DateTime<Utc>,
+    next_attempt_at: DateTime<Utc>,
+    /// Start of the active hours the delivery was deferred to, which its age counts from.
+    #[serde(default)]
+    deferred_until: Option<DateTime<Utc>>,
+}
+
+/// Webhook deliveries which failed with a transient error, persisted in `queue/` of the cache
+/// directory and retried by the dispatcher until they succeed or get too old.
+///
+/// Entries are files named after the time they were queued, so that the dispatcher sends them
+/// in order and picks up entries left over from previous runs.
+#[derive(Debug, Clone)]
+pub struct DeliveryQueue {
+    dir: PathBuf,
+    seq: Arc<AtomicU64>,
+    wake: Arc<tokio::sync::Notify>,
+}
+
+impl DeliveryQueue {
+    pub async fn open(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        tokio::fs::create_dir_all(&dir).await?;
+        Ok(Self {
+            dir,
+            seq: Default::default(),
+            wake: Default::default(),
+        })
+    }
+
+    /// Persists a delivery whose first attempt failed, to be retried by the dispatcher.
+    pub async fn enqueue(
+        &self,
+        target: &WebhookTarget,
+        payload: &serde_json::Value,
+    ) -> std::io::Result<()> {
+        let now = Utc::now();
+        self.push(QueuedDelivery {
+            target: target.clone(),
+            payload: payload.clone(),
+            attempts: 1,
+            enqueued_at: now,
+            next_attempt_at: now + retry_delay(1),
+            deferred_until: None,
+        })
+        .await
+    }
+
+    /// Persists a delivery made outside the active hours of the target, to be sent at `until`.
+    pub async fn defer(
+        &self,
+        target: &WebhookTarget,
+        payload: &serde_json::Value,
+        until: DateTime<Utc>,
+    ) -> std::io::Result<()> {
+        self.push(QueuedDelivery {
+            target: target.clone(),
+            payload: payload.clone(),
+            attempts: 0,
+            enqueued_at: Utc::now(),
+            next_attempt_at: until,
+            deferred_until: Some(until),
+        })
+        .await
+    }
+
+    async fn push(&self, entry: QueuedDelivery) -> std::io::Result<()> {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let name = format!(
+            "{:013}-{:06}",
+            entry.enqueued_at.timestamp_millis(),
+            seq % 1_000_000,
+        );
+        self.write(&name, &entry).await?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Returns the number of queued deliveries.
+    pub async fn len(&self) -> std::io::Result<usize> {
+        Ok(self.entry_names().await?.len())
+    }
+
+    /// Sends queued deliveries as they become due, until `shutdown` is cancelled.
+    pub async fn run_dispatcher(
+        &self,
+        executor: tweet_discord::WebhookExecutor,
+        shutdown: CancellationToken,
+    ) {
+        loop {
+            let next_due = match self.dispatch_due(&executor, &shutdown).await {
+                Ok(next_due) => next_due,
+                Err(e) => {
+                    log::error!("Failed to read delivery queue: {}", e);
+                    None
+                }
+            };
+            let sleep = next_due
+                .and_then(|due| (due - Utc::now()).to_std().ok())
+                .map_or(IDLE_POLL_INTERVAL, |d| d.min(IDLE_POLL_INTERVAL));
+            tokio::select! {
+                biased;
+                _ = shutdown.cancelled() => return,
+                _ = self.wake.notified() => {},
+                _ = tokio::time::sleep(sleep) => {},
+            }
+        }
+    }
+
+    /// Attempts every due delivery once, returning when the next one is due.
+    async fn dispatch_due(
+        &self,
+        executor: &tweet_discord::WebhookExecutor,
+        shutdown: &CancellationToken,
+    ) -> std::io::Result<Option<DateTime<Utc>>> {
+        let names = self.entry_names().await?;
+        metrics::set_gauge(metrics::DELIVERY_QUEUE_SIZE, &[], names.len() as f64);
+
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            let path = self.entry_path(&name);
+            match read_entry(&path).await {
+                Ok(entry) => entries.push((name, entry)),
+                Err(e) => {
+                    log::error!("Dropping unreadable queued delivery {}: {}", name, e);
+                    remove_entry(&path).await;
+                }
+            }
+        }
+        self.summarize_deferred(executor, &mut entries).await;
+
+        let mut next_due: Option<DateTime<Utc>> = None;
+        for (name, mut entry) in entries {
+            if shutdown.is_cancelled() {
+                break;
+            }
+            let path = self.entry_path(&name);
+            let host = tweet_discord::redact_url(entry.target.url());
+
+            let now = Utc::now();
+            let age = now - entry.deferred_until.unwrap_or(entry.enqueued_at);
+            if age > Duration::hours(MAX_AGE_HOURS) {
+                log::warn!(
+                    "Dropping delivery to {} queued at {} after {} attempt(s), too old",
+                    host,
+                    entry.enqueued_at,
+                    entry.attempts,
+                );
+                metrics::increment_counter(metrics::DELIVERIES_DROPPED, &[("reason", "expired")]);
+                remove_entry(&path).await;
+                continue;
+            }
+            let deferral = entry
+                .target
+                .active_hours()
+                .and_then(|hours| hours.deferral(now));
+            if let Some(until) = deferral {
+                // retries which missed the active hours wait for the next ones
+                if entry.next_attempt_at < until {
+                    entry.next_attempt_at = until;
+                    entry.deferred_until = Some(until);
+                    self.write(&name, &entry).await?;
+                }
+            }
+            if entry.next_attempt_at > now {
+                next_due =
+                    Some(next_due.map_or(entry.next_attempt_at, |t| t.min(entry.next_attempt_at)));
+                continue;
+            }
+
+            let ret = tweet_discord::execute_webhook_with_options(
+                executor,
+                entry.target.url(),
+                &entry.payload,
+                &entry.target.execute_options(),
+            )
+            .await;
+            match ret {
+                Ok(_) => {
+                    log::info!(
+                        "Delivered queued message to {} after {} attempt(s)",
+                        host,
+                        entry.attempts + 1,
+                    );
+                    remove_entry(&path).await;
+                }
+                Err(e) if e.is_permanent() => {
+                    log::warn!("Dropping delivery to {}: {}", host, e);
+                    metrics::increment_counter(metrics::DELIVERIES_DROPPED, &[("reason", "gone")]);
+                    remove_entry(&path).await;
+                }
+                Err(e) => {
+                    entry.attempts += 1;
+                    entry.next_attempt_at = now + retry_delay(entry.attempts);
+                    log::debug!(
+                        "Queued delivery to {} failed, attempt {}: {}",
+                        host,
+                        entry.attempts,
+                        e,
+                    );
+                    self.write(&name, &entry).await?;
+                    next_due = Some(
+                        next_due.map_or(entry.next_attempt_at, |t| t.min(entry.next_attempt_at)),
+                    );
+                }
+            }
+        }
+        Ok(next_due)
+    }
+
+    /// Replaces all but the newest `MAX_DEFERRED_PER_TARGET` due deferred deliveries of each
+    /// target with a notice of how many were skipped, like catch-up of the polling engines.
+    ///
+    /// `entries` are sorted from the oldest.
+    async fn summarize_deferred(
+        &self,
+        executor: &tweet_discord::WebhookExecutor,
+        entries: &mut Vec<(String, QueuedDelivery)>,
+    ) {
+        let now = Utc::now();
+        let mut due = HashMap::<(&str, Option<&str>), Vec<usize>>::new();
+        for (idx, (_, entry)) in entries.iter().enumerate() {
+            let waiting = entry.attempts == 0
+                && entry.deferred_until.is_some()
+                && entry.next_attempt_at <= now;
+            let active = entry
+                .target
+                .active_hours()
+                .filter(|hours| !hours.contains(now))
+                .is_none();
+            if waiting && active {
+                let key = (entry.target.url().as_str(), entry.target.thread_id());
+                due.entry(key).or_default().push(idx);
+            }
+        }
+
+        let mut summarized = HashSet::new();
+        for indices in due.values() {
+            if indices.len() <= MAX_DEFERRED_PER_TARGET {
+                continue;
+            }
+            let skipped = &indices[..indices.len() - MAX_DEFERRED_PER_TARGET];
+            let target = &entries[skipped[0]].1.target;
+            let host = tweet_discord::redact_url(target.url());
+            let message = format!(
+                "Skipping {} message{} which arrived outside active hours",
+                skipped.len(),
+                if skipped.len() == 1 { "" } else { "s" },
+            );
+            let payload = tweet_discord::make_notice_payload(message, target.identity());
+            let ret = tweet_discord::execute_webhook_with_options(
+                executor,
+                target.url(),
+                &payload.to_value(),
+                &target.execute_options(),
+            )
+            .await;
+            if let Err(e) = ret {
+                // deliver them one by one instead
+                log::warn!(
+                    "Failed to send deferred delivery summary to {}: {}",
+                    host,
+                    e
+                );
+                continue;
+            }
+            log::info!(
+                "Summarized {} deferred deliveries to {}",
+                skipped.len(),
+                host,
+            );
+            metrics::increment_counter_by(
+                metrics::DELIVERIES_DROPPED,
+                &[("reason", "summarized")],
+                skipped.len() as u64,
+            );
+            summarized.extend(skipped.iter().copied());
+        }
+
+        for &idx in &summarized {
+            remove_entry(&self.entry_path(&entries[idx].0)).await;
+        }
+        let mut idx = 0;
+        entries.retain(|_| {
+            let keep = !summarized.contains(&idx);
+            idx += 1;
+            keep
+        });
+    }
+
+    fn entry_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    async fn entry_names(&self) -> std::io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let file_name = entry.file_name();
+            let name = match file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+            {
+                Some(name) => name,
+                None => continue,
+            };
+            names.push(name.to_owned());
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    async fn write(&self, name: &str, entry: &QueuedDelivery) -> std::io::Result<()> {
+        let data = serde_json::to_vec(entry).unwrap();
+        // write to a temporary file first, so that the dispatcher never reads partial entries
+        let tmp_path = self.dir.join(format!(".{}.tmp", name));
+        tokio::fs::write(&tmp_path, &data).await?;
+        tokio::fs::rename(&tmp_path, self.entry_path(name)).await
+    }
+}
+
+async fn read_entry(path: &Path) -> std::io::Result<QueuedDelivery> {
+    let data = tokio::fs::read(path).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+async fn remove_entry(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        log::error!("Failed to remove queued delivery {}: {}", path.display(), e);
+    }
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    let secs = INITIAL_RETRY_DELAY_SECS << attempts.saturating_sub(1).min(16);
+    Duration::seconds(secs.min(MAX_RETRY_DELAY_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("tweet-broadcast-queue-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    fn target() -> WebhookTarget {
+        WebhookTarget::new("https://discord.com/api/webhooks/1/token".parse().unwrap())
+    }
+
+    fn payload(idx: usize) -> serde_json::Value {
+        serde_json::json!({ "content": format!("message {}", idx) })
+    }
+
+    async fn payloads(queue: &DeliveryQueue) -> Vec<serde_json::Value> {
+        let mut ret = Vec::new();
+        for name in queue.entry_names().await.unwrap() {
+            ret.push(read_entry(&queue.entry_path(&name)).await.unwrap().payload);
+        }
+        ret
+    }
+
+    #[tokio::test]
+    async fn entries_persist_in_order() {
+        let dir = temp_dir("persist");
+        let queue = DeliveryQueue::open(&dir).await.unwrap();
+        for idx in 0..5 {
+            queue.enqueue(&target(), &payload(idx)).await.unwrap();
+        }
+        drop(queue);
+
+        let queue = DeliveryQueue::open(&dir).await.unwrap();
+        assert_eq!(queue.len().await.unwrap(), 5);
+        assert_eq!(payloads(&queue).await, (0..5).map(payload).collect::<Vec<_>>());
+        let name = &queue.entry_names().await.unwrap()[0];
+        let entry = read_entry(&queue.entry_path(name)).await.unwrap();
+        assert_eq!(entry.attempts, 1);
+        assert_eq!(entry.next_attempt_at - entry.enqueued_at, retry_delay(1));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn unreadable_entries_are_dropped() {
+        let dir = temp_dir("unreadable");
+        let queue = DeliveryQueue::open(&dir).await.unwrap();
+        std::fs::write(dir.join("0000000000000-000000.json"), "{").unwrap();
+        // temporary files of partial writes aren't entries
+        std::fs::write(dir.join(".0000000000001-000000.tmp"), "{").unwrap();
+        assert_eq!(queue.len().await.unwrap(), 1);
+
+        let executor = Default::default();
+        let next_due = queue.dispatch_due(&executor, &CancellationToken::new()).await;
+        assert_eq!(next_due.unwrap(), None);
+        assert_eq!(queue.len().await.unwrap(), 0);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn old_entries_expire() {
+        let dir = temp_dir("expiry");
+        let queue = DeliveryQueue::open(&dir).await.unwrap();
+        let now = Utc::now();
+        let entry = |idx, enqueued_at, next_attempt_at, deferred_until| QueuedDelivery {
+            target: target(),
+            payload: payload(idx),
+            attempts: 1,
+            enqueued_at,
+            next_attempt_at,
+            deferred_until,
+        };
+        let expired = now - Duration::hours(MAX_AGE_HOURS + 1);
+        let later = now + Duration::hours(1);
+        queue.push(entry(0, expired, now, None)).await.unwrap();
+        // age counts from the deferral
+        queue.push(entry(1, expired, later, Some(later))).await.unwrap();
+        queue.push(entry(2, now, later + Duration::hours(1), None)).await.unwrap();
+
+        // nothing is due, so nothing is sent
+        let executor = Default::default();
+        let next_due = queue.dispatch_due(&executor, &CancellationToken::new()).await;
+        assert_eq!(next_due.unwrap(), Some(later));
+        assert_eq!(payloads(&queue).await, [payload(1), payload(2)]);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn retry_delay_backs_off() {
+        let delays = (0..=9).map(|attempts| retry_delay(attempts).num_seconds());
+        let delays = delays.collect::<Vec<_>>();
+        assert_eq!(delays, [30, 30, 60, 120, 240, 480, 960, 1920, 3600, 3600]);
+        assert_eq!(retry_delay(u32::MAX), Duration::seconds(MAX_RETRY_DELAY_SECS));
+    }
+}
@@ -0,0 +1,239 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use eyre::Result;
+use tokio::sync::watch;
+
+/// Directory configs and route scripts are read from, given with `--config-dir`.
+///
+/// Configs used to live in the cache directory, so those missing from the config directory are
+/// still read from there. Without a config directory, everything is read from the cache
+/// directory as before.
+#[derive(Debug, Clone)]
+pub struct ConfigDir {
+    dir: Option<PathBuf>,
+    cache_dir: PathBuf,
+}
+
+impl ConfigDir {
+    pub fn new(dir: Option<PathBuf>, cache_dir: PathBuf) -> Self {
+        Self { dir, cache_dir }
+    }
+
+    /// Returns the directory configs are read from.
+    pub fn dir(&self) -> &Path {
+        self.dir.as_deref().unwrap_or(&self.cache_dir)
+    }
+
+    /// Resolves a config path relative to the config directory, e.g. `lists/config.toml`.
+    pub fn path(&self, relative: impl AsRef<Path>) -> PathBuf {
+        let relative = relative.as_ref();
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return self.cache_dir.join(relative),
+        };
+        let path = dir.join(relative);
+        let legacy_path = self.cache_dir.join(relative);
+        if !path.exists() && legacy_path.exists() {
+            log::warn!(
+                "{} not found, reading {} in the cache directory instead",
+                path.display(),
+                legacy_path.display(),
+            );
+            return legacy_path;
+        }
+        path
+    }
+
+    /// Resolves a route script path. Relative paths are relative to the config directory if
+    /// given, or the working directory otherwise.
+    pub fn script_path(&self, path: &Path) -> PathBuf {
+        match &self.dir {
+            Some(dir) if path.is_relative() && (dir.join(path).exists() || !path.exists()) => {
+                dir.join(path)
+            }
+            _ => path.to_owned(),
+        }
+    }
+}
+
+/// Config file of an engine.
+pub trait EngineConfig: serde::de::DeserializeOwned + Send + Sync + 'static {
+    /// Checks the config beyond what deserialization does.
+    ///
+    /// Errors should name the offending key, e.g. `lists.123.webhooks[0]`.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the webhooks of the config with their keys, e.g. `lists.123.webhooks[0]`.
+    fn webhooks(&self) -> Vec<(String, &crate::webhook::WebhookTarget)> {
+        Vec::new()
+    }
+}
+
+/// Reads and validates a config file. Errors are prefixed with the path of the file.
+pub async fn read_config<T: EngineConfig>(path: &std::path::Path) -> Result<T> {
+    let ret = async {
+        let data = tokio::fs::read(path).await?;
+        let config = toml::from_slice::<T>(&data)?;
+        config.validate()?;
+        Ok(config)
+    }
+    .await;
+    ret.map_err(|e: eyre::Error| eyre::eyre!("{}: {}", path.display(), e))
+}
+
+/// Engine config shared with the running engine, which can be replaced while it runs.
+///
+/// Engines hold receivers from `subscribe` and take the current config on each tick.
+#[derive(Debug)]
+pub struct ConfigHolder<T> {
+    path: PathBuf,
+    tx: watch::Sender<Arc<T>>,
+    // kept so that sending never fails, even before engines subscribe
+    rx: watch::Receiver<Arc<T>>,
+}
+
+impl<T: EngineConfig> ConfigHolder<T> {
+    pub async fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let config = read_config(&path).await?;
+        let (tx, rx) = watch::channel(Arc::new(config));
+        Ok(Self { path, tx, rx })
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<T>> {
+        self.rx.clone()
+    }
+
+    pub fn replace(&self, config: T) {
+        self.tx.send(Arc::new(config)).ok();
+    }
+
+    /// Reads the config file again and swaps it in. Invalid configs are logged and rejected,
+    /// leaving the current one active.
+    pub async fn reload(&self) {
+        match read_config::<T>(&self.path).await {
+            Ok(config) => {
+                self.replace(config);
+                log::info!("Reloaded {}", self.path.display());
+            }
+            Err(e) => {
+                log::error!("Rejected new config, keeping the current one: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, serde::Deserialize)]
+    struct TestConfig {
+        interval: u64,
+    }
+
+    impl EngineConfig for TestConfig {
+        fn validate(&self) -> Result<()> {
+            if self.interval == 0 {
+                eyre::bail!("interval: must be positive");
+            }
+            Ok(())
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("tweet-broadcast-reload-{}-{}", name, std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn reload_swaps_valid_configs() {
+        let dir = temp_dir("swap");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "interval = 60\n").unwrap();
+        let holder = ConfigHolder::<TestConfig>::load(&path).await.unwrap();
+        let mut rx = holder.subscribe();
+        assert_eq!(rx.borrow().interval, 60);
+
+        std::fs::write(&path, "interval = 30\n").unwrap();
+        holder.reload().await;
+        rx.changed().await.unwrap();
+        let current = rx.borrow().clone();
+        assert_eq!(current.interval, 30);
+
+        // neither a parse error nor a validation error replaces the config
+        for invalid in ["interval = \"soon\"\n", "interval = 0\n"] {
+            std::fs::write(&path, invalid).unwrap();
+            holder.reload().await;
+            assert!(Arc::ptr_eq(&rx.borrow(), &current), "{}", invalid);
+        }
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    const WEBHOOK: &str = "https://discord.com/api/webhooks/1/token";
+
+    /// Reads `config` as the config file `name`, returning the validation error.
+    async fn config_error<T: EngineConfig + std::fmt::Debug>(name: &str, config: &str) -> String {
+        let dir = temp_dir(&format!("invalid-{}", name));
+        let path = dir.join(format!("{}.toml", name));
+        std::fs::write(&path, config).unwrap();
+        let e = read_config::<T>(&path).await.unwrap_err().to_string();
+        std::fs::remove_dir_all(&dir).ok();
+        assert!(e.starts_with(&path.display().to_string()), "{}", e);
+        e
+    }
+
+    #[tokio::test]
+    async fn invalid_configs_name_the_key() {
+        use crate::list::ListsConfig;
+        use crate::search::SearchConfig;
+        use crate::user::UsersConfig;
+
+        // a misspelled field
+        let config = format!(
+            "[lists.123]\nwebhooks = [{:?}]\nscore_treshold = 5.0\n",
+            WEBHOOK,
+        );
+        let e = config_error::<ListsConfig>("unknown-key", &config).await;
+        assert!(e.contains("`score_treshold`"), "{}", e);
+        assert!(e.contains("lists.123"), "{}", e);
+
+        for url in [
+            "http://discord.com/api/webhooks/1/token",
+            "https://example.com/api/webhooks/1/token",
+        ] {
+            let config = format!("[users.123]\nwebhooks = [{:?}]\n", url);
+            let e = config_error::<UsersConfig>("webhook", &config).await;
+            assert!(e.contains("users.123.webhooks[0]: "), "{}", e);
+        }
+
+        for threshold in ["nan", "inf", "-1.0", "0.0"] {
+            let config = format!(
+                "[lists.123]\nwebhooks = [{:?}]\nscore_threshold = {}\n",
+                WEBHOOK, threshold,
+            );
+            let e = config_error::<ListsConfig>("list-threshold", &config).await;
+            assert!(e.contains("lists.123.score_threshold: "), "{}", e);
+
+            let config = format!(
+                "[terms.art]\nterm = \"art\"\nwebhooks = []\nscore_threshold = {}\n",
+                threshold,
+            );
+            let e = config_error::<SearchConfig>("search-threshold", &config).await;
+            assert!(e.contains("terms.art.score_threshold: "), "{}", e);
+        }
+
+        let config = format!("[lists.art]\nwebhooks = [{:?}]\n", WEBHOOK);
+        let e = config_error::<ListsConfig>("list-id", &config).await;
+        assert!(e.contains("lists.art: list ID is not numeric"), "{}", e);
+        let config = format!("[users.\"@artist\"]\nwebhooks = [{:?}]\n", WEBHOOK);
+        let e = config_error::<UsersConfig>("user-id", &config).await;
+        assert!(e.contains("users.@artist: user ID is not numeric"), "{}", e);
+    }
+}
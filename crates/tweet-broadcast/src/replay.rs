@@ -0,0 +1,250 @@
+use std::collections::HashSet;
+
+use eyre::Result;
+use futures_util::future::BoxFuture;
+
+use tweet_fetch::TwitterClient;
+use tweet_model::{self as model, cache::*};
+use tweet_route::{CacheData, Router};
+
+use crate::cache::{FsCache, FsError};
+use crate::sink::SinkFactory;
+use crate::stream::UnroutedRecord;
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Only tweets newer than this ID are replayed.
+    pub since: u64,
+    /// Tag to replay tweets under. Tweets relayed before with other tags are skipped.
+    pub tag: Option<String>,
+    /// Replays tweets which were already relayed.
+    pub force: bool,
+}
+
+/// View of the cache for routing replayed tweets.
+///
+/// Every replayed tweet is in the cache, so tweets only count as cached if they were relayed,
+/// as they would have when they first arrived.
+struct ReplayCache<'a>(&'a FsCache);
+
+impl Cache for ReplayCache<'_> {
+    type Error = FsError;
+}
+
+impl LoadCache<model::Tweet> for ReplayCache<'_> {
+    fn load(&self, key: &str) -> BoxFuture<'_, Result<model::Tweet, Self::Error>> {
+        LoadCache::<model::Tweet>::load(self.0, key)
+    }
+
+    fn has(&self, key: &str) -> BoxFuture<'_, Result<bool, Self::Error>> {
+        LoadCache::<CacheData>::has(self.0, key)
+    }
+}
+
+impl LoadCache<CacheData> for ReplayCache<'_> {
+    fn load(&self, key: &str) -> BoxFuture<'_, Result<CacheData, Self::Error>> {
+        LoadCache::<CacheData>::load(self.0, key)
+    }
+
+    fn has(&self, key: &str) -> BoxFuture<'_, Result<bool, Self::Error>> {
+        LoadCache::<CacheData>::has(self.0, key)
+    }
+}
+
+/// Routes cached tweets newer than `options.since` again, in ID order, delivering to the
+/// resulting routes.
+///
+/// Only tweets which made it to the cache can be replayed.
+pub async fn run(
+    client: &TwitterClient,
+    sinks: &SinkFactory,
+    cache: &FsCache,
+    router: &mut Router,
+    options: &ReplayOptions,
+) -> Result<()> {
+    let mut tweet_ids = cache
+        .scan_keys("tweets")
+        .await?
+        .into_iter()
+        .filter_map(|key| key.parse::<u64>().ok())
+        .filter(|&id| id > options.since)
+        .collect::<Vec<_>>();
+    tweet_ids.sort_unstable();
+
+    // tweets relayed as retweet sources are recorded under the retweet
+    let mut relayed = HashSet::new();
+    for key in cache.scan_keys("stream").await? {
+        if !key.parse::<u64>().map(|id| id > options.since).unwrap_or(false) {
+            continue;
+        }
+        let data = match LoadCache::<CacheData>::load(cache, &key).await {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("Failed to load route data of {}: {}", key, e);
+                continue;
+            }
+        };
+        if let Some(target_tweet_id) = data.target_tweet_id() {
+            relayed.insert(target_tweet_id.to_owned());
+        }
+        relayed.insert(key);
+    }
+    log::info!("Replaying {} cached tweet(s) newer than {}", tweet_ids.len(), options.since);
+
+    let replay_cache = ReplayCache(cache);
+    let mut routed_ids = HashSet::new();
+    let mut routed_count = 0usize;
+    let mut failed_count = 0usize;
+    for id in tweet_ids {
+        let id = id.to_string();
+        if relayed.contains(&id) && !options.force {
+            log::debug!("Skipping {}, already relayed", id);
+            continue;
+        }
+        // tweets cached without routes keep the tags of their rules separately
+        let tags = match LoadCache::<CacheData>::load(cache, &id).await {
+            Ok(data) => data.tags().to_vec(),
+            Err(_) => match LoadCache::<UnroutedRecord>::load(cache, &id).await {
+                Ok(record) => record.tags().to_vec(),
+                Err(_) => Vec::new(),
+            },
+        };
+        let tags = match (&options.tag, tags.is_empty()) {
+            (Some(tag), false) if !tags.contains(tag) => continue,
+            (Some(tag), true) => vec![tag.clone()],
+            _ => tags,
+        };
+
+        let tweet = match resolve_item(client, cache, &id, tags).await {
+            Ok(tweet) => tweet,
+            Err(e) => {
+                log::error!("Failed to resolve tweet {}: {}", id, e);
+                failed_count += 1;
+                continue;
+            }
+        };
+        let route_result = match router.call(&tweet, &replay_cache).await {
+            Ok(route_result) => route_result,
+            Err(e) => {
+                log::error!("Failed to route {}: {}", id, e);
+                if let Some(js_error) = e.js_error() {
+                    log::error!("Route script stack trace:\n{}", js_error.format_stack());
+                }
+                failed_count += 1;
+                continue;
+            }
+        };
+        for tweet_route::ScriptError { origin, error } in route_result.errors() {
+            log::error!("Route script {} failed for {}: {}", origin, id, error);
+        }
+
+        let payload = route_result.payload();
+        let routes = route_result.routes();
+        // a retweet and its source may both be in the window
+        if routes.is_empty() || !routed_ids.insert(payload.tweet.id().to_owned()) {
+            continue;
+        }
+        routed_count += 1;
+        log::info!(
+            "Replaying tweet {} by @{}, score: {:.4}, script(s): {:?}",
+            payload.tweet.id(),
+            payload.author.username(),
+            payload.score,
+            routes.iter().map(|r| &*r.origin).collect::<Vec<_>>(),
+        );
+        if let Err(e) = route_result.cache_recursive(cache).await {
+            log::error!("Failed to save metadata: {}", e);
+        }
+        // replays are explicit, so they're delivered even if already delivered
+        let dedup = crate::dedup::DedupGuard::new(cache, false);
+        crate::stream::deliver_routes(sinks, &dedup, &tweet.data, &tweet.includes, routes).await;
+    }
+
+    log::info!("Replay done, {} tweet(s) routed", routed_count);
+    if failed_count > 0 {
+        eyre::bail!("{} tweet(s) failed to replay", failed_count);
+    }
+    Ok(())
+}
+
+/// Reconstructs a stream item from the cache, retrieving pieces missing from the cache.
+async fn resolve_item(
+    client: &TwitterClient,
+    cache: &FsCache,
+    id: &str,
+    tags: Vec<String>,
+) -> Result<model::ResponseItem<model::Tweet, model::StreamMeta>> {
+    let data = LoadCache::<model::Tweet>::load(cache, id).await?;
+    let mut includes = model::ResponseIncludes::default();
+    let mut to_retrieve = Vec::new();
+
+    let mut referenced = Vec::new();
+    for reference in data.referenced_tweets() {
+        match LoadCache::<model::Tweet>::load(cache, reference.id()).await {
+            Ok(tweet) => referenced.push(tweet),
+            Err(_) => to_retrieve.push(reference.id().to_owned()),
+        }
+    }
+    for tweet in std::iter::once(&data).chain(&referenced) {
+        let mut complete = true;
+        if let Some(author_id) = tweet.author_id() {
+            if includes.get_user(author_id).is_none() {
+                match LoadCache::<model::User>::load(cache, author_id).await {
+                    Ok(user) => includes.push_user(user),
+                    Err(_) => complete = false,
+                }
+            }
+        }
+        for media_key in tweet.media_keys() {
+            if includes.get_media(media_key).is_none() {
+                match LoadCache::<model::Media>::load(cache, media_key).await {
+                    Ok(media) => includes.push_media(media),
+                    Err(_) => complete = false,
+                }
+            }
+        }
+        if !complete {
+            to_retrieve.push(tweet.id().to_owned());
+        }
+    }
+
+    if !to_retrieve.is_empty() {
+        log::debug!("Retrieving {:?} missing from the cache", to_retrieve);
+        let mut res = client.retrieve(&to_retrieve).await?;
+        for tweet in res.data {
+            let known = tweet.id() == data.id() || referenced.iter().any(|t| t.id() == tweet.id());
+            if !known {
+                referenced.push(tweet);
+            }
+        }
+        includes.take_augment(&mut res.includes);
+    }
+    for tweet in referenced {
+        includes.push_tweet(tweet);
+    }
+
+    let target = match data.get_retweet_source() {
+        Some(source_id) => includes
+            .get_tweet(source_id)
+            .ok_or_else(|| eyre::eyre!("retweeted tweet {} not found", source_id))?,
+        None => &data,
+    };
+    for tweet in [&data, target] {
+        let author_id = tweet
+            .author_id()
+            .ok_or_else(|| eyre::eyre!("author of {} unknown", tweet.id()))?;
+        if includes.get_user(author_id).is_none() {
+            eyre::bail!("author {} of {} not found", author_id, tweet.id());
+        }
+    }
+
+    let matching_rules = tags
+        .into_iter()
+        .map(|tag| model::MatchingRule::new("", tag))
+        .collect();
+    Ok(model::ResponseItem {
+        data,
+        includes,
+        meta: model::StreamMeta::new(matching_rules),
+    })
+}
@@ -0,0 +1,111 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use sentry::protocol::Event;
+use sentry::Hub;
+
+/// Error messages of failures to parse API responses or cached files, which are usually
+/// transient and reported again on every fetch.
+const PARSE_ERROR_PREFIXES: &[&str] = &["Parse error: ", "error decoding response body"];
+
+/// Parses a `--sentry-tag` value of the form `key=value`.
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected key=value, got `{}`", s)),
+    }
+}
+
+/// Builds Sentry client options from the environment.
+///
+/// `SENTRY_ENVIRONMENT` sets the environment, e.g. `production` or `staging`.
+/// `SENTRY_TRACES_SAMPLE_RATE` sets the fraction of events sent, between 0 and 1; the Sentry
+/// client in use doesn't record transactions, so the rate applies to error events.
+///
+/// Events of parse errors are dropped if `drop_parse_errors` is set.
+pub fn client_options(drop_parse_errors: bool) -> sentry::ClientOptions {
+    let mut options = sentry::ClientOptions {
+        release: sentry::release_name!(),
+        ..Default::default()
+    };
+    if let Ok(environment) = std::env::var("SENTRY_ENVIRONMENT") {
+        options.environment = Some(Cow::Owned(environment));
+    }
+    if let Ok(rate) = std::env::var("SENTRY_TRACES_SAMPLE_RATE") {
+        match rate.parse::<f32>() {
+            Ok(rate) if (0.0..=1.0).contains(&rate) => options.sample_rate = rate,
+            _ => log::warn!("Ignoring invalid SENTRY_TRACES_SAMPLE_RATE `{}`", rate),
+        }
+    }
+
+    if drop_parse_errors {
+        options.before_send = Some(Arc::new(|event: Event<'static>| {
+            if is_parse_error(&event) {
+                None
+            } else {
+                Some(event)
+            }
+        }));
+    }
+    options
+}
+
+/// Tags every event with the given `--sentry-tag` values, including those of engine hubs created
+/// afterwards.
+pub fn set_tags(tags: &[(String, String)]) {
+    sentry::configure_scope(|scope| {
+        for (key, value) in tags {
+            scope.set_tag(key, value);
+        }
+    });
+}
+
+fn is_parse_error(event: &Event<'_>) -> bool {
+    event.exception.values.iter().any(|exception| {
+        let value = exception.value.as_deref().unwrap_or_default();
+        PARSE_ERROR_PREFIXES
+            .iter()
+            .any(|prefix| value.starts_with(prefix))
+    })
+}
+
+/// Creates a hub for an engine task, tagging its events with the engine name.
+///
+/// The hub inherits the scope of the main hub. Bind it to the engine future with
+/// `SentryFutureExt::bind_hub`, so that errors and panics of the engine are reported with the
+/// tag even though the future moves between worker threads.
+pub fn engine_hub(engine: impl std::fmt::Display) -> Arc<Hub> {
+    let hub = Arc::new(Hub::new_from_top(Hub::main()));
+    let engine = engine.to_string();
+    hub.configure_scope(|scope| scope.set_tag("engine", engine));
+    hub
+}
+
+/// Returns whether `error` was caused by Twitter rejecting the credentials, which retrying
+/// won't fix.
+pub fn is_auth_failure(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(error) = error.downcast_ref::<tweet_fetch::Error>() {
+            if error.is_unauthorized() {
+                return true;
+            }
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Logs and reports an auth failure of `engine`, tagging the event with `auth`.
+pub fn auth_failure(engine: crate::Engine, error: &(dyn std::error::Error + 'static)) {
+    log::error!(
+        "Twitter rejected the token of engine {}: {}. Check that {} holds a valid bearer \
+         token which isn't revoked; shutting down",
+        engine,
+        error,
+        crate::tokens::token_source(engine),
+    );
+    let mut event = sentry::event_from_error(error);
+    event.tags.insert(String::from("auth"), String::from("unauthorized"));
+    sentry::capture_event(event);
+}
@@ -0,0 +1,533 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::{mpsc, oneshot};
+
+use tweet_model::{self as model, cache::LoadCache};
+use tweet_route::{
+    CacheData, HeapStats, PreviousRoute, RouteOutcome, RouteResult, RouteResultItem, Router,
+    Scorer, TagRoute,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RouterError {
+    #[error("route scripts failed to load: {0}")]
+    NotLoaded(String),
+    #[error(transparent)]
+    Route(#[from] tweet_route::Error),
+    #[error("router thread is gone")]
+    Gone,
+}
+
+impl RouterError {
+    /// Returns the exception thrown by a route script, if the error is one.
+    pub fn js_error(&self) -> Option<&tweet_route::JsError> {
+        match self {
+            Self::Route(e) => e.js_error(),
+            _ => None,
+        }
+    }
+}
+
+struct RouteRequest {
+    tweet: model::Tweet,
+    includes: model::ResponseIncludes,
+    tags: Vec<String>,
+    reply: oneshot::Sender<Result<Vec<RouteResultItem>, RouterError>>,
+}
+
+struct CallRequest {
+    item: model::ResponseItem<model::Tweet, model::StreamMeta>,
+    previous: PreviousRoute,
+    reply: oneshot::Sender<Result<RouteOutcome, RouterError>>,
+}
+
+enum Request {
+    Route(Box<RouteRequest>),
+    Call(Box<CallRequest>),
+    Load(oneshot::Sender<Result<(), RouterError>>),
+    Reload,
+    ReloadScript {
+        name: String,
+        script: String,
+        reply: oneshot::Sender<Result<(), RouterError>>,
+    },
+    SetTagRoutes(BTreeMap<String, Vec<TagRoute>>),
+    HeapStats(oneshot::Sender<Option<HeapStats>>),
+}
+
+/// Handle to a `Router` running on its own thread, so that engines on the multithreaded runtime
+/// can use it and pools can route tweets in parallel.
+///
+/// V8 isolates can't move between threads, so the router lives on a dedicated thread and tweets
+/// are sent to it. The route scripts are loaded on the first request which needs them, so that
+/// engines not using the router don't need them.
+#[derive(Debug, Clone)]
+pub struct RouterHandle {
+    tx: mpsc::UnboundedSender<Request>,
+}
+
+impl RouterHandle {
+    fn spawn(
+        name: &str,
+        thread_name: String,
+        route_scripts: Vec<PathBuf>,
+        scorer: Arc<dyn Scorer>,
+    ) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let name = name.to_owned();
+        std::thread::Builder::new()
+            .name(thread_name)
+            .spawn(move || {
+                let member = Member {
+                    name,
+                    route_scripts,
+                    scorer,
+                    router: None,
+                    tag_routes: Default::default(),
+                };
+                run(member, rx)
+            })
+            .expect("Failed to spawn router thread");
+        Self { tx }
+    }
+
+    async fn request<T>(
+        &self,
+        request: impl FnOnce(oneshot::Sender<Result<T, RouterError>>) -> Request,
+    ) -> Result<T, RouterError> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(request(reply)).map_err(|_| RouterError::Gone)?;
+        rx.await.map_err(|_| RouterError::Gone)?
+    }
+
+    /// Routes `tweet` with the given tags in place of matching rules, returning its routes.
+    ///
+    /// Errors of individual scripts are logged; an error is returned only if every script
+    /// failed, or the scripts couldn't be loaded.
+    pub async fn route(
+        &self,
+        tweet: &model::Tweet,
+        includes: &model::ResponseIncludes,
+        tags: Vec<String>,
+    ) -> Result<Vec<RouteResultItem>, RouterError> {
+        self.request(|reply| {
+            Request::Route(Box::new(RouteRequest {
+                tweet: tweet.clone(),
+                includes: includes.clone(),
+                tags,
+                reply,
+            }))
+        })
+        .await
+    }
+
+    /// Routes a tweet of the filtered stream with its route data, like `Router::call_with`.
+    pub async fn call(
+        &self,
+        item: &model::ResponseItem<model::Tweet, model::StreamMeta>,
+        previous: PreviousRoute,
+    ) -> Result<RouteOutcome, RouterError> {
+        self.request(|reply| {
+            Request::Call(Box::new(CallRequest {
+                item: item.clone(),
+                previous,
+                reply,
+            }))
+        })
+        .await
+    }
+
+    /// Loads the route scripts now, if they aren't loaded yet.
+    pub async fn load(&self) -> Result<(), RouterError> {
+        self.request(Request::Load).await
+    }
+
+    /// Loads the route scripts again before routing the next tweet.
+    pub fn reload(&self) {
+        self.tx.send(Request::Reload).ok();
+    }
+
+    /// Replaces the script named `name`, like `Router::reload`, keeping the previous version if
+    /// the new one fails to load.
+    pub async fn reload_script(&self, name: &str, script: &str) -> Result<(), RouterError> {
+        self.request(|reply| Request::ReloadScript {
+            name: name.to_owned(),
+            script: script.to_owned(),
+            reply,
+        })
+        .await
+    }
+
+    /// Replaces the tag routes, like `Router::set_tag_routes`, also for routers loaded later.
+    pub fn set_tag_routes(&self, tag_routes: BTreeMap<String, Vec<TagRoute>>) {
+        self.tx.send(Request::SetTagRoutes(tag_routes)).ok();
+    }
+
+    /// Returns the heap statistics of the router, or `None` if the route scripts aren't loaded.
+    pub async fn heap_stats(&self) -> Option<HeapStats> {
+        let (reply, rx) = oneshot::channel();
+        self.tx.send(Request::HeapStats(reply)).ok()?;
+        rx.await.ok().flatten()
+    }
+}
+
+#[derive(Debug)]
+struct PoolMember {
+    handle: RouterHandle,
+    in_flight: AtomicUsize,
+}
+
+/// Decrements the in-flight count of a pool member, even if the request is cancelled.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Pool of routers, each with its own isolate and thread, routing tweets in parallel.
+///
+/// Every tweet is routed by a single member, the one with the fewest requests in flight, so
+/// routes of a tweet don't depend on which member routed the tweets before it. Members don't
+/// share any script state.
+#[derive(Debug, Clone)]
+pub struct RouterPool {
+    members: Arc<Vec<PoolMember>>,
+    scorer: Arc<dyn Scorer>,
+}
+
+impl RouterPool {
+    /// Spawns `size` routers, at least one, running `route_scripts`. `name` tells the engine
+    /// using the pool in logs and thread names.
+    pub fn spawn(
+        name: &str,
+        size: usize,
+        route_scripts: Vec<PathBuf>,
+        scorer: Arc<dyn Scorer>,
+    ) -> Self {
+        let members = (0..size.max(1))
+            .map(|idx| PoolMember {
+                handle: RouterHandle::spawn(
+                    name,
+                    format!("{}-router-{}", name, idx),
+                    route_scripts.clone(),
+                    scorer.clone(),
+                ),
+                in_flight: AtomicUsize::new(0),
+            })
+            .collect();
+        Self {
+            members: Arc::new(members),
+            scorer,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Scorer of the members, which `RouteOutcome::into_result` needs to build the payload the
+    /// member routed.
+    pub fn scorer(&self) -> &dyn Scorer {
+        &*self.scorer
+    }
+
+    /// Runs `f` with the member with the fewest requests in flight, counting the request.
+    async fn with_least_busy<'a, T, Fut>(&'a self, f: impl FnOnce(&'a RouterHandle) -> Fut) -> T
+    where
+        Fut: std::future::Future<Output = T>,
+    {
+        let member = self
+            .members
+            .iter()
+            .min_by_key(|member| member.in_flight.load(Ordering::Relaxed))
+            .expect("router pool is empty");
+        member.in_flight.fetch_add(1, Ordering::Relaxed);
+        let _in_flight = InFlight(&member.in_flight);
+        f(&member.handle).await
+    }
+
+    /// Routes `tweet` with the least busy member, like `RouterHandle::route`.
+    pub async fn route(
+        &self,
+        tweet: &model::Tweet,
+        includes: &model::ResponseIncludes,
+        tags: Vec<String>,
+    ) -> Result<Vec<RouteResultItem>, RouterError> {
+        self.with_least_busy(|handle| handle.route(tweet, includes, tags))
+            .await
+    }
+
+    /// Routes a tweet of the filtered stream with the least busy member, reading its route data
+    /// from `cache` first, like `Router::call`.
+    ///
+    /// Build the result with `RouteOutcome::into_result` and the scorer of the pool.
+    pub async fn call<Cache>(
+        &self,
+        item: &model::ResponseItem<model::Tweet, model::StreamMeta>,
+        cache: &Cache,
+    ) -> Result<RouteOutcome, RouterError>
+    where
+        Cache: LoadCache<CacheData>,
+    {
+        let previous = PreviousRoute::load(item, cache).await?;
+        self.with_least_busy(|handle| handle.call(item, previous))
+            .await
+    }
+
+    /// Loads the route scripts in every member now, failing if any of them fails.
+    pub async fn load(&self) -> Result<(), RouterError> {
+        let results =
+            futures_util::future::join_all(self.members.iter().map(|member| member.handle.load()))
+                .await;
+        results.into_iter().collect()
+    }
+
+    /// Loads the route scripts again in every member, each before routing its next tweet.
+    pub fn reload(&self) {
+        for member in self.members.iter() {
+            member.handle.reload();
+        }
+    }
+
+    /// Replaces the script named `name` in every member, like `RouterHandle::reload_script`.
+    pub async fn reload_script(&self, name: &str, script: &str) -> Result<(), RouterError> {
+        let results = futures_util::future::join_all(
+            self.members.iter().map(|member| member.handle.reload_script(name, script)),
+        )
+        .await;
+        results.into_iter().collect()
+    }
+
+    /// Replaces the tag routes of every member.
+    pub fn set_tag_routes(&self, tag_routes: BTreeMap<String, Vec<TagRoute>>) {
+        for member in self.members.iter() {
+            member.handle.set_tag_routes(tag_routes.clone());
+        }
+    }
+
+    /// Returns the heap statistics summed over the members which have loaded the route scripts,
+    /// or `None` if none of them has.
+    pub async fn heap_stats(&self) -> Option<HeapStats> {
+        let stats = futures_util::future::join_all(
+            self.members.iter().map(|member| member.handle.heap_stats()),
+        )
+        .await;
+        let loaded = stats.into_iter().flatten().collect::<Vec<_>>();
+        if loaded.is_empty() {
+            None
+        } else {
+            Some(loaded.into_iter().sum())
+        }
+    }
+}
+
+/// State of the thread of a `RouterHandle`.
+struct Member {
+    /// Engine using the router, for logs.
+    name: String,
+    route_scripts: Vec<PathBuf>,
+    scorer: Arc<dyn Scorer>,
+    router: Option<Result<Router, String>>,
+    tag_routes: BTreeMap<String, Vec<TagRoute>>,
+}
+
+impl Member {
+    async fn load(&self) -> Result<Router, String> {
+        match crate::load_router(&self.route_scripts, self.scorer.clone()).await {
+            Ok(mut router) => {
+                router.set_tag_routes(self.tag_routes.clone());
+                Ok(router)
+            }
+            Err(e) => {
+                log::error!("Failed to load route scripts for the {} router: {}", self.name, e);
+                Err(e.to_string())
+            }
+        }
+    }
+
+    /// Returns the router, loading it if it isn't loaded yet.
+    async fn router(&mut self) -> Result<&mut Router, RouterError> {
+        let loaded = match self.router.take() {
+            Some(loaded) => loaded,
+            None => self.load().await,
+        };
+        match self.router.insert(loaded) {
+            Ok(router) => Ok(router),
+            Err(e) => Err(RouterError::NotLoaded(e.clone())),
+        }
+    }
+}
+
+fn run(mut member: Member, mut rx: mpsc::UnboundedReceiver<Request>) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to build router runtime");
+    rt.block_on(async move {
+        while let Some(request) = rx.recv().await {
+            match request {
+                Request::Route(request) => {
+                    let RouteRequest {
+                        tweet,
+                        includes,
+                        tags,
+                        reply,
+                    } = *request;
+                    let ret = match member.router().await {
+                        Ok(router) => {
+                            let tags = tags.iter().map(|tag| &**tag).collect::<Vec<_>>();
+                            route(router, &tweet, &includes, &tags)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    reply.send(ret).ok();
+                }
+                Request::Call(request) => {
+                    let CallRequest {
+                        item,
+                        previous,
+                        reply,
+                    } = *request;
+                    // script errors are logged by the engine, along with the rest of the result
+                    let ret = match member.router().await {
+                        Ok(router) => router
+                            .call_with(&item, previous)
+                            .map(RouteResult::into_outcome)
+                            .map_err(Into::into),
+                        Err(e) => Err(e),
+                    };
+                    reply.send(ret).ok();
+                }
+                Request::Load(reply) => {
+                    let ret = member.router().await.map(|_| ());
+                    reply.send(ret).ok();
+                }
+                Request::Reload => {
+                    if member.router.take().is_some() {
+                        log::info!("Route scripts of the {} router will be reloaded", member.name);
+                    }
+                }
+                Request::ReloadScript {
+                    name,
+                    script,
+                    reply,
+                } => {
+                    let ret = match &mut member.router {
+                        Some(Ok(router)) => router.reload(&name, &script).map_err(Into::into),
+                        // loaded from the files, with the new script, when needed
+                        _ => {
+                            member.router = None;
+                            Ok(())
+                        }
+                    };
+                    reply.send(ret).ok();
+                }
+                Request::SetTagRoutes(tag_routes) => {
+                    if let Some(Ok(router)) = &mut member.router {
+                        router.set_tag_routes(tag_routes.clone());
+                    }
+                    member.tag_routes = tag_routes;
+                }
+                Request::HeapStats(reply) => {
+                    let stats = match &mut member.router {
+                        Some(Ok(router)) => Some(router.heap_stats()),
+                        _ => None,
+                    };
+                    reply.send(stats).ok();
+                }
+            }
+        }
+    });
+}
+
+fn route(
+    router: &mut Router,
+    tweet: &model::Tweet,
+    includes: &model::ResponseIncludes,
+    tags: &[&str],
+) -> Result<Vec<RouteResultItem>, RouterError> {
+    let route_result = router.call_plain(tweet, includes, tags)?;
+    for tweet_route::ScriptError { origin, error } in route_result.errors() {
+        log::error!(
+            "Route script {} failed for tweet {}: {}",
+            origin,
+            tweet.id(),
+            error
+        );
+        if let Some(js_error) = error.js_error() {
+            log::error!("Route script stack trace:\n{}", js_error.format_stack());
+        }
+        let mut ev = sentry::event_from_error(error);
+        ev.tags.insert(String::from("script"), origin.clone());
+        sentry::capture_event(ev);
+    }
+    Ok(route_result.into_routes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::testing::MemoryCache;
+
+    use super::*;
+
+    const ROUTE_TIME: Duration = Duration::from_secs(1);
+
+    /// Pool of `size` members which take `ROUTE_TIME` to route a tweet, without route scripts.
+    fn slow_pool(size: usize) -> RouterPool {
+        let members = (0..size)
+            .map(|_| {
+                let (tx, mut rx) = mpsc::unbounded_channel();
+                tokio::spawn(async move {
+                    while let Some(request) = rx.recv().await {
+                        if let Request::Call(request) = request {
+                            tokio::time::sleep(ROUTE_TIME).await;
+                            request.reply.send(Ok(RouteOutcome::default())).ok();
+                        }
+                    }
+                });
+                PoolMember {
+                    handle: RouterHandle { tx },
+                    in_flight: AtomicUsize::new(0),
+                }
+            })
+            .collect();
+        RouterPool {
+            members: Arc::new(members),
+            scorer: Arc::new(tweet_route::DefaultScorer::default()),
+        }
+    }
+
+    fn item(id: &str) -> model::ResponseItem<model::Tweet, model::StreamMeta> {
+        model::ResponseItem {
+            data: model::Tweet::new(id, "text"),
+            includes: Default::default(),
+            meta: model::StreamMeta::new(vec![]),
+        }
+    }
+
+    /// Returns how long routing two tweets at once takes with a pool of `size`.
+    async fn route_two(size: usize) -> Duration {
+        let pool = slow_pool(size);
+        let cache = MemoryCache::default();
+        let (first, second) = (item("1"), item("2"));
+        let start = tokio::time::Instant::now();
+        let (a, b) = futures_util::join!(pool.call(&first, &cache), pool.call(&second, &cache));
+        a.unwrap();
+        b.unwrap();
+        start.elapsed()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calls_run_in_parallel() {
+        assert_eq!(route_two(2).await, ROUTE_TIME);
+        // a single member routes one tweet at a time
+        assert_eq!(route_two(1).await, ROUTE_TIME * 2);
+    }
+}
@@ -0,0 +1,256 @@
+use std::path::PathBuf;
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use tweet_fetch::TwitterClient;
+use tweet_model::{NewStreamRule, StreamRule, StreamRulesUpdate};
+
+/// Subcommands of `rules`, managing the rules of the filtered stream.
+#[derive(Debug, clap::Subcommand)]
+pub enum RulesCommand {
+    /// Print the current rules.
+    List,
+    /// Add a rule, after validating it.
+    Add {
+        #[clap(long)]
+        value: String,
+        #[clap(long)]
+        tag: Option<String>,
+    },
+    /// Delete rules by ID or tag.
+    Delete {
+        #[clap(long = "id")]
+        ids: Vec<String>,
+        /// Delete every rule with this tag.
+        #[clap(long = "tag")]
+        tags: Vec<String>,
+    },
+    /// Add the rules of a file which are missing from the stream.
+    ///
+    /// Rules are identified by their value and tag, so a rule whose value changed is added as a
+    /// new one.
+    Sync {
+        #[clap(long)]
+        file: PathBuf,
+        /// Also delete rules which aren't in the file.
+        #[clap(long)]
+        prune: bool,
+    },
+}
+
+/// Rules file of `rules sync`.
+///
+/// ```toml
+/// [[rules]]
+/// value = "from:TwitterDev has:media"
+/// tag = "dev"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    rules: Vec<NewStreamRule>,
+}
+
+/// Changes made by a subcommand, printed with `--json`.
+#[derive(Debug, Default, Serialize)]
+struct RulesChange {
+    added: Vec<StreamRule>,
+    /// Rules which passed validation in a dry run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    would_add: Vec<NewStreamRule>,
+    deleted: Vec<StreamRule>,
+    dry_run: bool,
+}
+
+/// Runs a `rules` subcommand. With `dry_run`, changes are validated but not applied.
+pub async fn run(
+    client: &TwitterClient,
+    command: &RulesCommand,
+    json: bool,
+    dry_run: bool,
+) -> Result<()> {
+    match command {
+        RulesCommand::List => {
+            let rules = client.stream_rules().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&rules)?);
+            } else {
+                print_table(&rules);
+            }
+            Ok(())
+        }
+        RulesCommand::Add { value, tag } => {
+            let rule = NewStreamRule {
+                value: value.clone(),
+                tag: tag.clone(),
+            };
+            let change = add_rules(client, &[rule], dry_run).await?;
+            print_change(&change, json)
+        }
+        RulesCommand::Delete { ids, tags } => {
+            if ids.is_empty() && tags.is_empty() {
+                eyre::bail!("give rules to delete with --id or --tag");
+            }
+            let rules = client.stream_rules().await?;
+            let targets = rules
+                .into_iter()
+                .filter(|rule| {
+                    ids.iter().any(|id| id == rule.id())
+                        || matches!(rule.tag(), Some(tag) if tags.iter().any(|t| t == tag))
+                })
+                .collect::<Vec<_>>();
+            for id in ids {
+                if !targets.iter().any(|rule| rule.id() == id) {
+                    eyre::bail!("no rule with ID {}", id);
+                }
+            }
+            let change = delete_rules(client, targets, dry_run).await?;
+            print_change(&change, json)
+        }
+        RulesCommand::Sync { file, prune } => {
+            let content = tokio::fs::read_to_string(file)
+                .await
+                .map_err(|e| eyre::eyre!("{}: {}", file.display(), e))?;
+            let RulesFile { rules: wanted } = toml::from_str(&content)
+                .map_err(|e| eyre::eyre!("{}: {}", file.display(), e))?;
+
+            let current = client.stream_rules().await?;
+            let missing = wanted
+                .iter()
+                .filter(|rule| !current.iter().any(|c| rule.matches(c)))
+                .cloned()
+                .collect::<Vec<_>>();
+            let stale = current
+                .into_iter()
+                .filter(|c| !wanted.iter().any(|rule| rule.matches(c)))
+                .collect::<Vec<_>>();
+
+            let mut change = if missing.is_empty() {
+                RulesChange {
+                    dry_run,
+                    ..Default::default()
+                }
+            } else {
+                add_rules(client, &missing, dry_run).await?
+            };
+            if *prune {
+                change.deleted = delete_rules(client, stale, dry_run).await?.deleted;
+            } else if !stale.is_empty() {
+                log::warn!(
+                    "{} rule(s) not in {}, pass --prune to delete them",
+                    stale.len(),
+                    file.display(),
+                );
+            }
+            print_change(&change, json)
+        }
+    }
+}
+
+/// Validates `rules` with a dry run of the API, then adds them unless `dry_run` is set.
+async fn add_rules(
+    client: &TwitterClient,
+    rules: &[NewStreamRule],
+    dry_run: bool,
+) -> Result<RulesChange> {
+    let validation = client.add_stream_rules(rules, true).await?;
+    check_update(&validation)?;
+    if dry_run {
+        return Ok(RulesChange {
+            would_add: rules.to_vec(),
+            dry_run,
+            ..Default::default()
+        });
+    }
+
+    let update = client.add_stream_rules(rules, false).await?;
+    check_update(&update)?;
+    Ok(RulesChange {
+        added: update.rules().to_vec(),
+        dry_run,
+        ..Default::default()
+    })
+}
+
+async fn delete_rules(
+    client: &TwitterClient,
+    rules: Vec<StreamRule>,
+    dry_run: bool,
+) -> Result<RulesChange> {
+    if !rules.is_empty() {
+        let ids = rules.iter().map(|rule| rule.id()).collect::<Vec<_>>();
+        let update = client.delete_stream_rules(&ids, dry_run).await?;
+        check_update(&update)?;
+    }
+    Ok(RulesChange {
+        deleted: rules,
+        dry_run,
+        ..Default::default()
+    })
+}
+
+/// Fails if any rule of the update was rejected.
+fn check_update(update: &StreamRulesUpdate) -> Result<()> {
+    if update.errors().is_empty() {
+        return Ok(());
+    }
+    for error in update.errors() {
+        let rule = error.value().or_else(|| error.id()).unwrap_or("?");
+        eprintln!("Rule `{}`: {}", rule, error);
+    }
+    eyre::bail!("{} rule(s) rejected", update.errors().len())
+}
+
+fn print_change(change: &RulesChange, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(change)?);
+        return Ok(());
+    }
+
+    if change.dry_run {
+        println!("Dry run, rules weren't changed.");
+    }
+    if !change.would_add.is_empty() {
+        println!("Would add:");
+        for rule in &change.would_add {
+            println!("  {}  {}", rule.tag.as_deref().unwrap_or("-"), rule.value);
+        }
+    }
+    if !change.added.is_empty() {
+        println!("Added:");
+        print_table(&change.added);
+    }
+    if !change.deleted.is_empty() {
+        println!("{}:", if change.dry_run { "Would delete" } else { "Deleted" });
+        print_table(&change.deleted);
+    }
+    if change.added.is_empty() && change.deleted.is_empty() && !change.dry_run {
+        println!("Rules are up to date.");
+    }
+    Ok(())
+}
+
+fn print_table(rules: &[StreamRule]) {
+    if rules.is_empty() {
+        println!("No rules.");
+        return;
+    }
+    let id_width = rules.iter().map(|r| r.id().len()).max().unwrap_or(0).max(2);
+    let tag_width = rules
+        .iter()
+        .map(|r| r.tag().unwrap_or("-").chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(3);
+    println!("{:<id_width$}  {:<tag_width$}  VALUE", "ID", "TAG");
+    for rule in rules {
+        println!(
+            "{:<id_width$}  {:<tag_width$}  {}",
+            rule.id(),
+            rule.tag().unwrap_or("-"),
+            rule.value(),
+        );
+    }
+}
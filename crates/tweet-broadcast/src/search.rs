@@ -1,5 +1,5 @@
-use std::collections::{BinaryHeap, HashMap};
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
 use eyre::Result;
@@ -9,20 +9,51 @@ use tweet_fetch::TwitterClient;
 use tweet_model::{
     self as model,
     cache::*,
+    trace::TraceExt,
 };
 
+use crate::dedup::{DedupGuard, DeliveredMarker};
+use crate::image::SaveImages;
+use crate::reload::EngineConfig;
+use crate::settings::{self, SearchSettings};
+use crate::sink::{DeliveryOptions, SinkError, SinkFactory};
+use crate::webhook::WebhookTarget;
+use crate::Engine;
+
+/// Minimum score increase before messages of a relayed tweet are updated.
+const SCORE_EDIT_THRESHOLD: f64 = 1.0;
+/// How long relayed tweets are checked for deletion.
+const DELETION_CHECK_WINDOW_HOURS: i64 = 24;
+
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SearchConfig {
+    #[serde(default)]
+    settings: SearchSettings,
     terms: HashMap<String, SearchTermMetaInner>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct SearchTermMetaInner {
     term: String,
     #[serde(default)]
     trending: bool,
     score_threshold: Option<f64>,
-    webhooks: Vec<reqwest::Url>,
+    /// Seconds between fetches of this term, `poll_interval_secs` of `[settings]` by default.
+    fetch_interval_secs: Option<u64>,
+    /// Page size of fetches, between 10 and 100.
+    max_results: Option<u32>,
+    /// Languages of tweets to track, e.g. `["ja", "en"]`. Any language if empty.
+    #[serde(default)]
+    languages: Vec<String>,
+    /// Drops tweets without a language when `languages` is given.
+    #[serde(default)]
+    strict_language: bool,
+    /// Only tracks tweets with media, their own or of the tweet they quote.
+    #[serde(default)]
+    require_media: bool,
+    webhooks: Vec<WebhookTarget>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -31,92 +62,433 @@ pub struct SearchTermMeta<'a> {
     pub term: &'a str,
     pub trending: bool,
     pub score_threshold: f64,
-    pub webhooks: &'a [reqwest::Url],
+    pub fetch_interval_secs: Option<u64>,
+    pub max_results: Option<u32>,
+    pub languages: &'a [String],
+    pub strict_language: bool,
+    pub require_media: bool,
+    pub webhooks: &'a [WebhookTarget],
+}
+
+impl SearchTermMeta<'_> {
+    /// Returns whether the language of `tweet` is one of the languages of the term.
+    pub fn accepts_language(&self, tweet: &model::Tweet) -> bool {
+        if self.languages.is_empty() {
+            return true;
+        }
+        match tweet.lang() {
+            Some(lang) => self.languages.iter().any(|l| l.eq_ignore_ascii_case(lang)),
+            None => !self.strict_language,
+        }
+    }
+
+    /// Returns whether `tweet` passes the media requirement of the term.
+    pub fn accepts_media(&self, tweet: &model::Tweet, includes: &model::ResponseIncludes) -> bool {
+        !self.require_media || has_media(tweet, includes)
+    }
+}
+
+/// Returns whether `tweet`, or a tweet it retweets or quotes, has media in `includes`.
+fn has_media(tweet: &model::Tweet, includes: &model::ResponseIncludes) -> bool {
+    let own_media = |tweet: &model::Tweet| {
+        tweet
+            .media_keys()
+            .iter()
+            .any(|key| includes.get_media(key).is_some())
+    };
+    own_media(tweet)
+        || tweet
+            .referenced_tweets()
+            .iter()
+            .filter(|r| r.ref_type() != model::TweetReferenceType::RepliedTo)
+            .filter_map(|r| includes.get_tweet(r.id()))
+            .any(own_media)
 }
 
 impl SearchConfig {
-    pub async fn from_config(config: impl AsRef<Path>) -> Result<Self> {
-        let data = tokio::fs::read(config).await?;
-        let config = toml::from_slice::<SearchConfig>(&data)?;
-        Ok(config)
+    pub fn settings(&self) -> &SearchSettings {
+        &self.settings
     }
 
     pub fn terms(&self) -> impl Iterator<Item = SearchTermMeta<'_>> {
         self.terms
             .iter()
-            .map(|(id, meta)| SearchTermMeta {
-                id,
-                term: &meta.term,
-                trending: meta.trending,
-                score_threshold: meta.score_threshold.unwrap_or(15.0),
-                webhooks: &meta.webhooks,
+            .map(|(id, meta)| meta.as_meta(id))
+    }
+
+    pub fn term(&self, id: &str) -> Option<SearchTermMeta<'_>> {
+        self.terms
+            .get_key_value(id)
+            .map(|(id, meta)| meta.as_meta(id))
+    }
+}
+
+impl EngineConfig for SearchConfig {
+    fn validate(&self) -> Result<()> {
+        let settings = &self.settings;
+        settings::SEARCH_POLL
+            .check_key("settings.poll_interval_secs", settings.poll_interval_secs)?;
+        settings::SEARCH_TRACKER
+            .check_key("settings.tracker_interval_secs", settings.tracker_interval_secs)?;
+        for (key, value) in [
+            ("max_tracked", settings.max_tracked),
+            ("max_tracked_per_term", settings.max_tracked_per_term),
+            ("max_checks_per_run", settings.max_checks_per_run),
+        ] {
+            if value == Some(0) {
+                eyre::bail!("settings.{}: must be positive", key);
+            }
+        }
+        for (id, meta) in &self.terms {
+            if meta.term.trim().is_empty() {
+                eyre::bail!("terms.{}.term: search term is empty", id);
+            }
+            if let Some(threshold) = meta.score_threshold {
+                if !threshold.is_finite() || threshold <= 0.0 {
+                    eyre::bail!(
+                        "terms.{}.score_threshold: must be a positive number, got {}",
+                        id,
+                        threshold,
+                    );
+                }
+            }
+            settings::SEARCH_POLL
+                .check_key(&format!("terms.{}.fetch_interval_secs", id), meta.fetch_interval_secs)?;
+            if let Some(max_results) = meta.max_results {
+                if !(10..=100).contains(&max_results) {
+                    eyre::bail!(
+                        "terms.{}.max_results: must be between 10 and 100, got {}",
+                        id,
+                        max_results,
+                    );
+                }
+            }
+            if meta.languages.iter().any(|lang| lang.trim().is_empty()) {
+                eyre::bail!("terms.{}.languages: language codes must not be empty", id);
+            }
+            for (idx, webhook) in meta.webhooks.iter().enumerate() {
+                webhook
+                    .validate()
+                    .map_err(|e| eyre::eyre!("terms.{}.webhooks[{}]: {}", id, idx, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn webhooks(&self) -> Vec<(String, &WebhookTarget)> {
+        self.terms
+            .iter()
+            .flat_map(|(id, meta)| {
+                meta.webhooks.iter().enumerate().map(move |(idx, webhook)| {
+                    (format!("terms.{}.webhooks[{}]", id, idx), webhook)
+                })
             })
+            .collect()
+    }
+}
+
+impl SearchTermMetaInner {
+    fn as_meta<'a>(&'a self, id: &'a str) -> SearchTermMeta<'a> {
+        SearchTermMeta {
+            id,
+            term: &self.term,
+            trending: self.trending,
+            score_threshold: self.score_threshold.unwrap_or(15.0),
+            fetch_interval_secs: self.fetch_interval_secs,
+            max_results: self.max_results,
+            languages: &self.languages,
+            strict_language: self.strict_language,
+            require_media: self.require_media,
+            webhooks: &self.webhooks,
+        }
+    }
+}
+
+/// Discord messages a trending tweet was relayed as, kept to update them as the score climbs.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RelayedTweet {
+    tweet_id: String,
+    first_score: f64,
+    last_score: f64,
+    messages: Vec<RelayedMessage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RelayedMessage {
+    webhook_url: reqwest::Url,
+    /// ID of the first message sent, which has the content and the main embed.
+    message_id: Option<String>,
+    #[serde(default)]
+    thread_id: Option<String>,
+    #[serde(default)]
+    delete_on_tweet_deletion: bool,
+}
+
+impl CacheItem for RelayedTweet {
+    fn key(&self) -> &str {
+        &self.tweet_id
     }
 }
 
 #[derive(Debug)]
-struct TrendingEntry<'a> {
+struct TrendingEntry {
     check_due_at: DateTime<Utc>,
     tweet_id: String,
     created_at: DateTime<Utc>,
-    search_config: SearchTermMeta<'a>,
+    /// IDs of the search terms which matched the tweet, looked up on each check so that config
+    /// reloads apply.
+    term_ids: BTreeSet<String>,
     previous_score: f64,
     penalty: u32,
+    /// Number of score checks so far.
+    checks: u32,
+    /// Metrics when tracking began.
+    initial_metrics: Option<model::TweetPublicMetrics>,
 }
 
-impl PartialEq for TrendingEntry<'_> {
-    fn eq(&self, other: &Self) -> bool {
-        self.check_due_at == other.check_due_at
+impl TrendingEntry {
+    fn elapsed(&self) -> chrono::Duration {
+        Utc::now() - self.created_at
+    }
+
+    fn needs_check(&self) -> bool {
+        self.check_due_at <= Utc::now()
     }
-}
-impl Eq for TrendingEntry<'_> {}
 
-impl PartialOrd for TrendingEntry<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.check_due_at.cmp(&other.check_due_at))
+    /// Describes the growth of the tweet, checked again with `score` and `metrics`, for relay
+    /// messages.
+    fn trending_info(
+        &self,
+        terms: &[SearchTermMeta<'_>],
+        score: f64,
+        metrics: Option<&model::TweetPublicMetrics>,
+    ) -> tweet_discord::TrendingInfo {
+        let delta = |count: fn(&model::TweetPublicMetrics) -> u64| {
+            match (metrics, &self.initial_metrics) {
+                (Some(current), Some(initial)) => count(current) as i64 - count(initial) as i64,
+                _ => 0,
+            }
+        };
+        tweet_discord::TrendingInfo {
+            terms: terms.iter().map(|term| term.term.to_owned()).collect(),
+            score,
+            previous_score: (self.checks > 0).then_some(self.previous_score),
+            retweet_delta: delta(|m| m.retweet_count),
+            like_delta: delta(|m| m.like_count),
+            created_at: self.created_at,
+        }
     }
 }
-impl Ord for TrendingEntry<'_> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.check_due_at.cmp(&other.check_due_at)
+
+/// When each search term is due to be fetched next.
+#[derive(Debug, Default)]
+pub struct TermSchedule {
+    next_due: HashMap<String, tokio::time::Instant>,
+}
+
+impl TermSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the terms due at `now`. Terms never fetched are due immediately.
+    pub fn due_terms<'a>(
+        &self,
+        config: &'a SearchConfig,
+        now: tokio::time::Instant,
+    ) -> Vec<SearchTermMeta<'a>> {
+        config
+            .terms()
+            .filter(|term| !matches!(self.next_due.get(term.id), Some(&due) if due > now))
+            .collect()
+    }
+
+    /// Returns when the next term is due, `None` if there are no terms.
+    pub fn next_due(&self, config: &SearchConfig) -> Option<tokio::time::Instant> {
+        let now = tokio::time::Instant::now();
+        config
+            .terms()
+            .map(|term| self.next_due.get(term.id).copied().unwrap_or(now))
+            .min()
+    }
+
+    pub fn mark_fetched(&mut self, id: &str, now: tokio::time::Instant, interval: std::time::Duration) {
+        self.next_due.insert(id.to_owned(), now + interval);
+    }
+
+    /// Forgets terms removed from the config.
+    pub fn retain(&mut self, config: &SearchConfig) {
+        self.next_due.retain(|id, _| config.term(id).is_some());
     }
 }
 
-impl TrendingEntry<'_> {
-    fn elapsed(&self) -> chrono::Duration {
-        Utc::now() - self.created_at
+/// Bounds of the trending tracker, from `[settings]` of the search config.
+#[derive(Debug, Clone, Copy)]
+pub struct TrackerLimits {
+    max_tracked: usize,
+    max_tracked_per_term: usize,
+    max_checks_per_run: usize,
+}
+
+impl Default for TrackerLimits {
+    fn default() -> Self {
+        Self {
+            max_tracked: 10000,
+            max_tracked_per_term: 2000,
+            max_checks_per_run: 100,
+        }
     }
+}
 
-    fn needs_check(&self) -> bool {
-        self.check_due_at <= Utc::now()
+impl TrackerLimits {
+    pub fn from_settings(settings: &SearchSettings) -> Self {
+        let default = Self::default();
+        Self {
+            max_tracked: settings.max_tracked.unwrap_or(default.max_tracked),
+            max_tracked_per_term: settings
+                .max_tracked_per_term
+                .unwrap_or(default.max_tracked_per_term),
+            max_checks_per_run: settings.max_checks_per_run.unwrap_or(default.max_checks_per_run),
+        }
     }
 }
 
-#[derive(Debug, Default)]
-pub struct TrendingContext<'conf> {
-    tracking: BinaryHeap<std::cmp::Reverse<TrendingEntry<'conf>>>,
+/// Tracks tweets of trending search terms, checking their score until they're relayed or
+/// get old.
+///
+/// Each tweet is tracked once, however many terms matched it.
+#[derive(Debug)]
+pub struct TrendingContext {
+    tracking: HashMap<String, TrendingEntry>,
+    /// Check times of tracked tweets. Items whose time doesn't match the entry in `tracking`
+    /// are stale, and skipped.
+    schedule: BinaryHeap<std::cmp::Reverse<(DateTime<Utc>, String)>>,
+    limits: TrackerLimits,
+    /// Tweets relayed recently, with the time they were relayed, to check for deletion.
+    recently_relayed: VecDeque<(String, DateTime<Utc>)>,
+    /// Tweets dropped by the language filter since the last stats log, by term.
+    language_drops: HashMap<String, u64>,
+    scorer: Arc<dyn tweet_route::Scorer>,
 }
 
-impl<'conf> TrendingContext<'conf> {
+impl Default for TrendingContext {
+    fn default() -> Self {
+        Self {
+            tracking: Default::default(),
+            schedule: Default::default(),
+            limits: Default::default(),
+            recently_relayed: Default::default(),
+            language_drops: Default::default(),
+            scorer: Arc::new(tweet_route::DefaultScorer::default()),
+        }
+    }
+}
+
+impl TrendingContext {
     pub fn new() -> Self {
         Self::default()
     }
 
+    pub fn set_limits(&mut self, limits: TrackerLimits) {
+        self.limits = limits;
+    }
+
+    pub fn set_scorer(&mut self, scorer: Arc<dyn tweet_route::Scorer>) {
+        self.scorer = scorer;
+    }
+
+    /// Returns the number of tweets being tracked.
+    pub fn tracking_count(&self) -> usize {
+        self.tracking.len()
+    }
+
+    /// Returns the number of tweets being tracked, by term.
+    pub fn tracking_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for entry in self.tracking.values() {
+            for term_id in &entry.term_ids {
+                *counts.entry(term_id.clone()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// Evicts the entry with the lowest score, if the tracker or one of `term_ids` is full.
+    fn make_room(&mut self, term_ids: &BTreeSet<String>) {
+        let counts = self.tracking_counts();
+        let full_terms = term_ids
+            .iter()
+            .filter(|&id| counts.get(id).copied().unwrap_or(0) >= self.limits.max_tracked_per_term)
+            .collect::<Vec<_>>();
+        if full_terms.is_empty() && self.tracking.len() < self.limits.max_tracked {
+            return;
+        }
+
+        let lowest = self
+            .tracking
+            .values()
+            .filter(|entry| {
+                full_terms.is_empty() || full_terms.iter().any(|&id| entry.term_ids.contains(id))
+            })
+            .min_by(|a, b| a.previous_score.total_cmp(&b.previous_score))
+            .map(|entry| entry.tweet_id.clone());
+        if let Some(evicted) = lowest.and_then(|id| self.tracking.remove(&id)) {
+            log::debug!(
+                "Tweet {}: untracking, tracker full (score: {:.4})",
+                evicted.tweet_id,
+                evicted.previous_score,
+            );
+        }
+    }
+
     pub fn insert(
         &mut self,
         tweet: &model::Tweet,
         includes: &model::ResponseIncludes,
-        search_config: SearchTermMeta<'conf>,
+        search_config: SearchTermMeta<'_>,
     ) {
-        self.insert_inner(tweet, includes, search_config, None, None)
+        if !search_config.accepts_language(tweet) {
+            log::trace!("Tweet {}: language {:?} filtered out", tweet.id(), tweet.lang());
+            *self.language_drops.entry(search_config.id.to_owned()).or_default() += 1;
+            return;
+        }
+        if !search_config.accepts_media(tweet, includes) {
+            log::trace!("Tweet {}: no media, filtered out", tweet.id());
+            return;
+        }
+        if let Some(entry) = self.tracking.get_mut(tweet.id()) {
+            // matched by another term, keep the current schedule
+            entry.term_ids.insert(search_config.id.to_owned());
+            return;
+        }
+        let term_ids = std::iter::once(search_config.id.to_owned()).collect();
+        self.insert_inner(tweet, includes, term_ids, None, None)
+    }
+
+    /// Logs the number of tweets dropped by the language filter since the last call.
+    pub fn log_stats(&mut self) {
+        let mut drops = self.language_drops.drain().collect::<Vec<_>>();
+        if drops.is_empty() {
+            return;
+        }
+        drops.sort_unstable();
+        let drops = drops
+            .iter()
+            .map(|(term_id, count)| format!("{}: {}", term_id, count))
+            .collect::<Vec<_>>();
+        log::info!(
+            "Tracking {} tweet(s), dropped by language filter: {}",
+            self.tracking.len(),
+            drops.join(", "),
+        );
     }
 
     fn insert_inner(
         &mut self,
         tweet: &model::Tweet,
         includes: &model::ResponseIncludes,
-        search_config: SearchTermMeta<'conf>,
-        previous_entry: Option<&TrendingEntry<'conf>>,
+        term_ids: BTreeSet<String>,
+        previous_entry: Option<&TrendingEntry>,
         score: Option<f64>,
     ) {
         if tweet.get_retweet_source().is_some() {
@@ -168,92 +540,246 @@ impl<'conf> TrendingContext<'conf> {
             log::debug!("Tweet {}: check at {}", tweet_id, check_due_at);
         }
 
+        let (checks, initial_metrics) = match previous_entry {
+            Some(entry) => (entry.checks + 1, entry.initial_metrics.clone()),
+            None => (0, tweet.metrics().cloned()),
+        };
+
+        self.make_room(&term_ids);
+        self.schedule.push(std::cmp::Reverse((check_due_at, tweet_id.clone())));
         let entry = TrendingEntry {
             check_due_at,
-            tweet_id,
+            tweet_id: tweet_id.clone(),
             created_at,
-            search_config,
+            term_ids,
             previous_score: score.unwrap_or(0.0),
             penalty,
+            checks,
+            initial_metrics,
         };
-        self.tracking.push(std::cmp::Reverse(entry));
+        self.tracking.insert(tweet_id, entry);
     }
 
     pub async fn run_once<Cache>(
         &mut self,
         client: &TwitterClient,
+        sinks: &SinkFactory,
+        config: &SearchConfig,
         cache: &Cache
     ) -> Result<()>
     where
-        Cache: LoadCache<model::Tweet> + StoreCache<model::Tweet> + StoreCache<model::User> + StoreCache<model::Media>,
+        Cache: LoadCache<model::Tweet> + LoadCache<RelayedTweet> + StoreCache<model::Tweet> + StoreCache<model::User> + StoreCache<model::Media> + StoreCache<RelayedTweet> + LoadCache<DeliveredMarker> + StoreCache<DeliveredMarker> + SaveImages,
     {
-        use futures_util::{TryFutureExt, TryStreamExt};
+        use futures_util::{StreamExt, TryStreamExt};
 
+        let dedup = &DedupGuard::new(cache, sinks.dedup_enabled());
         let now = Utc::now();
         let mut needs_check = Vec::new();
-        while let Some(entry) = self.tracking.peek_mut() {
-            if entry.0.check_due_at > now {
+        while let Some(item) = self.schedule.peek_mut() {
+            let (check_due_at, tweet_id) = &item.0;
+            if *check_due_at > now {
                 break;
             }
-            needs_check.push(std::collections::binary_heap::PeekMut::pop(entry).0);
+            let current = matches!(
+                self.tracking.get(tweet_id),
+                Some(entry) if entry.check_due_at == *check_due_at
+            );
+            if !current {
+                std::collections::binary_heap::PeekMut::pop(item);
+                continue;
+            }
+            if needs_check.len() >= self.limits.max_checks_per_run {
+                // the rest stay due, and are checked first in the next run
+                log::debug!("Tracker check limit reached, deferring remaining tweets");
+                break;
+            }
+            let (_, tweet_id) = std::collections::binary_heap::PeekMut::pop(item).0;
+            needs_check.push(self.tracking.remove(&tweet_id).unwrap());
         }
         let ids = needs_check
             .iter()
             .map(|e| &*e.tweet_id)
             .collect::<Vec<_>>();
-        let entry_map = needs_check
-            .iter()
-            .map(|e| (e.tweet_id.clone(), e))
-            .collect::<HashMap<_, _>>();
+        let ret = client.retrieve(&ids).await;
         let model::ResponseItem {
             data: tweets,
             includes,
             ..
-        } = client.retrieve(&ids).await?;
+        } = match ret {
+            Ok(res) => res,
+            Err(e) => {
+                // check again in the next run
+                for entry in needs_check {
+                    self.schedule
+                        .push(std::cmp::Reverse((entry.check_due_at, entry.tweet_id.clone())));
+                    self.tracking.insert(entry.tweet_id.clone(), entry);
+                }
+                return Err(e.into());
+            }
+        };
+        let entry_map = needs_check
+            .iter()
+            .map(|e| (e.tweet_id.clone(), e))
+            .collect::<HashMap<_, _>>();
 
         let futures = futures_util::stream::FuturesUnordered::new();
+        let edit_futures = futures_util::stream::FuturesUnordered::new();
         let cache_futures = futures_util::stream::FuturesUnordered::new();
         for tweet in &tweets {
-            if cache.has(tweet.id()).await? {
-                log::debug!("Tweet {} is cached, skipping", tweet.id());
-                continue;
+            let score = match tweet_route::score_tweet(&*self.scorer, tweet, &includes) {
+                Some(score) => score,
+                None => {
+                    log::warn!("Tweet {}: untracking, author or metrics missing", tweet.id());
+                    continue;
+                }
+            };
+            debug_assert!(score.is_finite(), "score of tweet {} is {}", tweet.id(), score);
+            if log::log_enabled!(log::Level::Trace) {
+                let breakdown = tweet_route::score_tweet_breakdown(&*self.scorer, tweet, &includes);
+                if let Some(breakdown) = breakdown {
+                    log::trace!("Tweet {}: score breakdown {:?}", tweet.id(), breakdown);
+                }
             }
-            let tweet_metrics = tweet.metrics();
             let author = tweet
                 .author_id()
                 .and_then(|id| includes.get_user(id));
-            let author_metrics = author.and_then(|a| a.metrics());
             let created_at = tweet.created_at().unwrap();
-            let score = tweet_route::compute_score(
-                tweet_metrics.unwrap(),
-                author_metrics.unwrap(),
-                created_at,
-            );
             let &entry = entry_map.get(tweet.id()).unwrap();
-            let webhooks = entry.search_config.webhooks;
+            let terms = entry
+                .term_ids
+                .iter()
+                .filter_map(|id| config.term(id))
+                .collect::<Vec<_>>();
+            if terms.is_empty() {
+                log::debug!(
+                    "Tweet {}: untracking, search terms {:?} were removed",
+                    tweet.id(),
+                    entry.term_ids,
+                );
+                continue;
+            }
+            // relay once to each webhook of the matching terms, at the lowest threshold
+            let score_threshold = terms
+                .iter()
+                .map(|term| term.score_threshold)
+                .fold(f64::INFINITY, f64::min);
+            let mut webhooks = Vec::<&WebhookTarget>::new();
+            for webhook in terms.iter().flat_map(|term| term.webhooks) {
+                if !webhooks.iter().any(|w| w.url() == webhook.url()) {
+                    webhooks.push(webhook);
+                }
+            }
+            let fields = tweet_discord::trending_fields(
+                &entry.trending_info(&terms, score, tweet.metrics()),
+                now,
+            );
 
-            if score >= entry.search_config.score_threshold {
+            if LoadCache::<RelayedTweet>::has(cache, tweet.id()).await? {
+                let mut relayed = LoadCache::<RelayedTweet>::load(cache, tweet.id()).await?;
+                if score - relayed.last_score >= SCORE_EDIT_THRESHOLD {
+                    log::debug!(
+                        "Updating relayed tweet {}, score: {:.4} -> {:.4}",
+                        tweet.id(),
+                        relayed.last_score,
+                        score,
+                    );
+                    relayed.last_score = score;
+                    let score_line = format!("score: {:.1} \u{2192} {:.1}", relayed.first_score, score);
+                    for &webhook in &webhooks {
+                        let message_id = relayed
+                            .messages
+                            .iter()
+                            .find(|m| &m.webhook_url == webhook.url())
+                            .and_then(|m| m.message_id.clone());
+                        let message_id = if let Some(message_id) = message_id {
+                            message_id
+                        } else {
+                            continue;
+                        };
+                        let sink = sinks.build(webhook);
+                        let options = DeliveryOptions {
+                            score: Some(score),
+                            note: Some(score_line.clone()),
+                            fields: fields.clone(),
+                            ..Default::default()
+                        };
+                        let includes = &includes;
+                        edit_futures.push(async move {
+                            let ret = sink.update(&message_id, tweet, includes, &options).await;
+                            if let Err(e) = &ret {
+                                if e.is_render_failure() {
+                                    log::warn!("Not updating tweet {}: {}", tweet.id(), e);
+                                }
+                            }
+                            ret
+                        });
+                    }
+                    cache_futures.push(cache.store(&relayed));
+                }
+            } else if LoadCache::<model::Tweet>::has(cache, tweet.id()).await? {
+                log::debug!("Tweet {} is cached, skipping", tweet.id());
+                continue;
+            } else if !terms.iter().any(|term| term.accepts_media(tweet, &includes)) {
+                log::debug!("Tweet {}: untracking, no media", tweet.id());
+                continue;
+            } else if score >= score_threshold {
                 log::debug!(
                     "Relaying tweet {id} by @{author_username}, score: {score:.4}",
                     id = tweet.id(),
                     author_username = author.unwrap().username(),
                     score = score,
                 );
-                for webhook in webhooks {
-                    futures.push(tweet_discord::send_webhook(
-                        client,
-                        webhook,
-                        tweet,
-                        &includes,
-                    ));
+                for &webhook in &webhooks {
+                    if dedup.is_delivered(tweet.id(), webhook).await {
+                        continue;
+                    }
+                    let includes = &includes;
+                    let sink = sinks.build(webhook);
+                    let span = model::span!(
+                        "deliver",
+                        engine = "search",
+                        tweet_id = %tweet.id(),
+                        destination = %tweet_discord::redact_destination(webhook.url()),
+                    );
+                    let options = DeliveryOptions {
+                        score: Some(score),
+                        fields: fields.clone(),
+                        ..Default::default()
+                    };
+                    futures.push(async move {
+                        let receipt = match sink.deliver(tweet, includes, &options).await {
+                            Ok(receipt) => receipt,
+                            Err(e) => {
+                                if e.is_render_failure() {
+                                    log::warn!("Skipping tweet {}: {}", tweet.id(), e);
+                                }
+                                return Err(e);
+                            }
+                        };
+                        dedup.mark_delivered(tweet.id(), webhook).await;
+                        let relayed = RelayedMessage {
+                            webhook_url: webhook.url().clone(),
+                            message_id: receipt.first_message_id().map(String::from),
+                            thread_id: webhook.execute_options().thread_id,
+                            delete_on_tweet_deletion: webhook.delete_on_tweet_deletion(),
+                        };
+                        Ok::<_, SinkError>((tweet.id().to_owned(), score, relayed))
+                    }.in_span(span));
                 }
 
+                let sources = terms
+                    .iter()
+                    .map(|term| format!("term:{}", term.id))
+                    .collect::<Vec<_>>();
+                sinks.summary().record_relay(&sources, tweet, &includes, Some(score));
+                sinks.status().record_relayed(Engine::Search);
+
                 cache_futures.push(cache.store(tweet));
                 cache_futures.push(cache.store(author.unwrap()));
                 for media_key in tweet.media_keys() {
                     cache_futures.push(cache.store(includes.get_media(media_key).unwrap()));
                 }
-                continue;
+                cache.save_images(tweet.media_keys().iter().filter_map(|key| includes.get_media(key)));
             }
 
             let elapsed = now - created_at;
@@ -270,13 +796,204 @@ impl<'conf> TrendingContext<'conf> {
                 continue;
             }
 
-            // insert again
-            self.insert_inner(tweet, &includes, entry.search_config, Some(entry), Some(score));
+            // insert again, relayed tweets are tracked to update their score
+            let term_ids = terms.iter().map(|term| term.id.to_owned()).collect();
+            self.insert_inner(tweet, &includes, term_ids, Some(entry), Some(score));
+        }
+        let (cache_ret, send_results, edit_results) = futures_util::join!(
+            cache_futures.try_collect::<Vec<_>>(),
+            futures.collect::<Vec<_>>(),
+            edit_futures.collect::<Vec<_>>(),
+        );
+
+        let mut relayed_map = HashMap::new();
+        let mut send_ret = Ok(());
+        for result in send_results {
+            let (tweet_id, score, message) = match result {
+                Ok(sent) => sent,
+                // logged above, and other tweets are fine
+                Err(e) if e.is_render_failure() => continue,
+                Err(e) => {
+                    send_ret = Err(e);
+                    continue;
+                }
+            };
+            let relayed = relayed_map
+                .entry(tweet_id.clone())
+                .or_insert_with(|| RelayedTweet {
+                    tweet_id,
+                    first_score: score,
+                    last_score: score,
+                    messages: Vec::new(),
+                });
+            relayed.messages.push(message);
+        }
+        for relayed in relayed_map.values() {
+            cache.store(relayed).await?;
+            self.recently_relayed.push_back((relayed.tweet_id.clone(), now));
+        }
+
+        cache_ret?;
+        send_ret?;
+        edit_results
+            .into_iter()
+            .filter(|ret| !matches!(ret, Err(e) if e.is_render_failure()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(())
+    }
+
+    /// Deletes messages of recently relayed tweets which were deleted since, for webhooks with
+    /// `delete_on_tweet_deletion` set. With `dry_run`, only logs the messages to delete.
+    pub async fn reconcile_deletions<Cache>(
+        &mut self,
+        client: &TwitterClient,
+        sinks: &SinkFactory,
+        cache: &Cache,
+        dry_run: bool,
+    ) -> Result<()>
+    where
+        Cache: LoadCache<RelayedTweet>,
+    {
+        let window_start = Utc::now() - chrono::Duration::hours(DELETION_CHECK_WINDOW_HOURS);
+        while let Some((_, relayed_at)) = self.recently_relayed.front() {
+            if *relayed_at >= window_start {
+                break;
+            }
+            self.recently_relayed.pop_front();
+        }
+        if self.recently_relayed.is_empty() {
+            return Ok(());
+        }
+
+        let ids = self
+            .recently_relayed
+            .iter()
+            .map(|(id, _)| &**id)
+            .collect::<Vec<_>>();
+        let missing = match client.retrieve(&ids).await {
+            Ok(model::ResponseItem { data: tweets, .. }) => {
+                let found = tweets.iter().map(|t| t.id()).collect::<HashSet<_>>();
+                ids.iter()
+                    .filter(|id| !found.contains(*id))
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+            }
+            Err(tweet_fetch::Error::Twitter(e)) => e.not_found_ids().map(String::from).collect(),
+            Err(e) => return Err(e.into()),
+        };
+
+        for tweet_id in &missing {
+            let relayed = LoadCache::<RelayedTweet>::load(cache, tweet_id).await?;
+            for message in &relayed.messages {
+                let message_id = match &message.message_id {
+                    Some(message_id) if message.delete_on_tweet_deletion => message_id,
+                    _ => continue,
+                };
+                if dry_run {
+                    log::info!(
+                        "Tweet {} was deleted, would delete message {} of webhook {}",
+                        tweet_id,
+                        message_id,
+                        tweet_discord::redact_url(&message.webhook_url),
+                    );
+                    continue;
+                }
+
+                log::info!("Tweet {} was deleted, deleting message {}", tweet_id, message_id);
+                let target = WebhookTarget::new(message.webhook_url.clone())
+                    .with_thread_id(message.thread_id.clone());
+                let ret = sinks.build(&target).retract(message_id).await;
+                if let Err(e) = ret {
+                    log::error!("Failed to delete message {}: {}", message_id, e);
+                    sentry::capture_error(&e);
+                }
+            }
         }
-        futures_util::try_join!(
-            cache_futures.try_collect::<Vec<_>>().map_err(eyre::Report::new),
-            futures.try_collect::<()>().map_err(eyre::Report::new),
-        )?;
+        self.recently_relayed.retain(|(id, _)| !missing.contains(id));
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn config(toml: &str) -> SearchConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn ids(terms: Vec<SearchTermMeta<'_>>) -> Vec<&str> {
+        let mut ids = terms.into_iter().map(|term| term.id).collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    const TERMS: &str = r#"
+        [terms.fast]
+        term = "fast"
+        fetch_interval_secs = 60
+        webhooks = []
+
+        [terms.slow]
+        term = "slow"
+        fetch_interval_secs = 300
+        webhooks = []
+    "#;
+
+    #[tokio::test(start_paused = true)]
+    async fn terms_are_due_by_their_own_interval() {
+        let config = config(TERMS);
+        let mut schedule = TermSchedule::new();
+        let start = Instant::now();
+        assert_eq!(ids(schedule.due_terms(&config, start)), ["fast", "slow"]);
+        assert_eq!(schedule.next_due(&config), Some(start));
+
+        for term in schedule.due_terms(&config, start) {
+            let interval = Duration::from_secs(term.fetch_interval_secs.unwrap());
+            schedule.mark_fetched(term.id, start, interval);
+        }
+        assert!(schedule.due_terms(&config, start).is_empty());
+        assert_eq!(schedule.next_due(&config), Some(start + MINUTE));
+
+        tokio::time::sleep_until(schedule.next_due(&config).unwrap()).await;
+        let now = Instant::now();
+        assert_eq!(ids(schedule.due_terms(&config, now)), ["fast"]);
+        schedule.mark_fetched("fast", now, Duration::from_secs(60));
+        assert_eq!(schedule.next_due(&config), Some(start + 2 * MINUTE));
+
+        tokio::time::advance(Duration::from_secs(240)).await;
+        let now = Instant::now();
+        assert_eq!(now - start, Duration::from_secs(300));
+        assert_eq!(ids(schedule.due_terms(&config, now)), ["fast", "slow"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn schedule_follows_config_changes() {
+        let mut schedule = TermSchedule::new();
+        let start = Instant::now();
+        schedule.mark_fetched("fast", start, Duration::from_secs(60));
+        schedule.mark_fetched("slow", start, Duration::from_secs(300));
+
+        // an added term is due right away, without waiting for the others
+        let added = config(&format!(
+            "{}\n[terms.new]\nterm = \"new\"\nwebhooks = []\n",
+            TERMS,
+        ));
+        assert_eq!(ids(schedule.due_terms(&added, start)), ["new"]);
+        assert_eq!(schedule.next_due(&added), Some(start));
+
+        // a removed term is forgotten, so it's due right away if it comes back
+        let removed = config("[terms.fast]\nterm = \"fast\"\nwebhooks = []\n");
+        schedule.retain(&removed);
+        assert_eq!(schedule.next_due(&removed), Some(start + MINUTE));
+        assert_eq!(ids(schedule.due_terms(&config(TERMS), start)), ["slow"]);
+
+        assert_eq!(schedule.next_due(&config("terms = {}")), None);
+    }
+}
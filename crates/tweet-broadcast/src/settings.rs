@@ -0,0 +1,204 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+
+/// Fetches of lists or user timelines in flight at once.
+pub const MAX_CONCURRENT_FETCHES: usize = 4;
+
+/// `[settings]` table of list and user timeline configs.
+///
+/// Read on startup; changes take effect on restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PollSettings {
+    /// Seconds between fetches.
+    pub poll_interval_secs: Option<u64>,
+}
+
+/// `[settings]` table of search configs.
+///
+/// Read on startup; changes take effect on restart.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchSettings {
+    /// Seconds between search term fetches, which also check relayed tweets for deletion.
+    pub poll_interval_secs: Option<u64>,
+    /// Seconds between metric updates of trending tweets.
+    pub tracker_interval_secs: Option<u64>,
+    /// Tweets tracked for trending at once, over all terms.
+    pub max_tracked: Option<usize>,
+    /// Tweets tracked for trending at once, per term.
+    pub max_tracked_per_term: Option<usize>,
+    /// Tweets checked in a single tracker update. The rest are checked in the next updates.
+    pub max_checks_per_run: Option<usize>,
+}
+
+/// Polling interval of an engine, with a minimum to stay within rate limits.
+#[derive(Debug, Clone, Copy)]
+pub struct IntervalSpec {
+    name: &'static str,
+    default_secs: u64,
+    min_secs: u64,
+}
+
+pub const LIST_POLL: IntervalSpec = IntervalSpec {
+    name: "list poll interval",
+    default_secs: 60,
+    min_secs: 15,
+};
+pub const USER_POLL: IntervalSpec = IntervalSpec {
+    name: "user timeline poll interval",
+    default_secs: 120,
+    min_secs: 15,
+};
+pub const SEARCH_POLL: IntervalSpec = IntervalSpec {
+    name: "search poll interval",
+    default_secs: 180,
+    min_secs: 30,
+};
+pub const SEARCH_TRACKER: IntervalSpec = IntervalSpec {
+    name: "search tracker interval",
+    default_secs: 30,
+    min_secs: 10,
+};
+
+impl IntervalSpec {
+    /// Checks an interval given in the config, naming the key in the error.
+    pub fn check_key(&self, key: &str, secs: Option<u64>) -> Result<()> {
+        self.check(secs).map_err(|e| eyre::eyre!("{}: {}", key, e))
+    }
+
+    pub fn check(&self, secs: Option<u64>) -> Result<()> {
+        match secs {
+            Some(secs) if secs < self.min_secs => eyre::bail!(
+                "{} must be at least {} seconds, got {}",
+                self.name,
+                self.min_secs,
+                secs,
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the interval to use, preferring `override_secs` given on the command line over
+    /// `config_secs`.
+    pub fn resolve(&self, override_secs: Option<u64>, config_secs: Option<u64>) -> Result<Duration> {
+        let secs = override_secs.or(config_secs).unwrap_or(self.default_secs);
+        self.check(Some(secs))?;
+        Ok(Duration::from_secs(secs))
+    }
+}
+
+/// Returns how long to delay fetching `key` in each run, spreading fetches over the first half
+/// of `interval` so that they don't hit the rate limit all at once. The same key always gets the
+/// same offset.
+pub fn stagger_offset(key: &str, interval: Duration) -> Duration {
+    use std::hash::{Hash, Hasher};
+
+    let window = interval.as_millis() as u64 / 2;
+    if window == 0 {
+        return Duration::ZERO;
+    }
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    key.hash(&mut hasher);
+    Duration::from_millis(hasher.finish() % window)
+}
+
+/// Config of scoring, at `score/config.toml`, applied to every engine.
+///
+/// Read on startup; changes take effect on restart.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScoreConfig {
+    #[serde(default)]
+    pub score: tweet_route::ScoreParams,
+}
+
+impl crate::reload::EngineConfig for ScoreConfig {
+    fn validate(&self) -> Result<()> {
+        let params = &self.score;
+        let positive = [
+            ("retweet_divisor", params.retweet_divisor),
+            ("quote_divisor", params.quote_divisor),
+            ("like_divisor", params.like_divisor),
+            ("follower_adjust_min", params.follower_adjust_min),
+            ("follower_scale", params.follower_scale),
+            ("max_follow_ratio", params.max_follow_ratio),
+            ("multiplier", params.multiplier),
+            ("freshness_base", params.freshness_base),
+            ("freshness_days", params.freshness_days),
+            ("account_age_days", params.account_age_days),
+            ("media_bonus", params.media_bonus),
+        ];
+        for (key, value) in positive {
+            if !value.is_finite() || value <= 0.0 {
+                eyre::bail!("score.{}: expected a positive number, got {}", key, value);
+            }
+        }
+        if let Some(max_score) = params.max_score {
+            if !max_score.is_finite() || max_score <= 0.0 {
+                eyre::bail!("score.max_score: expected a positive number, got {}", max_score);
+            }
+        }
+        if !params.follow_rate_weight.is_finite() || params.follow_rate_weight < 0.0 {
+            eyre::bail!(
+                "score.follow_rate_weight: expected a non-negative number, got {}",
+                params.follow_rate_weight,
+            );
+        }
+        if !(0.0..=1.0).contains(&params.new_account_factor) || params.new_account_factor == 0.0 {
+            eyre::bail!(
+                "score.new_account_factor: expected a number in (0, 1], got {}",
+                params.new_account_factor,
+            );
+        }
+        if !params.follower_adjust_max.is_finite()
+            || params.follower_adjust_max <= params.follower_adjust_min
+        {
+            eyre::bail!(
+                "score.follower_adjust_max: expected more than follower_adjust_min, got {}",
+                params.follower_adjust_max,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Loads the scorer configured at `path`, or the default one if it doesn't exist.
+pub async fn load_scorer(path: &std::path::Path) -> Result<Arc<dyn tweet_route::Scorer>> {
+    if !path.exists() {
+        return Ok(tweet_route::ScoreParams::default().into_scorer());
+    }
+    let config = crate::reload::read_config::<ScoreConfig>(path).await?;
+    if config.score != tweet_route::ScoreParams::default() {
+        log::info!("Using score config from {}", path.display());
+    }
+    Ok(config.score.into_scorer())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stagger_offsets_fit_in_first_half() {
+        let interval = Duration::from_secs(60);
+        let offsets = (0..100)
+            .map(|id| stagger_offset(&id.to_string(), interval))
+            .collect::<Vec<_>>();
+        assert!(offsets.iter().all(|&offset| offset < interval / 2));
+        // spread over the window instead of clumping at its start
+        assert!(offsets.iter().any(|&offset| offset >= interval / 4));
+        let distinct = offsets.iter().collect::<std::collections::HashSet<_>>();
+        assert!(distinct.len() > 90, "{} distinct offsets", distinct.len());
+
+        // the same key waits the same in every run
+        assert_eq!(stagger_offset("42", interval), offsets[42]);
+        // too short to spread over
+        for interval in [Duration::ZERO, Duration::from_millis(1)] {
+            assert_eq!(stagger_offset("42", interval), Duration::ZERO);
+        }
+    }
+}
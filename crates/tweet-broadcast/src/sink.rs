@@ -0,0 +1,553 @@
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+
+use tweet_model as model;
+
+use crate::queue::DeliveryQueue;
+use crate::status::StatusRegistry;
+use crate::summary::SummaryRecorder;
+use crate::webhook::WebhookTarget;
+
+/// Result of a delivery.
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryReceipt {
+    /// IDs of the messages created, in order. The first one has the main content.
+    pub message_ids: Vec<String>,
+}
+
+impl DeliveryReceipt {
+    pub fn first_message_id(&self) -> Option<&str> {
+        self.message_ids.first().map(|id| &**id)
+    }
+}
+
+/// Per-delivery rendering options, applied on top of the sink's own configuration.
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryOptions {
+    /// Score of the tweet, for sinks configured to show it.
+    pub score: Option<f64>,
+    /// Rendering overrides given by route scripts.
+    pub render: Option<tweet_route::RouteRender>,
+    /// Extra line appended to the message.
+    pub note: Option<String>,
+    /// Inline fields appended to the main embed, e.g. the growth of a trending tweet.
+    pub fields: Vec<(String, String)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SinkError {
+    #[error(transparent)]
+    Discord(#[from] tweet_discord::WebhookError),
+    #[error("{0} is not supported by this sink")]
+    Unsupported(&'static str),
+}
+
+impl SinkError {
+    /// Returns whether the tweet could not be rendered, which fails only that tweet.
+    pub fn is_render_failure(&self) -> bool {
+        matches!(self, Self::Discord(tweet_discord::WebhookError::Render(_)))
+    }
+
+    /// Returns whether the delivery may succeed if tried again later.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Discord(e) => e.is_transient(),
+            Self::Unsupported(_) => false,
+        }
+    }
+
+    /// Returns whether the sink will not accept any further deliveries.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::Discord(e) => e.is_permanent(),
+            Self::Unsupported(_) => false,
+        }
+    }
+}
+
+/// Destination tweets are delivered to.
+///
+/// Implementations handle their own rate limiting.
+pub trait Sink: std::fmt::Debug + Send + Sync {
+    /// Name of the sink for logs, without secrets.
+    fn name(&self) -> String;
+
+    /// Returns whether the sink can still accept deliveries.
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    /// Delivers a tweet, rendered by the sink.
+    fn deliver<'a>(
+        &'a self,
+        tweet: &'a model::Tweet,
+        includes: &'a model::ResponseIncludes,
+        options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>>;
+
+    /// Delivers consecutive tweets in as few messages as the sink can, in order. Sinks which
+    /// can't batch deliver them one by one.
+    fn deliver_batch<'a>(
+        &'a self,
+        tweets: &'a [&'a model::Tweet],
+        includes: &'a model::ResponseIncludes,
+        options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        deliver_each(self, tweets, includes, options)
+    }
+
+    /// Delivers a payload constructed elsewhere, e.g. by route scripts.
+    fn deliver_raw<'a>(
+        &'a self,
+        payload: &'a serde_json::Value,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>>;
+
+    /// Delivers a plain message about the relay itself, e.g. list initialization.
+    fn notify<'a>(&'a self, message: &'a str) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>>;
+
+    /// Re-renders a previously delivered message.
+    fn update<'a>(
+        &'a self,
+        _message_id: &'a str,
+        _tweet: &'a model::Tweet,
+        _includes: &'a model::ResponseIncludes,
+        _options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async { Err(SinkError::Unsupported("updating messages")) })
+    }
+
+    /// Removes a previously delivered message.
+    fn retract<'a>(&'a self, _message_id: &'a str) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async { Err(SinkError::Unsupported("retracting messages")) })
+    }
+}
+
+fn deliver_each<'a, S: Sink + ?Sized>(
+    sink: &'a S,
+    tweets: &'a [&'a model::Tweet],
+    includes: &'a model::ResponseIncludes,
+    options: &'a DeliveryOptions,
+) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+    Box::pin(async move {
+        let mut receipt = DeliveryReceipt::default();
+        for tweet in tweets {
+            let ret = sink.deliver(tweet, includes, options).await?;
+            receipt.message_ids.extend(ret.message_ids);
+        }
+        Ok(receipt)
+    })
+}
+
+/// Discord webhook sink.
+#[derive(Debug)]
+pub struct DiscordSink {
+    executor: tweet_discord::WebhookExecutor,
+    target: WebhookTarget,
+    status: Arc<StatusRegistry>,
+    queue: Option<DeliveryQueue>,
+}
+
+impl DiscordSink {
+    /// Queues a delivery which failed with a transient error, so that it's retried later.
+    ///
+    /// Queued deliveries return an empty receipt, as their messages don't exist yet.
+    async fn queue_failed(
+        &self,
+        payload: impl FnOnce() -> serde_json::Value,
+        e: tweet_discord::WebhookError,
+    ) -> Result<DeliveryReceipt, SinkError> {
+        let queue = match &self.queue {
+            Some(queue) if !e.is_permanent() => queue,
+            _ => return Err(e.into()),
+        };
+        match queue.enqueue(&self.target, &payload()).await {
+            Ok(()) => {
+                log::warn!("Delivery to {} failed, queued for retry: {}", self.name(), e);
+                Ok(DeliveryReceipt::default())
+            }
+            Err(io_error) => {
+                log::error!("Failed to queue delivery to {}: {}", self.name(), io_error);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Queues the delivery until the target's active hours start, if it's outside them. Returns
+    /// whether it was queued.
+    async fn defer(&self, payload: impl FnOnce() -> serde_json::Value) -> bool {
+        let until = match self.target.active_hours() {
+            Some(hours) => match hours.deferral(chrono::Utc::now()) {
+                Some(until) => until,
+                None => return false,
+            },
+            None => return false,
+        };
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => return false,
+        };
+        match queue.defer(&self.target, &payload(), until).await {
+            Ok(()) => {
+                log::debug!("Delivery to {} deferred until {}", self.name(), until);
+                true
+            }
+            Err(e) => {
+                log::error!("Failed to defer delivery to {}, sending now: {}", self.name(), e);
+                false
+            }
+        }
+    }
+
+    fn render_options(&self, options: &DeliveryOptions) -> tweet_discord::RenderOptions {
+        let mut ret = self.target.render_options(options.score);
+        if let Some(render) = &options.render {
+            if render.content_prefix.is_some() {
+                ret.content_prefix = render.content_prefix.clone();
+            }
+            if render.content_suffix.is_some() {
+                ret.content_suffix = render.content_suffix.clone();
+            }
+            if render.color.is_some() {
+                ret.color = render.color;
+            }
+            if render.username.is_some() {
+                ret.username = render.username.clone();
+            }
+            ret.suppress_media |= render.suppress_media;
+        }
+        if let Some(note) = &options.note {
+            let suffix = ret.content_suffix.get_or_insert_with(String::new);
+            suffix.push('\n');
+            suffix.push_str(note);
+        }
+        ret.fields = options.fields.clone();
+        ret
+    }
+}
+
+impl Sink for DiscordSink {
+    fn name(&self) -> String {
+        tweet_discord::redact_url(self.target.url())
+    }
+
+    fn is_available(&self) -> bool {
+        !self.executor.is_gone(self.target.url())
+    }
+
+    fn deliver<'a>(
+        &'a self,
+        tweet: &'a model::Tweet,
+        includes: &'a model::ResponseIncludes,
+        options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            let payload =
+                tweet_discord::make_tweet_payload(tweet, includes, &self.render_options(options))
+                    .map_err(tweet_discord::WebhookError::from)?
+                    .to_value();
+            let payload = || payload.clone();
+            if self.defer(payload).await {
+                return Ok(DeliveryReceipt::default());
+            }
+            let message_ids = tweet_discord::send_webhook_with_options(
+                &self.executor,
+                self.target.url(),
+                tweet,
+                includes,
+                &self.render_options(options),
+                &self.target.execute_options(),
+            )
+            .await;
+            self.status.record_delivery(message_ids.is_ok());
+            match message_ids {
+                Ok(message_ids) => Ok(DeliveryReceipt { message_ids }),
+                Err(e) => self.queue_failed(payload, e).await,
+            }
+        })
+    }
+
+    /// Sends the tweets together, up to Discord's limits per message. Tweets with media to
+    /// upload are sent one by one, as uploads are done per tweet.
+    fn deliver_batch<'a>(
+        &'a self,
+        tweets: &'a [&'a model::Tweet],
+        includes: &'a model::ResponseIncludes,
+        options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        let render_options = self.render_options(options);
+        let uploads_media = tweets.iter().any(|tweet| {
+            !tweet_discord::spoiler_media_urls(tweet, includes, &render_options).is_empty()
+                || !tweet_discord::attached_media_urls(tweet, includes, &render_options).is_empty()
+        });
+        if tweets.len() < 2 || uploads_media {
+            return deliver_each(self, tweets, includes, options);
+        }
+        Box::pin(async move {
+            let payloads = tweets
+                .iter()
+                .map(|tweet| tweet_discord::make_tweet_payload(tweet, includes, &render_options))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(tweet_discord::WebhookError::from)?;
+            let mut receipt = DeliveryReceipt::default();
+            for message in crate::batch::pack_payloads(payloads) {
+                let ret = self.deliver_raw(&message.to_value()).await?;
+                receipt.message_ids.extend(ret.message_ids);
+            }
+            Ok(receipt)
+        })
+    }
+
+    fn deliver_raw<'a>(
+        &'a self,
+        payload: &'a serde_json::Value,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            if self.defer(|| payload.clone()).await {
+                return Ok(DeliveryReceipt::default());
+            }
+            let message_ids = tweet_discord::execute_webhook_with_options(
+                &self.executor,
+                self.target.url(),
+                payload,
+                &self.target.execute_options(),
+            )
+            .await;
+            self.status.record_delivery(message_ids.is_ok());
+            match message_ids {
+                Ok(message_ids) => Ok(DeliveryReceipt { message_ids }),
+                Err(e) => self.queue_failed(|| payload.clone(), e).await,
+            }
+        })
+    }
+
+    fn notify<'a>(&'a self, message: &'a str) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        Box::pin(async move {
+            let payload = tweet_discord::make_notice_payload(message, self.target.identity());
+            self.deliver_raw(&payload.to_value()).await
+        })
+    }
+
+    fn update<'a>(
+        &'a self,
+        message_id: &'a str,
+        tweet: &'a model::Tweet,
+        includes: &'a model::ResponseIncludes,
+        options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let payload =
+                tweet_discord::make_tweet_payload(tweet, includes, &self.render_options(options))
+                    .map_err(tweet_discord::WebhookError::from)?;
+            tweet_discord::edit_webhook_message_with_options(
+                &self.executor,
+                self.target.url(),
+                message_id,
+                &payload.to_value(),
+                &self.target.execute_options(),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn retract<'a>(&'a self, message_id: &'a str) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            tweet_discord::delete_webhook_message_with_options(
+                &self.executor,
+                self.target.url(),
+                message_id,
+                &self.target.execute_options(),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+}
+
+/// Sink logging what it would deliver instead of delivering, for dry runs.
+#[derive(Debug)]
+pub struct DryRunSink {
+    inner: DiscordSink,
+}
+
+impl DryRunSink {
+    fn log_payload(&self, payload: &serde_json::Value) {
+        let embeds = payload
+            .get("embeds")
+            .and_then(|embeds| embeds.as_array())
+            .map_or(0, |embeds| embeds.len());
+        let content = payload
+            .get("content")
+            .and_then(|content| content.as_str())
+            .and_then(|content| content.lines().next())
+            .unwrap_or("");
+        log::info!(
+            "[dry run] Would send to {}: {} embed(s), content: {:?}",
+            self.inner.target.url().host_str().unwrap_or("?"),
+            embeds,
+            content,
+        );
+    }
+}
+
+impl Sink for DryRunSink {
+    fn name(&self) -> String {
+        format!("{} (dry run)", self.inner.name())
+    }
+
+    fn deliver<'a>(
+        &'a self,
+        tweet: &'a model::Tweet,
+        includes: &'a model::ResponseIncludes,
+        options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        let payload =
+            tweet_discord::make_tweet_payload(tweet, includes, &self.inner.render_options(options));
+        let ret = match payload {
+            Ok(payload) => {
+                self.log_payload(&payload.to_value());
+                Ok(DeliveryReceipt::default())
+            }
+            Err(e) => Err(tweet_discord::WebhookError::from(e).into()),
+        };
+        Box::pin(async { ret })
+    }
+
+    fn deliver_raw<'a>(
+        &'a self,
+        payload: &'a serde_json::Value,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        self.log_payload(payload);
+        Box::pin(async { Ok(DeliveryReceipt::default()) })
+    }
+
+    fn notify<'a>(&'a self, message: &'a str) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        let payload = tweet_discord::make_notice_payload(message, self.inner.target.identity());
+        self.log_payload(&payload.to_value());
+        Box::pin(async { Ok(DeliveryReceipt::default()) })
+    }
+
+    fn update<'a>(
+        &'a self,
+        message_id: &'a str,
+        _tweet: &'a model::Tweet,
+        _includes: &'a model::ResponseIncludes,
+        _options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        log::info!("[dry run] Would update message {}", message_id);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn retract<'a>(&'a self, message_id: &'a str) -> BoxFuture<'a, Result<(), SinkError>> {
+        log::info!("[dry run] Would delete message {}", message_id);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Builds sinks in place of the Discord ones, so that tests can observe deliveries.
+#[cfg(test)]
+pub trait BuildSink: std::fmt::Debug + Send + Sync {
+    fn build(&self, target: &WebhookTarget) -> Box<dyn Sink>;
+}
+
+/// Constructs sinks from config entries, sharing rate limit state between sinks of the same
+/// kind.
+#[derive(Debug, Clone)]
+pub struct SinkFactory {
+    discord: tweet_discord::WebhookExecutor,
+    status: Arc<StatusRegistry>,
+    dry_run: bool,
+    queue: Option<DeliveryQueue>,
+    summary: Arc<SummaryRecorder>,
+    dedup: bool,
+    scorer: Arc<dyn tweet_route::Scorer>,
+    #[cfg(test)]
+    builder: Option<Arc<dyn BuildSink>>,
+}
+
+impl SinkFactory {
+    /// Creates a factory whose sinks count deliveries in `status`.
+    pub fn new(status: Arc<StatusRegistry>) -> Self {
+        Self {
+            discord: Default::default(),
+            status,
+            dry_run: false,
+            queue: None,
+            summary: Default::default(),
+            dedup: true,
+            scorer: Arc::new(tweet_route::DefaultScorer::default()),
+            #[cfg(test)]
+            builder: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_builder(&mut self, builder: Arc<dyn BuildSink>) {
+        self.builder = Some(builder);
+    }
+
+    /// Status registry, which engines record received and relayed tweets into.
+    pub fn status(&self) -> &Arc<StatusRegistry> {
+        &self.status
+    }
+
+    /// Counters of the daily summary, which engines record relayed tweets into.
+    pub fn summary(&self) -> &Arc<SummaryRecorder> {
+        &self.summary
+    }
+
+    /// Scorer of relayed tweets and score thresholds.
+    pub fn scorer(&self) -> &Arc<dyn tweet_route::Scorer> {
+        &self.scorer
+    }
+
+    pub fn set_scorer(&mut self, scorer: Arc<dyn tweet_route::Scorer>) {
+        self.scorer = scorer;
+    }
+
+    /// Makes the factory build sinks which only log what they would deliver.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    /// Sets whether engines skip webhooks which already received a tweet from another engine.
+    pub fn set_dedup(&mut self, dedup: bool) {
+        self.dedup = dedup;
+    }
+
+    pub fn dedup_enabled(&self) -> bool {
+        self.dedup
+    }
+
+    /// Makes sinks queue deliveries which fail with transient errors into `queue`.
+    pub fn set_queue(&mut self, queue: DeliveryQueue) {
+        self.queue = Some(queue);
+    }
+
+    pub fn queue(&self) -> Option<&DeliveryQueue> {
+        self.queue.as_ref()
+    }
+
+    /// Executor shared by Discord sinks, for sending queued deliveries.
+    pub fn discord_executor(&self) -> &tweet_discord::WebhookExecutor {
+        &self.discord
+    }
+
+    pub fn build(&self, target: &WebhookTarget) -> Box<dyn Sink> {
+        let sink = DiscordSink {
+            executor: self.discord.clone(),
+            target: target.clone(),
+            status: self.status.clone(),
+            queue: self.queue.clone(),
+        };
+        if self.dry_run {
+            return Box::new(DryRunSink { inner: sink });
+        }
+        #[cfg(test)]
+        if let Some(builder) = &self.builder {
+            return builder.build(target);
+        }
+        Box::new(sink)
+    }
+}
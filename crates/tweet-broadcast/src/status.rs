@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::cache::FsCache;
+use crate::delivery::DeliveryStats;
+use crate::metrics::PrometheusRecorder;
+use crate::Engine;
+
+/// The stream is reported unhealthy after failing to reconnect for this long.
+const STREAM_UNHEALTHY_AFTER_MINUTES: i64 = 5;
+/// Extra time given to engines over twice their interval before they're reported unhealthy.
+const ENGINE_GRACE_SECS: i64 = 60;
+
+/// Liveness information engines report into, served by `serve`.
+#[derive(Debug)]
+pub struct StatusRegistry {
+    started_at: DateTime<Utc>,
+    engines: Mutex<BTreeMap<String, EngineStatus>>,
+    stream: Mutex<Option<StreamStatus>>,
+    /// Tweets tracked for trending, by search term.
+    tracker_sizes: Mutex<BTreeMap<String, usize>>,
+    /// Tweets received and relayed, by engine.
+    activity: Mutex<BTreeMap<String, EngineActivity>>,
+    deliveries_succeeded: AtomicU64,
+    deliveries_failed: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct EngineStatus {
+    interval_secs: u64,
+    last_tick_at: Option<DateTime<Utc>>,
+    last_success_at: Option<DateTime<Utc>>,
+    last_error: Option<ErrorStatus>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct StreamStatus {
+    connected: bool,
+    last_tweet_at: Option<DateTime<Utc>>,
+    /// Time of the first error since the last tweet received.
+    failing_since: Option<DateTime<Utc>>,
+    last_error: Option<ErrorStatus>,
+}
+
+/// When an engine last received and relayed a tweet, watched by the watchdog, and how many it
+/// did since startup.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EngineActivity {
+    pub last_received_at: Option<DateTime<Utc>>,
+    pub last_relayed_at: Option<DateTime<Utc>>,
+    pub received: u64,
+    pub relayed: u64,
+}
+
+/// State of an engine in `EngineSummary`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineState {
+    /// Registered but not done anything yet.
+    Starting,
+    Ok,
+    /// The last tick failed, or the stream is reconnecting since the given time.
+    Failing(DateTime<Utc>),
+}
+
+/// Point-in-time view of an engine, aggregated from the registry for the status log.
+#[derive(Debug, Clone)]
+pub struct EngineSummary {
+    pub name: String,
+    pub activity: EngineActivity,
+    pub state: EngineState,
+    pub last_error: Option<(DateTime<Utc>, String)>,
+    /// Tweets tracked for trending, for engines which track them.
+    pub tracker_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ErrorStatus {
+    at: DateTime<Utc>,
+    message: String,
+}
+
+impl ErrorStatus {
+    fn now(error: &dyn std::fmt::Display) -> Self {
+        Self {
+            at: Utc::now(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl Default for StatusRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatusRegistry {
+    pub fn new() -> Self {
+        Self {
+            started_at: Utc::now(),
+            engines: Default::default(),
+            stream: Default::default(),
+            tracker_sizes: Default::default(),
+            activity: Default::default(),
+            deliveries_succeeded: AtomicU64::new(0),
+            deliveries_failed: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers a polling engine, expected to tick every `interval`.
+    pub fn register_engine(&self, name: impl ToString, interval: std::time::Duration) {
+        self.engines.lock().unwrap().insert(
+            name.to_string(),
+            EngineStatus {
+                interval_secs: interval.as_secs(),
+                last_tick_at: None,
+                last_success_at: None,
+                last_error: None,
+            },
+        );
+    }
+
+    pub fn record_success(&self, name: impl ToString) {
+        let now = Utc::now();
+        if let Some(engine) = self.engines.lock().unwrap().get_mut(&name.to_string()) {
+            engine.last_tick_at = Some(now);
+            engine.last_success_at = Some(now);
+        }
+    }
+
+    pub fn record_error(&self, name: impl ToString, error: &dyn std::fmt::Display) {
+        let error = ErrorStatus::now(error);
+        if let Some(engine) = self.engines.lock().unwrap().get_mut(&name.to_string()) {
+            engine.last_tick_at = Some(error.at);
+            engine.last_error = Some(error);
+        }
+    }
+
+    /// Registers the filtered stream.
+    pub fn register_stream(&self) {
+        *self.stream.lock().unwrap() = Some(Default::default());
+    }
+
+    pub fn record_stream_tweet(&self) {
+        if let Some(stream) = &mut *self.stream.lock().unwrap() {
+            stream.connected = true;
+            stream.last_tweet_at = Some(Utc::now());
+            stream.failing_since = None;
+        }
+    }
+
+    pub fn record_stream_error(&self, error: &dyn std::fmt::Display) {
+        let error = ErrorStatus::now(error);
+        if let Some(stream) = &mut *self.stream.lock().unwrap() {
+            stream.connected = false;
+            stream.failing_since.get_or_insert(error.at);
+            stream.last_error = Some(error);
+        }
+    }
+
+    /// Records tweets received by an engine, before filtering and routing.
+    pub fn record_received(&self, name: impl ToString, count: usize) {
+        if count == 0 {
+            return;
+        }
+        let mut activity = self.activity.lock().unwrap();
+        let activity = activity.entry(name.to_string()).or_default();
+        activity.last_received_at = Some(Utc::now());
+        activity.received += count as u64;
+    }
+
+    /// Records a tweet relayed by an engine.
+    pub fn record_relayed(&self, name: impl ToString) {
+        let mut activity = self.activity.lock().unwrap();
+        let activity = activity.entry(name.to_string()).or_default();
+        activity.last_relayed_at = Some(Utc::now());
+        activity.relayed += 1;
+    }
+
+    pub fn activity(&self, name: &str) -> EngineActivity {
+        self.activity.lock().unwrap().get(name).cloned().unwrap_or_default()
+    }
+
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    pub fn record_tracker_sizes(&self, sizes: BTreeMap<String, usize>) {
+        *self.tracker_sizes.lock().unwrap() = sizes;
+    }
+
+    pub fn record_delivery(&self, succeeded: bool) {
+        let counter = if succeeded {
+            &self.deliveries_succeeded
+        } else {
+            &self.deliveries_failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Summarizes the registered engines, in name order.
+    pub fn engine_summaries(&self) -> Vec<EngineSummary> {
+        let activity = self.activity.lock().unwrap();
+        let tracker_size = self.tracker_sizes.lock().unwrap().values().sum::<usize>();
+        let mut summaries = Vec::new();
+        for (name, engine) in &*self.engines.lock().unwrap() {
+            let last_error = engine.last_error.as_ref();
+            let state = match (last_error, engine.last_success_at) {
+                (Some(error), Some(success_at)) if success_at >= error.at => EngineState::Ok,
+                (Some(error), _) => EngineState::Failing(error.at),
+                (None, Some(_)) => EngineState::Ok,
+                (None, None) => EngineState::Starting,
+            };
+            summaries.push(EngineSummary {
+                name: name.clone(),
+                activity: activity.get(name).cloned().unwrap_or_default(),
+                state,
+                last_error: last_error.map(|e| (e.at, e.message.clone())),
+                tracker_size: (*name == Engine::Search.to_string()).then_some(tracker_size),
+            });
+        }
+        if let Some(stream) = &*self.stream.lock().unwrap() {
+            let name = Engine::FilteredStream.to_string();
+            let state = match (stream.failing_since, stream.connected) {
+                (Some(since), _) => EngineState::Failing(since),
+                (None, true) => EngineState::Ok,
+                (None, false) => EngineState::Starting,
+            };
+            summaries.push(EngineSummary {
+                activity: activity.get(&name).cloned().unwrap_or_default(),
+                name,
+                state,
+                last_error: stream.last_error.as_ref().map(|e| (e.at, e.message.clone())),
+                tracker_size: None,
+            });
+            summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+        summaries
+    }
+
+    /// Returns the reasons the process is unhealthy, empty if it's healthy.
+    pub fn check_health(&self) -> Vec<String> {
+        let now = Utc::now();
+        let mut reasons = Vec::new();
+        for (name, engine) in &*self.engines.lock().unwrap() {
+            let last_tick_at = engine.last_tick_at.unwrap_or(self.started_at);
+            let deadline = last_tick_at
+                + Duration::seconds(engine.interval_secs as i64 * 2 + ENGINE_GRACE_SECS);
+            if deadline < now {
+                reasons.push(format!("engine {} has not ticked since {}", name, last_tick_at));
+            }
+        }
+        if let Some(stream) = &*self.stream.lock().unwrap() {
+            if let Some(failing_since) = stream.failing_since {
+                if now - failing_since >= Duration::minutes(STREAM_UNHEALTHY_AFTER_MINUTES) {
+                    reasons.push(format!("stream has been failing since {}", failing_since));
+                }
+            }
+        }
+        reasons
+    }
+
+    fn to_json(
+        &self,
+        cache: serde_json::Value,
+        delivery_stats: &DeliveryStats,
+    ) -> serde_json::Value {
+        let (today, destinations) = delivery_stats.today();
+        serde_json::json!({
+            "started_at": self.started_at,
+            "healthy": self.check_health().is_empty(),
+            "engines": &*self.engines.lock().unwrap(),
+            "stream": &*self.stream.lock().unwrap(),
+            "tracker": &*self.tracker_sizes.lock().unwrap(),
+            "activity": &*self.activity.lock().unwrap(),
+            "deliveries": {
+                "succeeded": self.deliveries_succeeded.load(Ordering::Relaxed),
+                "failed": self.deliveries_failed.load(Ordering::Relaxed),
+            },
+            "cache": cache,
+            "destinations": {
+                "date": today.format("%Y-%m-%d").to_string(),
+                "counters": destinations,
+            },
+        })
+    }
+}
+
+/// Serves `/healthz`, `/status` and `/metrics` on `addr` until `shutdown` is cancelled.
+pub async fn serve(
+    addr: std::net::SocketAddr,
+    status: Arc<StatusRegistry>,
+    cache: FsCache,
+    delivery_stats: Arc<DeliveryStats>,
+    metrics: &'static PrometheusRecorder,
+    shutdown: CancellationToken,
+) -> hyper::Result<()> {
+    let make_service = hyper::service::make_service_fn(move |_| {
+        let status = status.clone();
+        let cache = cache.clone();
+        let delivery_stats = delivery_stats.clone();
+        async move {
+            Ok::<_, Infallible>(hyper::service::service_fn(move |req| {
+                handle(req, status.clone(), cache.clone(), delivery_stats.clone(), metrics)
+            }))
+        }
+    });
+    log::info!("Serving status on {}", addr);
+    hyper::Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await
+}
+
+async fn handle(
+    req: Request<Body>,
+    status: Arc<StatusRegistry>,
+    cache: FsCache,
+    delivery_stats: Arc<DeliveryStats>,
+    metrics: &'static PrometheusRecorder,
+) -> Result<Response<Body>, Infallible> {
+    let resp = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") => {
+            let reasons = status.check_health();
+            if reasons.is_empty() {
+                Response::new(Body::from("ok\n"))
+            } else {
+                let mut resp = Response::new(Body::from(reasons.join("\n") + "\n"));
+                *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                resp
+            }
+        }
+        (&Method::GET, "/status") => {
+            let cache_stats = match cache.stats().await {
+                Ok(stats) => serde_json::to_value(stats).unwrap(),
+                Err(e) => serde_json::json!({ "error": e.to_string() }),
+            };
+            let body = serde_json::to_vec(&status.to_json(cache_stats, &delivery_stats)).unwrap();
+            let mut resp = Response::new(Body::from(body));
+            resp.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("application/json"),
+            );
+            resp
+        }
+        (&Method::GET, "/metrics") => {
+            let mut resp = Response::new(Body::from(metrics.render()));
+            resp.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            resp
+        }
+        _ => {
+            let mut resp = Response::new(Body::empty());
+            *resp.status_mut() = StatusCode::NOT_FOUND;
+            resp
+        }
+    };
+    Ok(resp)
+}
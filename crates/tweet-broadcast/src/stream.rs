@@ -1,38 +1,349 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
 use eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
 
-use tweet_fetch::TwitterClient;
 use tweet_model::{
     self as model,
     cache::*,
+    trace::TraceExt,
+    metrics,
 };
-use tweet_route::Router;
 
-pub async fn run_line_loop<Cache>(
-    client: &TwitterClient,
+use crate::admin::AdminNotifier;
+use crate::dedup::{DedupGuard, DeliveredMarker, RecentIds};
+use crate::image::SaveImages;
+use crate::reload::{self, EngineConfig};
+use crate::router::RouterPool;
+use crate::sink::{DeliveryOptions, SinkFactory};
+use crate::status::StatusRegistry;
+use crate::webhook::WebhookTarget;
+use crate::Engine;
+
+/// Number of routed tweets between heap statistics log lines.
+const HEAP_STATS_INTERVAL: u64 = 500;
+
+const DEFAULT_DUPLICATE_WINDOW_SIZE: usize = 1000;
+const DEFAULT_DUPLICATE_WINDOW_SECS: u64 = 30;
+
+/// Config of the filtered stream, `stream/config.toml` in the cache directory. Required when it
+/// runs without route scripts, optional otherwise.
+///
+/// Without route scripts, tweets are sent with the standard rendering to the webhooks of the tags
+/// of their matching rules.
+///
+/// ```toml
+/// [stream]
+/// store_unrouted = true
+/// duplicate_window_secs = 30
+///
+/// [stream.tag_webhooks]
+/// art = ["https://discord.com/api/webhooks/..."]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StreamConfig {
+    stream: StreamMeta,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StreamMeta {
+    #[serde(default)]
+    tag_webhooks: BTreeMap<String, Vec<WebhookTarget>>,
+    /// Caches tweets which matched a rule but got no routes, with an `UnroutedRecord` each.
+    #[serde(default)]
+    store_unrouted: bool,
+    /// Number of recent tweet IDs remembered to drop payloads delivered twice. 0 disables it.
+    #[serde(default = "default_duplicate_window_size")]
+    duplicate_window_size: usize,
+    /// How long a tweet ID is remembered to drop payloads delivered twice, in seconds.
+    #[serde(default = "default_duplicate_window_secs")]
+    duplicate_window_secs: u64,
+}
+
+fn default_duplicate_window_size() -> usize {
+    DEFAULT_DUPLICATE_WINDOW_SIZE
+}
+
+fn default_duplicate_window_secs() -> u64 {
+    DEFAULT_DUPLICATE_WINDOW_SECS
+}
+
+impl EngineConfig for StreamConfig {
+    fn validate(&self) -> Result<()> {
+        for (tag, webhooks) in &self.stream.tag_webhooks {
+            for (idx, webhook) in webhooks.iter().enumerate() {
+                webhook
+                    .validate()
+                    .map_err(|e| eyre::eyre!("stream.tag_webhooks.{}[{}]: {}", tag, idx, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn webhooks(&self) -> Vec<(String, &WebhookTarget)> {
+        self.stream
+            .tag_webhooks
+            .iter()
+            .flat_map(|(tag, webhooks)| {
+                webhooks.iter().enumerate().map(move |(idx, webhook)| {
+                    (format!("stream.tag_webhooks.{}[{}]", tag, idx), webhook)
+                })
+            })
+            .collect()
+    }
+}
+
+impl StreamConfig {
+    fn tag_routes(&self) -> BTreeMap<String, Vec<tweet_route::TagRoute>> {
+        self.stream
+            .tag_webhooks
+            .iter()
+            .map(|(tag, webhooks)| {
+                let routes = webhooks
+                    .iter()
+                    .map(|webhook| tweet_route::TagRoute {
+                        url: webhook.url().clone(),
+                        thread_id: webhook.thread_id().map(|id| id.to_owned()),
+                    })
+                    .collect();
+                (tag.clone(), routes)
+            })
+            .collect()
+    }
+}
+
+/// Record of a stream tweet which matched a rule but got no routes, kept for later analysis.
+///
+/// Unlike route data, it doesn't mark the tweet as relayed, so replays still deliver it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnroutedRecord {
+    tweet_id: String,
+    tags: Vec<String>,
+    score: f64,
+    at: DateTime<Utc>,
+}
+
+impl CacheItem for UnroutedRecord {
+    fn key(&self) -> &str {
+        &self.tweet_id
+    }
+}
+
+impl UnroutedRecord {
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+}
+
+/// Route script reloads requested by the operator, e.g. on SIGUSR1.
+#[derive(Debug)]
+pub struct ScriptReloader {
+    requested: tokio::sync::Notify,
+    scripts: Vec<PathBuf>,
+    /// Stream config, reloaded along with the scripts.
+    config_path: PathBuf,
+    /// Whether tweets are routed by the tag routes of the stream config, which is required then.
+    tag_routing: bool,
+    store_unrouted: AtomicBool,
+    /// Tweets seen recently, kept across reconnections.
+    recent: Mutex<RecentIds>,
+    /// Notified of scripts which failed to load.
+    admin: AdminNotifier,
+}
+
+impl ScriptReloader {
+    pub fn new(
+        scripts: Vec<PathBuf>,
+        config_path: PathBuf,
+        tag_routing: bool,
+        admin: AdminNotifier,
+    ) -> Self {
+        Self {
+            requested: tokio::sync::Notify::new(),
+            scripts,
+            config_path,
+            tag_routing,
+            store_unrouted: AtomicBool::new(false),
+            recent: Mutex::new(RecentIds::new(
+                DEFAULT_DUPLICATE_WINDOW_SIZE,
+                Duration::from_secs(DEFAULT_DUPLICATE_WINDOW_SECS),
+            )),
+            admin,
+        }
+    }
+
+    /// Reads the stream config and applies it, routing tweets of `router` by rule tag if there
+    /// are no route scripts.
+    pub async fn load_config(&self, router: &RouterPool) -> Result<()> {
+        if !self.tag_routing && !self.config_path.exists() {
+            self.store_unrouted.store(false, Ordering::Relaxed);
+            self.recent.lock().unwrap().set_limits(
+                DEFAULT_DUPLICATE_WINDOW_SIZE,
+                Duration::from_secs(DEFAULT_DUPLICATE_WINDOW_SECS),
+            );
+            return Ok(());
+        }
+        let config = reload::read_config::<StreamConfig>(&self.config_path).await?;
+        if self.tag_routing {
+            router.set_tag_routes(config.tag_routes());
+        }
+        self.store_unrouted.store(config.stream.store_unrouted, Ordering::Relaxed);
+        self.recent.lock().unwrap().set_limits(
+            config.stream.duplicate_window_size,
+            Duration::from_secs(config.stream.duplicate_window_secs),
+        );
+        Ok(())
+    }
+
+    /// Returns whether tweets without routes are cached.
+    pub fn store_unrouted(&self) -> bool {
+        self.store_unrouted.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether a tweet was received recently, recording it otherwise.
+    fn is_duplicate(&self, tweet_id: &str) -> bool {
+        self.recent.lock().unwrap().check(tweet_id)
+    }
+
+    /// Asks the stream loop to reload the route scripts before routing the next tweet.
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+
+    /// Reads every route script and the stream config again and swaps them in. Those which fail
+    /// to load are reported and keep their previous version.
+    async fn reload(&self, router: &RouterPool) {
+        match self.load_config(router).await {
+            Ok(()) => log::info!("Reloaded stream config {}", self.config_path.display()),
+            Err(e) => {
+                log::error!("Failed to reload stream config, keeping the old one: {}", e);
+                let message = format!("Failed to reload stream config: {}", e);
+                self.admin.notify(&message).await;
+            }
+        }
+        for path in &self.scripts {
+            let name = path.file_name().unwrap_or(path.as_os_str()).to_string_lossy();
+            let result = match tokio::fs::read_to_string(path).await {
+                Ok(script) => router
+                    .reload_script(&name, &script)
+                    .await
+                    .map(|()| sha256_hex(script.as_bytes()))
+                    .map_err(|e| {
+                        if let Some(js_error) = e.js_error() {
+                            log::error!("Route script stack trace:\n{}", js_error.format_stack());
+                        }
+                        sentry::capture_error(&e);
+                        e.to_string()
+                    }),
+                Err(e) => Err(e.to_string()),
+            };
+            match result {
+                Ok(digest) => log::info!("Reloaded route script {}, SHA-256 {}", name, digest),
+                Err(e) => {
+                    log::error!("Failed to reload route script {}, keeping the old one: {}", name, e);
+                    let message = format!("Failed to reload route script {}: {}", name, e);
+                    self.admin.notify(&message).await;
+                }
+            }
+        }
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Routes and delivers tweets of `lines`, a connection to the filtered stream, until it fails or
+/// `shutdown` is cancelled.
+pub async fn run_line_loop<Cache, Lines>(
+    lines: Lines,
+    sinks: &SinkFactory,
     cache: &Cache,
-    router: &mut Router,
-) -> Result<std::convert::Infallible>
+    router: &RouterPool,
+    shutdown: &CancellationToken,
+    status: &StatusRegistry,
+    reloader: &ScriptReloader,
+) -> Result<()>
 where
-    Cache: LoadCache<model::Tweet> + StoreCache<model::Tweet> + StoreCache<model::User> + StoreCache<model::Media> + StoreCache<tweet_route::CacheData>,
+    Cache: LoadCache<model::Tweet> + LoadCache<tweet_route::CacheData> + StoreCache<model::Tweet> + StoreCache<model::User> + StoreCache<model::Media> + StoreCache<tweet_route::CacheData> + StoreCache<UnroutedRecord> + LoadCache<DeliveredMarker> + StoreCache<DeliveredMarker> + SaveImages,
+    Lines: futures_util::Stream<Item = Result<model::ResponseItem<model::Tweet, model::StreamMeta>, tweet_fetch::Error>>,
 {
     use futures_util::{StreamExt, TryStreamExt};
-    let discord_client = reqwest::Client::builder().build().unwrap();
 
-    let lines = client.make_stream();
-    tokio::pin!(lines);
+    let dedup = &DedupGuard::new(cache, sinks.dedup_enabled());
+    // tweets are routed by the members of the pool in parallel, and delivered in order
+    let routed = lines
+        .try_filter_map(|mut tweet| {
+            status.record_stream_tweet();
+            status.record_received(Engine::FilteredStream, 1);
+            metrics::increment_counter(metrics::TWEETS_RECEIVED, &[("engine", "filtered_stream")]);
+            if reloader.is_duplicate(tweet.data.id()) {
+                log::debug!("Tweet {} was received again, dropping", tweet.data.id());
+                metrics::increment_counter(metrics::STREAM_DUPLICATES, &[]);
+                return futures_util::future::ready(Ok(None));
+            }
 
+            // leftovers of augmenting other tweets would be routed and cached with this one
+            let pruned = tweet.prune();
+            if pruned > 0 {
+                log::trace!("Pruned {} unrelated include(s) of tweet {}", pruned, tweet.data.id());
+            }
+            futures_util::future::ready(Ok(Some(tweet)))
+        })
+        .map_ok(|tweet| async move {
+            let span = model::span!(
+                "stream_tweet",
+                engine = "filtered_stream",
+                tweet_id = %tweet.data.id(),
+            );
+            let outcome = router.call(&tweet, cache).in_span(span.clone()).await;
+            Ok::<_, tweet_fetch::Error>((tweet, span, outcome))
+        })
+        .try_buffered(router.size());
+    tokio::pin!(routed);
+
+    let mut routed_since_stats = 0u64;
     loop {
-        let tweet = match lines.next().await {
-            Some(line_result) => line_result?,
+        let next = tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = reloader.requested.notified() => {
+                reloader.reload(router).await;
+                continue;
+            },
+            next = routed.next() => next,
+        };
+        let (tweet, span, outcome) = match next {
+            Some(next) => next?,
             None => {
                 eyre::bail!("stream closed");
             }
         };
 
-        let route_result = match router.call(&tweet, cache).await {
+        let route_result = outcome.and_then(|outcome| {
+            outcome
+                .into_result(router.scorer(), &tweet)
+                .map_err(Into::into)
+        });
+        let route_result = match route_result {
             Ok(route_result) => route_result,
             Err(e) => {
                 log::error!("Failed to route: {}, input: {:?}", e, tweet);
+                if let Some(js_error) = e.js_error() {
+                    log::error!("Route script stack trace:\n{}", js_error.format_stack());
+                }
                 let mut ev = sentry::event_from_error(&e);
                 ev.extra
                     .insert(String::from("data"), format!("{:?}", tweet).into());
@@ -41,9 +352,42 @@ where
             }
         };
 
+        routed_since_stats += 1;
+        if routed_since_stats >= HEAP_STATS_INTERVAL {
+            routed_since_stats = 0;
+            if let Some(stats) = router.heap_stats().await {
+                log::info!("Router heap: {}", stats);
+                let used = stats.used_heap_size as f64;
+                metrics::set_gauge(metrics::ROUTER_HEAP_USED_BYTES, &[], used);
+            }
+        }
+
+        for tweet_route::ScriptError { origin, error } in route_result.errors() {
+            log::error!("Route script {} failed: {}, input: {:?}", origin, error, tweet);
+            if let Some(js_error) = error.js_error() {
+                log::error!("Route script stack trace:\n{}", js_error.format_stack());
+            }
+            let mut ev = sentry::event_from_error(error);
+            ev.tags.insert(String::from("script"), origin.clone());
+            sentry::capture_event(ev);
+        }
+
         let payload = route_result.payload();
         let routes = route_result.routes();
         let cached = route_result.cached();
+        log::trace!(
+            "Tweet {}: score breakdown {:?}",
+            payload.tweet.id(),
+            payload.score_breakdown,
+        );
+        if let Some(score) = route_result.score_override() {
+            log::debug!(
+                "Tweet {}: score overridden by route script, {:.4} -> {:.4}",
+                payload.tweet.id(),
+                payload.score_breakdown.total,
+                score,
+            );
+        }
         if routes.is_empty() {
             log::debug!(
                 "No routes: {}{}, score: {:.4}",
@@ -63,9 +407,33 @@ where
                     });
                 }
                 futures.try_collect::<()>().await?;
+                cache.save_images(payload.media.iter().copied());
+            }
+
+            if reloader.store_unrouted() && !cached {
+                sinks.summary().record_unrouted(&payload.tags);
+                if let Err(e) = store_unrouted(cache, payload).await {
+                    log::error!("Failed to save unrouted tweet {}: {}", payload.tweet.id(), e);
+                    sentry::capture_error(&e);
+                }
+                cache.save_images(payload.media.iter().copied());
             }
         } else {
-            if !cached {
+            metrics::increment_counter(metrics::TWEETS_ROUTED, &[]);
+            log::debug!(
+                "Relaying tweet {id} by @{author_username}, matching rule(s): {rules:?}, score: {score:.4}, script(s): {origins:?}",
+                id = payload.tweet.id(),
+                author_username = payload.author.username(),
+                rules = payload.tags,
+                score = payload.score,
+                origins = routes.iter().map(|r| &*r.origin).collect::<Vec<_>>(),
+            );
+
+            let delivered = deliver_routes(sinks, dedup, &tweet.data, &tweet.includes, routes)
+                .in_span(span)
+                .await;
+            // cached tweets count as relayed, so keep the tweet uncached if no route got it
+            if !cached && delivered > 0 {
                 let ret = async {
                     futures_util::try_join!(
                         cache.store(&tweet_route::CacheData::from(payload)),
@@ -77,31 +445,194 @@ where
                     log::error!("Failed to save metadata: {}", e);
                     sentry::capture_error(&e);
                 }
+            } else if delivered == 0 {
+                log::warn!("Tweet {} was not delivered to any route", payload.tweet.id());
             }
+            cache.save_images(payload.media.iter().copied());
+            let sources = payload
+                .tags
+                .iter()
+                .map(|tag| format!("rule:{}", tag))
+                .collect::<Vec<_>>();
+            sinks
+                .summary()
+                .record_relay(&sources, payload.tweet, &tweet.includes, Some(payload.score));
+            status.record_relayed(Engine::FilteredStream);
+        }
+    }
+}
 
-            log::debug!(
-                "Relaying tweet {id} by @{author_username}, matching rule(s): {rules:?}, score: {score:.4}",
-                id = payload.tweet.id(),
-                author_username = payload.author.username(),
-                rules = payload.tags,
-                score = payload.score,
-            );
+/// Caches a tweet without routes with its includes and an `UnroutedRecord`, but no route data,
+/// so that it counts as not relayed yet.
+async fn store_unrouted<Cache>(
+    cache: &Cache,
+    payload: &tweet_route::RoutePayload<'_>,
+) -> Result<(), Cache::Error>
+where
+    Cache: StoreCache<model::Tweet> + StoreCache<model::User> + StoreCache<model::Media> + StoreCache<UnroutedRecord>,
+{
+    use futures_util::TryStreamExt;
 
-            let webhook_fut = futures_util::stream::FuturesUnordered::new();
-            for route in routes {
-                webhook_fut.push(async {
-                    let result = tweet_discord::execute_webhook(
-                        &discord_client,
-                        &route.url,
-                        &route.payload,
-                    ).await;
-                    if let Err(e) = result {
-                        log::error!("Failed to send: {}", e);
-                        sentry::capture_error(&e);
+    let record = UnroutedRecord {
+        tweet_id: payload.tweet.id().to_owned(),
+        tags: payload.tags.iter().map(|&tag| tag.to_owned()).collect(),
+        score: payload.score,
+        at: Utc::now(),
+    };
+    let futures = futures_util::stream::FuturesUnordered::new();
+    futures.push(cache.store(&record));
+    futures.push(cache.store(payload.tweet));
+    futures.push(cache.store(payload.author));
+    if let Some(tweet) = payload.original_tweet {
+        futures.push(cache.store(tweet));
+    }
+    if let Some(author) = payload.original_author {
+        futures.push(cache.store(author));
+    }
+    for &media in &payload.media {
+        futures.push(cache.store(media));
+    }
+    futures.try_collect::<Vec<_>>().await?;
+    Ok(())
+}
+
+/// Attempts of delivering a tweet to a route, including the first one. The webhook executor
+/// retries each attempt on its own; these retries cover outages outlasting it.
+const ROUTE_DELIVERY_ATTEMPTS: u32 = 3;
+/// Wait before the first retry of a route delivery, doubled on each retry.
+const ROUTE_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Delivers a routed tweet to every route concurrently, retrying transient failures and logging
+/// the rest. Routes which already received the tweet are skipped.
+///
+/// Returns the number of routes which have the tweet, including skipped ones.
+pub async fn deliver_routes<Cache>(
+    sinks: &SinkFactory,
+    dedup: &DedupGuard<'_, Cache>,
+    tweet: &model::Tweet,
+    includes: &model::ResponseIncludes,
+    routes: &[tweet_route::RouteResultItem],
+) -> usize
+where
+    Cache: LoadCache<DeliveredMarker> + StoreCache<DeliveredMarker>,
+{
+    use futures_util::StreamExt;
+
+    let webhook_fut = futures_util::stream::FuturesUnordered::new();
+    for route in routes {
+        let target = WebhookTarget::new(route.url.clone())
+            .with_thread_id(route.thread_id.clone());
+        let span = model::span!(
+            "deliver",
+            tweet_id = %tweet.id(),
+            destination = %tweet_discord::redact_destination(&route.url),
+        );
+        webhook_fut.push(async move {
+            if dedup.is_delivered(tweet.id(), &target).await {
+                return true;
+            }
+            let sink = sinks.build(&target);
+            let mut attempts = 0;
+            let result = loop {
+                attempts += 1;
+                let result = if let Some(render) = &route.render {
+                    let options = DeliveryOptions {
+                        render: Some(render.clone()),
+                        ..Default::default()
+                    };
+                    sink.deliver(tweet, includes, &options).await
+                } else {
+                    sink.deliver_raw(&route.payload).await
+                };
+                match result {
+                    Err(e) if e.is_transient() && attempts < ROUTE_DELIVERY_ATTEMPTS => {
+                        let delay = ROUTE_RETRY_DELAY * (1 << (attempts - 1));
+                        log::warn!(
+                            "Failed to send to {}: {}, retrying after {:?}",
+                            sink.name(),
+                            e,
+                            delay,
+                        );
+                        tokio::time::sleep(delay).await;
                     }
-                });
+                    result => break result,
+                }
+            };
+            match result {
+                Ok(_) => {
+                    dedup.mark_delivered(tweet.id(), &target).await;
+                    true
+                }
+                Err(e) => {
+                    log::error!("Failed to send to {}: {}", sink.name(), e);
+                    sentry::capture_error(&e);
+                    false
+                }
             }
-            webhook_fut.collect::<()>().await;
+        }.in_span(span));
+    }
+    webhook_fut.filter(|&delivered| async move { delivered }).count().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Delivery, MemoryCache, RecordingSinks};
+
+    fn route(name: &str) -> tweet_route::RouteResultItem {
+        let url = format!("https://discord.com/api/webhooks/1/{}", name);
+        tweet_route::RouteResultItem {
+            origin: String::from("test"),
+            url: url.parse().unwrap(),
+            thread_id: None,
+            payload: serde_json::json!({ "content": "routed" }),
+            render: None,
         }
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_failures_are_retried() {
+        let cache = MemoryCache::default();
+        let dedup = DedupGuard::new(&cache, true);
+        let recording = RecordingSinks::default();
+        let sinks = recording.factory();
+        let tweet = model::Tweet::new("10", "text");
+        let includes = model::ResponseIncludes::default();
+        let routes = [route("hook")];
+
+        recording.fail_next(2);
+        let start = tokio::time::Instant::now();
+        let delivered = deliver_routes(&sinks, &dedup, &tweet, &includes, &routes).await;
+        assert_eq!(delivered, 1);
+        assert_eq!(recording.attempts(), 3);
+        assert_eq!(start.elapsed(), ROUTE_RETRY_DELAY * 3);
+        let target = String::from("https://discord.com/api/webhooks/1/hook");
+        assert_eq!(recording.deliveries(), [Delivery::Raw { target }]);
+
+        // delivered routes are skipped
+        let delivered = deliver_routes(&sinks, &dedup, &tweet, &includes, &routes).await;
+        assert_eq!(delivered, 1);
+        assert_eq!(recording.attempts(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_are_limited() {
+        let cache = MemoryCache::default();
+        let dedup = DedupGuard::new(&cache, true);
+        let recording = RecordingSinks::default();
+        let sinks = recording.factory();
+        let tweet = model::Tweet::new("10", "text");
+        let includes = model::ResponseIncludes::default();
+        let routes = [route("hook")];
+
+        recording.fail_next(ROUTE_DELIVERY_ATTEMPTS as usize);
+        let delivered = deliver_routes(&sinks, &dedup, &tweet, &includes, &routes).await;
+        assert_eq!(delivered, 0);
+        assert_eq!(recording.attempts(), ROUTE_DELIVERY_ATTEMPTS as usize);
+        assert!(recording.deliveries().is_empty());
+
+        // not marked as delivered, so tried again
+        let delivered = deliver_routes(&sinks, &dedup, &tweet, &includes, &routes).await;
+        assert_eq!(delivered, 1);
+    }
 }
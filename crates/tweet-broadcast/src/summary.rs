@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use tweet_model as model;
+
+use crate::active_hours;
+use crate::reload::EngineConfig;
+use crate::sink::SinkFactory;
+use crate::webhook::WebhookTarget;
+
+/// Tweets listed in the top tweets field of the summary.
+const TOP_TWEET_COUNT: usize = 5;
+/// Sources and engines listed in the summary, by count.
+const MAX_LISTED_SOURCES: usize = 15;
+/// How often the counters are written to disk, so that restarts don't lose the day.
+const PERSIST_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+const SUMMARY_COLOR: u32 = 0x1d9bf0;
+
+/// Config of the daily summary, `summary/config.toml` in the cache directory.
+///
+/// ```toml
+/// [summary]
+/// send_at = "09:00"
+/// tz = "Asia/Seoul"
+/// webhooks = ["https://discord.com/api/webhooks/..."]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SummaryConfig {
+    summary: SummaryMeta,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SummaryMeta {
+    /// Local time to send the summary at, as `HH:MM`.
+    send_at: String,
+    /// Time zone name or UTC offset of `send_at`, UTC by default.
+    #[serde(default = "default_tz")]
+    tz: String,
+    webhooks: Vec<WebhookTarget>,
+}
+
+fn default_tz() -> String {
+    String::from("UTC")
+}
+
+impl EngineConfig for SummaryConfig {
+    fn validate(&self) -> Result<()> {
+        self.schedule()?;
+        for (idx, webhook) in self.summary.webhooks.iter().enumerate() {
+            webhook
+                .validate()
+                .map_err(|e| eyre::eyre!("summary.webhooks[{}]: {}", idx, e))?;
+        }
+        Ok(())
+    }
+
+    fn webhooks(&self) -> Vec<(String, &WebhookTarget)> {
+        self.summary
+            .webhooks
+            .iter()
+            .enumerate()
+            .map(|(idx, webhook)| (format!("summary.webhooks[{}]", idx), webhook))
+            .collect()
+    }
+}
+
+impl SummaryConfig {
+    /// Returns the time zone and minutes after midnight to send the summary at.
+    fn schedule(&self) -> Result<(active_hours::Zone, u32)> {
+        let zone = active_hours::parse_zone(&self.summary.tz)
+            .map_err(|e| eyre::eyre!("summary.{}", e))?;
+        let send_at = active_hours::parse_time(&self.summary.send_at)
+            .filter(|&t| t < 24 * 60)
+            .ok_or_else(|| {
+                eyre::eyre!(
+                    "summary.send_at: expected HH:MM before 24:00, got {:?}",
+                    self.summary.send_at,
+                )
+            })?;
+        Ok((zone, send_at))
+    }
+
+    /// Returns when the next summary is due after `now`.
+    pub fn next_send_at(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        // validated on load
+        let (zone, send_at) = self.schedule().unwrap();
+        zone.next_local_time(send_at, now)
+    }
+}
+
+/// Tweet among the highest scoring of the day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopTweet {
+    id: String,
+    url: String,
+    author: Option<String>,
+    score: f64,
+}
+
+/// Counters of the day, since the last summary.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DailyStats {
+    since: Option<DateTime<Utc>>,
+    relayed: u64,
+    /// Relayed tweets by source, e.g. `rule:art` or `list:123`.
+    sources: BTreeMap<String, u64>,
+    /// Errors by engine.
+    errors: BTreeMap<String, u64>,
+    /// Stream tweets which matched a rule but got no routes, by rule tag.
+    unrouted: BTreeMap<String, u64>,
+    top: Vec<TopTweet>,
+}
+
+impl DailyStats {
+    fn record_relay(
+        &mut self,
+        sources: &[String],
+        tweet: &model::Tweet,
+        includes: &model::ResponseIncludes,
+        score: Option<f64>,
+    ) {
+        self.relayed += 1;
+        for source in sources {
+            *self.sources.entry(source.clone()).or_default() += 1;
+        }
+
+        let score = match score {
+            Some(score) => score,
+            None => return,
+        };
+        let author = tweet
+            .author_id()
+            .and_then(|id| includes.get_user(id))
+            .map(|user| user.username().to_owned());
+        let url = format!(
+            "https://twitter.com/{}/status/{}",
+            author.as_deref().unwrap_or("i/web"),
+            tweet.id(),
+        );
+        // the same tweet may be relayed by several engines
+        self.top.retain(|top| top.id != tweet.id());
+        self.top.push(TopTweet {
+            id: tweet.id().to_owned(),
+            url,
+            author,
+            score,
+        });
+        self.top.sort_by(|a, b| b.score.total_cmp(&a.score));
+        self.top.truncate(TOP_TWEET_COUNT);
+    }
+
+    fn record_error(&mut self, engine: &str) {
+        *self.errors.entry(engine.to_owned()).or_default() += 1;
+    }
+
+    fn record_unrouted(&mut self, tags: &[&str]) {
+        for &tag in tags {
+            *self.unrouted.entry(format!("rule:{}", tag)).or_default() += 1;
+        }
+    }
+}
+
+/// Collects the counters of the daily summary, reported into by engines.
+#[derive(Debug, Default)]
+pub struct SummaryRecorder {
+    stats: Mutex<DailyStats>,
+}
+
+impl SummaryRecorder {
+    /// Records a relayed tweet, counted once for each of `sources`.
+    pub fn record_relay(
+        &self,
+        sources: &[String],
+        tweet: &model::Tweet,
+        includes: &model::ResponseIncludes,
+        score: Option<f64>,
+    ) {
+        self.lock().record_relay(sources, tweet, includes, score);
+    }
+
+    pub fn record_error(&self, engine: impl std::fmt::Display) {
+        self.lock().record_error(&engine.to_string());
+    }
+
+    /// Records a stream tweet without routes, counted once for each rule tag it matched.
+    pub fn record_unrouted(&self, tags: &[&str]) {
+        self.lock().record_unrouted(tags);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DailyStats> {
+        let mut stats = self.stats.lock().unwrap();
+        stats.since.get_or_insert_with(Utc::now);
+        stats
+    }
+
+    /// Restores counters persisted by a previous run, if any.
+    pub async fn restore(&self, path: &Path) {
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+            Err(e) => {
+                log::error!("Failed to read {}: {}", path.display(), e);
+                return;
+            }
+        };
+        match serde_json::from_slice::<DailyStats>(&data) {
+            Ok(stats) => *self.stats.lock().unwrap() = stats,
+            Err(e) => log::error!(
+                "Ignoring invalid summary counters {}: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    async fn persist(&self, path: &Path) {
+        let data = serde_json::to_vec(&*self.stats.lock().unwrap()).unwrap();
+        let tmp_path = path.with_extension("tmp");
+        let ret = async {
+            tokio::fs::write(&tmp_path, &data).await?;
+            tokio::fs::rename(&tmp_path, path).await
+        }
+        .await;
+        if let Err(e) = ret {
+            log::error!("Failed to write {}: {}", path.display(), e);
+        }
+    }
+
+    /// Returns the counters so far, starting over from now.
+    fn take(&self) -> DailyStats {
+        let next = DailyStats {
+            since: Some(Utc::now()),
+            ..Default::default()
+        };
+        std::mem::replace(&mut *self.stats.lock().unwrap(), next)
+    }
+}
+
+/// Renders the summary of `stats`, covering up to `until`.
+pub fn render_summary(
+    stats: &DailyStats,
+    until: DateTime<Utc>,
+    identity: &tweet_discord::WebhookIdentity,
+) -> tweet_discord::WebhookPayload {
+    let since = stats.since.unwrap_or(until);
+    let description = format!(
+        "{} tweet{} relayed since <t:{}:f>",
+        stats.relayed,
+        if stats.relayed == 1 { "" } else { "s" },
+        since.timestamp(),
+    );
+    let mut embed = tweet_discord::Embed::new()
+        .title("Daily summary")
+        .description(description)
+        .color(SUMMARY_COLOR)
+        .timestamp(Some(until));
+
+    if !stats.top.is_empty() {
+        let lines = stats
+            .top
+            .iter()
+            .enumerate()
+            .map(|(idx, top)| {
+                let author = top.author.as_deref().unwrap_or("unknown");
+                format!(
+                    "{}. [@{}]({}) \u{2014} {:.1}",
+                    idx + 1,
+                    author,
+                    top.url,
+                    top.score
+                )
+            })
+            .collect::<Vec<_>>();
+        embed = embed.field("Top tweets", lines.join("\n"), false);
+    }
+    embed = embed.field("By source", format_counts(&stats.sources), true);
+    embed = embed.field("Errors", format_counts(&stats.errors), true);
+    if !stats.unrouted.is_empty() {
+        embed = embed.field("Matched but unrouted", format_counts(&stats.unrouted), true);
+    }
+
+    tweet_discord::make_notice_payload("", identity).embed(embed)
+}
+
+/// Lists counts from the largest, one per line.
+fn format_counts(counts: &BTreeMap<String, u64>) -> String {
+    if counts.is_empty() {
+        return String::from("None");
+    }
+    let mut counts = counts.iter().collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(a.1));
+    let mut lines = counts
+        .iter()
+        .take(MAX_LISTED_SOURCES)
+        .map(|(name, count)| format!("`{}` {}", name, count))
+        .collect::<Vec<_>>();
+    if counts.len() > MAX_LISTED_SOURCES {
+        lines.push(format!(
+            "\u{2026}and {} more",
+            counts.len() - MAX_LISTED_SOURCES
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Sends the daily summary at the configured time, until `shutdown` is cancelled.
+///
+/// Counters are persisted to `stats_path` hourly and on shutdown, and restored on startup.
+pub async fn run(
+    recorder: Arc<SummaryRecorder>,
+    mut config_rx: watch::Receiver<Arc<SummaryConfig>>,
+    sinks: SinkFactory,
+    stats_path: PathBuf,
+    shutdown: CancellationToken,
+) {
+    let mut persist_timer = tokio::time::interval(PERSIST_INTERVAL);
+    loop {
+        let next_send_at = config_rx.borrow().next_send_at(Utc::now());
+        let until_send = (next_send_at - Utc::now()).to_std().unwrap_or_default();
+        log::debug!("Next daily summary at {}", next_send_at);
+        tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => break,
+            // the send time may have changed
+            ret = config_rx.changed() => {
+                if ret.is_err() {
+                    break;
+                }
+                continue;
+            },
+            _ = persist_timer.tick() => {
+                recorder.persist(&stats_path).await;
+                continue;
+            },
+            _ = tokio::time::sleep(until_send) => {},
+        }
+
+        let stats = recorder.take();
+        let config = config_rx.borrow().clone();
+        for webhook in &config.summary.webhooks {
+            let payload = render_summary(&stats, Utc::now(), webhook.identity());
+            let sink = sinks.build(webhook);
+            if let Err(e) = sink.deliver_raw(&payload.to_value()).await {
+                log::error!("Failed to send daily summary to {}: {}", sink.name(), e);
+            }
+        }
+        log::info!("Sent daily summary, {} tweet(s) relayed", stats.relayed);
+        recorder.persist(&stats_path).await;
+    }
+    recorder.persist(&stats_path).await;
+}
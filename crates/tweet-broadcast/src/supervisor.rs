@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Delay before the first restart of a panicked engine.
+const INITIAL_RESTART_DELAY: Duration = Duration::from_secs(1);
+/// Upper bound of the restart delay, which doubles on each consecutive panic.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+/// Runs an engine task, spawning it again with `spawn` whenever it panics.
+///
+/// `spawn` should build the engine from scratch, so that restarts get fresh state. Restarts are
+/// delayed with exponential backoff, reset once the engine runs longer than the maximum delay.
+/// Returns when the engine returns, or is cancelled by `shutdown` while waiting to restart.
+///
+/// Panics are reported to Sentry by its panic integration.
+pub async fn supervise(
+    name: impl std::fmt::Display,
+    shutdown: CancellationToken,
+    mut spawn: impl FnMut() -> JoinHandle<()>,
+) {
+    let mut delay = INITIAL_RESTART_DELAY;
+    loop {
+        let started_at = tokio::time::Instant::now();
+        let e = match spawn().await {
+            Ok(()) => return,
+            Err(e) if e.is_panic() => e,
+            Err(_) => {
+                log::warn!("Engine {} was cancelled", name);
+                return;
+            }
+        };
+
+        if started_at.elapsed() > MAX_RESTART_DELAY {
+            delay = INITIAL_RESTART_DELAY;
+        }
+        log::error!(
+            "Engine {} panicked: {}, restarting in {} second(s)",
+            name,
+            panic_message(e.into_panic()),
+            delay.as_secs(),
+        );
+        tokio::select! {
+            biased;
+            _ = shutdown.cancelled() => return,
+            _ = tokio::time::sleep(delay) => {},
+        }
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+        log::info!("Restarting engine {}", name);
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("(non-string panic payload)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_panicked_engine_with_backoff() {
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let started_at = tokio::time::Instant::now();
+        let spawn = {
+            let spawned = spawned.clone();
+            move || {
+                let attempt = spawned.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    if attempt < 2 {
+                        panic!("attempt {}", attempt);
+                    }
+                })
+            }
+        };
+        supervise("test", CancellationToken::new(), spawn).await;
+        assert_eq!(spawned.load(Ordering::SeqCst), 3);
+        // waited 1 second after the first panic, and 2 after the second
+        assert_eq!(started_at.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_restart() {
+        let shutdown = CancellationToken::new();
+        let spawned = Arc::new(AtomicUsize::new(0));
+        let spawn = {
+            let spawned = spawned.clone();
+            move || {
+                spawned.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async { panic!("always") })
+            }
+        };
+        let cancel = {
+            let shutdown = shutdown.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                shutdown.cancel();
+            }
+        };
+        tokio::join!(supervise("test", shutdown, spawn), cancel);
+        assert_eq!(spawned.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn panic_messages() {
+        assert_eq!(panic_message(Box::new("static")), "static");
+        assert_eq!(panic_message(Box::new(String::from("owned"))), "owned");
+        assert_eq!(panic_message(Box::new(42)), "(non-string panic payload)");
+    }
+}
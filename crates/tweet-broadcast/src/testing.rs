@@ -0,0 +1,214 @@
+//! Test doubles of sinks and caches, which record what engines do with them.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures_util::future::BoxFuture;
+use serde::{de::DeserializeOwned, Serialize};
+
+use tweet_model::{self as model, cache::*};
+
+use crate::image::SaveImages;
+use crate::sink::{BuildSink, DeliveryOptions, DeliveryReceipt, Sink, SinkError, SinkFactory};
+use crate::status::StatusRegistry;
+use crate::webhook::WebhookTarget;
+
+/// Message received by a `RecordingSink`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Delivery {
+    Tweet { target: String, id: String },
+    Raw { target: String },
+    Notice { target: String, message: String },
+}
+
+impl Delivery {
+    pub fn tweet_id(&self) -> Option<&str> {
+        match self {
+            Self::Tweet { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+
+    pub fn notice(&self) -> Option<&str> {
+        match self {
+            Self::Notice { message, .. } => Some(message),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecordingState {
+    deliveries: Vec<Delivery>,
+    attempts: usize,
+    failures_left: usize,
+}
+
+/// Builds `RecordingSink`s, which record into the same log.
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSinks {
+    state: Arc<Mutex<RecordingState>>,
+}
+
+impl RecordingSinks {
+    /// Returns a factory building sinks which record here.
+    pub fn factory(&self) -> SinkFactory {
+        let mut factory = SinkFactory::new(Arc::new(StatusRegistry::new()));
+        factory.set_builder(Arc::new(self.clone()));
+        factory
+    }
+
+    /// Fails the next `count` deliveries with a transient error.
+    pub fn fail_next(&self, count: usize) {
+        self.state.lock().unwrap().failures_left = count;
+    }
+
+    /// Returns the successful deliveries, in order.
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.state.lock().unwrap().deliveries.clone()
+    }
+
+    /// Returns the IDs of the tweets delivered, in order.
+    pub fn tweet_ids(&self) -> Vec<String> {
+        let deliveries = self.deliveries();
+        deliveries
+            .iter()
+            .filter_map(|delivery| delivery.tweet_id().map(String::from))
+            .collect()
+    }
+
+    /// Returns the number of deliveries tried, including failed ones.
+    pub fn attempts(&self) -> usize {
+        self.state.lock().unwrap().attempts
+    }
+
+    pub fn clear(&self) {
+        self.state.lock().unwrap().deliveries.clear();
+    }
+}
+
+impl BuildSink for RecordingSinks {
+    fn build(&self, target: &WebhookTarget) -> Box<dyn Sink> {
+        Box::new(RecordingSink {
+            target: target.url().to_string(),
+            state: self.state.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RecordingSink {
+    target: String,
+    state: Arc<Mutex<RecordingState>>,
+}
+
+impl RecordingSink {
+    fn record(&self, delivery: Delivery) -> Result<DeliveryReceipt, SinkError> {
+        let mut state = self.state.lock().unwrap();
+        state.attempts += 1;
+        if state.failures_left > 0 {
+            state.failures_left -= 1;
+            return Err(tweet_discord::WebhookError::RetriesExhausted {
+                url: self.target.clone(),
+                attempts: 1,
+                reason: String::from("test failure"),
+            }
+            .into());
+        }
+        state.deliveries.push(delivery);
+        let message_id = state.deliveries.len().to_string();
+        Ok(DeliveryReceipt {
+            message_ids: vec![message_id],
+        })
+    }
+}
+
+impl Sink for RecordingSink {
+    fn name(&self) -> String {
+        format!("recording sink {}", self.target)
+    }
+
+    fn deliver<'a>(
+        &'a self,
+        tweet: &'a model::Tweet,
+        _includes: &'a model::ResponseIncludes,
+        _options: &'a DeliveryOptions,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        let ret = self.record(Delivery::Tweet {
+            target: self.target.clone(),
+            id: tweet.id().to_owned(),
+        });
+        Box::pin(async { ret })
+    }
+
+    fn deliver_raw<'a>(
+        &'a self,
+        _payload: &'a serde_json::Value,
+    ) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        let ret = self.record(Delivery::Raw {
+            target: self.target.clone(),
+        });
+        Box::pin(async { ret })
+    }
+
+    fn notify<'a>(&'a self, message: &'a str) -> BoxFuture<'a, Result<DeliveryReceipt, SinkError>> {
+        let ret = self.record(Delivery::Notice {
+            target: self.target.clone(),
+            message: message.to_owned(),
+        });
+        Box::pin(async { ret })
+    }
+}
+
+/// Cache keeping items as JSON in memory, keyed by their type and key. Images are not saved.
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    items: Mutex<HashMap<(&'static str, String), serde_json::Value>>,
+    read_only: AtomicBool,
+}
+
+impl MemoryCache {
+    /// Makes every store a no-op, like `FsCache::set_read_only`.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
+}
+
+impl Cache for MemoryCache {
+    type Error = std::io::Error;
+}
+
+impl<Item: CacheItem + DeserializeOwned + Send + 'static> LoadCache<Item> for MemoryCache {
+    fn load(&self, key: &str) -> BoxFuture<'_, Result<Item, Self::Error>> {
+        let items = self.items.lock().unwrap();
+        let ret = match items.get(&(std::any::type_name::<Item>(), key.to_owned())) {
+            Some(value) => serde_json::from_value(value.clone()).map_err(Into::into),
+            None => Err(std::io::ErrorKind::NotFound.into()),
+        };
+        Box::pin(async { ret })
+    }
+
+    fn has(&self, key: &str) -> BoxFuture<'_, Result<bool, Self::Error>> {
+        let items = self.items.lock().unwrap();
+        let ret = items.contains_key(&(std::any::type_name::<Item>(), key.to_owned()));
+        Box::pin(async move { Ok(ret) })
+    }
+}
+
+impl<Item: CacheItem + Serialize> StoreCache<Item> for MemoryCache {
+    fn store(&self, item: &Item) -> BoxFuture<'_, Result<String, Self::Error>> {
+        let key = item.key().to_owned();
+        if self.read_only.load(Ordering::Relaxed) {
+            return Box::pin(async { Ok(key) });
+        }
+        let value = serde_json::to_value(item).unwrap();
+        let mut items = self.items.lock().unwrap();
+        items.insert((std::any::type_name::<Item>(), key.clone()), value);
+        Box::pin(async { Ok(key) })
+    }
+}
+
+impl SaveImages for MemoryCache {
+    fn save_images<'a>(&self, _media: impl IntoIterator<Item = &'a model::Media>) {}
+}
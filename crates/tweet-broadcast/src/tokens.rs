@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use eyre::Result;
+
+use tweet_fetch::TwitterClient;
+
+use crate::Engine;
+
+/// Token used by every engine without its own.
+const GENERIC_TOKEN_VAR: &str = "TWITTER_APP_TOKEN";
+/// Token of the filtered stream, which holds a connection of its app at all times.
+const STREAM_TOKEN_VAR: &str = "TWITTER_STREAM_TOKEN";
+/// Token of the polling engines.
+const POLL_TOKEN_VAR: &str = "TWITTER_POLL_TOKEN";
+
+fn token_var(engine: Engine) -> &'static str {
+    match engine {
+        Engine::FilteredStream => STREAM_TOKEN_VAR,
+        Engine::Search | Engine::List | Engine::User => POLL_TOKEN_VAR,
+    }
+}
+
+/// Names the variables the token of `engine` is read from, for error messages.
+pub fn token_source(engine: Engine) -> String {
+    format!("{} or {}", token_var(engine), GENERIC_TOKEN_VAR)
+}
+
+/// Twitter app tokens from the environment, resolved per engine.
+///
+/// An engine uses its own token if set, e.g. `TWITTER_STREAM_TOKEN`, and `TWITTER_APP_TOKEN`
+/// otherwise.
+#[derive(Debug, Default)]
+pub struct TokenSet {
+    vars: HashMap<&'static str, String>,
+}
+
+impl TokenSet {
+    pub fn from_env() -> Self {
+        let vars = [GENERIC_TOKEN_VAR, STREAM_TOKEN_VAR, POLL_TOKEN_VAR]
+            .into_iter()
+            .filter_map(|var| {
+                let token = std::env::var(var).ok()?;
+                (!token.is_empty()).then_some((var, token))
+            })
+            .collect();
+        Self { vars }
+    }
+
+    /// Returns the token of `engine`.
+    pub fn resolve(&self, engine: Engine) -> Result<&str> {
+        let var = token_var(engine);
+        self.vars
+            .get(var)
+            .or_else(|| self.vars.get(GENERIC_TOKEN_VAR))
+            .map(|token| &**token)
+            .ok_or_else(|| {
+                eyre::eyre!("no token for engine {}, set {} or {}", engine, var, GENERIC_TOKEN_VAR)
+            })
+    }
+}
+
+/// Clients of engines, one per distinct token so that engines sharing a token share its rate
+/// limits.
+#[derive(Debug, Default)]
+pub struct ClientSet {
+    clients: HashMap<String, TwitterClient>,
+}
+
+impl ClientSet {
+    /// Returns the client of `engine`, creating one if no other engine uses the same token.
+    pub fn client(&mut self, tokens: &TokenSet, engine: Engine) -> Result<TwitterClient> {
+        let token = tokens.resolve(engine)?;
+        let client = self
+            .clients
+            .entry(token.to_owned())
+            .or_insert_with(|| TwitterClient::new(token));
+        Ok(client.clone())
+    }
+}
@@ -1,161 +1,158 @@
 use std::collections::HashMap;
-use std::path::Path;
 
 use eyre::Result;
+use futures_util::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 
 use tweet_fetch::{UserTimelineHead, TwitterClient};
-use tweet_model::{
-    self as model,
-    cache::*,
-};
+use tweet_model::{self as model, cache::*, trace::Span};
+
+use crate::dedup::DeliveredMarker;
+use crate::filter::ContentFilter;
+use crate::image::SaveImages;
+use crate::poll::{self, PollResult, SourceMeta, TweetSource};
+use crate::reload::EngineConfig;
+use crate::settings::{self, PollSettings};
+use crate::sink::SinkFactory;
+use crate::webhook::WebhookTarget;
+use crate::Engine;
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UserMeta {
-    webhooks: Vec<reqwest::Url>,
+    /// Relays original tweets and quotes only, same as `exclude_retweets` of `filter`.
+    #[serde(default)]
+    skip_retweets: bool,
+    /// Same as `exclude_replies` of `filter`.
+    #[serde(default)]
+    skip_replies: bool,
+    #[serde(default)]
+    filter: ContentFilter,
+    webhooks: Vec<WebhookTarget>,
 }
 
 impl UserMeta {
-    pub fn webhooks(&self) -> &[reqwest::Url] {
+    /// Returns the content filter of the user, including `skip_retweets` and `skip_replies`.
+    pub fn content_filter(&self) -> ContentFilter {
+        self.filter
+            .clone()
+            .with_exclude_retweets(self.skip_retweets)
+            .with_exclude_replies(self.skip_replies)
+    }
+}
+
+impl SourceMeta for UserMeta {
+    fn webhooks(&self) -> &[WebhookTarget] {
         &self.webhooks
     }
+
+    fn filter<'a>(
+        &self,
+        _scorer: &dyn tweet_route::Scorer,
+        tweets: &'a [model::Tweet],
+        includes: &model::ResponseIncludes,
+    ) -> Vec<&'a model::Tweet> {
+        self.content_filter().apply(tweets, includes)
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UsersConfig {
+    #[serde(default)]
+    settings: PollSettings,
     users: HashMap<String, UserMeta>,
 }
 
-impl UsersConfig {
-    pub async fn from_config(config: impl AsRef<Path>) -> Result<Self> {
-        let data = tokio::fs::read(config).await?;
-        let config = toml::from_slice::<UsersConfig>(&data)?;
-        Ok(config)
+impl EngineConfig for UsersConfig {
+    fn validate(&self) -> Result<()> {
+        settings::USER_POLL
+            .check_key("settings.poll_interval_secs", self.settings.poll_interval_secs)?;
+        for (id, meta) in &self.users {
+            if id.parse::<u64>().is_err() {
+                eyre::bail!("users.{}: user ID is not numeric", id);
+            }
+            for (idx, webhook) in meta.webhooks.iter().enumerate() {
+                webhook
+                    .validate()
+                    .map_err(|e| eyre::eyre!("users.{}.webhooks[{}]: {}", id, idx, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn webhooks(&self) -> Vec<(String, &WebhookTarget)> {
+        self.users
+            .iter()
+            .flat_map(|(id, meta)| {
+                meta.webhooks.iter().enumerate().map(move |(idx, webhook)| {
+                    (format!("users.{}.webhooks[{}]", id, idx), webhook)
+                })
+            })
+            .collect()
     }
 }
 
 impl UsersConfig {
+    pub fn settings(&self) -> &PollSettings {
+        &self.settings
+    }
+
     pub fn users(&self) -> impl Iterator<Item = (&String, &UserMeta)> {
         self.users.iter()
     }
 }
 
-async fn send_first_time_webhook(
-    client: &reqwest::Client,
-    webhook_url: &reqwest::Url,
-    user_id: &str,
-) -> Result<()> {
-    let message = format!("User `{}` initialized", user_id);
-    let payload = serde_json::json!({
-        "username": "tweet-broadcast",
-        "content": message,
-    });
-
-    tweet_discord::execute_webhook(client, webhook_url, &payload).await?;
-    Ok(())
-}
+impl TweetSource for UserTimelineHead {
+    type Meta = UserMeta;
 
-async fn send_catchup_webhook(
-    client: &reqwest::Client,
-    webhook_url: &reqwest::Url,
-    user_id: &str,
-    tweet_count: usize,
-) -> Result<()> {
-    let message = format!(
-        "Skipping {} tweet{} of user `{}` during user timeline catch-up",
-        tweet_count,
-        if tweet_count == 1 { "" } else { "s" },
-        user_id,
-    );
-    let payload = serde_json::json!({
-        "username": "tweet-broadcast",
-        "content": message,
-    });
-
-    tweet_discord::execute_webhook(client, webhook_url, &payload).await?;
-    Ok(())
+    const ENGINE: Engine = Engine::User;
+    const KIND: &'static str = "user";
+    const NAME: &'static str = "user timeline";
+    const DELIVERY_INTERVAL: Option<std::time::Duration> = Some(std::time::Duration::from_secs(1));
+
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn span(id: &str) -> Span {
+        model::span!("user_poll", engine = "user", user_id = %id)
+    }
+
+    fn is_new(&self) -> bool {
+        self.head().is_none()
+    }
+
+    fn poll<'a>(
+        &'a mut self,
+        client: &'a TwitterClient,
+        catchup: bool,
+    ) -> BoxFuture<'a, Result<PollResult, tweet_fetch::Error>> {
+        Box::pin(async move {
+            let model::ResponseItem { data, includes, .. } =
+                self.load_and_update(client, catchup).await?;
+            Ok(model::ResponseItem {
+                data,
+                includes,
+                meta: None,
+            })
+        })
+    }
 }
 
-pub async fn run_list_once<Cache: LoadCache<UserTimelineHead> + StoreCache<UserTimelineHead>>(
+pub async fn run_timelines_once<Cache: LoadCache<UserTimelineHead> + StoreCache<UserTimelineHead> + StoreCache<model::Tweet> + LoadCache<DeliveredMarker> + StoreCache<DeliveredMarker> + SaveImages>(
     client: &TwitterClient,
+    sinks: &SinkFactory,
     config: &UsersConfig,
     catchup: bool,
+    interval: std::time::Duration,
     cache: &Cache,
-) {
-    use futures_util::{StreamExt, TryStreamExt};
-
-    let webhook_client = reqwest::Client::builder().build().unwrap();
-
-    let stream = futures_util::stream::FuturesUnordered::new();
-    for (id, meta) in config.users() {
-        let webhook_client = &webhook_client;
-        let fut = async move {
-            let ret = async {
-                let mut head = cache.load(id).await?;
-                let first_time = head.head().is_none();
-                let tweets = head.load_and_update(client, catchup).await?;
-                cache.store(&head).await?;
-                Ok::<_, eyre::Error>((tweets, first_time))
-            }
-            .await;
-            let (tweets, first_time) = match ret {
-                Ok(tweets) => tweets,
-                Err(e) => {
-                    log::error!("User timeline fetch for {} failed: {}", id, e);
-                    let mut event = sentry::event_from_error(AsRef::<dyn std::error::Error + 'static>::as_ref(&e));
-                    event.tags.insert(String::from("id"), id.into());
-                    sentry::capture_event(event);
-                    return;
-                }
-            };
-            let model::ResponseItem {
-                data: tweets,
-                includes,
-                ..
-            } = &tweets;
-
-            let webhooks_fut = futures_util::stream::FuturesUnordered::new();
-            for webhook in meta.webhooks() {
-                let webhook_client = &webhook_client;
-                webhooks_fut.push(async move {
-                    if catchup && tweets.len() > 5 {
-                        send_catchup_webhook(
-                            webhook_client,
-                            webhook,
-                            id,
-                            tweets.len(),
-                        )
-                        .await?;
-                    } else if first_time {
-                        send_first_time_webhook(webhook_client, webhook, id).await?;
-                    } else {
-                        for tweet in tweets {
-                            tweet_discord::send_webhook(
-                                webhook_client,
-                                webhook,
-                                tweet,
-                                includes,
-                            )
-                            .await?;
-                            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                        }
-                    }
-                    Ok::<_, eyre::Error>(())
-                });
-            }
-
-            let ret = webhooks_fut.try_collect::<()>().await;
-            if let Err(e) = ret {
-                log::error!("Failed to send webhook for {}: {}", id, e);
-                let mut event = sentry::event_from_error(AsRef::<dyn std::error::Error + 'static>::as_ref(&e));
-                event.tags.insert(String::from("id"), id.into());
-                sentry::capture_event(event);
-                return;
-            }
-
-            log::debug!("User timeline fetch for {} successful", id);
-        };
-        stream.push(fut);
-    }
-    stream.collect::<()>().await;
+) -> Result<()> {
+    poll::run_source_once::<UserTimelineHead, _>(
+        client,
+        sinks,
+        None,
+        config.users(),
+        catchup,
+        interval,
+        cache,
+    )
+    .await
 }
@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+
+use crate::admin::AdminConfig;
+use crate::list::ListsConfig;
+use crate::reload::{self, ConfigDir, EngineConfig};
+use crate::search::SearchConfig;
+use crate::settings::ScoreConfig;
+use crate::stream::StreamConfig;
+use crate::summary::SummaryConfig;
+use crate::user::UsersConfig;
+use crate::Engine;
+
+/// Checks the configs of `engines` and the route scripts, or the tag routes if there are no route
+/// scripts, printing the result of each check.
+/// Returns whether everything is valid.
+///
+/// With no engines given, every config present in `config_dir` is checked and missing ones are
+/// skipped. With `probe`, every webhook of the configs is fetched to check that it exists.
+pub async fn validate_config(
+    config_dir: &ConfigDir,
+    engines: &[Engine],
+    route_scripts: &[PathBuf],
+    probe: bool,
+) -> bool {
+    let required = !engines.is_empty();
+    let enabled = |engine| !required || engines.contains(&engine);
+    let client = reqwest::Client::new();
+    let probe = probe.then_some(&client);
+
+    let mut ok = true;
+    if enabled(Engine::Search) {
+        let path = config_dir.path("searches/config.toml");
+        ok &= check_config::<SearchConfig>(&path, required, probe).await;
+    }
+    if enabled(Engine::List) {
+        let path = config_dir.path("lists/config.toml");
+        ok &= check_config::<ListsConfig>(&path, required, probe).await;
+    }
+    if enabled(Engine::User) {
+        let path = config_dir.path("users/config.toml");
+        ok &= check_config::<UsersConfig>(&path, required, probe).await;
+    }
+    if enabled(Engine::FilteredStream) {
+        // without route scripts the stream is routed by rule tag, with a required config
+        let path = config_dir.path("stream/config.toml");
+        if route_scripts.iter().any(|path| path.exists()) {
+            ok &= check_route_scripts(route_scripts, required).await;
+            ok &= check_config::<StreamConfig>(&path, false, probe).await;
+        } else {
+            ok &= check_config::<StreamConfig>(&path, required, probe).await;
+        }
+    }
+    // the daily summary, admin notices and score weights are optional regardless of the engines
+    let path = config_dir.path("summary/config.toml");
+    ok &= check_config::<SummaryConfig>(&path, false, probe).await;
+    ok &= check_config::<AdminConfig>(&config_dir.path("admin/config.toml"), false, probe).await;
+    ok &= check_config::<ScoreConfig>(&config_dir.path("score/config.toml"), false, probe).await;
+    ok
+}
+
+async fn check_config<T: EngineConfig>(
+    path: &Path,
+    required: bool,
+    probe: Option<&reqwest::Client>,
+) -> bool {
+    if !required && !path.exists() {
+        println!("skip: {} (not found)", path.display());
+        return true;
+    }
+    let config = match reload::read_config::<T>(path).await {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return false;
+        }
+    };
+    let mut ok = true;
+    if let Some(client) = probe {
+        for (key, webhook) in config.webhooks() {
+            if let Err(e) = tweet_discord::probe_webhook(client, webhook.url()).await {
+                eprintln!("error: {}: {}: {}", path.display(), key, e);
+                ok = false;
+            }
+        }
+    }
+    if ok {
+        println!("ok: {}", path.display());
+    }
+    ok
+}
+
+async fn check_route_scripts(paths: &[PathBuf], required: bool) -> bool {
+    if !required && !paths.iter().any(|path| path.exists()) {
+        println!("skip: route scripts (not found)");
+        return true;
+    }
+    let scorer = tweet_route::ScoreParams::default().into_scorer();
+    let mut router = match crate::load_router(paths, scorer).await {
+        Ok(router) => router,
+        Err(e) => {
+            eprintln!("error: route scripts: {}", e);
+            return false;
+        }
+    };
+
+    let sample = tweet_route::SamplePayload::new();
+    match router.validate(&sample.payload()) {
+        Ok(report) if report.is_ok() => {
+            println!(
+                "ok: route scripts, {} route(s) for the sample payload",
+                report.route_count,
+            );
+            true
+        }
+        Ok(report) => {
+            for problem in &report.problems {
+                eprintln!("error: route script {}", problem);
+            }
+            false
+        }
+        Err(e) => {
+            eprintln!("error: route scripts failed on the sample payload: {}", e);
+            if let Some(js_error) = e.js_error() {
+                eprintln!("{}", js_error.format_stack());
+            }
+            false
+        }
+    }
+}
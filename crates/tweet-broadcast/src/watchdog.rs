@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use eyre::Result;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::admin::{AdminConfig, AdminNotifier};
+use crate::status::{EngineActivity, StatusRegistry};
+use crate::Engine;
+
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// Longest interval between repeated warnings of an engine which stays silent.
+const MAX_REPEAT_HOURS: i64 = 24;
+
+/// Watchdog thresholds, `[watchdog]` of the admin config.
+///
+/// ```toml
+/// [watchdog.silence_secs]
+/// filtered_stream = 7200
+/// list = 43200
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Seconds an engine may go without relaying a tweet, by engine name.
+    #[serde(default)]
+    silence_secs: BTreeMap<String, u64>,
+}
+
+impl WatchdogConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (engine, &secs) in &self.silence_secs {
+            engine
+                .parse::<Engine>()
+                .map_err(|_| eyre::eyre!("silence_secs.{}: unknown engine", engine))?;
+            if secs == 0 {
+                eyre::bail!("silence_secs.{}: must be positive", engine);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how long `engine` may go without relaying a tweet before it's reported.
+    pub fn silence_threshold(&self, engine: &str) -> Duration {
+        if let Some(&secs) = self.silence_secs.get(engine) {
+            return Duration::seconds(secs as i64);
+        }
+        match engine.parse::<Engine>() {
+            Ok(Engine::FilteredStream) => Duration::hours(2),
+            Ok(Engine::Search) => Duration::hours(6),
+            Ok(Engine::List) => Duration::hours(12),
+            Ok(Engine::User) | Err(_) => Duration::hours(24),
+        }
+    }
+}
+
+/// Warning state of an engine.
+#[derive(Debug, Default)]
+struct SilenceState {
+    /// Start of the silence warned about, if any.
+    warned_since: Option<DateTime<Utc>>,
+    next_warning_at: Option<DateTime<Utc>>,
+    repeat: Option<Duration>,
+}
+
+#[derive(Debug)]
+enum Alert {
+    Silent {
+        since: DateTime<Utc>,
+        last_received_at: Option<DateTime<Utc>>,
+    },
+    Resumed {
+        silent_since: DateTime<Utc>,
+    },
+}
+
+impl SilenceState {
+    /// Checks `activity` at `now`, returning the alert to send if any.
+    ///
+    /// Warnings of a silent engine repeat at doubling intervals, starting from the threshold,
+    /// until it relays a tweet again.
+    fn check(
+        &mut self,
+        activity: &EngineActivity,
+        started_at: DateTime<Utc>,
+        threshold: Duration,
+        now: DateTime<Utc>,
+    ) -> Option<Alert> {
+        let since = activity.last_relayed_at.unwrap_or(started_at);
+        if now - since < threshold {
+            let silent_since = self.warned_since.take()?;
+            self.next_warning_at = None;
+            self.repeat = None;
+            return Some(Alert::Resumed { silent_since });
+        }
+
+        if matches!(self.next_warning_at, Some(next) if now < next) {
+            return None;
+        }
+        let repeat = match self.repeat {
+            Some(repeat) => std::cmp::min(repeat * 2, Duration::hours(MAX_REPEAT_HOURS)),
+            None => threshold,
+        };
+        self.repeat = Some(repeat);
+        self.next_warning_at = Some(now + repeat);
+        self.warned_since = Some(since);
+        Some(Alert::Silent {
+            since,
+            last_received_at: activity.last_received_at,
+        })
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.num_seconds();
+    if secs % 3600 == 0 {
+        format!("{}h", secs / 3600)
+    } else if secs % 60 == 0 {
+        format!("{}m", secs / 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Warns in the log, Sentry and the admin webhooks when an engine of `engines` relays no tweets
+/// for longer than its threshold, until `shutdown` is cancelled.
+pub async fn run(
+    engines: Vec<String>,
+    status: Arc<StatusRegistry>,
+    admin: AdminNotifier,
+    config_rx: Option<watch::Receiver<Arc<AdminConfig>>>,
+    shutdown: CancellationToken,
+) {
+    let default_config = WatchdogConfig::default();
+    let mut states = engines
+        .into_iter()
+        .map(|engine| (engine, SilenceState::default()))
+        .collect::<BTreeMap<_, _>>();
+    let mut timer = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            _ = timer.tick() => {},
+        }
+
+        let now = Utc::now();
+        for (engine, state) in &mut states {
+            let threshold = match &config_rx {
+                Some(config_rx) => config_rx.borrow().watchdog().silence_threshold(engine),
+                None => default_config.silence_threshold(engine),
+            };
+            let activity = status.activity(engine);
+            let message = match state.check(&activity, status.started_at(), threshold, now) {
+                None => continue,
+                Some(Alert::Silent {
+                    since,
+                    last_received_at,
+                }) => {
+                    let received = match last_received_at {
+                        Some(at) => format!("last tweet received <t:{}:R>", at.timestamp()),
+                        None => String::from("no tweets received either"),
+                    };
+                    let message = format!(
+                        "Engine {} has relayed no tweets since <t:{}:f>, over {}; {}",
+                        engine,
+                        since.timestamp(),
+                        format_duration(threshold),
+                        received,
+                    );
+                    log::warn!("{}", message);
+                    sentry::with_scope(
+                        |scope| scope.set_tag("engine", engine),
+                        || sentry::capture_message(&message, sentry::Level::Warning),
+                    );
+                    message
+                }
+                Some(Alert::Resumed { silent_since }) => {
+                    let message = format!(
+                        "Engine {} is relaying tweets again, after silence since <t:{}:f>",
+                        engine,
+                        silent_since.timestamp(),
+                    );
+                    log::info!("{}", message);
+                    message
+                }
+            };
+            admin.notify(&message).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn start() -> DateTime<Utc> {
+        Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)
+    }
+
+    fn relayed_at(at: DateTime<Utc>) -> EngineActivity {
+        EngineActivity {
+            last_relayed_at: Some(at),
+            ..Default::default()
+        }
+    }
+
+    /// Checks `state` every minute from `from` until `until`, returning when it warned.
+    fn warnings(
+        state: &mut SilenceState,
+        activity: &EngineActivity,
+        threshold: Duration,
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Vec<i64> {
+        let mut now = from;
+        let mut warned_at = Vec::new();
+        while now <= until {
+            match state.check(activity, start(), threshold, now) {
+                Some(Alert::Silent { since, .. }) => {
+                    assert_eq!(since, activity.last_relayed_at.unwrap_or_else(start));
+                    warned_at.push((now - start()).num_hours());
+                }
+                Some(alert) => panic!("unexpected {:?} at {}", alert, now),
+                None => {}
+            }
+            now = now + Duration::minutes(1);
+        }
+        warned_at
+    }
+
+    #[test]
+    fn silence_warnings_back_off() {
+        let threshold = Duration::hours(2);
+        let mut state = SilenceState::default();
+        let activity = EngineActivity::default();
+        // silent since startup, warned on crossing the threshold, then at doubling intervals
+        // capped at a day
+        let until = start() + Duration::hours(100);
+        let warned_at = warnings(&mut state, &activity, threshold, start(), until);
+        assert_eq!(warned_at, [2, 4, 8, 16, 32, 56, 80]);
+    }
+
+    #[test]
+    fn activity_resets_silence() {
+        let threshold = Duration::hours(2);
+        let mut state = SilenceState::default();
+        let activity = relayed_at(start());
+        let until = start() + Duration::hours(5);
+        let warned_at = warnings(&mut state, &activity, threshold, start(), until);
+        assert_eq!(warned_at, [2, 4]);
+
+        let resumed_at = start() + Duration::hours(5) + Duration::minutes(30);
+        let activity = relayed_at(resumed_at);
+        let mut check = || state.check(&activity, start(), threshold, resumed_at);
+        match check() {
+            Some(Alert::Resumed { silent_since }) => assert_eq!(silent_since, start()),
+            alert => panic!("expected a resume, got {:?}", alert),
+        }
+        assert!(check().is_none());
+
+        // the next silence is warned from the threshold again, not the backed off interval
+        let until = resumed_at + Duration::hours(5);
+        let warned_at = warnings(&mut state, &activity, threshold, resumed_at, until);
+        assert_eq!(warned_at, [7, 9]);
+    }
+}
@@ -0,0 +1,272 @@
+use serde::{Deserialize, Serialize};
+
+use crate::active_hours::ActiveHours;
+use crate::batch::ThreadGrouping;
+
+/// Webhook destination in engine configs.
+///
+/// Accepts either a bare URL string or a table:
+///
+/// ```toml
+/// webhooks = [
+///   "https://discord.com/api/webhooks/...",
+///   { url = "https://discord.com/api/webhooks/...", thread_id = "123" },
+///   { url = "https://discord.com/api/webhooks/...", sensitive_media = "spoiler" },
+///   { url = "https://discord.com/api/webhooks/...", color = 0xff8800, show_score = true },
+///   { url = "https://discord.com/api/webhooks/...", attach_media = true },
+///   { url = "https://discord.com/api/webhooks/...", min_interval_ms = 2000 },
+///   { url = "https://discord.com/api/webhooks/...", batch_threads = true },
+///   { url = "https://discord.com/api/webhooks/...", identity = { fixed = { username = "Art feed" } } },
+/// ]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "WebhookTargetRepr")]
+pub struct WebhookTarget {
+    url: reqwest::Url,
+    #[serde(flatten)]
+    options: WebhookOptions,
+}
+
+/// Per-webhook delivery options, given in the table form of `WebhookTarget`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookOptions {
+    thread_id: Option<String>,
+    content_overflow: tweet_discord::limits::ContentOverflow,
+    /// Truncates embed fields over Discord's limits instead of failing.
+    truncate_fields: bool,
+    sensitive_media: tweet_discord::SensitiveMediaPolicy,
+    /// Name and avatar to post under, the tweet author by default.
+    identity: tweet_discord::WebhookIdentity,
+    /// Mention types to resolve, e.g. `["users"]`. Nothing is resolved by default.
+    allowed_mentions: Option<Vec<tweet_discord::MentionType>>,
+    /// Embed color, as a 24-bit RGB integer.
+    color: Option<u32>,
+    footer_text: Option<String>,
+    footer_icon_url: Option<String>,
+    /// Shows the score in the footer, for engines which compute one.
+    show_score: bool,
+    /// Adds "Open tweet" and "Open profile" buttons instead of the tweet URL in the content.
+    buttons: bool,
+    /// Adds buttons linking to the original media.
+    media_buttons: bool,
+    /// Uploads media as attachments, so messages survive deletion of the tweet.
+    attach_media: bool,
+    /// Size cap of attached media. Larger media are linked instead.
+    max_attachment_bytes: Option<u64>,
+    /// Deletes relayed messages when the tweet is deleted, for engines which track them.
+    delete_on_tweet_deletion: bool,
+    /// Queues deliveries outside these hours until they start.
+    active_hours: Option<ActiveHours>,
+    /// Minimum milliseconds between messages sent to the webhook, shared by every engine and
+    /// target with the same URL. Only Discord's rate limits apply by default.
+    min_interval_ms: Option<u64>,
+    /// Sends consecutive tweets of the same author in one poll as a single message, for the
+    /// list and user timeline engines.
+    batch_threads: bool,
+    /// Which consecutive tweets `batch_threads` sends together.
+    batch_threads_by: ThreadGrouping,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WebhookTargetRepr {
+    Url(reqwest::Url),
+    Table {
+        url: reqwest::Url,
+        #[serde(flatten)]
+        options: Box<WebhookOptions>,
+    },
+}
+
+impl From<WebhookTargetRepr> for WebhookTarget {
+    fn from(repr: WebhookTargetRepr) -> Self {
+        match repr {
+            WebhookTargetRepr::Url(url) => Self {
+                url,
+                options: Default::default(),
+            },
+            WebhookTargetRepr::Table { url, options } => Self {
+                url,
+                options: *options,
+            },
+        }
+    }
+}
+
+impl WebhookTarget {
+    pub fn new(url: reqwest::Url) -> Self {
+        Self {
+            url,
+            options: Default::default(),
+        }
+    }
+
+    pub fn with_thread_id(mut self, thread_id: Option<String>) -> Self {
+        self.options.thread_id = thread_id;
+        self
+    }
+
+    pub fn url(&self) -> &reqwest::Url {
+        &self.url
+    }
+
+    /// Checks that the URL is a Discord webhook URL,
+    /// `https://discord.com/api/webhooks/{id}/{token}`.
+    pub fn validate(&self) -> eyre::Result<()> {
+        let redacted = tweet_discord::redact_url(&self.url);
+        tweet_discord::validate_webhook_url(&self.url)
+            .map_err(|e| eyre::eyre!("{}: {}", redacted, e))?;
+        if let Some(thread_id) = &self.options.thread_id {
+            if thread_id.parse::<u64>().is_err() {
+                eyre::bail!("{}: thread ID {:?} is not numeric", redacted, thread_id);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn render_options(&self, score: Option<f64>) -> tweet_discord::RenderOptions {
+        tweet_discord::RenderOptions {
+            color: self.options.color,
+            sensitive_media: self.options.sensitive_media,
+            identity: self.options.identity.clone(),
+            footer_text: self.options.footer_text.clone(),
+            footer_icon_url: self.options.footer_icon_url.clone(),
+            score: score.filter(|_| self.options.show_score),
+            link_buttons: self.options.buttons,
+            media_buttons: self.options.media_buttons,
+            attach_media: self.options.attach_media,
+            max_attachment_bytes: self.options.max_attachment_bytes,
+            ..Default::default()
+        }
+    }
+
+    pub fn identity(&self) -> &tweet_discord::WebhookIdentity {
+        &self.options.identity
+    }
+
+    pub fn active_hours(&self) -> Option<&ActiveHours> {
+        self.options.active_hours.as_ref()
+    }
+
+    pub fn thread_id(&self) -> Option<&str> {
+        self.options.thread_id.as_deref()
+    }
+
+    /// Grouping of tweets sent as a single message, if `batch_threads` is set.
+    pub fn thread_grouping(&self) -> Option<ThreadGrouping> {
+        Some(self.options.batch_threads_by).filter(|_| self.options.batch_threads)
+    }
+
+    pub fn delete_on_tweet_deletion(&self) -> bool {
+        self.options.delete_on_tweet_deletion
+    }
+
+    pub fn execute_options(&self) -> tweet_discord::ExecuteOptions {
+        tweet_discord::ExecuteOptions {
+            thread_id: self.options.thread_id.clone(),
+            content_overflow: self.options.content_overflow,
+            truncate_fields: self.options.truncate_fields,
+            allowed_mentions: self
+                .options
+                .allowed_mentions
+                .clone()
+                .map(|parse| tweet_discord::AllowedMentions { parse }),
+            min_interval: self.options.min_interval_ms.map(std::time::Duration::from_millis),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tweet_discord::{SensitiveMediaPolicy, WebhookIdentity};
+
+    use super::*;
+
+    const URL: &str = "https://discord.com/api/webhooks/1/token";
+
+    #[derive(Debug, Deserialize)]
+    struct Config {
+        webhooks: Vec<WebhookTarget>,
+    }
+
+    fn parse(toml: &str) -> Result<Vec<WebhookTarget>, toml::de::Error> {
+        toml::from_str::<Config>(toml).map(|config| config.webhooks)
+    }
+
+    #[test]
+    fn bare_url_has_default_options() {
+        let webhooks = parse(&format!("webhooks = [{:?}]", URL)).unwrap();
+        let webhook = &webhooks[0];
+        assert_eq!(webhook.url().as_str(), URL);
+        assert_eq!(webhook.thread_id(), None);
+        assert_eq!(webhook.thread_grouping(), None);
+        assert_eq!(webhook.identity(), &WebhookIdentity::Author);
+        assert!(webhook.active_hours().is_none());
+        assert!(!webhook.delete_on_tweet_deletion());
+        let render = webhook.render_options(Some(1.0));
+        assert_eq!(render.sensitive_media, SensitiveMediaPolicy::Drop);
+        assert_eq!(render.score, None);
+        let execute = webhook.execute_options();
+        assert!(execute.allowed_mentions.is_none());
+        assert_eq!(execute.min_interval, None);
+    }
+
+    #[test]
+    fn table_sets_options() {
+        let toml = format!(
+            r#"webhooks = [
+                {{ url = {url:?}, thread_id = "123", sensitive_media = "spoiler" }},
+                {{ url = {url:?}, color = 0xff8800, show_score = true, min_interval_ms = 2000 }},
+                {{ url = {url:?}, batch_threads = true, batch_threads_by = "conversation" }},
+                {{ url = {url:?}, identity = {{ fixed = {{ username = "Art feed" }} }} }},
+            ]"#,
+            url = URL,
+        );
+        let webhooks = parse(&toml).unwrap();
+        assert!(webhooks.iter().all(|webhook| webhook.url().as_str() == URL));
+
+        assert_eq!(webhooks[0].thread_id(), Some("123"));
+        let execute = webhooks[0].execute_options();
+        assert_eq!(execute.thread_id.as_deref(), Some("123"));
+        let render = webhooks[0].render_options(None);
+        assert_eq!(render.sensitive_media, SensitiveMediaPolicy::Spoiler);
+
+        let render = webhooks[1].render_options(Some(1.0));
+        assert_eq!(render.color, Some(0xff8800));
+        assert_eq!(render.score, Some(1.0));
+        let min_interval = webhooks[1].execute_options().min_interval;
+        assert_eq!(min_interval, Some(std::time::Duration::from_secs(2)));
+
+        let grouping = webhooks[2].thread_grouping();
+        assert_eq!(grouping, Some(ThreadGrouping::Conversation));
+
+        let identity = WebhookIdentity::Fixed {
+            username: String::from("Art feed"),
+            avatar_url: None,
+        };
+        assert_eq!(webhooks[3].identity(), &identity);
+    }
+
+    #[test]
+    fn invalid_targets_are_rejected() {
+        assert!(parse(r#"webhooks = ["not a url"]"#).is_err());
+        assert!(parse(r#"webhooks = [{ thread_id = "123" }]"#).is_err());
+        assert!(parse(r#"webhooks = [{ url = "not a url" }]"#).is_err());
+        let toml = format!(r#"webhooks = [{{ url = {:?}, color = "red" }}]"#, URL);
+        assert!(parse(&toml).is_err());
+    }
+
+    #[test]
+    fn table_round_trips() {
+        let toml = format!(
+            r#"webhooks = [{{ url = {:?}, thread_id = "123", batch_threads = true }}]"#,
+            URL,
+        );
+        let webhook = &parse(&toml).unwrap()[0];
+        let json = serde_json::to_string(webhook).unwrap();
+        let webhook = serde_json::from_str::<WebhookTarget>(&json).unwrap();
+        assert_eq!(webhook.thread_id(), Some("123"));
+        assert_eq!(webhook.thread_grouping(), Some(ThreadGrouping::Author));
+    }
+}
@@ -0,0 +1,453 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::limits::truncate_text;
+
+pub const MAX_TITLE_CHARS: usize = 256;
+pub const MAX_DESCRIPTION_CHARS: usize = 4096;
+pub const MAX_AUTHOR_NAME_CHARS: usize = 256;
+pub const MAX_FOOTER_CHARS: usize = 2048;
+pub const MAX_FIELD_NAME_CHARS: usize = 256;
+pub const MAX_FIELD_VALUE_CHARS: usize = 1024;
+pub const MAX_FIELDS: usize = 25;
+pub const MAX_USERNAME_CHARS: usize = 80;
+pub const MAX_BUTTON_LABEL_CHARS: usize = 80;
+pub const MAX_BUTTONS_PER_ROW: usize = 5;
+pub const MAX_ACTION_ROWS: usize = 5;
+
+/// Truncates `value` to `max_chars`, warning if it was too long.
+fn limit(field: &str, value: String, max_chars: usize) -> String {
+    let len = value.chars().count();
+    if len <= max_chars {
+        return value;
+    }
+    log::warn!(
+        "Embed {} has {} characters, truncating to {}",
+        field,
+        len,
+        max_chars,
+    );
+    truncate_text(&value, max_chars)
+}
+
+/// Message sent through a webhook.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WebhookPayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    embeds: Vec<Embed>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allowed_mentions: Option<AllowedMentions>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    components: Vec<Component>,
+}
+
+/// Mention types Discord resolves in message content.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, Serialize)]
+pub struct AllowedMentions {
+    pub parse: Vec<MentionType>,
+}
+
+impl AllowedMentions {
+    /// Allows no mentions at all.
+    pub fn none() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MentionType {
+    Roles,
+    Users,
+    Everyone,
+}
+
+impl WebhookPayload {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets message content. Content over the limit is handled on execution, according to
+    /// `ExecuteOptions::content_overflow`.
+    pub fn content(mut self, content: impl Into<String>) -> Self {
+        self.content = Some(content.into());
+        self
+    }
+
+    pub fn username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(limit("username", username.into(), MAX_USERNAME_CHARS));
+        self
+    }
+
+    pub fn avatar_url(mut self, url: impl Into<String>) -> Self {
+        self.avatar_url = Some(url.into());
+        self
+    }
+
+    /// Appends an embed. Embeds over the per-message limits are split on execution.
+    pub fn embed(mut self, embed: Embed) -> Self {
+        self.embeds.push(embed);
+        self
+    }
+
+    pub fn allowed_mentions(mut self, allowed_mentions: AllowedMentions) -> Self {
+        self.allowed_mentions = Some(allowed_mentions);
+        self
+    }
+
+    /// Appends a button, starting a new action row if the last one is full. Buttons over
+    /// `MAX_ACTION_ROWS` rows are dropped.
+    pub fn button(mut self, button: Button) -> Self {
+        let last_row = match self.components.last_mut() {
+            Some(Component::ActionRow { components }) if components.len() < MAX_BUTTONS_PER_ROW => {
+                Some(components)
+            }
+            _ => None,
+        };
+        if let Some(row) = last_row {
+            row.push(Component::Button(button));
+        } else if self.components.len() < MAX_ACTION_ROWS {
+            self.components.push(Component::ActionRow {
+                components: vec![Component::Button(button)],
+            });
+        } else {
+            log::warn!(
+                "Message already has {} action rows, dropping button",
+                MAX_ACTION_ROWS
+            );
+        }
+        self
+    }
+
+    /// Appends the content, embeds and buttons of `other`, keeping the username, avatar and
+    /// allowed mentions of `self`. Contents are joined by a line break.
+    pub fn append(mut self, other: Self) -> Self {
+        self.content = match (self.content.take(), other.content) {
+            (Some(content), Some(other)) => Some(format!("{}\n{}", content, other)),
+            (content, other) => content.or(other),
+        };
+        self.embeds.extend(other.embeds);
+        for component in other.components {
+            let buttons = match component {
+                Component::ActionRow { components } => components,
+                button => vec![button],
+            };
+            for button in buttons {
+                if let Component::Button(button) = button {
+                    self = self.button(button);
+                }
+            }
+        }
+        self
+    }
+
+    pub fn content_text(&self) -> Option<&str> {
+        self.content.as_deref()
+    }
+
+    pub fn embeds(&self) -> &[Embed] {
+        &self.embeds
+    }
+
+    /// Characters of the embeds, counted towards `MAX_EMBED_CHARS_PER_MESSAGE`.
+    pub fn embed_chars(&self) -> usize {
+        self.embeds
+            .iter()
+            .map(|embed| crate::limits::embed_char_count(&serde_json::to_value(embed).unwrap()))
+            .sum()
+    }
+
+    pub fn button_count(&self) -> usize {
+        self.components
+            .iter()
+            .map(|component| match component {
+                Component::ActionRow { components } => components.len(),
+                Component::Button(_) => 1,
+            })
+            .sum()
+    }
+
+    /// Checks the payload against Discord's limits, as if sent as a single message.
+    pub fn validate(&self) -> Vec<crate::limits::LimitViolation> {
+        crate::limits::validate_payload(&self.to_value())
+    }
+
+    pub fn to_value(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Embed {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<EmbedAuthor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    footer: Option<EmbedFooter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<EmbedImage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    fields: Vec<EmbedField>,
+}
+
+impl Embed {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(limit("title", title.into(), MAX_TITLE_CHARS));
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(limit(
+            "description",
+            description.into(),
+            MAX_DESCRIPTION_CHARS,
+        ));
+        self
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: Option<DateTime<Utc>>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn color(mut self, color: u32) -> Self {
+        self.color = Some(color);
+        self
+    }
+
+    pub fn author(mut self, author: EmbedAuthor) -> Self {
+        self.author = Some(author);
+        self
+    }
+
+    pub fn footer(mut self, footer: EmbedFooter) -> Self {
+        self.footer = Some(footer);
+        self
+    }
+
+    pub fn image(mut self, image: EmbedImage) -> Self {
+        self.image = Some(image);
+        self
+    }
+
+    /// Appends a field, dropping it if the embed already has `MAX_FIELDS` fields.
+    pub fn field(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+        inline: bool,
+    ) -> Self {
+        if self.fields.len() >= MAX_FIELDS {
+            log::warn!("Embed already has {} fields, dropping field", MAX_FIELDS);
+            return self;
+        }
+        self.fields.push(EmbedField {
+            name: limit("field name", name.into(), MAX_FIELD_NAME_CHARS),
+            value: limit("field value", value.into(), MAX_FIELD_VALUE_CHARS),
+            inline,
+        });
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbedAuthor {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon_url: Option<String>,
+}
+
+impl EmbedAuthor {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: limit("author name", name.into(), MAX_AUTHOR_NAME_CHARS),
+            url: None,
+            icon_url: None,
+        }
+    }
+
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    pub fn icon_url(mut self, url: impl Into<String>) -> Self {
+        self.icon_url = Some(url.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbedFooter {
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon_url: Option<String>,
+}
+
+impl EmbedFooter {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: limit("footer", text.into(), MAX_FOOTER_CHARS),
+            icon_url: None,
+        }
+    }
+
+    pub fn icon_url(mut self, url: impl Into<String>) -> Self {
+        self.icon_url = Some(url.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbedImage {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u64>,
+}
+
+impl EmbedImage {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            width: None,
+            height: None,
+        }
+    }
+
+    pub fn size(mut self, width: u64, height: u64) -> Self {
+        self.width = Some(width);
+        self.height = Some(height);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmbedField {
+    name: String,
+    value: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    inline: bool,
+}
+
+/// Message component. Only link buttons are supported, as webhooks can't receive interactions.
+#[derive(Debug, Clone, Serialize)]
+#[serde(into = "serde_json::Value")]
+pub enum Component {
+    ActionRow { components: Vec<Component> },
+    Button(Button),
+}
+
+impl From<Component> for serde_json::Value {
+    fn from(component: Component) -> Self {
+        match component {
+            Component::ActionRow { components } => serde_json::json!({
+                "type": 1,
+                "components": components,
+            }),
+            Component::Button(button) => serde_json::json!({
+                "type": 2,
+                "style": 5,
+                "label": button.label,
+                "url": button.url,
+            }),
+        }
+    }
+}
+
+/// Link-style button.
+#[derive(Debug, Clone)]
+pub struct Button {
+    label: String,
+    url: String,
+}
+
+impl Button {
+    pub fn link(label: impl Into<String>, url: impl Into<String>) -> Self {
+        Self {
+            label: limit("button label", label.into(), MAX_BUTTON_LABEL_CHARS),
+            url: url.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row_sizes(payload: &WebhookPayload) -> Vec<usize> {
+        payload
+            .components
+            .iter()
+            .map(|row| match row {
+                Component::ActionRow { components } => components.len(),
+                Component::Button(_) => panic!("button outside of an action row"),
+            })
+            .collect()
+    }
+
+    fn with_buttons(payload: WebhookPayload, count: usize) -> WebhookPayload {
+        (0..count).fold(payload, |payload, idx| {
+            let label = format!("Button {}", idx);
+            payload.button(Button::link(label, "https://example.com/"))
+        })
+    }
+
+    #[test]
+    fn buttons_fill_rows() {
+        let payload = with_buttons(WebhookPayload::new(), 7);
+        assert_eq!(row_sizes(&payload), [5, 2]);
+        assert_eq!(payload.button_count(), 7);
+
+        let value = payload.to_value();
+        let rows = value["components"].as_array().unwrap();
+        assert!(rows.iter().all(|row| row["type"] == 1));
+        assert_eq!(rows[1]["components"][1]["label"], "Button 6");
+
+        // buttons over the row limit are dropped
+        let payload = with_buttons(WebhookPayload::new(), 27);
+        assert_eq!(row_sizes(&payload), [5; MAX_ACTION_ROWS]);
+        assert!(payload.validate().is_empty());
+    }
+
+    #[test]
+    fn appended_buttons_fill_rows() {
+        let first = with_buttons(WebhookPayload::new(), 3);
+        let second = with_buttons(WebhookPayload::new(), 4);
+        let payload = first.append(second);
+        assert_eq!(row_sizes(&payload), [5, 2]);
+    }
+
+    #[test]
+    fn button_labels_are_limited() {
+        let button = Button::link("x".repeat(100), "https://example.com/");
+        assert_eq!(button.label.chars().count(), MAX_BUTTON_LABEL_CHARS);
+    }
+}
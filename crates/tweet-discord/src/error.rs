@@ -0,0 +1,99 @@
+#[derive(Debug, thiserror::Error)]
+pub enum WebhookError {
+    #[error("webhook {0} does not exist")]
+    Gone(String),
+    #[error("not authorized to execute webhook {0}")]
+    Unauthorized(String),
+    #[error("webhook {url} failed after {attempts} attempt(s): {reason}")]
+    RetriesExhausted {
+        url: String,
+        attempts: u32,
+        reason: String,
+    },
+    #[error("payload for webhook {url} exceeds limits: {}", format_violations(.violations))]
+    InvalidPayload {
+        url: String,
+        violations: Vec<crate::limits::LimitViolation>,
+    },
+    #[error("webhook {url} rejected the request with {status}")]
+    Rejected {
+        url: String,
+        status: reqwest::StatusCode,
+    },
+    #[error("failed to render tweet: {0}")]
+    Render(#[from] DiscordRenderError),
+}
+
+/// Failure to render a tweet, because its includes lack something it can't be rendered without.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DiscordRenderError {
+    #[error("retweeted tweet {0} is not included")]
+    MissingReferencedTweet(String),
+}
+
+impl WebhookError {
+    /// Returns whether the webhook will not accept any further requests.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::Gone(_) | Self::Unauthorized(_))
+    }
+
+    /// Returns whether the request may succeed if sent again later, i.e. it failed with network
+    /// errors, 429 or 5xx on every attempt.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::RetriesExhausted { .. })
+    }
+}
+
+fn format_violations(violations: &[crate::limits::LimitViolation]) -> String {
+    violations
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Formats a webhook URL for messages, replacing the token.
+pub fn redact_url(url: &reqwest::Url) -> String {
+    let mut segments = url
+        .path_segments()
+        .map(|s| s.collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let Some(pos) = segments.iter().position(|&s| s == "webhooks") {
+        if let Some(token) = segments.get_mut(pos + 2) {
+            *token = "[redacted]";
+        }
+    }
+    format!(
+        "{}/{}",
+        url.origin().ascii_serialization(),
+        segments.join("/")
+    )
+}
+
+/// Formats a webhook URL for accounting, as the host and the last 4 characters of the token.
+pub fn redact_destination(url: &reqwest::Url) -> String {
+    let host = url.host_str().unwrap_or("?");
+    let token = url
+        .path_segments()
+        .and_then(|segments| {
+            let segments = segments.collect::<Vec<_>>();
+            let pos = segments.iter().position(|&s| s == "webhooks")?;
+            segments.get(pos + 2).map(|token| token.to_string())
+        })
+        .unwrap_or_default();
+    let chars = token.chars().collect::<Vec<_>>();
+    let tail = chars[chars.len().saturating_sub(4)..].iter().collect::<String>();
+    format!("{}/\u{2026}{}", host, tail)
+}
+
+/// Describes a request error without its URL, which contains the webhook token.
+pub(crate) fn describe_error(e: &reqwest::Error) -> String {
+    if let Some(status) = e.status() {
+        return status.to_string();
+    }
+    match std::error::Error::source(e) {
+        Some(source) => source.to_string(),
+        None if e.is_timeout() => String::from("timed out"),
+        None => String::from("request failed"),
+    }
+}
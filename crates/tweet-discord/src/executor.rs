@@ -0,0 +1,532 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use tweet_model as model;
+use model::trace::TraceExt;
+
+use crate::error::{describe_error, redact_destination, redact_url, WebhookError};
+
+/// Discord error code for 404 responses about a message, rather than the webhook itself.
+const UNKNOWN_MESSAGE_CODE: u64 = 10008;
+
+/// Default number of attempts for a single request, including the first one.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Rate limit state of a single webhook, as last reported by Discord.
+#[derive(Debug, Default)]
+struct Bucket {
+    remaining: Option<u32>,
+    reset_at: Option<Instant>,
+    /// When the last request to the webhook was sent, for pacing.
+    last_sent_at: Option<Instant>,
+}
+
+impl Bucket {
+    fn update(&mut self, headers: &reqwest::header::HeaderMap) {
+        let remaining = headers
+            .get("x-ratelimit-remaining")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u32>().ok());
+        if let Some(remaining) = remaining {
+            self.remaining = Some(remaining);
+        }
+        if let Some(reset_after) = reset_after(headers) {
+            self.reset_at = Some(Instant::now() + reset_after);
+        }
+    }
+
+    /// Returns when the next request may be sent, if it has to wait.
+    fn wait_until(&self) -> Option<Instant> {
+        match (self.remaining, self.reset_at) {
+            (Some(0), Some(reset_at)) if reset_at > Instant::now() => Some(reset_at),
+            _ => None,
+        }
+    }
+}
+
+/// Requests sent to a webhook, counted by the executor.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct DeliveryCounters {
+    /// Requests which succeeded.
+    pub sent: u64,
+    /// Requests which failed after retries, or without them.
+    pub failed: u64,
+    /// Waits for a rate limit of the webhook or a global one.
+    pub rate_limited: u64,
+    /// Body bytes of requests which succeeded.
+    pub bytes: u64,
+}
+
+impl DeliveryCounters {
+    pub fn add(&mut self, other: &Self) {
+        self.sent += other.sent;
+        self.failed += other.failed;
+        self.rate_limited += other.rate_limited;
+        self.bytes += other.bytes;
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    client: reqwest::Client,
+    buckets: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Bucket>>>>,
+    global_reset_at: Mutex<Option<Instant>>,
+    gone: Mutex<HashSet<String>>,
+    /// Counters since they were last taken, by redacted destination.
+    counters: Mutex<HashMap<String, DeliveryCounters>>,
+}
+
+/// Sends webhook requests, queueing them per webhook according to Discord's rate limit headers.
+///
+/// Cloning is cheap; clones share the same rate limit state.
+#[derive(Debug, Clone)]
+pub struct WebhookExecutor {
+    inner: Arc<Inner>,
+    max_attempts: u32,
+}
+
+impl Default for WebhookExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebhookExecutor {
+    pub fn new() -> Self {
+        Self::with_client(reqwest::Client::builder().build().unwrap())
+    }
+
+    pub fn with_client(client: reqwest::Client) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                buckets: Mutex::new(HashMap::new()),
+                global_reset_at: Mutex::new(None),
+                gone: Mutex::new(HashSet::new()),
+                counters: Mutex::new(HashMap::new()),
+            }),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    /// Sets the number of attempts for a single request, including the first one.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn client(&self) -> &reqwest::Client {
+        &self.inner.client
+    }
+
+    fn bucket(&self, url: &reqwest::Url) -> Arc<tokio::sync::Mutex<Bucket>> {
+        let mut buckets = self.inner.buckets.lock().unwrap();
+        buckets.entry(bucket_key(url)).or_default().clone()
+    }
+
+    async fn wait_global(&self) {
+        loop {
+            let reset_at = *self.inner.global_reset_at.lock().unwrap();
+            match reset_at {
+                Some(reset_at) if reset_at > Instant::now() => {
+                    log::debug!(
+                        "Globally ratelimited, waiting {:?}",
+                        reset_at - Instant::now()
+                    );
+                    tokio::time::sleep_until(reset_at).await;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Returns the counters of every webhook since they were last taken, by destination as
+    /// formatted by `redact_destination`, and starts them over.
+    pub fn take_counters(&self) -> HashMap<String, DeliveryCounters> {
+        std::mem::take(&mut *self.inner.counters.lock().unwrap())
+    }
+
+    fn count(&self, url: &reqwest::Url, f: impl FnOnce(&mut DeliveryCounters)) {
+        let mut counters = self.inner.counters.lock().unwrap();
+        f(counters.entry(redact_destination(url)).or_default());
+    }
+
+    /// Returns whether the webhook was found to be deleted or unauthorized earlier.
+    pub fn is_gone(&self, url: &reqwest::Url) -> bool {
+        self.inner.gone.lock().unwrap().contains(&bucket_key(url))
+    }
+
+    /// Sends a request built by `make_request`, waiting for the webhook's bucket.
+    ///
+    /// Requests to the same webhook are sent one at a time, in the order they were made, and at
+    /// least `min_interval` after the previous one if given. 429, 5xx and network errors are
+    /// retried up to `max_attempts` times in total; 401, 403 and 404 fail immediately.
+    pub(crate) async fn send(
+        &self,
+        url: &reqwest::Url,
+        min_interval: Option<Duration>,
+        make_request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, WebhookError> {
+        let span = model::span!("webhook_send", destination = %redact_destination(url));
+        let ret = self.send_inner(url, min_interval, make_request).in_span(span).await;
+        let name = match &ret {
+            Ok((_, bytes)) => {
+                self.count(url, |counters| {
+                    counters.sent += 1;
+                    counters.bytes += *bytes as u64;
+                });
+                model::metrics::WEBHOOKS_SENT
+            }
+            Err(_) => {
+                self.count(url, |counters| counters.failed += 1);
+                model::metrics::WEBHOOKS_FAILED
+            }
+        };
+        model::metrics::increment_counter(name, &[("host", url.host_str().unwrap_or(""))]);
+        ret.map(|(resp, _)| resp)
+    }
+
+    async fn send_inner(
+        &self,
+        url: &reqwest::Url,
+        min_interval: Option<Duration>,
+        make_request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> Result<(reqwest::Response, usize), WebhookError> {
+        if self.is_gone(url) {
+            return Err(WebhookError::Gone(redact_url(url)));
+        }
+
+        let bucket = self.bucket(url);
+        let mut bucket = bucket.lock().await;
+        if let (Some(min_interval), Some(last_sent_at)) = (min_interval, bucket.last_sent_at) {
+            let next_at = last_sent_at + min_interval;
+            if next_at > Instant::now() {
+                log::debug!("Pacing webhook, waiting {:?}", next_at - Instant::now());
+                tokio::time::sleep_until(next_at).await;
+            }
+        }
+        let mut attempts = 0;
+        loop {
+            if let Some(wait_until) = bucket.wait_until() {
+                log::debug!(
+                    "Webhook bucket exhausted, waiting {:?}",
+                    wait_until - Instant::now(),
+                );
+                self.count(url, |counters| counters.rate_limited += 1);
+                tokio::time::sleep_until(wait_until).await;
+            }
+            self.wait_global().await;
+
+            attempts += 1;
+            let give_up = attempts >= self.max_attempts;
+            let request = make_request(&self.inner.client).build();
+            let bytes = request
+                .as_ref()
+                .ok()
+                .and_then(|request| request.body())
+                .and_then(|body| body.as_bytes())
+                .map(|body| body.len())
+                .unwrap_or(0);
+            let resp = match request {
+                Ok(request) => self.inner.client.execute(request).await,
+                Err(e) => Err(e),
+            };
+            bucket.last_sent_at = Some(Instant::now());
+            let resp = match resp {
+                Ok(resp) => resp,
+                Err(e) if give_up => {
+                    return Err(WebhookError::RetriesExhausted {
+                        url: redact_url(url),
+                        attempts,
+                        reason: describe_error(&e),
+                    });
+                }
+                Err(e) => {
+                    let duration = Duration::from_secs(5);
+                    log::debug!(
+                        "Webhook request failed: {}, retrying after {:?}",
+                        describe_error(&e),
+                        duration,
+                    );
+                    record_backoff("network");
+                    tokio::time::sleep(duration).await;
+                    continue;
+                }
+            };
+            bucket.update(resp.headers());
+
+            let status = resp.status();
+            match status {
+                reqwest::StatusCode::NOT_FOUND => {
+                    let body = resp.bytes().await.unwrap_or_default();
+                    let code = serde_json::from_slice::<serde_json::Value>(&body)
+                        .ok()
+                        .and_then(|v| v.get("code").and_then(|c| c.as_u64()));
+                    if code == Some(UNKNOWN_MESSAGE_CODE) {
+                        return Err(WebhookError::Rejected {
+                            url: redact_url(url),
+                            status,
+                        });
+                    }
+                    self.inner.gone.lock().unwrap().insert(bucket_key(url));
+                    return Err(WebhookError::Gone(redact_url(url)));
+                }
+                reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                    self.inner.gone.lock().unwrap().insert(bucket_key(url));
+                    return Err(WebhookError::Unauthorized(redact_url(url)));
+                }
+                _ => {}
+            }
+            let retryable =
+                status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+            if !retryable {
+                if status.is_client_error() {
+                    return Err(WebhookError::Rejected {
+                        url: redact_url(url),
+                        status,
+                    });
+                }
+                return Ok((resp, bytes));
+            }
+            if give_up {
+                return Err(WebhookError::RetriesExhausted {
+                    url: redact_url(url),
+                    attempts,
+                    reason: status.to_string(),
+                });
+            }
+
+            let headers = resp.headers();
+            let duration = retry_delay(headers, chrono::Utc::now());
+            if status.is_server_error() {
+                log::debug!("Webhook returned {}, retrying after {:?}", status, duration);
+                record_backoff("server");
+                tokio::time::sleep(duration).await;
+            } else if headers.contains_key("x-ratelimit-global") {
+                log::debug!(
+                    "Webhook is globally ratelimited, retrying after {:?}",
+                    duration
+                );
+                record_backoff("ratelimit");
+                self.count(url, |counters| counters.rate_limited += 1);
+                *self.inner.global_reset_at.lock().unwrap() = Some(Instant::now() + duration);
+            } else {
+                log::debug!("Webhook is ratelimited, retrying after {:?}", duration);
+                record_backoff("ratelimit");
+                self.count(url, |counters| counters.rate_limited += 1);
+                bucket.remaining = Some(0);
+                bucket.reset_at = Some(Instant::now() + duration);
+            }
+        }
+    }
+}
+
+/// Upper bound of delays given by response headers, so that a bogus value can't stall the
+/// delivery for good.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+/// Delay before retrying if the response doesn't say how long to wait.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Parses `X-RateLimit-Reset-After`, seconds with a fraction. Invalid values are ignored.
+fn reset_after(headers: &reqwest::header::HeaderMap) -> Option<Duration> {
+    let secs = headers
+        .get("x-ratelimit-reset-after")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<f64>()
+        .ok()?;
+    if !(0.0..=MAX_RETRY_DELAY.as_secs_f64()).contains(&secs) {
+        return None;
+    }
+    Some(Duration::from_secs_f64(secs))
+}
+
+/// Parses `Retry-After`, either seconds or an HTTP date, which proxies in front of Discord may
+/// send. Invalid values are ignored.
+fn retry_after(
+    headers: &reqwest::header::HeaderMap,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<Duration> {
+    let value = headers.get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+            (date.with_timezone(&chrono::Utc) - now)
+                .to_std()
+                .unwrap_or(Duration::ZERO)
+        }
+    };
+    Some(delay.min(MAX_RETRY_DELAY))
+}
+
+/// Returns how long to wait before retrying a rate limited or failed request.
+fn retry_delay(
+    headers: &reqwest::header::HeaderMap,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Duration {
+    reset_after(headers)
+        .or_else(|| retry_after(headers, now))
+        .unwrap_or(DEFAULT_RETRY_DELAY)
+}
+
+/// Identifies a webhook; the token is part of the path, and query parameters such as
+/// `thread_id` share the same rate limit.
+fn bucket_key(url: &reqwest::Url) -> String {
+    format!("{}{}", url.origin().ascii_serialization(), url.path())
+}
+
+fn record_backoff(kind: &str) {
+    model::metrics::increment_counter(model::metrics::BACKOFF_SLEEPS, &[("kind", kind)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+
+    use chrono::TimeZone;
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for &(name, value) in pairs {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn now() -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc.ymd(2015, 10, 21).and_hms(7, 28, 0)
+    }
+
+    #[test]
+    fn retry_delay_reads_reset_after() {
+        let headers = headers(&[("x-ratelimit-reset-after", "1.5"), ("retry-after", "30")]);
+        assert_eq!(retry_delay(&headers, now()), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn retry_delay_reads_retry_after_seconds() {
+        let headers = headers(&[("retry-after", "30")]);
+        assert_eq!(retry_delay(&headers, now()), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn retry_delay_reads_retry_after_date() {
+        let future = headers(&[("retry-after", "Wed, 21 Oct 2015 07:28:12 GMT")]);
+        assert_eq!(retry_delay(&future, now()), Duration::from_secs(12));
+        let past = headers(&[("retry-after", "Wed, 21 Oct 2015 07:00:00 GMT")]);
+        assert_eq!(retry_delay(&past, now()), Duration::ZERO);
+    }
+
+    #[test]
+    fn retry_delay_falls_back_on_invalid_values() {
+        for pairs in [
+            &[("x-ratelimit-reset-after", "soon")][..],
+            &[("x-ratelimit-reset-after", "-1")],
+            &[("x-ratelimit-reset-after", "NaN")],
+            &[("retry-after", "tomorrow")],
+            &[("retry-after", "-5")],
+            &[],
+        ] {
+            assert_eq!(retry_delay(&headers(pairs), now()), DEFAULT_RETRY_DELAY, "{:?}", pairs);
+        }
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("99999999"));
+        assert_eq!(retry_delay(&headers, now()), MAX_RETRY_DELAY);
+    }
+
+    /// Serves `responses` in order, one per connection, and returns its address and the number
+    /// of requests it received.
+    fn serve(responses: Vec<String>) -> (std::net::SocketAddr, Arc<Mutex<usize>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(0));
+        std::thread::spawn({
+            let requests = requests.clone();
+            move || {
+                for (stream, response) in listener.incoming().zip(responses) {
+                    let mut stream = stream.unwrap();
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    let body_len = loop {
+                        let len = stream.read(&mut buf).unwrap();
+                        request.extend_from_slice(&buf[..len]);
+                        let head = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                        if let Some(end) = head.find("\r\n\r\n") {
+                            let content_length = head
+                                .lines()
+                                .find_map(|line| line.strip_prefix("content-length:"))
+                                .map(|len| len.trim().parse::<usize>().unwrap())
+                                .unwrap_or(0);
+                            break end + 4 + content_length;
+                        }
+                    };
+                    while request.len() < body_len {
+                        let len = stream.read(&mut buf).unwrap();
+                        request.extend_from_slice(&buf[..len]);
+                    }
+                    *requests.lock().unwrap() += 1;
+                    stream.write_all(response.as_bytes()).unwrap();
+                }
+            }
+        });
+        (addr, requests)
+    }
+
+    fn response(status: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+            status,
+            headers,
+            body.len(),
+            body,
+        )
+    }
+
+    #[test]
+    fn rate_limited_request_is_retried_after_delay() {
+        let (addr, requests) = serve(vec![
+            response(
+                "429 Too Many Requests",
+                "X-RateLimit-Reset-After: 0.3\r\nRetry-After: 30\r\n",
+                r#"{"message":"You are being rate limited.","retry_after":0.3}"#,
+            ),
+            response("200 OK", "", r#"{"id":"42"}"#),
+        ]);
+        let url = format!("http://{}/api/webhooks/1/token", addr);
+        let url = reqwest::Url::parse(&url).unwrap();
+        let executor = WebhookExecutor::new();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let started_at = std::time::Instant::now();
+        let body = runtime.block_on(async {
+            let resp = executor
+                .send(&url, None, |client| client.post(url.clone()).body("{}"))
+                .await
+                .unwrap();
+            resp.json::<serde_json::Value>().await.unwrap()
+        });
+
+        assert!(started_at.elapsed() >= Duration::from_millis(300));
+        assert_eq!(body["id"], "42");
+        assert_eq!(*requests.lock().unwrap(), 2);
+        let counters = executor.take_counters();
+        let counters = &counters[&redact_destination(&url)];
+        assert_eq!((counters.sent, counters.failed), (1, 0));
+    }
+}
@@ -1,116 +1,1449 @@
 use tweet_model as model;
 
+pub mod embed;
+mod error;
+mod executor;
+pub mod limits;
+mod multipart;
+mod webhook_url;
+
+pub use embed::{
+    AllowedMentions, Button, Component, Embed, EmbedAuthor, EmbedFooter, EmbedImage, MentionType,
+    WebhookPayload,
+};
+pub use error::{redact_destination, redact_url, DiscordRenderError, WebhookError};
+pub use executor::{DeliveryCounters, WebhookExecutor, DEFAULT_MAX_ATTEMPTS};
+pub use multipart::Attachment;
+pub use webhook_url::{probe_webhook, validate_webhook_url, WebhookInfo, WebhookUrlError};
+
+const DEFAULT_EMBED_COLOR: u32 = 1940464;
+const DEFAULT_FOOTER_TEXT: &str = "Twitter";
+const DEFAULT_FOOTER_ICON_URL: &str = "https://abs.twimg.com/favicons/favicon.png";
+const DEFAULT_NOTICE_USERNAME: &str = "tweet-broadcast";
+
+/// Size cap of attached media, Discord's upload limit for servers without boosts.
+pub const DEFAULT_MAX_ATTACHMENT_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Knobs applied on top of the standard tweet rendering of `send_webhook`.
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    /// Text prepended to the message content.
+    pub content_prefix: Option<String>,
+    /// Text appended to the message content.
+    pub content_suffix: Option<String>,
+    /// Color of the main embed, as a 24-bit RGB integer.
+    pub color: Option<u32>,
+    /// Omit image embeds entirely.
+    pub suppress_media: bool,
+    /// Name and avatar to post under.
+    pub identity: WebhookIdentity,
+    /// Webhook username to use instead of the one given by `identity`.
+    pub username: Option<String>,
+    /// How to deliver media of possibly sensitive tweets.
+    pub sensitive_media: SensitiveMediaPolicy,
+    /// Footer text instead of "Twitter".
+    pub footer_text: Option<String>,
+    /// Footer icon URL instead of the Twitter favicon.
+    pub footer_icon_url: Option<String>,
+    /// Score to show in the footer.
+    pub score: Option<f64>,
+    /// Adds "Open tweet" and "Open profile" buttons, leaving the tweet URL out of the content.
+    pub link_buttons: bool,
+    /// Adds an "Open media (orig)" button for each media.
+    pub media_buttons: bool,
+    /// Uploads media as attachments instead of linking to Twitter.
+    pub attach_media: bool,
+    /// Size cap of attached media, `DEFAULT_MAX_ATTACHMENT_BYTES` if unset. Larger media are
+    /// linked instead.
+    pub max_attachment_bytes: Option<u64>,
+    /// Inline fields, as names and values, appended to the main embed.
+    pub fields: Vec<(String, String)>,
+}
+
+/// Delivery of media attached to tweets flagged as possibly sensitive.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensitiveMediaPolicy {
+    /// Omit the media.
+    #[default]
+    Drop,
+    /// List the media URLs in the description, hidden behind spoiler markers.
+    LinkOnly,
+    /// Re-upload the media as spoilered attachments.
+    Spoiler,
+}
+
+/// Name and avatar messages are posted under.
+///
+/// ```toml
+/// identity = "author"
+/// identity = { fixed = { username = "Art feed", avatar_url = "https://..." } }
+/// identity = "none"
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookIdentity {
+    /// Impersonate the tweet author. Notices are posted as "tweet-broadcast".
+    #[default]
+    Author,
+    /// Post under a fixed name and avatar, leaving the author inside the embed.
+    Fixed {
+        username: String,
+        #[serde(default)]
+        avatar_url: Option<String>,
+    },
+    /// Use the name and avatar configured on the webhook itself.
+    None,
+}
+
+/// Options affecting where and how a webhook is executed.
+#[derive(Debug, Clone, Default)]
+pub struct ExecuteOptions {
+    /// Posts into the given thread of the webhook's channel.
+    pub thread_id: Option<String>,
+    /// How to handle content over Discord's length limit.
+    pub content_overflow: limits::ContentOverflow,
+    /// Truncates embed fields over Discord's limits instead of failing.
+    pub truncate_fields: bool,
+    /// Mentions to resolve, overriding the payload. If `None`, payloads without
+    /// `allowed_mentions` get `AllowedMentions::none()`.
+    pub allowed_mentions: Option<AllowedMentions>,
+    /// Minimum time between new messages sent to the webhook, on top of Discord's rate limits.
+    /// Measured from the previous request to the webhook, whichever options it was sent with.
+    pub min_interval: Option<std::time::Duration>,
+}
+
 pub async fn send_webhook(
-    client: &reqwest::Client,
+    executor: &WebhookExecutor,
     webhook_url: &reqwest::Url,
     tweet: &model::Tweet,
     includes: &model::ResponseIncludes,
-) -> reqwest::Result<()> {
-    let original_tweet = tweet;
-    let original_author = includes
-        .get_user(original_tweet.author_id().unwrap())
-        .unwrap();
+) -> Result<(), WebhookError> {
+    send_webhook_with_options(
+        executor,
+        webhook_url,
+        tweet,
+        includes,
+        &Default::default(),
+        &Default::default(),
+    )
+    .await?;
+    Ok(())
+}
 
-    let tweet_data = original_tweet
-        .referenced_tweets()
-        .iter()
-        .find(|t| t.ref_type() == model::TweetReferenceType::Retweeted);
-    let tweet_data = if let Some(ref_tweet) = tweet_data {
-        includes.get_tweet(ref_tweet.id()).unwrap()
+/// Sends a tweet, returning the IDs of the messages sent.
+pub async fn send_webhook_with_options(
+    executor: &WebhookExecutor,
+    webhook_url: &reqwest::Url,
+    tweet: &model::Tweet,
+    includes: &model::ResponseIncludes,
+    options: &RenderOptions,
+    execute_options: &ExecuteOptions,
+) -> Result<Vec<String>, WebhookError> {
+    let mut payload = make_tweet_payload(tweet, includes, options)?.to_value();
+
+    let mut attachments = Vec::new();
+    for url in spoiler_media_urls(tweet, includes, options) {
+        match Attachment::download(executor.client(), &url, true).await {
+            Ok(attachment) => attachments.push(attachment),
+            Err(e) => log::warn!("Failed to download sensitive media {}: {}", url, e),
+        }
+    }
+    let max_bytes = options
+        .max_attachment_bytes
+        .unwrap_or(DEFAULT_MAX_ATTACHMENT_BYTES);
+    for url in attached_media_urls(tweet, includes, options) {
+        match Attachment::download_limited(executor.client(), &url, false, max_bytes).await {
+            Ok(Some(attachment)) => {
+                reference_attachment(&mut payload, url.as_str(), &attachment.reference_url());
+                attachments.push(attachment);
+            }
+            Ok(None) => log::debug!("Media {} is larger than {} bytes, linking", url, max_bytes),
+            Err(e) => log::warn!("Failed to download media {}, linking: {}", url, e),
+        }
+    }
+    execute_webhook_with_attachments(
+        executor,
+        webhook_url,
+        &payload,
+        &attachments,
+        execute_options,
+    )
+    .await
+}
+
+/// Resolves retweets into the retweeted tweet, whose content is rendered.
+fn resolve_tweet<'a>(
+    tweet: &'a model::Tweet,
+    includes: &'a model::ResponseIncludes,
+) -> Result<&'a model::Tweet, DiscordRenderError> {
+    if let Some(source) = tweet.get_retweet_source() {
+        includes
+            .get_tweet(source)
+            .ok_or_else(|| DiscordRenderError::MissingReferencedTweet(source.to_owned()))
     } else {
-        original_tweet
+        Ok(tweet)
+    }
+}
+
+/// Author of a rendered tweet, which may be missing from the includes, e.g. when suspended.
+#[derive(Debug, Clone, Copy)]
+struct TweetAuthor<'a> {
+    id: Option<&'a str>,
+    user: Option<&'a model::User>,
+}
+
+impl<'a> TweetAuthor<'a> {
+    fn of(tweet: &'a model::Tweet, includes: &'a model::ResponseIncludes) -> Self {
+        let id = tweet.author_id();
+        let user = id.and_then(|id| includes.get_user(id));
+        if user.is_none() {
+            log::debug!(
+                "Author {} of tweet {} is not included",
+                id.unwrap_or("(unknown)"),
+                tweet.id(),
+            );
+        }
+        Self { id, user }
+    }
+
+    /// Name shown for the author, the user ID if the user is missing.
+    fn display_name(&self) -> String {
+        match (self.user, self.id) {
+            (Some(user), _) => format!("{} (@{})", user.name(), user.username()),
+            (None, Some(id)) => format!("User {}", id),
+            (None, None) => String::from("Unknown user"),
+        }
+    }
+
+    fn profile_url(&self) -> Option<String> {
+        match (self.user, self.id) {
+            (Some(user), _) => Some(format!("https://twitter.com/{}", user.username())),
+            (None, Some(id)) => Some(format!("https://twitter.com/i/user/{}", id)),
+            (None, None) => None,
+        }
+    }
+
+    fn tweet_url(&self, tweet: &model::Tweet) -> String {
+        // `i/web` redirects to the tweet without knowing its author
+        let username = self.user.map_or("i/web", |user| user.username());
+        format!("https://twitter.com/{}/status/{}", username, tweet.id())
+    }
+
+    fn icon_url(&self) -> Option<reqwest::Url> {
+        self.user?.profile_image_url_orig()
+    }
+}
+
+/// Returns the media URLs to re-upload as spoilered attachments under `SensitiveMediaPolicy::Spoiler`.
+pub fn spoiler_media_urls(
+    tweet: &model::Tweet,
+    includes: &model::ResponseIncludes,
+    options: &RenderOptions,
+) -> Vec<reqwest::Url> {
+    let tweet_data = match resolve_tweet(tweet, includes) {
+        Ok(tweet_data) => tweet_data,
+        Err(_) => return Vec::new(),
     };
-    let author = includes.get_user(tweet_data.author_id().unwrap()).unwrap();
+    if !tweet_data.possibly_sensitive()
+        || options.suppress_media
+        || options.sensitive_media != SensitiveMediaPolicy::Spoiler
+    {
+        return Vec::new();
+    }
+    tweet_data
+        .media_keys()
+        .iter()
+        .filter_map(|key| includes.get_media(key)?.url_orig())
+        .collect()
+}
+
+/// Returns the media URLs to upload as attachments under `RenderOptions::attach_media`.
+pub fn attached_media_urls(
+    tweet: &model::Tweet,
+    includes: &model::ResponseIncludes,
+    options: &RenderOptions,
+) -> Vec<reqwest::Url> {
+    let tweet_data = match resolve_tweet(tweet, includes) {
+        Ok(tweet_data) => tweet_data,
+        Err(_) => return Vec::new(),
+    };
+    if !options.attach_media || tweet_data.possibly_sensitive() || options.suppress_media {
+        return Vec::new();
+    }
+    tweet_data
+        .media_keys()
+        .iter()
+        .filter_map(|key| includes.get_media(key)?.url_orig())
+        .collect()
+}
 
-    let payload_media = if tweet_data.possibly_sensitive() {
+/// Points embed images linking to `url` at the attachment `reference` instead.
+fn reference_attachment(payload: &mut serde_json::Value, url: &str, reference: &str) {
+    let embeds = payload
+        .get_mut("embeds")
+        .and_then(|embeds| embeds.as_array_mut());
+    for embed in embeds.into_iter().flatten() {
+        if let Some(image_url) = embed.pointer_mut("/image/url") {
+            if image_url.as_str() == Some(url) {
+                *image_url = serde_json::Value::from(reference);
+            }
+        }
+    }
+}
+
+/// Renders a tweet.
+///
+/// Missing authors are shown by their ID, and missing media are left out with a note in the
+/// footer. Fails only if the retweeted tweet of a retweet is missing.
+pub fn make_tweet_payload(
+    tweet: &model::Tweet,
+    includes: &model::ResponseIncludes,
+    options: &RenderOptions,
+) -> Result<WebhookPayload, DiscordRenderError> {
+    let original_tweet = tweet;
+    let original_author = TweetAuthor::of(original_tweet, includes);
+
+    let tweet_data = resolve_tweet(original_tweet, includes)?;
+    let author = TweetAuthor::of(tweet_data, includes);
+    let tweet_url = author.tweet_url(tweet_data);
+
+    let media = tweet_data
+        .media_keys()
+        .iter()
+        .filter_map(|key| includes.get_media(key))
+        .collect::<Vec<_>>();
+    let missing_media = tweet_data.media_keys().len() - media.len();
+    if missing_media > 0 {
+        log::debug!(
+            "{} media of tweet {} not included",
+            missing_media,
+            tweet_data.id(),
+        );
+    }
+    let payload_media = if tweet_data.possibly_sensitive() || options.suppress_media {
         Vec::new()
     } else {
-        tweet_data
-            .media_keys()
+        media
             .iter()
-            .map(|key| {
-                let media = includes.get_media(key).unwrap();
-                serde_json::json!({
-                    "url": media.url_orig(),
-                    "width": media.width(),
-                    "height": media.height(),
-                })
+            .filter_map(|media| {
+                // videos and GIFs only have `preview_image_url`, which `url_orig` falls back to
+                let url = media.url_orig()?;
+                Some(EmbedImage::new(url).size(media.width(), media.height()))
             })
             .collect::<Vec<_>>()
     };
 
-    let mut payload_embed = vec![serde_json::json!({
-        "author": {
-            "name": format!("{} (@{})", author.name(), author.username()),
-            "url": format!("https://twitter.com/{}", author.username()),
-            "icon_url": author.profile_image_url_orig(),
+    let mut description = tweet_data.markdown_text();
+    for media in &media {
+        if let Some(line) = video_line(media, &tweet_url) {
+            description.push('\n');
+            description.push_str(&line);
+        }
+    }
+    if tweet_data.possibly_sensitive()
+        && !options.suppress_media
+        && options.sensitive_media == SensitiveMediaPolicy::LinkOnly
+    {
+        for url in media.iter().filter_map(|media| media.url_orig()) {
+            description.push_str(&format!("\n||{}||", url));
+        }
+    }
+
+    let mut embed_author = EmbedAuthor::new(author.display_name());
+    if let Some(profile_url) = author.profile_url() {
+        embed_author = embed_author.url(profile_url);
+    }
+    if let Some(icon_url) = author.icon_url() {
+        embed_author = embed_author.icon_url(icon_url);
+    }
+    let mut footer_text = options
+        .footer_text
+        .as_deref()
+        .unwrap_or(DEFAULT_FOOTER_TEXT)
+        .to_owned();
+    if let Some(score) = options.score {
+        footer_text.push_str(&format!(" \u{b7} score {:.1}", score));
+    }
+    if missing_media > 0 {
+        footer_text.push_str(&format!(" \u{b7} {} media unavailable", missing_media));
+    }
+    let footer = EmbedFooter::new(footer_text).icon_url(
+        options
+            .footer_icon_url
+            .as_deref()
+            .unwrap_or(DEFAULT_FOOTER_ICON_URL),
+    );
+
+    let mut payload_media = payload_media.into_iter();
+    let mut main_embed = Embed::new()
+        .author(embed_author)
+        .description(description)
+        .timestamp(tweet_data.created_at())
+        .url(&*tweet_url)
+        .color(options.color.unwrap_or(DEFAULT_EMBED_COLOR))
+        .footer(footer);
+    if let Some(image) = payload_media.next() {
+        main_embed = main_embed.image(image);
+    }
+    for poll_id in tweet_data.poll_ids() {
+        match includes.get_poll(poll_id) {
+            Some(poll) => {
+                let (name, value) = poll_field(poll, chrono::Utc::now());
+                main_embed = main_embed.field(name, value, false);
+            }
+            None => log::debug!(
+                "Poll {} of tweet {} is not included",
+                poll_id,
+                tweet_data.id()
+            ),
+        }
+    }
+    for (name, value) in &options.fields {
+        main_embed = main_embed.field(name, value, true);
+    }
+
+    let content = format!(
+        "{}{}{}{}",
+        options.content_prefix.as_deref().unwrap_or(""),
+        if tweet_data.possibly_sensitive() {
+            "\u{26a0} Possibly sensitive\n"
+        } else {
+            ""
         },
-        "description": tweet_data.unescaped_text(),
-        "timestamp": tweet_data.created_at(),
-        "url": format!("https://twitter.com/{}/status/{}", author.username(), tweet_data.id()),
-        "color": 1940464,
-        "footer": {
-            "text": "Twitter",
-            "icon_url": "https://abs.twimg.com/favicons/favicon.png",
+        if options.link_buttons {
+            ""
+        } else {
+            &*tweet_url
         },
-        "image": payload_media.first(),
-    })];
-    payload_embed.extend(
-        payload_media
-            .into_iter()
-            .map(|v| serde_json::json!({ "image": v }))
-            .skip(1),
+        options.content_suffix.as_deref().unwrap_or(""),
     );
+    let mut payload = WebhookPayload::new()
+        .embed(main_embed)
+        .allowed_mentions(AllowedMentions::none());
+    if !content.trim().is_empty() {
+        payload = payload.content(content);
+    }
+    match &options.identity {
+        WebhookIdentity::Author => {
+            payload = payload.username(original_author.display_name());
+            if let Some(avatar_url) = original_author.icon_url() {
+                payload = payload.avatar_url(avatar_url);
+            }
+        }
+        WebhookIdentity::Fixed {
+            username,
+            avatar_url,
+        } => {
+            payload = payload.username(username);
+            if let Some(avatar_url) = avatar_url {
+                payload = payload.avatar_url(avatar_url);
+            }
+        }
+        WebhookIdentity::None => {}
+    }
+    if let Some(username) = &options.username {
+        payload = payload.username(username);
+    }
+    for image in payload_media {
+        payload = payload.embed(Embed::new().image(image));
+    }
 
-    let content = format!(
-        "{}https://twitter.com/{}/status/{}",
-        if tweet_data.possibly_sensitive() { "\u{26a0} Possibly sensitive\n" } else { "" },
-        author.username(),
-        tweet_data.id(),
+    if options.link_buttons {
+        payload = payload.button(Button::link("Open tweet", &*tweet_url));
+        if let Some(profile_url) = author.profile_url() {
+            payload = payload.button(Button::link("Open profile", profile_url));
+        }
+    }
+    let media_hidden = options.suppress_media
+        || (tweet_data.possibly_sensitive()
+            && options.sensitive_media == SensitiveMediaPolicy::Drop);
+    if options.media_buttons && !media_hidden {
+        let media_urls = media
+            .iter()
+            .filter_map(|media| match media.best_video_variant() {
+                Some(variant) => Some(variant.url().clone()),
+                None => media.url_orig(),
+            })
+            .collect::<Vec<_>>();
+        for (idx, url) in media_urls.iter().enumerate() {
+            let label = if media_urls.len() == 1 {
+                String::from("Open media (orig)")
+            } else {
+                format!("Open media {} (orig)", idx + 1)
+            };
+            payload = payload.button(Button::link(label, url.as_str()));
+        }
+    }
+    Ok(payload)
+}
+
+/// Makes a plain message about the relay itself, e.g. list initialization.
+pub fn make_notice_payload(
+    message: impl Into<String>,
+    identity: &WebhookIdentity,
+) -> WebhookPayload {
+    let payload = WebhookPayload::new()
+        .content(message)
+        .allowed_mentions(AllowedMentions::none());
+    match identity {
+        WebhookIdentity::Author => payload.username(DEFAULT_NOTICE_USERNAME),
+        WebhookIdentity::Fixed {
+            username,
+            avatar_url: Some(avatar_url),
+        } => payload.username(username).avatar_url(avatar_url),
+        WebhookIdentity::Fixed { username, .. } => payload.username(username),
+        WebhookIdentity::None => payload,
+    }
+}
+
+/// Renders a description line for video and animated GIF media, linking to the MP4 when
+/// available, or the tweet otherwise.
+fn video_line(media: &model::Media, tweet_url: &str) -> Option<String> {
+    let label = match media.media_type() {
+        model::MediaType::Photo => return None,
+        model::MediaType::Video => match media.duration() {
+            Some(duration) => format!("\u{25b6} video ({})", format_duration(duration)),
+            None => String::from("\u{25b6} video"),
+        },
+        model::MediaType::AnimatedGif => String::from("\u{25b6} GIF"),
+    };
+    let link = media
+        .best_video_variant()
+        .map(|v| v.url().as_str())
+        .unwrap_or(tweet_url);
+    Some(format!("[{}]({})", label, link))
+}
+
+/// Renders a poll as the name and value of an embed field, e.g. "1,234 votes · ends in 3h".
+pub fn poll_field(poll: &model::Poll, now: chrono::DateTime<chrono::Utc>) -> (String, String) {
+    let total = poll.total_votes();
+    let closed = poll.is_closed(now);
+    let leading_votes = poll.options().iter().map(|o| o.votes()).max().unwrap_or(0);
+
+    let mut lines = Vec::new();
+    for option in poll.options() {
+        let percent = if total == 0 {
+            0.0
+        } else {
+            option.votes() as f64 * 100.0 / total as f64
+        };
+        let label = if closed && total > 0 && option.votes() == leading_votes {
+            format!("**{}** \u{2714}", option.label())
+        } else {
+            format!("**{}**", option.label())
+        };
+        lines.push(format!(
+            "{} \u{2014} {:.1}% ({})",
+            label,
+            percent,
+            format_count(option.votes()),
+        ));
+    }
+
+    let status = if closed {
+        String::from("final results")
+    } else {
+        match poll.end_datetime() {
+            Some(end) => format!("ends in {}", format_remaining(end - now)),
+            None => String::from("open"),
+        }
+    };
+    lines.push(format!(
+        "{} vote{} \u{b7} {}",
+        format_count(total),
+        if total == 1 { "" } else { "s" },
+        status,
+    ));
+
+    let name = if closed { "Poll (closed)" } else { "Poll" };
+    (String::from(name), lines.join("\n"))
+}
+
+/// Growth of a tweet relayed by the trending tracker, rendered by `trending_fields`.
+#[derive(Debug, Clone)]
+pub struct TrendingInfo {
+    /// Search terms which matched the tweet.
+    pub terms: Vec<String>,
+    pub score: f64,
+    /// Score at the previous check, `None` on the first one.
+    pub previous_score: Option<f64>,
+    /// Retweets gained since tracking began.
+    pub retweet_delta: i64,
+    /// Likes gained since tracking began.
+    pub like_delta: i64,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Renders the growth of a trending tweet as embed fields, for `RenderOptions::fields`.
+pub fn trending_fields(
+    info: &TrendingInfo,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<(String, String)> {
+    let score = match info.previous_score {
+        Some(previous) => format!("{:.1} (was {:.1})", info.score, previous),
+        None => format!("{:.1}", info.score),
+    };
+    let growth = format!(
+        "{} RTs \u{b7} {} likes",
+        format_delta(info.retweet_delta),
+        format_delta(info.like_delta),
     );
-    let payload = serde_json::json!({
-        "username": format!("{} (@{})", original_author.name(), original_author.username()),
-        "avatar_url": original_author.profile_image_url_orig(),
-        "content": content,
-        "embeds": payload_embed,
-    });
+    let age = now - info.created_at;
+    let posted = if age < chrono::Duration::minutes(1) {
+        String::from("just now")
+    } else {
+        format!("{} ago", format_remaining(age))
+    };
+    vec![
+        (String::from("Score"), score),
+        (String::from("Since tracked"), growth),
+        (String::from("Posted"), posted),
+        (String::from("Search term"), info.terms.join(", ")),
+    ]
+}
+
+fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, format_count(delta.unsigned_abs()))
+}
+
+fn format_count(count: u64) -> String {
+    let digits = count.to_string();
+    let mut groups = digits
+        .as_bytes()
+        .rchunks(3)
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect::<Vec<_>>();
+    groups.reverse();
+    groups.join(",")
+}
 
-    execute_webhook(client, webhook_url, &payload).await
+fn format_remaining(remaining: chrono::Duration) -> String {
+    if remaining.num_days() >= 1 {
+        format!("{}d", remaining.num_days())
+    } else if remaining.num_hours() >= 1 {
+        format!("{}h", remaining.num_hours())
+    } else if remaining.num_minutes() >= 1 {
+        format!("{}m", remaining.num_minutes())
+    } else {
+        String::from("less than a minute")
+    }
+}
+
+fn format_duration(duration: std::time::Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
 }
 
 pub async fn execute_webhook(
-    client: &reqwest::Client,
+    executor: &WebhookExecutor,
     url: &reqwest::Url,
     payload: &serde_json::Value,
-) -> reqwest::Result<()> {
-    loop {
-        log::trace!(
-            "Sending payload {}",
-            serde_json::to_string(payload).unwrap()
+) -> Result<(), WebhookError> {
+    execute_webhook_with_options(executor, url, payload, &Default::default()).await?;
+    Ok(())
+}
+
+pub async fn execute_webhook_with_options(
+    executor: &WebhookExecutor,
+    url: &reqwest::Url,
+    payload: &serde_json::Value,
+    options: &ExecuteOptions,
+) -> Result<Vec<String>, WebhookError> {
+    execute_webhook_with_attachments(executor, url, payload, &[], options).await
+}
+
+fn apply_allowed_mentions(
+    payload: &serde_json::Value,
+    options: &ExecuteOptions,
+) -> serde_json::Value {
+    let mut payload = payload.clone();
+    if let Some(payload) = payload.as_object_mut() {
+        match &options.allowed_mentions {
+            Some(allowed_mentions) => {
+                payload.insert(
+                    String::from("allowed_mentions"),
+                    serde_json::to_value(allowed_mentions).unwrap(),
+                );
+            }
+            None => {
+                payload
+                    .entry("allowed_mentions")
+                    .or_insert_with(|| serde_json::to_value(AllowedMentions::none()).unwrap());
+            }
+        }
+    }
+    payload
+}
+
+/// Executes a webhook, uploading each of `attachments` with the message whose embeds reference
+/// it, or the first one.
+///
+/// Returns the IDs of the messages sent, in order.
+pub async fn execute_webhook_with_attachments(
+    executor: &WebhookExecutor,
+    url: &reqwest::Url,
+    payload: &serde_json::Value,
+    attachments: &[Attachment],
+    options: &ExecuteOptions,
+) -> Result<Vec<String>, WebhookError> {
+    let mut payload = apply_allowed_mentions(payload, options);
+    if options.truncate_fields {
+        limits::truncate_fields(&mut payload);
+    }
+
+    let messages = limits::apply_content_limit(&payload, options.content_overflow)
+        .iter()
+        .flat_map(limits::split_payload)
+        .collect::<Vec<_>>();
+    let violations = messages
+        .iter()
+        .flat_map(limits::validate_payload)
+        .collect::<Vec<_>>();
+    if !violations.is_empty() {
+        for violation in &violations {
+            log::warn!("Webhook payload exceeds limit: {}", violation);
+        }
+        return Err(WebhookError::InvalidPayload {
+            url: redact_url(url),
+            violations,
+        });
+    }
+
+    let mut message_ids = Vec::new();
+    let attachments = multipart::distribute(&messages, attachments);
+    for (message, attachments) in messages.iter().zip(attachments) {
+        let message_id = execute_single(executor, url, message, &attachments, options).await?;
+        message_ids.extend(message_id);
+    }
+    Ok(message_ids)
+}
+
+/// Replaces the content of a message previously sent by the webhook.
+pub async fn edit_webhook_message(
+    executor: &WebhookExecutor,
+    url: &reqwest::Url,
+    message_id: &str,
+    payload: &serde_json::Value,
+) -> Result<(), WebhookError> {
+    edit_webhook_message_with_options(executor, url, message_id, payload, &Default::default()).await
+}
+
+/// Replaces the content of a message previously sent by the webhook.
+///
+/// Content over the limit is always truncated, and embeds which don't fit in a single message
+/// are dropped.
+pub async fn edit_webhook_message_with_options(
+    executor: &WebhookExecutor,
+    url: &reqwest::Url,
+    message_id: &str,
+    payload: &serde_json::Value,
+    options: &ExecuteOptions,
+) -> Result<(), WebhookError> {
+    let mut payload = apply_allowed_mentions(payload, options);
+    if options.truncate_fields {
+        limits::truncate_fields(&mut payload);
+    }
+    let payload =
+        limits::apply_content_limit(&payload, limits::ContentOverflow::Truncate).swap_remove(0);
+    let mut messages = limits::split_payload(&payload);
+    if messages.len() > 1 {
+        log::warn!("Edited message has too many embeds, dropping some");
+    }
+    let payload = messages.swap_remove(0);
+    let violations = limits::validate_payload(&payload);
+    if !violations.is_empty() {
+        for violation in &violations {
+            log::warn!("Webhook payload exceeds limit: {}", violation);
+        }
+        return Err(WebhookError::InvalidPayload {
+            url: redact_url(url),
+            violations,
+        });
+    }
+
+    let message_url = message_url(url, message_id);
+    log::trace!(
+        "Editing message {} with payload {}",
+        message_id,
+        serde_json::to_string(&payload).unwrap()
+    );
+    executor
+        .send(url, None, |client| {
+            let mut req = client.patch(message_url.clone());
+            if let Some(thread_id) = &options.thread_id {
+                req = req.query(&[("thread_id", thread_id)]);
+            }
+            req.json(&payload)
+        })
+        .await?;
+    Ok(())
+}
+
+/// Deletes a message previously sent by the webhook.
+pub async fn delete_webhook_message(
+    executor: &WebhookExecutor,
+    url: &reqwest::Url,
+    message_id: &str,
+) -> Result<(), WebhookError> {
+    delete_webhook_message_with_options(executor, url, message_id, &Default::default()).await
+}
+
+/// Deletes a message previously sent by the webhook. Only `thread_id` of `options` is used.
+pub async fn delete_webhook_message_with_options(
+    executor: &WebhookExecutor,
+    url: &reqwest::Url,
+    message_id: &str,
+    options: &ExecuteOptions,
+) -> Result<(), WebhookError> {
+    let message_url = message_url(url, message_id);
+    log::trace!("Deleting message {}", message_id);
+    executor
+        .send(url, None, |client| {
+            let mut req = client.delete(message_url.clone());
+            if let Some(thread_id) = &options.thread_id {
+                req = req.query(&[("thread_id", thread_id)]);
+            }
+            req
+        })
+        .await?;
+    Ok(())
+}
+
+/// Returns `{webhook_url}/messages/{message_id}`.
+fn message_url(url: &reqwest::Url, message_id: &str) -> reqwest::Url {
+    let mut ret = url.clone();
+    ret.path_segments_mut()
+        .unwrap()
+        .pop_if_empty()
+        .push("messages")
+        .push(message_id);
+    ret
+}
+
+async fn execute_single(
+    executor: &WebhookExecutor,
+    url: &reqwest::Url,
+    payload: &serde_json::Value,
+    attachments: &[&Attachment],
+    options: &ExecuteOptions,
+) -> Result<Option<String>, WebhookError> {
+    log::trace!(
+        "Sending payload {}",
+        serde_json::to_string(payload).unwrap()
+    );
+    let multipart_body = if attachments.is_empty() {
+        None
+    } else {
+        Some(multipart::encode(payload, attachments))
+    };
+    let resp = executor
+        .send(url, options.min_interval, |client| {
+            let mut req = client.post(url.clone()).query(&[("wait", "true")]);
+            if payload.get("components").is_some() {
+                // required for webhooks not owned by an application
+                req = req.query(&[("with_components", "true")]);
+            }
+            if let Some(thread_id) = &options.thread_id {
+                req = req.query(&[("thread_id", thread_id)]);
+            }
+            match &multipart_body {
+                None => req.json(payload),
+                Some((content_type, body)) => req
+                    .header(reqwest::header::CONTENT_TYPE, content_type)
+                    .body(body.clone()),
+            }
+        })
+        .await?;
+
+    let message = match resp.bytes().await {
+        Ok(body) => serde_json::from_slice::<serde_json::Value>(&body).ok(),
+        Err(e) => {
+            log::debug!(
+                "Failed to read webhook response: {}",
+                error::describe_error(&e)
+            );
+            None
+        }
+    };
+    Ok(message
+        .as_ref()
+        .and_then(|m| m.get("id"))
+        .and_then(|id| id.as_str())
+        .map(String::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use super::*;
+
+    const TWEET_URL: &str = "https://twitter.com/author/status/10";
+
+    fn url(url: &str) -> reqwest::Url {
+        url.parse().unwrap()
+    }
+
+    fn created_at() -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)
+    }
+
+    fn author() -> model::User {
+        let icon = url("https://pbs.twimg.com/profile_images/1/icon_normal.png");
+        model::User::new("1", "Author", "author").with_profile_image_url(icon)
+    }
+
+    fn tweet(media_keys: &[&str]) -> model::Tweet {
+        model::Tweet::new("10", "text")
+            .with_author_id("1")
+            .with_created_at(created_at())
+            .with_media_keys(media_keys.iter().map(|&key| key.to_owned()).collect())
+    }
+
+    fn photo(key: &str) -> model::Media {
+        let photo_url = url(&format!("https://pbs.twimg.com/media/{}.jpg", key));
+        model::Media::new(key, model::MediaType::Photo, 1200, 800).with_url(photo_url)
+    }
+
+    /// Video or GIF with MP4 variants, which only have a preview image.
+    fn video(key: &str, ty: &str, duration_ms: Option<u64>) -> model::Media {
+        serde_json::from_value(json!({
+            "media_key": key,
+            "type": ty,
+            "width": 1280,
+            "height": 720,
+            "preview_image_url": format!("https://pbs.twimg.com/thumb/{}.jpg", key),
+            "duration_ms": duration_ms,
+            "variants": [
+                {
+                    "bit_rate": 256000,
+                    "content_type": "video/mp4",
+                    "url": format!("https://video.twimg.com/{}/low.mp4", key),
+                },
+                {
+                    "bit_rate": 2176000,
+                    "content_type": "video/mp4",
+                    "url": format!("https://video.twimg.com/{}/high.mp4", key),
+                },
+                {
+                    "content_type": "application/x-mpegURL",
+                    "url": format!("https://video.twimg.com/{}/list.m3u8", key),
+                },
+            ],
+        }))
+        .unwrap()
+    }
+
+    fn includes(media: Vec<model::Media>) -> model::ResponseIncludes {
+        let mut includes = model::ResponseIncludes::default();
+        includes.push_user(author());
+        for media in media {
+            includes.push_media(media);
+        }
+        includes
+    }
+
+    fn render(
+        tweet: &model::Tweet,
+        includes: &model::ResponseIncludes,
+        options: &RenderOptions,
+    ) -> serde_json::Value {
+        make_tweet_payload(tweet, includes, options)
+            .unwrap()
+            .to_value()
+    }
+
+    fn image_urls(payload: &serde_json::Value) -> Vec<&str> {
+        payload["embeds"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|embed| embed.pointer("/image/url")?.as_str())
+            .collect()
+    }
+
+    /// Labels and URLs of the buttons of `payload`, over every action row.
+    fn buttons(payload: &serde_json::Value) -> Vec<(&str, &str)> {
+        let rows = payload.get("components").and_then(|rows| rows.as_array());
+        rows.into_iter()
+            .flatten()
+            .flat_map(|row| row["components"].as_array().unwrap())
+            .map(|button| {
+                let label = button["label"].as_str().unwrap();
+                (label, button["url"].as_str().unwrap())
+            })
+            .collect()
+    }
+
+    /// Username and avatar URL `payload` is posted under.
+    fn identity_of(payload: &serde_json::Value) -> (Option<&str>, Option<&str>) {
+        (payload["username"].as_str(), payload["avatar_url"].as_str())
+    }
+
+    fn description(payload: &serde_json::Value) -> &str {
+        payload["embeds"][0]["description"].as_str().unwrap()
+    }
+
+    #[test]
+    fn renders_each_media_type() {
+        let tweet = tweet(&["p", "v", "g", "t"]);
+        let includes = includes(vec![
+            photo("p"),
+            video("v", "video", Some(83_000)),
+            video("g", "animated_gif", None),
+            // a video with only its preview, e.g. one still processing
+            model::Media::new("t", model::MediaType::Video, 640, 360)
+                .with_preview_image_url(url("https://pbs.twimg.com/thumb/t.jpg")),
+        ]);
+        let payload = render(&tweet, &includes, &Default::default());
+
+        // the first image goes in the main embed, the rest in their own
+        assert_eq!(
+            image_urls(&payload),
+            [
+                "https://pbs.twimg.com/media/p.jpg?name=orig",
+                "https://pbs.twimg.com/thumb/v.jpg",
+                "https://pbs.twimg.com/thumb/g.jpg",
+                "https://pbs.twimg.com/thumb/t.jpg",
+            ],
         );
-        let resp = client
-            .post(url.clone())
-            .query(&[("wait", "true")])
-            .json(payload)
-            .send()
-            .await?;
-
-        if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
-            let duration = if let Some(reset_after) = resp.headers().get("x-ratelimit-reset-after")
-            {
-                let reset_after = reset_after.to_str().unwrap().parse::<f32>().unwrap();
-                std::time::Duration::from_secs_f32(reset_after)
-            } else if let Some(retry_after) = resp.headers().get(reqwest::header::RETRY_AFTER) {
-                let retry_after = retry_after.to_str().unwrap().parse::<u64>().unwrap();
-                std::time::Duration::from_secs(retry_after)
-            } else {
-                std::time::Duration::from_secs(5)
+        assert_eq!(payload["embeds"][0]["image"]["width"], 1200);
+        assert_eq!(payload["embeds"][0]["image"]["height"], 800);
+        assert_eq!(
+            description(&payload),
+            format!(
+                "text\n\
+                 [\u{25b6} video (1:23)](https://video.twimg.com/v/high.mp4)\n\
+                 [\u{25b6} GIF](https://video.twimg.com/g/high.mp4)\n\
+                 [\u{25b6} video]({})",
+                TWEET_URL,
+            ),
+        );
+    }
+
+    #[test]
+    fn video_lines() {
+        assert_eq!(video_line(&photo("p"), TWEET_URL), None);
+        let long = video("v", "video", Some(3_723_000));
+        assert_eq!(
+            video_line(&long, TWEET_URL).unwrap(),
+            "[\u{25b6} video (1:02:03)](https://video.twimg.com/v/high.mp4)",
+        );
+        let gif = video("g", "animated_gif", None);
+        assert_eq!(
+            video_line(&gif, TWEET_URL).unwrap(),
+            "[\u{25b6} GIF](https://video.twimg.com/g/high.mp4)",
+        );
+    }
+
+    #[test]
+    fn sensitive_media_policies() {
+        let tweet = tweet(&["p", "v"]).with_possibly_sensitive(true);
+        let includes = includes(vec![photo("p"), video("v", "video", None)]);
+        let orig_urls = [
+            "https://pbs.twimg.com/media/p.jpg?name=orig",
+            "https://pbs.twimg.com/thumb/v.jpg",
+        ];
+        let options = |sensitive_media| RenderOptions {
+            sensitive_media,
+            media_buttons: true,
+            ..Default::default()
+        };
+
+        // media are never embedded as is, and the content warns of them
+        for policy in [
+            SensitiveMediaPolicy::Drop,
+            SensitiveMediaPolicy::LinkOnly,
+            SensitiveMediaPolicy::Spoiler,
+        ] {
+            let payload = render(&tweet, &includes, &options(policy));
+            assert!(image_urls(&payload).is_empty(), "{:?}", policy);
+            let content = payload["content"].as_str().unwrap();
+            let warning = "\u{26a0} Possibly sensitive\n";
+            assert!(content.starts_with(warning), "{:?}", policy);
+        }
+
+        // dropped media don't get buttons either
+        let drop = options(SensitiveMediaPolicy::Drop);
+        let payload = render(&tweet, &includes, &drop);
+        assert!(!description(&payload).contains("||"));
+        assert!(buttons(&payload).is_empty());
+        assert!(spoiler_media_urls(&tweet, &includes, &drop).is_empty());
+
+        let link_only = options(SensitiveMediaPolicy::LinkOnly);
+        let payload = render(&tweet, &includes, &link_only);
+        let spoilers = description(&payload)
+            .lines()
+            .filter(|line| line.starts_with("||"))
+            .collect::<Vec<_>>();
+        assert_eq!(spoilers, orig_urls.map(|url| format!("||{}||", url)));
+        assert!(spoiler_media_urls(&tweet, &includes, &link_only).is_empty());
+
+        let spoiler = options(SensitiveMediaPolicy::Spoiler);
+        let payload = render(&tweet, &includes, &spoiler);
+        assert!(!description(&payload).contains("||"));
+        let urls = spoiler_media_urls(&tweet, &includes, &spoiler);
+        let urls = urls.iter().map(|url| url.as_str()).collect::<Vec<_>>();
+        assert_eq!(urls, orig_urls);
+
+        // buttons open the largest MP4 of videos
+        for options in [link_only, spoiler] {
+            let payload = render(&tweet, &includes, &options);
+            assert_eq!(
+                buttons(&payload),
+                [
+                    ("Open media 1 (orig)", orig_urls[0]),
+                    ("Open media 2 (orig)", "https://video.twimg.com/v/high.mp4"),
+                ],
+            );
+        }
+
+        // suppressing media overrides every policy
+        let suppressed = RenderOptions {
+            suppress_media: true,
+            ..options(SensitiveMediaPolicy::LinkOnly)
+        };
+        let payload = render(&tweet, &includes, &suppressed);
+        assert!(!description(&payload).contains("||"));
+        assert!(buttons(&payload).is_empty());
+    }
+
+    fn poll(votes: &[(&str, u64)]) -> model::Poll {
+        let options = votes
+            .iter()
+            .enumerate()
+            .map(|(idx, &(label, votes))| model::PollOption::new(idx as u32 + 1, label, votes))
+            .collect();
+        model::Poll::new("100", options)
+    }
+
+    #[test]
+    fn poll_fields() {
+        let now = created_at();
+        let votes = [("Yes", 1234), ("No", 766)];
+
+        let open = poll(&votes).with_end_datetime(now + chrono::Duration::hours(3));
+        let (name, value) = poll_field(&open, now);
+        assert_eq!(name, "Poll");
+        assert_eq!(
+            value,
+            "**Yes** \u{2014} 61.7% (1,234)\n\
+             **No** \u{2014} 38.3% (766)\n\
+             2,000 votes \u{b7} ends in 3h",
+        );
+
+        // closed by its status, or by its end time without one
+        let closed = [
+            poll(&votes).with_voting_status(model::PollVotingStatus::Closed),
+            poll(&votes).with_end_datetime(now - chrono::Duration::minutes(1)),
+        ];
+        for closed in closed {
+            let (name, value) = poll_field(&closed, now);
+            assert_eq!(name, "Poll (closed)");
+            assert_eq!(
+                value,
+                "**Yes** \u{2714} \u{2014} 61.7% (1,234)\n\
+                 **No** \u{2014} 38.3% (766)\n\
+                 2,000 votes \u{b7} final results",
+            );
+        }
+
+        let (_, value) = poll_field(&poll(&[("Yes", 0), ("No", 0)]), now);
+        assert_eq!(
+            value,
+            "**Yes** \u{2014} 0.0% (0)\n**No** \u{2014} 0.0% (0)\n0 votes \u{b7} open",
+        );
+        let (_, value) = poll_field(&poll(&[("Yes", 1), ("No", 0)]), now);
+        assert!(value.ends_with("\n1 vote \u{b7} open"), "{}", value);
+    }
+
+    #[test]
+    fn polls_render_as_fields() {
+        let tweet = tweet(&[]).with_poll_ids(vec![String::from("100")]);
+        let mut includes = includes(Vec::new());
+        // a poll missing from the includes is left out
+        let payload = render(&tweet, &includes, &Default::default());
+        assert!(payload["embeds"][0].get("fields").is_none());
+
+        includes.push_poll(poll(&[("Yes", 1), ("No", 0)]));
+        let payload = render(&tweet, &includes, &Default::default());
+        let fields = payload["embeds"][0]["fields"].as_array().unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0]["name"], "Poll");
+        assert!(fields[0].get("inline").is_none());
+    }
+
+    #[test]
+    fn trending_fields_show_growth() {
+        let now = created_at();
+        let mut info = TrendingInfo {
+            terms: vec![String::from("art"), String::from("fanart")],
+            score: 18.25,
+            previous_score: None,
+            retweet_delta: 0,
+            like_delta: 1500,
+            created_at: now - chrono::Duration::seconds(30),
+        };
+        let field = |name: &str, value: &str| (name.to_owned(), value.to_owned());
+
+        // first relay, right after the tweet was posted
+        assert_eq!(
+            trending_fields(&info, now),
+            [
+                field("Score", "18.2"),
+                field("Since tracked", "+0 RTs \u{b7} +1,500 likes"),
+                field("Posted", "just now"),
+                field("Search term", "art, fanart"),
+            ],
+        );
+
+        info.score = 42.0;
+        info.previous_score = Some(18.25);
+        info.retweet_delta = 12345;
+        // likes may be taken back
+        info.like_delta = -3;
+        let fields = trending_fields(&info, now + chrono::Duration::hours(5));
+        assert_eq!(fields[0], field("Score", "42.0 (was 18.2)"));
+        let growth = "+12,345 RTs \u{b7} -3 likes";
+        assert_eq!(fields[1], field("Since tracked", growth));
+        assert_eq!(fields[2], field("Posted", "5h ago"));
+
+        let options = RenderOptions {
+            fields,
+            ..Default::default()
+        };
+        let payload = render(&tweet(&[]), &includes(Vec::new()), &options);
+        let fields = payload["embeds"][0]["fields"].as_array().unwrap();
+        assert_eq!(fields.len(), 4);
+        assert!(fields.iter().all(|field| field["inline"] == true));
+    }
+
+    #[test]
+    fn renders_without_author() {
+        let tweet = tweet(&["p"]);
+        let mut includes = model::ResponseIncludes::default();
+        includes.push_media(photo("p"));
+        let payload = render(&tweet, &includes, &Default::default());
+
+        assert_eq!(payload["username"], "User 1");
+        assert!(payload.get("avatar_url").is_none());
+        let author = &payload["embeds"][0]["author"];
+        assert_eq!(author["name"], "User 1");
+        assert_eq!(author["url"], "https://twitter.com/i/user/1");
+        assert!(author.get("icon_url").is_none());
+        let tweet_url = "https://twitter.com/i/web/status/10";
+        assert_eq!(payload["embeds"][0]["url"], tweet_url);
+        assert_eq!(payload["content"], tweet_url);
+        assert_eq!(image_urls(&payload).len(), 1);
+    }
+
+    #[test]
+    fn renders_without_some_media() {
+        let tweet = tweet(&["p", "q", "r"]);
+        let includes = includes(vec![photo("q")]);
+        let payload = render(&tweet, &includes, &Default::default());
+        let image_url = "https://pbs.twimg.com/media/q.jpg?name=orig";
+        assert_eq!(image_urls(&payload), [image_url]);
+        assert_eq!(
+            payload["embeds"][0]["footer"]["text"],
+            "Twitter \u{b7} 2 media unavailable",
+        );
+    }
+
+    #[test]
+    fn retweet_needs_its_source() {
+        let retweet = model::Tweet::new("11", "RT @author: text")
+            .with_author_id("2")
+            .with_referenced_tweet(model::TweetReferenceType::Retweeted, "10");
+        let mut includes = includes(Vec::new());
+        let e = make_tweet_payload(&retweet, &includes, &Default::default()).unwrap_err();
+        let expected = DiscordRenderError::MissingReferencedTweet(String::from("10"));
+        assert_eq!(e, expected);
+
+        // the retweeted tweet is rendered, posted as the retweeter, who is shown by ID
+        includes.push_tweet(tweet(&[]));
+        let payload = render(&retweet, &includes, &Default::default());
+        assert_eq!(payload["username"], "User 2");
+        assert_eq!(payload["embeds"][0]["author"]["name"], "Author (@author)");
+        assert_eq!(payload["embeds"][0]["description"], "text");
+        assert_eq!(payload["content"], TWEET_URL);
+    }
+
+    #[test]
+    fn render_options_override_standard_rendering() {
+        let tweet = tweet(&["p", "q"]);
+        let includes = includes(vec![photo("p"), photo("q")]);
+        let standard = render(&tweet, &includes, &Default::default());
+        assert_eq!(standard["content"], TWEET_URL);
+        assert_eq!(standard["username"], "Author (@author)");
+        assert_eq!(standard["embeds"][0]["color"], DEFAULT_EMBED_COLOR);
+        assert_eq!(image_urls(&standard).len(), 2);
+
+        let options = RenderOptions {
+            content_prefix: Some(String::from("New art: ")),
+            content_suffix: Some(String::from(" #art")),
+            color: Some(0xff8800),
+            suppress_media: true,
+            username: Some(String::from("Art feed")),
+            ..Default::default()
+        };
+        let payload = render(&tweet, &includes, &options);
+        assert_eq!(payload["content"], format!("New art: {} #art", TWEET_URL));
+        assert_eq!(payload["embeds"][0]["color"], 0xff8800);
+        assert_eq!(payload["username"], "Art feed");
+        // the avatar of the author stays, only the name is overridden
+        assert_eq!(payload["avatar_url"], standard["avatar_url"]);
+        assert!(image_urls(&payload).is_empty());
+        assert_eq!(payload["embeds"].as_array().unwrap().len(), 1);
+        assert_eq!(description(&payload), description(&standard));
+    }
+
+    #[test]
+    fn payloads_allow_no_mentions() {
+        let no_mentions = json!({ "parse": [] });
+        let tweet = model::Tweet::new("10", "@everyone look").with_author_id("1");
+        let payload = render(&tweet, &includes(Vec::new()), &Default::default());
+        assert_eq!(payload["allowed_mentions"], no_mentions);
+        for identity in [WebhookIdentity::Author, WebhookIdentity::None] {
+            let notice = make_notice_payload("List `1` initialized", &identity).to_value();
+            assert_eq!(notice["allowed_mentions"], no_mentions);
+        }
+
+        // injected into payloads built elsewhere, e.g. by route scripts
+        let raw = json!({ "content": "@everyone" });
+        let payload = apply_allowed_mentions(&raw, &Default::default());
+        assert_eq!(payload["allowed_mentions"], no_mentions);
+        let raw = json!({ "content": "<@1>", "allowed_mentions": { "parse": ["users"] } });
+        let payload = apply_allowed_mentions(&raw, &Default::default());
+        assert_eq!(payload["allowed_mentions"], raw["allowed_mentions"]);
+
+        // options of the webhook override the payload
+        let options = ExecuteOptions {
+            allowed_mentions: Some(AllowedMentions {
+                parse: vec![MentionType::Users, MentionType::Roles],
+            }),
+            ..Default::default()
+        };
+        let payload = apply_allowed_mentions(&raw, &options);
+        let allowed = json!({ "parse": ["users", "roles"] });
+        assert_eq!(payload["allowed_mentions"], allowed);
+        assert_eq!(payload["content"], "<@1>");
+    }
+
+    #[test]
+    fn branding_options() {
+        let tweet = tweet(&[]);
+        let includes = includes(Vec::new());
+        let payload = render(&tweet, &includes, &Default::default());
+        let embed = &payload["embeds"][0];
+        assert_eq!(embed["color"], 1940464);
+        assert_eq!(
+            embed["footer"],
+            json!({ "text": "Twitter", "icon_url": DEFAULT_FOOTER_ICON_URL }),
+        );
+
+        let options = RenderOptions {
+            color: Some(0x00ff00),
+            footer_text: Some(String::from("Art feed")),
+            footer_icon_url: Some(String::from("https://example.com/icon.png")),
+            score: Some(42.06),
+            ..Default::default()
+        };
+        let payload = render(&tweet, &includes, &options);
+        let embed = &payload["embeds"][0];
+        assert_eq!(embed["color"], 0x00ff00);
+        assert_eq!(
+            embed["footer"],
+            json!({
+                "text": "Art feed \u{b7} score 42.1",
+                "icon_url": "https://example.com/icon.png",
+            }),
+        );
+
+        // the score follows the default footer too
+        let options = RenderOptions {
+            score: Some(0.0),
+            ..Default::default()
+        };
+        let payload = render(&tweet, &includes, &options);
+        assert_eq!(
+            payload["embeds"][0]["footer"]["text"],
+            "Twitter \u{b7} score 0.0",
+        );
+    }
+
+    #[test]
+    fn link_buttons_replace_tweet_url() {
+        let tweet = tweet(&["p"]);
+        let includes = includes(vec![photo("p")]);
+        let options = RenderOptions {
+            link_buttons: true,
+            media_buttons: true,
+            ..Default::default()
+        };
+        let payload = render(&tweet, &includes, &options);
+        assert!(payload.get("content").is_none());
+        let media_url = "https://pbs.twimg.com/media/p.jpg?name=orig";
+        assert_eq!(
+            buttons(&payload),
+            [
+                ("Open tweet", TWEET_URL),
+                ("Open profile", "https://twitter.com/author"),
+                ("Open media (orig)", media_url),
+            ],
+        );
+        assert_eq!(
+            payload["components"][0]["components"][0],
+            json!({ "type": 2, "style": 5, "label": "Open tweet", "url": TWEET_URL }),
+        );
+    }
+
+    #[test]
+    fn identity_modes() {
+        let tweet = tweet(&[]);
+        let includes = includes(Vec::new());
+        let author_avatar = "https://pbs.twimg.com/profile_images/1/icon.png";
+        let fixed = WebhookIdentity::Fixed {
+            username: String::from("Art feed"),
+            avatar_url: Some(String::from("https://example.com/bot.png")),
+        };
+        let fixed_name_only = WebhookIdentity::Fixed {
+            username: String::from("Art feed"),
+            avatar_url: None,
+        };
+        let author = Some("Author (@author)");
+        let cases = [
+            (WebhookIdentity::Author, author, Some(author_avatar)),
+            (fixed, Some("Art feed"), Some("https://example.com/bot.png")),
+            (fixed_name_only, Some("Art feed"), None),
+            (WebhookIdentity::None, None, None),
+        ];
+        for (identity, username, avatar_url) in cases {
+            let options = RenderOptions {
+                identity: identity.clone(),
+                ..Default::default()
             };
-            log::debug!("Webhook is ratelimited, retrying after {:?}", duration);
-            tokio::time::sleep(duration).await;
-        } else {
-            resp.error_for_status()?;
-            return Ok(());
+            let payload = render(&tweet, &includes, &options);
+            assert_eq!(identity_of(&payload), (username, avatar_url));
+            // the author is always in the embed
+            let embed_author = &payload["embeds"][0]["author"];
+            assert_eq!(embed_author["name"], "Author (@author)");
+            assert_eq!(embed_author["icon_url"], author_avatar);
+
+            // notices have no author to impersonate
+            let notice = make_notice_payload("notice", &identity).to_value();
+            let notice_identity = match identity {
+                WebhookIdentity::Author => (Some(DEFAULT_NOTICE_USERNAME), None),
+                _ => (username, avatar_url),
+            };
+            assert_eq!(identity_of(&notice), notice_identity);
         }
     }
 }
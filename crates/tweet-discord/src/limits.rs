@@ -0,0 +1,657 @@
+/// Maximum characters of message content.
+pub const MAX_CONTENT_CHARS: usize = 2000;
+/// Maximum number of embeds in a single message.
+pub const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+/// Maximum total characters across all embeds of a single message.
+pub const MAX_EMBED_CHARS_PER_MESSAGE: usize = 6000;
+
+/// Counts characters of an embed which count towards `MAX_EMBED_CHARS_PER_MESSAGE`.
+pub fn embed_char_count(embed: &serde_json::Value) -> usize {
+    fn count(value: Option<&serde_json::Value>) -> usize {
+        value
+            .and_then(|v| v.as_str())
+            .map(|s| s.chars().count())
+            .unwrap_or(0)
+    }
+
+    let mut ret = count(embed.get("title"))
+        + count(embed.get("description"))
+        + count(embed.pointer("/footer/text"))
+        + count(embed.pointer("/author/name"));
+    if let Some(fields) = embed.get("fields").and_then(|f| f.as_array()) {
+        for field in fields {
+            ret += count(field.get("name")) + count(field.get("value"));
+        }
+    }
+    ret
+}
+
+/// Splits a webhook payload into several messages so that each one stays within the embed count
+/// and embed character limits.
+///
+/// `content` and `components` are kept only on the first message; other top-level keys
+/// (username, avatar) are copied to every message.
+pub fn split_payload(payload: &serde_json::Value) -> Vec<serde_json::Value> {
+    let (base, embeds) = match (
+        payload.as_object(),
+        payload.get("embeds").and_then(|e| e.as_array()),
+    ) {
+        (Some(base), Some(embeds)) => (base, embeds),
+        _ => return vec![payload.clone()],
+    };
+
+    let mut chunks = Vec::<Vec<&serde_json::Value>>::new();
+    let mut current = Vec::new();
+    let mut current_chars = 0usize;
+    for embed in embeds {
+        let chars = embed_char_count(embed);
+        let full = current.len() >= MAX_EMBEDS_PER_MESSAGE
+            || current_chars + chars > MAX_EMBED_CHARS_PER_MESSAGE;
+        if full && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_chars = 0;
+        }
+        current.push(embed);
+        current_chars += chars;
+    }
+    if chunks.is_empty() {
+        return vec![payload.clone()];
+    }
+    chunks.push(current);
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let mut message = base.clone();
+            if idx != 0 {
+                message.remove("content");
+                message.remove("components");
+            }
+            message.insert(
+                String::from("embeds"),
+                serde_json::Value::Array(chunk.into_iter().cloned().collect()),
+            );
+            serde_json::Value::Object(message)
+        })
+        .collect()
+}
+
+/// What to do with message content longer than `MAX_CONTENT_CHARS`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentOverflow {
+    /// Cut the content, appending an ellipsis.
+    #[default]
+    Truncate,
+    /// Send the rest of the content in follow-up messages.
+    Split,
+}
+
+/// Returns the byte index to cut `s` at so that the first part has at most `max_chars`
+/// characters, preferring a line break or whitespace in the latter half.
+fn find_cut(s: &str, max_chars: usize) -> usize {
+    let hard_cut = s
+        .char_indices()
+        .nth(max_chars)
+        .map(|(idx, _)| idx)
+        .unwrap_or(s.len());
+    if hard_cut == s.len() {
+        return hard_cut;
+    }
+
+    let window = &s[..hard_cut];
+    let min_cut = window
+        .char_indices()
+        .nth(max_chars / 2)
+        .map(|(idx, _)| idx)
+        .unwrap_or(0);
+    window
+        .rfind('\n')
+        .filter(|&idx| idx >= min_cut)
+        .or_else(|| {
+            window
+                .rfind(char::is_whitespace)
+                .filter(|&idx| idx >= min_cut)
+        })
+        .unwrap_or(hard_cut)
+}
+
+/// Truncates `s` to at most `max_chars` characters, including the trailing ellipsis.
+pub fn truncate_text(s: &str, max_chars: usize) -> String {
+    if s.chars().count() <= max_chars {
+        return s.to_owned();
+    }
+    let cut = find_cut(s, max_chars.saturating_sub(1));
+    let mut ret = s[..cut].trim_end().to_owned();
+    ret.push('\u{2026}');
+    ret
+}
+
+/// Splits `s` into pieces of at most `max_chars` characters.
+pub fn split_text(s: &str, max_chars: usize) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut rest = s;
+    while rest.chars().count() > max_chars {
+        let cut = find_cut(rest, max_chars);
+        let (piece, next) = rest.split_at(cut);
+        let piece = piece.trim_end();
+        if !piece.is_empty() {
+            ret.push(piece.to_owned());
+        }
+        rest = next.trim_start();
+    }
+    if !rest.is_empty() || ret.is_empty() {
+        ret.push(rest.to_owned());
+    }
+    ret
+}
+
+/// Makes `content` of the payload fit in `MAX_CONTENT_CHARS`, returning the messages to send.
+pub fn apply_content_limit(
+    payload: &serde_json::Value,
+    overflow: ContentOverflow,
+) -> Vec<serde_json::Value> {
+    let content = match payload.get("content").and_then(|c| c.as_str()) {
+        Some(content) if content.chars().count() > MAX_CONTENT_CHARS => content,
+        _ => return vec![payload.clone()],
+    };
+    log::debug!(
+        "Message content has {} characters, applying {:?}",
+        content.chars().count(),
+        overflow,
+    );
+
+    let mut first = payload.clone();
+    match overflow {
+        ContentOverflow::Truncate => {
+            first["content"] = truncate_text(content, MAX_CONTENT_CHARS).into();
+            vec![first]
+        }
+        ContentOverflow::Split => {
+            let mut pieces = split_text(content, MAX_CONTENT_CHARS).into_iter();
+            first["content"] = pieces.next().unwrap_or_default().into();
+
+            let mut ret = vec![first];
+            for piece in pieces {
+                let mut message = serde_json::Map::new();
+                for key in ["username", "avatar_url", "allowed_mentions"] {
+                    if let Some(value) = payload.get(key) {
+                        message.insert(String::from(key), value.clone());
+                    }
+                }
+                message.insert(String::from("content"), piece.into());
+                ret.push(serde_json::Value::Object(message));
+            }
+            ret
+        }
+    }
+}
+
+/// Discord limit a payload exceeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LimitViolation {
+    /// Path of the offending value, e.g. `embeds[0].description`.
+    pub path: String,
+    pub limit: usize,
+    pub actual: usize,
+}
+
+impl std::fmt::Display for LimitViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} has {}, limit is {}",
+            self.path, self.actual, self.limit
+        )
+    }
+}
+
+fn char_count(value: Option<&serde_json::Value>) -> usize {
+    value
+        .and_then(|v| v.as_str())
+        .map(|s| s.chars().count())
+        .unwrap_or(0)
+}
+
+fn array_len(value: Option<&serde_json::Value>) -> usize {
+    value
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0)
+}
+
+/// Text fields of an embed with their limits, as JSON pointers relative to the embed.
+const EMBED_TEXT_LIMITS: [(&str, &str, usize); 4] = [
+    ("/title", "title", crate::embed::MAX_TITLE_CHARS),
+    (
+        "/description",
+        "description",
+        crate::embed::MAX_DESCRIPTION_CHARS,
+    ),
+    (
+        "/footer/text",
+        "footer.text",
+        crate::embed::MAX_FOOTER_CHARS,
+    ),
+    (
+        "/author/name",
+        "author.name",
+        crate::embed::MAX_AUTHOR_NAME_CHARS,
+    ),
+];
+
+/// Checks a single webhook message against Discord's limits.
+pub fn validate_payload(payload: &serde_json::Value) -> Vec<LimitViolation> {
+    let mut ret = Vec::new();
+    let mut check = |path: String, limit: usize, actual: usize| {
+        if actual > limit {
+            ret.push(LimitViolation {
+                path,
+                limit,
+                actual,
+            });
+        }
+    };
+
+    check(
+        String::from("content"),
+        MAX_CONTENT_CHARS,
+        char_count(payload.get("content")),
+    );
+    check(
+        String::from("username"),
+        crate::embed::MAX_USERNAME_CHARS,
+        char_count(payload.get("username")),
+    );
+
+    let embeds = payload
+        .get("embeds")
+        .and_then(|e| e.as_array())
+        .map(|e| &**e)
+        .unwrap_or(&[]);
+    check(String::from("embeds"), MAX_EMBEDS_PER_MESSAGE, embeds.len());
+    check(
+        String::from("embeds (total characters)"),
+        MAX_EMBED_CHARS_PER_MESSAGE,
+        embeds.iter().map(embed_char_count).sum(),
+    );
+    for (idx, embed) in embeds.iter().enumerate() {
+        for (pointer, name, limit) in EMBED_TEXT_LIMITS {
+            check(
+                format!("embeds[{}].{}", idx, name),
+                limit,
+                char_count(embed.pointer(pointer)),
+            );
+        }
+        check(
+            format!("embeds[{}].fields", idx),
+            crate::embed::MAX_FIELDS,
+            array_len(embed.get("fields")),
+        );
+        let fields = embed.get("fields").and_then(|f| f.as_array());
+        for (field_idx, field) in fields.into_iter().flatten().enumerate() {
+            check(
+                format!("embeds[{}].fields[{}].name", idx, field_idx),
+                crate::embed::MAX_FIELD_NAME_CHARS,
+                char_count(field.get("name")),
+            );
+            check(
+                format!("embeds[{}].fields[{}].value", idx, field_idx),
+                crate::embed::MAX_FIELD_VALUE_CHARS,
+                char_count(field.get("value")),
+            );
+        }
+    }
+
+    let rows = payload.get("components").and_then(|c| c.as_array());
+    check(
+        String::from("components"),
+        crate::embed::MAX_ACTION_ROWS,
+        rows.map(|r| r.len()).unwrap_or(0),
+    );
+    for (idx, row) in rows.into_iter().flatten().enumerate() {
+        check(
+            format!("components[{}].components", idx),
+            crate::embed::MAX_BUTTONS_PER_ROW,
+            array_len(row.get("components")),
+        );
+    }
+
+    ret
+}
+
+/// Truncates embed text fields and the username over their limits, dropping fields over
+/// `MAX_FIELDS`.
+pub fn truncate_fields(payload: &mut serde_json::Value) {
+    fn truncate_at(value: &mut serde_json::Value, pointer: &str, max_chars: usize) {
+        if let Some(v) = value.pointer_mut(pointer) {
+            if let Some(s) = v.as_str() {
+                if s.chars().count() > max_chars {
+                    *v = truncate_text(s, max_chars).into();
+                }
+            }
+        }
+    }
+
+    truncate_at(payload, "/username", crate::embed::MAX_USERNAME_CHARS);
+    let embeds = payload.get_mut("embeds").and_then(|e| e.as_array_mut());
+    for embed in embeds.into_iter().flatten() {
+        for (pointer, _, limit) in EMBED_TEXT_LIMITS {
+            truncate_at(embed, pointer, limit);
+        }
+        if let Some(fields) = embed.get_mut("fields").and_then(|f| f.as_array_mut()) {
+            fields.truncate(crate::embed::MAX_FIELDS);
+            for field in fields {
+                truncate_at(field, "/name", crate::embed::MAX_FIELD_NAME_CHARS);
+                truncate_at(field, "/value", crate::embed::MAX_FIELD_VALUE_CHARS);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn embed(chars: usize) -> serde_json::Value {
+        json!({ "description": "a".repeat(chars) })
+    }
+
+    fn payload(embeds: Vec<serde_json::Value>) -> serde_json::Value {
+        json!({
+            "content": "content",
+            "username": "name",
+            "components": [{ "type": 1, "components": [] }],
+            "embeds": embeds,
+        })
+    }
+
+    fn embed_counts(messages: &[serde_json::Value]) -> Vec<usize> {
+        messages
+            .iter()
+            .map(|message| array_len(message.get("embeds")))
+            .collect()
+    }
+
+    #[test]
+    fn splits_by_embed_count() {
+        let messages = split_payload(&payload(vec![embed(10); 11]));
+        assert_eq!(embed_counts(&messages), [10, 1]);
+
+        let messages = split_payload(&payload(vec![embed(10); 10]));
+        assert_eq!(embed_counts(&messages), [10]);
+    }
+
+    #[test]
+    fn splits_by_embed_chars() {
+        let messages = split_payload(&payload(vec![embed(2500); 3]));
+        assert_eq!(embed_counts(&messages), [2, 1]);
+
+        // exactly at the limit
+        let messages = split_payload(&payload(vec![embed(3000); 2]));
+        assert_eq!(embed_counts(&messages), [2]);
+    }
+
+    #[test]
+    fn oversize_embed_gets_own_message() {
+        let big = MAX_EMBED_CHARS_PER_MESSAGE + 1;
+        let messages = split_payload(&payload(vec![embed(big)]));
+        assert_eq!(embed_counts(&messages), [1]);
+
+        let messages = split_payload(&payload(vec![embed(10), embed(big), embed(10)]));
+        assert_eq!(embed_counts(&messages), [1, 1, 1]);
+        assert_eq!(embed_char_count(&messages[1]["embeds"][0]), big);
+    }
+
+    #[test]
+    fn content_and_components_only_on_first_chunk() {
+        let messages = split_payload(&payload(vec![embed(10); 21]));
+        assert_eq!(embed_counts(&messages), [10, 10, 1]);
+        assert_eq!(messages[0]["content"], "content");
+        assert_eq!(array_len(messages[0].get("components")), 1);
+        for message in &messages[1..] {
+            assert!(message.get("content").is_none());
+            assert!(message.get("components").is_none());
+        }
+        for message in &messages {
+            assert_eq!(message["username"], "name");
+        }
+    }
+
+    fn char_counts(pieces: &[String]) -> Vec<usize> {
+        pieces.iter().map(|piece| piece.chars().count()).collect()
+    }
+
+    #[test]
+    fn content_limit_boundaries() {
+        for len in [MAX_CONTENT_CHARS - 1, MAX_CONTENT_CHARS] {
+            let content = "a".repeat(len);
+            assert_eq!(truncate_text(&content, MAX_CONTENT_CHARS), content);
+            assert_eq!(split_text(&content, MAX_CONTENT_CHARS), [content.as_str()]);
+            let message = json!({ "content": content });
+            for overflow in [ContentOverflow::Truncate, ContentOverflow::Split] {
+                let messages = apply_content_limit(&message, overflow);
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0], message);
+            }
+        }
+
+        let content = "a".repeat(MAX_CONTENT_CHARS + 1);
+        let truncated = truncate_text(&content, MAX_CONTENT_CHARS);
+        assert_eq!(truncated.chars().count(), MAX_CONTENT_CHARS);
+        assert!(truncated.ends_with('\u{2026}'));
+        let pieces = split_text(&content, MAX_CONTENT_CHARS);
+        assert_eq!(char_counts(&pieces), [MAX_CONTENT_CHARS, 1]);
+    }
+
+    #[test]
+    fn content_overflow_policies() {
+        let content = "a".repeat(MAX_CONTENT_CHARS + 1);
+        let message = json!({ "content": content, "username": "name", "embeds": [embed(10)] });
+
+        let messages = apply_content_limit(&message, ContentOverflow::Truncate);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(char_count(messages[0].get("content")), MAX_CONTENT_CHARS);
+
+        let messages = apply_content_limit(&message, ContentOverflow::Split);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(char_count(messages[0].get("content")), MAX_CONTENT_CHARS);
+        assert_eq!(array_len(messages[0].get("embeds")), 1);
+        // follow-ups keep the identity, but not the embeds
+        assert_eq!(messages[1], json!({ "content": "a", "username": "name" }));
+    }
+
+    #[test]
+    fn cuts_at_word_boundaries() {
+        assert_eq!(truncate_text("one two three", 10), "one two\u{2026}");
+        assert_eq!(split_text("one two three", 8), ["one two", "three"]);
+        // line breaks are preferred over spaces
+        assert_eq!(split_text("aaaa\nbb cc", 8), ["aaaa", "bb cc"]);
+        // no boundary in the latter half, cut in the middle of the word
+        assert_eq!(truncate_text("a bcdefghijk", 6), "a bcd\u{2026}");
+    }
+
+    #[test]
+    fn cuts_are_char_safe() {
+        let content = "\u{ac00}".repeat(MAX_CONTENT_CHARS + 1);
+        let truncated = truncate_text(&content, MAX_CONTENT_CHARS);
+        assert_eq!(truncated.chars().count(), MAX_CONTENT_CHARS);
+
+        let content = "\u{1f600}".repeat(MAX_CONTENT_CHARS + 1000);
+        let pieces = split_text(&content, MAX_CONTENT_CHARS);
+        assert_eq!(char_counts(&pieces), [MAX_CONTENT_CHARS, 1000]);
+
+        let text = "\u{1f600}\u{1f600} \u{1f600}\u{1f600}\u{1f600}";
+        assert_eq!(truncate_text(text, 5), "\u{1f600}\u{1f600}\u{2026}");
+        let smileys = |count| "\u{1f600}".repeat(count);
+        assert_eq!(split_text(text, 4), [smileys(2), smileys(3)]);
+    }
+
+    fn text(chars: usize) -> String {
+        "a".repeat(chars)
+    }
+
+    fn fields(count: usize, name: usize, value: usize) -> serde_json::Value {
+        let field = json!({ "name": text(name), "value": text(value) });
+        json!([{ "fields": vec![field; count] }])
+    }
+
+    fn rows(count: usize, buttons: usize) -> serde_json::Value {
+        let button = json!({ "type": 2, "style": 5, "label": "a", "url": "https://a" });
+        let row = json!({ "type": 1, "components": vec![button; buttons] });
+        json!(vec![row; count])
+    }
+
+    /// Top-level key, its value for a count of characters or items, the limit of the count and
+    /// the path of its violation.
+    type Boundary = (
+        &'static str,
+        fn(usize) -> serde_json::Value,
+        usize,
+        &'static str,
+    );
+
+    const BOUNDARIES: [Boundary; 13] = [
+        ("content", |n| json!(text(n)), MAX_CONTENT_CHARS, "content"),
+        (
+            "username",
+            |n| json!(text(n)),
+            crate::embed::MAX_USERNAME_CHARS,
+            "username",
+        ),
+        (
+            "embeds",
+            |n| json!(vec![embed(10); n]),
+            MAX_EMBEDS_PER_MESSAGE,
+            "embeds",
+        ),
+        (
+            "embeds",
+            |n| json!([embed(3000), embed(n - 3000)]),
+            MAX_EMBED_CHARS_PER_MESSAGE,
+            "embeds (total characters)",
+        ),
+        (
+            "embeds",
+            |n| json!([{ "title": text(n) }]),
+            crate::embed::MAX_TITLE_CHARS,
+            "embeds[0].title",
+        ),
+        (
+            "embeds",
+            |n| json!([embed(n)]),
+            crate::embed::MAX_DESCRIPTION_CHARS,
+            "embeds[0].description",
+        ),
+        (
+            "embeds",
+            |n| json!([{ "footer": { "text": text(n) } }]),
+            crate::embed::MAX_FOOTER_CHARS,
+            "embeds[0].footer.text",
+        ),
+        (
+            "embeds",
+            |n| json!([{ "author": { "name": text(n) } }]),
+            crate::embed::MAX_AUTHOR_NAME_CHARS,
+            "embeds[0].author.name",
+        ),
+        (
+            "embeds",
+            |n| fields(n, 1, 1),
+            crate::embed::MAX_FIELDS,
+            "embeds[0].fields",
+        ),
+        (
+            "embeds",
+            |n| fields(1, n, 1),
+            crate::embed::MAX_FIELD_NAME_CHARS,
+            "embeds[0].fields[0].name",
+        ),
+        (
+            "embeds",
+            |n| fields(1, 1, n),
+            crate::embed::MAX_FIELD_VALUE_CHARS,
+            "embeds[0].fields[0].value",
+        ),
+        (
+            "components",
+            |n| rows(n, 1),
+            crate::embed::MAX_ACTION_ROWS,
+            "components",
+        ),
+        (
+            "components",
+            |n| rows(1, n),
+            crate::embed::MAX_BUTTONS_PER_ROW,
+            "components[0].components",
+        ),
+    ];
+
+    #[test]
+    fn validates_each_limit() {
+        for (key, make, limit, path) in BOUNDARIES {
+            let at_limit = json!({ key: make(limit) });
+            assert_eq!(validate_payload(&at_limit), [], "{} at the limit", path);
+
+            let over = json!({ key: make(limit + 1) });
+            let violations = validate_payload(&over);
+            let expected = LimitViolation {
+                path: String::from(path),
+                limit,
+                actual: limit + 1,
+            };
+            assert_eq!(violations, [expected], "{} over the limit", path);
+        }
+    }
+
+    #[test]
+    fn truncates_fields_to_limits() {
+        let mut fields = vec![json!({ "name": "a", "value": "a" }); crate::embed::MAX_FIELDS];
+        fields[0] = json!({
+            "name": text(crate::embed::MAX_FIELD_NAME_CHARS + 1),
+            "value": text(crate::embed::MAX_FIELD_VALUE_CHARS + 1),
+        });
+        let mut payload = json!({
+            "username": text(crate::embed::MAX_USERNAME_CHARS + 1),
+            "embeds": [{
+                "title": text(crate::embed::MAX_TITLE_CHARS + 1),
+                "footer": { "text": text(crate::embed::MAX_FOOTER_CHARS) },
+                "author": { "name": text(crate::embed::MAX_AUTHOR_NAME_CHARS + 1) },
+                "fields": fields,
+            }],
+        });
+        payload["embeds"][0]["fields"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "name": "dropped", "value": "a" }));
+        assert_eq!(validate_payload(&payload).len(), 6);
+
+        truncate_fields(&mut payload);
+        assert_eq!(validate_payload(&payload), []);
+        let embed = &payload["embeds"][0];
+        let field = &embed["fields"][0];
+        let counts = [
+            char_count(payload.get("username")),
+            char_count(embed.get("title")),
+            char_count(embed.pointer("/author/name")),
+            char_count(field.get("name")),
+            char_count(field.get("value")),
+            array_len(embed.get("fields")),
+        ];
+        let limits = [
+            crate::embed::MAX_USERNAME_CHARS,
+            crate::embed::MAX_TITLE_CHARS,
+            crate::embed::MAX_AUTHOR_NAME_CHARS,
+            crate::embed::MAX_FIELD_NAME_CHARS,
+            crate::embed::MAX_FIELD_VALUE_CHARS,
+            crate::embed::MAX_FIELDS,
+        ];
+        assert_eq!(counts, limits);
+        // values at the limit are left as is
+        let footer = text(crate::embed::MAX_FOOTER_CHARS);
+        assert_eq!(embed["footer"]["text"], footer);
+    }
+}
@@ -0,0 +1,195 @@
+/// File uploaded along with a webhook message.
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+impl Attachment {
+    /// Downloads the file at `url`, naming it after the last path segment of the URL.
+    pub async fn download(
+        client: &reqwest::Client,
+        url: &reqwest::Url,
+        spoiler: bool,
+    ) -> reqwest::Result<Self> {
+        let data = client
+            .get(url.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        Ok(Self {
+            filename: filename_for(url, spoiler),
+            data: data.to_vec(),
+        })
+    }
+
+    /// Downloads the file at `url` like `download`, giving up with `None` once it turns out to
+    /// be larger than `max_bytes`.
+    pub async fn download_limited(
+        client: &reqwest::Client,
+        url: &reqwest::Url,
+        spoiler: bool,
+        max_bytes: u64,
+    ) -> reqwest::Result<Option<Self>> {
+        let mut resp = client.get(url.clone()).send().await?.error_for_status()?;
+        if resp.content_length().map(|len| len > max_bytes) == Some(true) {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = resp.chunk().await? {
+            if (data.len() + chunk.len()) as u64 > max_bytes {
+                return Ok(None);
+            }
+            data.extend_from_slice(&chunk);
+        }
+        Ok(Some(Self {
+            filename: filename_for(url, spoiler),
+            data,
+        }))
+    }
+
+    /// URL referencing the attachment from embeds of the same message.
+    pub fn reference_url(&self) -> String {
+        format!("attachment://{}", self.filename)
+    }
+}
+
+fn filename_for(url: &reqwest::Url, spoiler: bool) -> String {
+    let name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("media");
+    if spoiler {
+        format!("SPOILER_{}", name)
+    } else {
+        name.to_owned()
+    }
+}
+
+/// Returns whether `value` has the string `needle` anywhere inside.
+fn contains_str(value: &serde_json::Value, needle: &str) -> bool {
+    match value {
+        serde_json::Value::String(s) => s == needle,
+        serde_json::Value::Array(items) => items.iter().any(|item| contains_str(item, needle)),
+        serde_json::Value::Object(map) => map.values().any(|item| contains_str(item, needle)),
+        _ => false,
+    }
+}
+
+/// Assigns `attachments` to the split `messages` of a payload, each one to the first message
+/// whose embeds reference it, as embeds can only show attachments of their own message.
+/// Attachments not referenced by any embed, e.g. spoilered media, go with the first message.
+pub(crate) fn distribute<'a>(
+    messages: &[serde_json::Value],
+    attachments: &'a [Attachment],
+) -> Vec<Vec<&'a Attachment>> {
+    let mut ret = vec![Vec::new(); messages.len()];
+    for attachment in attachments {
+        let reference = attachment.reference_url();
+        let idx = messages
+            .iter()
+            .position(|message| match message.get("embeds") {
+                Some(embeds) => contains_str(embeds, &reference),
+                None => false,
+            })
+            .unwrap_or(0);
+        if let Some(message_attachments) = ret.get_mut(idx) {
+            message_attachments.push(attachment);
+        }
+    }
+    ret
+}
+
+/// Encodes a webhook payload with attachments as `multipart/form-data`, returning the content
+/// type and the body.
+pub(crate) fn encode(
+    payload: &serde_json::Value,
+    attachments: &[&Attachment],
+) -> (String, Vec<u8>) {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let boundary = format!("tweet-discord-{:x}", nanos);
+
+    let mut body = Vec::new();
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\nContent-Type: application/json\r\n\r\n",
+            boundary,
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(serde_json::to_string(payload).unwrap().as_bytes());
+    body.extend_from_slice(b"\r\n");
+    for (idx, attachment) in attachments.iter().enumerate() {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"files[{}]\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                boundary,
+                idx,
+                attachment.filename.replace('"', "_"),
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&attachment.data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attachment(filename: &str) -> Attachment {
+        Attachment {
+            filename: filename.to_owned(),
+            data: Vec::new(),
+        }
+    }
+
+    fn message(images: &[&str]) -> serde_json::Value {
+        let embeds = images
+            .iter()
+            .map(|image| serde_json::json!({ "image": { "url": image } }))
+            .collect::<Vec<_>>();
+        serde_json::json!({ "embeds": embeds })
+    }
+
+    fn filenames<'a>(messages: &[Vec<&'a Attachment>]) -> Vec<Vec<&'a str>> {
+        messages
+            .iter()
+            .map(|attachments| attachments.iter().map(|a| &*a.filename).collect())
+            .collect()
+    }
+
+    #[test]
+    fn attachments_follow_their_embeds() {
+        let messages = [
+            message(&["attachment://a.jpg", "https://pbs.twimg.com/media/b.jpg"]),
+            message(&["attachment://c.jpg"]),
+            message(&["attachment://d.png", "attachment://e.png"]),
+        ];
+        let attachments = ["e.png", "c.jpg", "a.jpg", "d.png"].map(attachment);
+        let distributed = distribute(&messages, &attachments);
+        assert_eq!(
+            filenames(&distributed),
+            [vec!["a.jpg"], vec!["c.jpg"], vec!["e.png", "d.png"]],
+        );
+    }
+
+    #[test]
+    fn unreferenced_attachments_go_first() {
+        let messages = [message(&[]), message(&["attachment://b.jpg"])];
+        let attachments = ["SPOILER_a.jpg", "b.jpg"].map(attachment);
+        let distributed = distribute(&messages, &attachments);
+        assert_eq!(filenames(&distributed), [vec!["SPOILER_a.jpg"], vec!["b.jpg"]]);
+        assert!(distribute(&[], &attachments).is_empty());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::error::{describe_error, redact_url, WebhookError};
+
+/// Hosts serving the Discord API, including the test clients.
+const DISCORD_HOSTS: &[&str] = &[
+    "discord.com",
+    "discordapp.com",
+    "ptb.discord.com",
+    "canary.discord.com",
+];
+
+/// ID and token of a Discord webhook, as given by its URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookInfo {
+    pub id: u64,
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum WebhookUrlError {
+    #[error("not an HTTPS URL")]
+    NotHttps,
+    #[error("host {0} is not Discord")]
+    UnknownHost(String),
+    #[error("not a webhook URL, expected /api/webhooks/{{id}}/{{token}}")]
+    NotWebhook,
+    #[error("webhook ID is not numeric")]
+    InvalidId,
+    #[error("webhook token is missing")]
+    MissingToken,
+    #[error("unexpected path segments after the webhook token")]
+    TrailingSegments,
+}
+
+/// Checks that `url` is a Discord webhook URL, `https://discord.com/api/webhooks/{id}/{token}`,
+/// and returns its ID and token.
+///
+/// Versioned API paths such as `/api/v10/webhooks/...` are accepted too.
+pub fn validate_webhook_url(url: &reqwest::Url) -> Result<WebhookInfo, WebhookUrlError> {
+    if url.scheme() != "https" {
+        return Err(WebhookUrlError::NotHttps);
+    }
+    let host = url.host_str().unwrap_or_default();
+    if !DISCORD_HOSTS.contains(&host) {
+        return Err(WebhookUrlError::UnknownHost(host.to_owned()));
+    }
+
+    let segments = url
+        .path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let rest = match &segments[..] {
+        ["api", "webhooks", rest @ ..] => rest,
+        ["api", version, "webhooks", rest @ ..]
+            if version.strip_prefix('v').and_then(|v| v.parse::<u32>().ok()).is_some() =>
+        {
+            rest
+        }
+        _ => return Err(WebhookUrlError::NotWebhook),
+    };
+    match rest {
+        [] => Err(WebhookUrlError::NotWebhook),
+        [id, ..] if id.parse::<u64>().is_err() => Err(WebhookUrlError::InvalidId),
+        [_] => Err(WebhookUrlError::MissingToken),
+        [id, token] => Ok(WebhookInfo {
+            id: id.parse().unwrap(),
+            token: token.to_string(),
+        }),
+        _ => Err(WebhookUrlError::TrailingSegments),
+    }
+}
+
+/// Fetches the webhook at `url` to check that it exists and its token is valid.
+///
+/// Unlike deliveries, the request is not retried.
+pub async fn probe_webhook(client: &reqwest::Client, url: &reqwest::Url) -> Result<(), WebhookError> {
+    let resp = client
+        .get(url.clone())
+        .send()
+        .await
+        .map_err(|e| WebhookError::RetriesExhausted {
+            url: redact_url(url),
+            attempts: 1,
+            reason: describe_error(&e),
+        })?;
+    match resp.status() {
+        status if status.is_success() => Ok(()),
+        reqwest::StatusCode::NOT_FOUND => Err(WebhookError::Gone(redact_url(url))),
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+            Err(WebhookError::Unauthorized(redact_url(url)))
+        }
+        status => Err(WebhookError::Rejected {
+            url: redact_url(url),
+            status,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(url: &str) -> Result<WebhookInfo, WebhookUrlError> {
+        validate_webhook_url(&url.parse().unwrap())
+    }
+
+    #[test]
+    fn accepts_webhook_urls() {
+        let expected = WebhookInfo {
+            id: 123,
+            token: String::from("abc-DEF_456"),
+        };
+        for url in [
+            "https://discord.com/api/webhooks/123/abc-DEF_456",
+            "https://discordapp.com/api/webhooks/123/abc-DEF_456",
+            "https://ptb.discord.com/api/webhooks/123/abc-DEF_456",
+            "https://canary.discord.com/api/webhooks/123/abc-DEF_456",
+            "https://discord.com/api/v10/webhooks/123/abc-DEF_456",
+            "https://discord.com/api/v6/webhooks/123/abc-DEF_456/",
+            "https://discord.com/api/webhooks/123/abc-DEF_456?thread_id=1",
+        ] {
+            assert_eq!(validate(url).as_ref(), Ok(&expected), "{}", url);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_urls() {
+        use WebhookUrlError::*;
+
+        let unknown_host = |host: &str| Err(UnknownHost(host.to_owned()));
+        assert_eq!(
+            validate("http://discord.com/api/webhooks/123/token"),
+            Err(NotHttps),
+        );
+        assert_eq!(
+            validate("https://example.com/api/webhooks/123/token"),
+            unknown_host("example.com"),
+        );
+        assert_eq!(
+            validate("https://discord.com.evil.test/api/webhooks/123/token"),
+            unknown_host("discord.com.evil.test"),
+        );
+
+        for (path, expected) in [
+            ("/api/channels/123/token", NotWebhook),
+            ("/api/vnext/webhooks/123/token", NotWebhook),
+            ("/webhooks/123/token", NotWebhook),
+            ("/api/webhooks", NotWebhook),
+            ("/api/webhooks/abc/token", InvalidId),
+            ("/api/webhooks/-1/token", InvalidId),
+            ("/api/webhooks/123", MissingToken),
+            ("/api/webhooks/123/", MissingToken),
+            ("/api/webhooks/123/token/github", TrailingSegments),
+            ("/api/v10/webhooks/123/token/slack", TrailingSegments),
+        ] {
+            let url = format!("https://discord.com{}", path);
+            assert_eq!(validate(&url), Err(expected), "{}", url);
+        }
+    }
+}
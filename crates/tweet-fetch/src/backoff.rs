@@ -1,8 +1,13 @@
 use futures_util::future::BoxFuture;
 
+use tweet_model as model;
+
+type ObserverFn = Box<dyn FnMut(BackoffType, u32, std::time::Duration) + Send>;
+
 #[non_exhaustive]
 pub struct Backoff {
     backoff_fn: Box<dyn FnMut(std::time::Duration) -> BoxFuture<'static, ()> + Send>,
+    observer: Option<ObserverFn>,
 }
 
 impl std::fmt::Debug for Backoff {
@@ -15,6 +20,7 @@ impl Default for Backoff {
     fn default() -> Self {
         Self {
             backoff_fn: Box::new(Backoff::default_backoff_fn),
+            observer: None,
         }
     }
 }
@@ -37,6 +43,15 @@ impl Backoff {
         self.backoff_fn = Box::new(f);
     }
 
+    /// Calls `f` with the kind, the number of consecutive failures and the duration of every
+    /// backoff, before waiting.
+    pub fn on_backoff(
+        &mut self,
+        f: impl FnMut(BackoffType, u32, std::time::Duration) + Send + 'static,
+    ) {
+        self.observer = Some(Box::new(f));
+    }
+
     pub async fn run_fn<Ret, Func, Fut>(&mut self, mut f: Func) -> Ret
     where
         Func: FnMut() -> Fut,
@@ -46,6 +61,15 @@ impl Backoff {
         loop {
             if state.should_backoff() {
                 let duration = std::time::Duration::from_millis(state.sleep_msecs());
+                model::metrics::increment_counter(
+                    model::metrics::BACKOFF_SLEEPS,
+                    &[("kind", state.kind_label())],
+                );
+                if let (Some(observer), Some((kind, failures))) =
+                    (&mut self.observer, state.kind())
+                {
+                    observer(kind, failures, duration);
+                }
                 (self.backoff_fn)(duration).await;
             }
 
@@ -66,6 +90,16 @@ pub enum BackoffType {
     Network,
 }
 
+impl std::fmt::Display for BackoffType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ratelimit => "rate limit",
+            Self::Server => "server error",
+            Self::Network => "network error",
+        })
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 enum BackoffState {
     None,
@@ -104,6 +138,24 @@ impl BackoffState {
         }
     }
 
+    fn kind_label(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Ratelimit(_) => "ratelimit",
+            Self::Server(_) => "server",
+            Self::Network(_) => "network",
+        }
+    }
+
+    fn kind(&self) -> Option<(BackoffType, u32)> {
+        match *self {
+            Self::None => None,
+            Self::Ratelimit(n) => Some((BackoffType::Ratelimit, n)),
+            Self::Server(n) => Some((BackoffType::Server, n)),
+            Self::Network(n) => Some((BackoffType::Network, n)),
+        }
+    }
+
     fn should_backoff(&self) -> bool {
         !matches!(self, Self::None)
     }
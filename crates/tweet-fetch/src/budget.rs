@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::header::HeaderMap;
+
+use tweet_model as model;
+
+/// Priority of requests, deciding how much of a rate limit window they may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Requests which can be skipped, e.g. metric updates of tracked tweets. Skipped when less
+    /// than a quarter of the window remains.
+    Low,
+    /// Regular polling. Waits for the next window when less than a tenth remains.
+    Normal,
+    /// Requests whose failure loses data, e.g. augmenting stream tweets. Uses up the window.
+    High,
+}
+
+impl Priority {
+    /// Fraction of the window kept for higher priorities.
+    fn reserve(self) -> f64 {
+        match self {
+            Self::Low => 0.25,
+            Self::Normal => 0.1,
+            Self::High => 0.0,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    limit: u32,
+    remaining: u32,
+    reset_at: SystemTime,
+}
+
+/// Rate limit windows of Twitter API endpoints, shared by clones of a `TwitterClient`.
+///
+/// Windows are learned from the `x-rate-limit-*` headers of responses. Endpoints without a
+/// known window are not limited.
+#[derive(Debug, Default)]
+pub struct ApiBudget {
+    windows: Mutex<HashMap<&'static str, Window>>,
+}
+
+/// Outcome of asking the budget for a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Admission {
+    Proceed,
+    /// Wait this long, until the window resets.
+    Wait(Duration),
+    /// Skip the request, the window resets in this long.
+    Skip(Duration),
+}
+
+impl ApiBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the window of `endpoint` from response headers.
+    pub fn update(&self, endpoint: &'static str, headers: &HeaderMap) {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+        };
+        let (limit, remaining, reset) = match (
+            header("x-rate-limit-limit"),
+            header("x-rate-limit-remaining"),
+            header("x-rate-limit-reset"),
+        ) {
+            (Some(limit), Some(remaining), Some(reset)) => (limit, remaining, reset),
+            _ => return,
+        };
+        self.set_window(
+            endpoint,
+            limit as u32,
+            remaining as u32,
+            UNIX_EPOCH + Duration::from_secs(reset),
+        );
+    }
+
+    pub fn set_window(
+        &self,
+        endpoint: &'static str,
+        limit: u32,
+        remaining: u32,
+        reset_at: SystemTime,
+    ) {
+        self.windows.lock().unwrap().insert(
+            endpoint,
+            Window {
+                limit,
+                remaining,
+                reset_at,
+            },
+        );
+        model::metrics::set_gauge(
+            model::metrics::TWITTER_BUDGET_REMAINING,
+            &[("endpoint", endpoint)],
+            remaining as f64,
+        );
+    }
+
+    /// Returns the remaining requests and the limit of the current window of `endpoint`, if it's
+    /// known and hasn't reset by `now`.
+    pub fn remaining(&self, endpoint: &str, now: SystemTime) -> Option<(u32, u32)> {
+        let windows = self.windows.lock().unwrap();
+        windows
+            .get(endpoint)
+            .filter(|window| window.reset_at > now)
+            .map(|window| (window.remaining, window.limit))
+    }
+
+    /// Decides whether a request to `endpoint` may be sent now, counting it against the window
+    /// if so.
+    pub fn admit(&self, endpoint: &'static str, priority: Priority, now: SystemTime) -> Admission {
+        let mut windows = self.windows.lock().unwrap();
+        let window = match windows.get_mut(endpoint) {
+            Some(window) => window,
+            None => return Admission::Proceed,
+        };
+        let reset_in = match window.reset_at.duration_since(now) {
+            Ok(reset_in) => reset_in,
+            // the window has reset, the next response tells the new one
+            Err(_) => {
+                windows.remove(endpoint);
+                return Admission::Proceed;
+            }
+        };
+
+        let reserved = (window.limit as f64 * priority.reserve()).ceil() as u32;
+        if window.remaining > reserved {
+            window.remaining -= 1;
+            Admission::Proceed
+        } else if priority == Priority::Low {
+            Admission::Skip(reset_in)
+        } else {
+            Admission::Wait(reset_in)
+        }
+    }
+
+    /// Waits until a request to `endpoint` may be sent. Returns `false` if the request should
+    /// be skipped.
+    pub async fn acquire(&self, endpoint: &'static str, priority: Priority) -> bool {
+        loop {
+            let (outcome, duration) = match self.admit(endpoint, priority, SystemTime::now()) {
+                Admission::Proceed => return true,
+                Admission::Wait(duration) => ("wait", duration),
+                Admission::Skip(duration) => ("skip", duration),
+            };
+            model::metrics::increment_counter(
+                model::metrics::TWITTER_BUDGET_THROTTLED,
+                &[
+                    ("endpoint", endpoint),
+                    ("priority", priority.as_str()),
+                    ("outcome", outcome),
+                ],
+            );
+            if outcome == "skip" {
+                log::debug!(
+                    "Skipping {} request, budget low until reset in {} second(s)",
+                    endpoint,
+                    duration.as_secs(),
+                );
+                return false;
+            }
+            log::info!(
+                "Waiting {} second(s) for {} budget to reset",
+                duration.as_secs(),
+                endpoint,
+            );
+            tokio::time::sleep(duration + Duration::from_secs(1)).await;
+        }
+    }
+}
@@ -14,6 +14,8 @@ pub enum Error {
     ),
     #[error("stream closed")]
     StreamClosed,
+    #[error("API budget of {0} is low, request skipped")]
+    BudgetExhausted(&'static str),
     #[error("route error: {0}")]
     Route(
         #[from]
@@ -22,4 +24,15 @@ pub enum Error {
     ),
     #[error(transparent)]
     Twitter(#[from] tweet_model::ResponseError),
+    /// Twitter rejected the credentials with 401, or 403 naming the client, e.g. a revoked or
+    /// mistyped bearer token, or an app without access to the API.
+    #[error("unauthorized (HTTP {status}): {body}")]
+    Unauthorized { status: u16, body: String },
+}
+
+impl Error {
+    /// Returns whether Twitter rejected the credentials, which retrying won't fix.
+    pub fn is_unauthorized(&self) -> bool {
+        matches!(self, Self::Unauthorized { .. })
+    }
 }
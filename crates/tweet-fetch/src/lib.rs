@@ -1,16 +1,20 @@
 use std::ops::Deref;
+use std::sync::Arc;
 
 use reqwest::{
-    header::{self, HeaderMap, HeaderValue},
+    header::{self, HeaderValue},
     Client,
 };
 
 use tweet_model as model;
 
 pub mod backoff;
+mod budget;
 mod error;
 #[cfg(feature = "list")]
 mod list;
+#[cfg(feature = "stream")]
+mod rules;
 #[cfg(feature = "search")]
 mod search;
 #[cfg(feature = "stream")]
@@ -21,29 +25,28 @@ mod user;
 mod util;
 
 use concat_param;
+pub use budget::{Admission, ApiBudget, Priority};
 pub use error::Error;
 #[cfg(feature = "list")]
-pub use list::ListHead;
+pub use list::{GapInfo, ListHead};
 #[cfg(feature = "search")]
 pub use search::{SearchHead, SearchPager};
+#[cfg(feature = "stream")]
+pub use stream::StreamEvent;
 #[cfg(feature = "user")]
 pub use user::UserTimelineHead;
 
+/// Twitter API client. Clones share the connection pool and the rate limit budget.
 #[derive(Debug, Clone)]
 pub struct TwitterClient {
     client: reqwest::Client,
+    authorization: HeaderValue,
+    budget: Arc<ApiBudget>,
+    priority: Priority,
 }
 
 impl TwitterClient {
     pub fn new(token: impl AsRef<str>) -> Self {
-        let token = token.as_ref();
-
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            header::AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
-        );
-
         let client = Client::builder()
             .gzip(true)
             .brotli(true)
@@ -52,12 +55,61 @@ impl TwitterClient {
                 "/",
                 env!("CARGO_PKG_VERSION")
             ))
-            .default_headers(headers)
             .build()
             .expect("Failed to build HTTP client");
+        Self::with_client(token, client)
+    }
+
+    /// Creates a client sending requests with `client`, e.g. to share its connection pool or
+    /// middleware.
+    ///
+    /// The token is added to each request, so `client` needs no default headers. Compression and
+    /// the user agent, which `new` sets up, are up to the caller.
+    pub fn with_client(token: impl AsRef<str>, client: reqwest::Client) -> Self {
+        let mut authorization =
+            HeaderValue::from_str(&format!("Bearer {}", token.as_ref())).unwrap();
+        authorization.set_sensitive(true);
 
         Self {
             client,
+            authorization,
+            budget: Default::default(),
+            priority: Priority::Normal,
+        }
+    }
+
+    /// Starts a GET request with the token.
+    pub fn get(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.client
+            .get(url)
+            .header(header::AUTHORIZATION, self.authorization.clone())
+    }
+
+    /// Starts a POST request with the token.
+    pub fn post(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.client
+            .post(url)
+            .header(header::AUTHORIZATION, self.authorization.clone())
+    }
+
+    /// Returns a client sending requests with `priority`, sharing the budget with this one.
+    pub fn with_priority(&self, priority: Priority) -> Self {
+        Self {
+            priority,
+            ..self.clone()
+        }
+    }
+
+    pub fn budget(&self) -> &ApiBudget {
+        &self.budget
+    }
+
+    /// Waits until the budget allows a request to `endpoint` at the priority of the client.
+    pub(crate) async fn acquire(&self, endpoint: &'static str) -> Result<(), Error> {
+        if self.budget.acquire(endpoint, self.priority).await {
+            Ok(())
+        } else {
+            Err(Error::BudgetExhausted(endpoint))
         }
     }
 }
@@ -67,7 +119,7 @@ impl TwitterClient {
         &self,
         ids: &[impl AsRef<str>],
     ) -> Result<model::ResponseItem<Vec<model::Tweet>>, Error> {
-        use futures_util::{TryFutureExt, TryStreamExt};
+        use futures_util::{FutureExt, TryFutureExt, TryStreamExt};
 
         const TWEET_ENDPOINT: &str = "https://api.twitter.com/2/tweets";
         let mut url = TWEET_ENDPOINT.parse::<reqwest::Url>().unwrap();
@@ -78,10 +130,10 @@ impl TwitterClient {
             [id] => {
                 url.path_segments_mut().unwrap().push(id.as_ref());
 
-                let res = self
-                    .client
-                    .get(url)
-                    .send()
+                self.acquire("tweets").await?;
+                let resp = self.get(url).send().await;
+                util::record_request(self, "tweets", &resp);
+                let res = util::check_auth(resp?)
                     .await?
                     .error_for_status()?
                     .json::<model::TwitterResponse<model::Tweet>>()
@@ -102,6 +154,7 @@ impl TwitterClient {
                 let mut req_fut = futures_util::stream::FuturesOrdered::new();
                 for ids in ids.chunks(100) {
                     // 100 tweets at a time
+                    self.acquire("tweets").await?;
                     let mut id_param = String::new();
                     for (idx, id) in ids.iter().enumerate() {
                         if idx != 0 {
@@ -113,12 +166,13 @@ impl TwitterClient {
                     url.query_pairs_mut().append_pair("ids", &id_param).finish();
 
                     req_fut.push(
-                        self.client
-                            .get(url)
+                        self.get(url)
                             .send()
+                            .inspect(|resp| util::record_request(self, "tweets", resp))
                             .map_err(Error::from)
                             .and_then(|resp| async move {
-                                let resp = resp
+                                let resp = util::check_auth(resp)
+                                    .await?
                                     .error_for_status()?
                                     .json::<model::TwitterResponse<Vec<model::Tweet>>>()
                                     .await?
@@ -144,10 +198,23 @@ impl TwitterClient {
 
     #[cfg(feature = "stream")]
     pub fn make_stream(&self) -> impl futures_util::Stream<Item = Result<model::ResponseItem<model::Tweet, model::StreamMeta>, Error>> {
-        stream::make_stream(self.clone())
+        // augmenting loses media if it fails
+        stream::make_stream(self.with_priority(Priority::High), None)
+    }
+
+    /// Connects to the filtered stream like `make_stream`, calling `observer` on connection and
+    /// backoff events.
+    #[cfg(feature = "stream")]
+    pub fn make_observed_stream(
+        &self,
+        observer: impl Fn(StreamEvent) + Send + Sync + 'static,
+    ) -> impl futures_util::Stream<Item = Result<model::ResponseItem<model::Tweet, model::StreamMeta>, Error>> {
+        stream::make_stream(self.with_priority(Priority::High), Some(std::sync::Arc::new(observer)))
     }
 }
 
+/// Gives access to the underlying client. Requests made with it don't have the token, unlike the
+/// ones made with `TwitterClient::get` and `TwitterClient::post`.
 impl Deref for TwitterClient {
     type Target = reqwest::Client;
 
@@ -1,4 +1,6 @@
 use tweet_model as model;
+use model::trace::TraceExt;
+use model::iter::TweetIterExt;
 use crate::{
     util,
     concat_param,
@@ -6,6 +8,42 @@ use crate::{
     TwitterClient,
 };
 
+/// The list tweets endpoint only returns this many of the newest tweets of a list.
+const MAX_LIST_TWEETS: usize = 800;
+
+/// Tweets of a list which could not be fetched, because pagination ended before reaching the
+/// head.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GapInfo {
+    /// ID of the head, the newest tweet seen before the gap.
+    pub from_id: String,
+    /// ID of the oldest tweet fetched after the gap.
+    pub to_id: String,
+    /// Number of tweets in the gap, extrapolated from the rate of the fetched tweets.
+    pub estimated_missed: Option<u64>,
+}
+
+impl GapInfo {
+    fn new(from_id: &str, tweets: &[model::Tweet]) -> Option<Self> {
+        // tweets are newest first
+        let newest = tweets.first()?;
+        let oldest = tweets.last()?;
+        let timestamp = |id: &str| id.parse::<u64>().ok().map(|id| id >> 22);
+        let timestamps = (timestamp(from_id), timestamp(oldest.id()), timestamp(newest.id()));
+        let estimated_missed = match timestamps {
+            (Some(from), Some(oldest), Some(newest)) if newest > oldest && oldest > from => {
+                Some(tweets.len() as u64 * (oldest - from) / (newest - oldest))
+            }
+            _ => None,
+        };
+        Some(Self {
+            from_id: from_id.to_owned(),
+            to_id: oldest.id().to_owned(),
+            estimated_missed,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ListHead {
     id: String,
@@ -33,12 +71,16 @@ impl ListHead {
         self.head.as_deref()
     }
 
+    /// Fetches the tweets since the head, oldest first, and moves the head to the newest one.
+    ///
+    /// The meta of the result tells the tweets which could not be fetched, if any.
     pub async fn load_and_update(
         &mut self,
         client: &TwitterClient,
         catchup: bool,
-    ) -> Result<model::ResponseItem<Vec<model::Tweet>>, Error> {
-        let mut res = load_list_since(client, self, catchup).await?;
+    ) -> Result<model::ResponseItem<Vec<model::Tweet>, Option<GapInfo>>, Error> {
+        let span = model::span!("list_fetch", list_id = %self.id);
+        let mut res = load_list_since(client, self, catchup).in_span(span.clone()).await?;
         if let Some(last_tweet) = res.data.last() {
             let updating = self.head.is_some();
 
@@ -52,8 +94,9 @@ impl ListHead {
 
             // augment
             if updating && (!catchup || res.data.len() <= 5) {
-                let augment_data =
-                    util::load_batch_augment_data(client, &res.data, &res.includes).await?;
+                let augment_data = util::load_batch_augment_data(client, &res.data, &res.includes)
+                    .in_span(span)
+                    .await?;
                 if let Some(model::ResponseItem { includes, .. }) = augment_data {
                     res.includes.augment(includes);
                 }
@@ -74,7 +117,8 @@ fn create_endpoint_url(id: &str, max_results: u32, pagination_token: Option<&str
                 "author_id",
                 "referenced_tweets.id",
                 "referenced_tweets.id.author_id",
-                "attachments.media_keys"
+                "attachments.media_keys",
+                "attachments.poll_ids"
             ],
         )
         .append_pair(
@@ -83,27 +127,72 @@ fn create_endpoint_url(id: &str, max_results: u32, pagination_token: Option<&str
                 "created_at",
                 "entities",
                 "public_metrics",
-                "possibly_sensitive"
+                "possibly_sensitive",
+                "lang",
+                "conversation_id"
             ],
         )
         .append_pair(
             "user.fields",
-            concat_param!["profile_image_url", "public_metrics"],
+            concat_param!["created_at", "profile_image_url", "public_metrics"],
         )
         .append_pair(
             "media.fields",
-            concat_param!["width", "height", "url", "preview_image_url"],
+            concat_param![
+                "width",
+                "height",
+                "url",
+                "preview_image_url",
+                "duration_ms",
+                "variants"
+            ],
+        )
+        .append_pair(
+            "poll.fields",
+            concat_param!["duration_minutes", "end_datetime", "voting_status"],
         )
         .extend_pairs(pagination_token.map(|token| ("pagination_token", token)))
         .finish();
     url
 }
 
+/// What to do after fetching a page of list tweets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PageStep {
+    /// The page reached the head, so every tweet since the head is fetched.
+    ReachedHead,
+    /// Pagination ended before reaching the head, leaving a gap.
+    Gap,
+    /// Fetch the page of the token.
+    Next(String),
+}
+
+/// Decides what to do after a page of `result_count` tweets, of which `new_tweets` are newer
+/// than the head, with `fetched` new tweets so far.
+fn next_page(
+    new_tweets: usize,
+    result_count: i32,
+    next_token: Option<&str>,
+    fetched: usize,
+) -> PageStep {
+    // a page cut short by the head, or one with nothing new
+    if new_tweets == 0 || new_tweets as i32 != result_count {
+        return PageStep::ReachedHead;
+    }
+    if fetched >= MAX_LIST_TWEETS {
+        return PageStep::Gap;
+    }
+    match next_token {
+        Some(token) => PageStep::Next(token.to_owned()),
+        None => PageStep::Gap,
+    }
+}
+
 async fn load_list_since(
     client: &TwitterClient,
     list: &ListHead,
     catchup: bool,
-) -> Result<model::ResponseItem<Vec<model::Tweet>>, Error> {
+) -> Result<model::ResponseItem<Vec<model::Tweet>, Option<GapInfo>>, Error> {
     let list_id = &list.id;
     let since_id = list.head.as_deref();
     let max_results = if since_id.is_some() {
@@ -119,9 +208,10 @@ async fn load_list_since(
     let make_request = |token: Option<String>| {
         let url = create_endpoint_url(list_id, max_results, token.as_deref());
         async {
-            let base_ret = client
-                .get(url)
-                .send()
+            client.acquire("lists").await?;
+            let resp = client.get(url).send().await;
+            util::record_request(client, "lists", &resp);
+            let base_ret = util::check_auth(resp?)
                 .await?
                 .json::<model::TwitterResponse<Vec<model::Tweet>, model::ListMeta>>()
                 .await?;
@@ -136,15 +226,18 @@ async fn load_list_since(
     let since_id = if let Some(since_id) = since_id {
         since_id
     } else {
-        let (mut ret, _) = make_request(None).await?.take_meta();
-        ret.data.reverse();
-        return Ok(ret);
+        let model::ResponseItem { mut data, includes, .. } = make_request(None).await?;
+        data.reverse();
+        return Ok(model::ResponseItem {
+            data,
+            includes,
+            meta: None,
+        });
     };
 
     let mut ret = model::ResponseItem::<Vec<model::Tweet>>::default();
-    let mut next_token = Some(None::<String>);
-
-    while let Some(token) = next_token {
+    let mut token = None::<String>;
+    let reached_head = loop {
         let model::ResponseItem {
             data,
             includes,
@@ -153,19 +246,112 @@ async fn load_list_since(
 
         let data = data
             .into_iter()
-            .take_while(|item| item.id() > since_id)
+            .newer_than(since_id)
             .collect::<Vec<_>>();
-        let data_len = data.len();
+        let new_tweets = data.len();
 
         ret.data.extend(data);
         ret.includes.augment(includes);
 
-        if data_len as i32 != next_meta.result_count() {
-            break;
+        let step = next_page(
+            new_tweets,
+            next_meta.result_count(),
+            next_meta.next_token(),
+            ret.data.len(),
+        );
+        match step {
+            PageStep::ReachedHead => break true,
+            PageStep::Gap => break false,
+            PageStep::Next(next_token) => token = Some(next_token),
         }
-        next_token = next_meta.next_token().map(|s| Some(s.to_owned()));
-    }
+    };
 
+    let gap = if reached_head {
+        None
+    } else {
+        GapInfo::new(since_id, &ret.data)
+    };
+    if let Some(gap) = &gap {
+        log::debug!(
+            "List {}: pagination ended before reaching the head {}, oldest tweet fetched is {}",
+            list_id,
+            gap.from_id,
+            gap.to_id,
+        );
+    }
     ret.data.reverse();
-    Ok(ret)
+    Ok(model::ResponseItem {
+        data: ret.data,
+        includes: ret.includes,
+        meta: gap,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ID of a tweet posted at `timestamp`, in milliseconds since the Twitter epoch.
+    fn id_at(timestamp: u64) -> String {
+        (timestamp << 22).to_string()
+    }
+
+    fn tweets_at(timestamps: &[u64]) -> Vec<model::Tweet> {
+        timestamps
+            .iter()
+            .map(|&timestamp| model::Tweet::new(id_at(timestamp), "text"))
+            .collect()
+    }
+
+    #[test]
+    fn page_reaching_head_is_done() {
+        assert_eq!(next_page(3, 5, Some("next"), 3), PageStep::ReachedHead);
+        assert_eq!(next_page(0, 5, Some("next"), 10), PageStep::ReachedHead);
+        assert_eq!(next_page(0, 0, None, 0), PageStep::ReachedHead);
+    }
+
+    #[test]
+    fn full_page_continues() {
+        let step = next_page(5, 5, Some("next"), 10);
+        assert_eq!(step, PageStep::Next(String::from("next")));
+        assert_eq!(next_page(5, 5, None, 10), PageStep::Gap);
+    }
+
+    #[test]
+    fn page_cap_leaves_gap() {
+        let step = next_page(100, 100, Some("next"), MAX_LIST_TWEETS);
+        assert_eq!(step, PageStep::Gap);
+        let step = next_page(100, 100, Some("next"), MAX_LIST_TWEETS - 1);
+        assert_eq!(step, PageStep::Next(String::from("next")));
+    }
+
+    #[test]
+    fn gap_extrapolates_missed_tweets() {
+        // three tweets in 1000 ms, after a gap of 1000 ms
+        let tweets = tweets_at(&[3000, 2500, 2000]);
+        let gap = GapInfo::new(&id_at(1000), &tweets).unwrap();
+        assert_eq!(gap.from_id, id_at(1000));
+        assert_eq!(gap.to_id, id_at(2000));
+        assert_eq!(gap.estimated_missed, Some(3));
+
+        let gap = GapInfo::new(&id_at(1000), &tweets_at(&[3000, 2900])).unwrap();
+        assert_eq!(gap.estimated_missed, Some(2 * 1900 / 100));
+    }
+
+    #[test]
+    fn gap_without_rate_has_no_estimate() {
+        // a single tweet, or tweets at the same time
+        let gap = GapInfo::new(&id_at(1000), &tweets_at(&[2000])).unwrap();
+        assert_eq!(gap.estimated_missed, None);
+        let gap = GapInfo::new(&id_at(1000), &tweets_at(&[2000, 2000])).unwrap();
+        assert_eq!(gap.estimated_missed, None);
+        // IDs not from Snowflake
+        let gap = GapInfo::new("head", &tweets_at(&[3000, 2000])).unwrap();
+        assert_eq!(gap.estimated_missed, None);
+        // head newer than the tweets
+        let gap = GapInfo::new(&id_at(2500), &tweets_at(&[3000, 2000])).unwrap();
+        assert_eq!(gap.estimated_missed, None);
+
+        assert_eq!(GapInfo::new(&id_at(1000), &[]), None);
+    }
 }
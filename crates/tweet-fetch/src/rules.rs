@@ -0,0 +1,71 @@
+use tweet_model as model;
+use crate::{util, Error, TwitterClient};
+
+const RULES_ENDPOINT: &str = "https://api.twitter.com/2/tweets/search/stream/rules";
+
+fn create_endpoint_url(dry_run: bool) -> reqwest::Url {
+    let mut url = reqwest::Url::parse(RULES_ENDPOINT).unwrap();
+    if dry_run {
+        url.query_pairs_mut().append_pair("dry_run", "true").finish();
+    }
+    url
+}
+
+impl TwitterClient {
+    /// Returns the current rules of the filtered stream.
+    pub async fn stream_rules(&self) -> Result<Vec<model::StreamRule>, Error> {
+        self.acquire("rules").await?;
+        let resp = self.get(create_endpoint_url(false)).send().await;
+        util::record_request(self, "rules", &resp);
+        let rules = util::check_auth(resp?)
+            .await?
+            .error_for_status()?
+            .json::<model::StreamRules>()
+            .await?;
+        Ok(rules.into_rules())
+    }
+
+    /// Adds rules to the filtered stream.
+    ///
+    /// With `dry_run`, rules are only validated. Invalid and duplicate rules are returned in the
+    /// errors of the update, and the other rules are still added.
+    pub async fn add_stream_rules(
+        &self,
+        rules: &[model::NewStreamRule],
+        dry_run: bool,
+    ) -> Result<model::StreamRulesUpdate, Error> {
+        let body = serde_json::json!({ "add": rules });
+        self.update_stream_rules(&body, dry_run).await
+    }
+
+    /// Deletes rules of the filtered stream by ID.
+    pub async fn delete_stream_rules(
+        &self,
+        ids: &[impl AsRef<str>],
+        dry_run: bool,
+    ) -> Result<model::StreamRulesUpdate, Error> {
+        let ids = ids.iter().map(|id| id.as_ref()).collect::<Vec<_>>();
+        let body = serde_json::json!({ "delete": { "ids": ids } });
+        self.update_stream_rules(&body, dry_run).await
+    }
+
+    async fn update_stream_rules(
+        &self,
+        body: &serde_json::Value,
+        dry_run: bool,
+    ) -> Result<model::StreamRulesUpdate, Error> {
+        self.acquire("rules").await?;
+        let resp = self
+            .post(create_endpoint_url(dry_run))
+            .json(body)
+            .send()
+            .await;
+        util::record_request(self, "rules", &resp);
+        let update = util::check_auth(resp?)
+            .await?
+            .error_for_status()?
+            .json::<model::StreamRulesUpdate>()
+            .await?;
+        Ok(update)
+    }
+}
@@ -23,7 +23,8 @@ fn create_endpoint_url(
                 "author_id",
                 "referenced_tweets.id",
                 "referenced_tweets.id.author_id",
-                "attachments.media_keys"
+                "attachments.media_keys",
+                "attachments.poll_ids"
             ],
         )
         .append_pair(
@@ -32,16 +33,29 @@ fn create_endpoint_url(
                 "created_at",
                 "entities",
                 "public_metrics",
-                "possibly_sensitive"
+                "possibly_sensitive",
+                "lang",
+                "conversation_id"
             ],
         )
         .append_pair(
             "user.fields",
-            concat_param!["profile_image_url", "public_metrics"],
+            concat_param!["created_at", "profile_image_url", "public_metrics"],
         )
         .append_pair(
             "media.fields",
-            concat_param!["width", "height", "url", "preview_image_url"],
+            concat_param![
+                "width",
+                "height",
+                "url",
+                "preview_image_url",
+                "duration_ms",
+                "variants"
+            ],
+        )
+        .append_pair(
+            "poll.fields",
+            concat_param!["duration_minutes", "end_datetime", "voting_status"],
         )
         .extend_pairs(since_id.map(|id| ("since_id", id)))
         .extend_pairs(next_token.map(|token| ("next_token", token)))
@@ -53,11 +67,22 @@ fn create_endpoint_url(
 pub struct SearchHead {
     term: String,
     head: Option<String>,
+    max_results: Option<u32>,
 }
 
 impl SearchHead {
     pub fn new(term: String, head: Option<String>) -> Self {
-        Self { term, head }
+        Self {
+            term,
+            head,
+            max_results: None,
+        }
+    }
+
+    /// Sets the page size of fetches, between 10 and 100. By default the first fetch gets 20
+    /// tweets and later ones get pages of 100.
+    pub fn set_max_results(&mut self, max_results: Option<u32>) {
+        self.max_results = max_results;
     }
 }
 
@@ -127,17 +152,21 @@ impl SearchPager<'_> {
             next_token,
         );
 
+        client.acquire("search").await?;
         let mut backoff = crate::backoff::Backoff::new();
         let res = backoff.run_fn(move || {
             let url = url.clone();
             async {
-                match client.get(url).send().await {
+                let resp = client.get(url).send().await;
+                util::record_request(client, "search", &resp);
+                match resp {
                     Ok(v) => Ok(v),
                     Err(_) => Err(crate::backoff::BackoffType::Network),
                 }
             }
         }).await;
-        let res = res
+        let res = util::check_auth(res)
+            .await?
             .json::<model::TwitterResponse<Option<Vec<model::Tweet>>, model::SearchMeta>>()
             .await?
             .into_result()?;
@@ -167,7 +196,7 @@ impl SearchPager<'_> {
         client: &TwitterClient,
     ) -> Result<model::ResponseItem<Vec<model::Tweet>>, Error> {
         if self.is_unbound() {
-            let ret = self.next(client, 20).await?;
+            let ret = self.next(client, self.head.max_results.unwrap_or(20)).await?;
             self.apply_head();
             return Ok(if let Some(ret) = ret {
                 ret
@@ -177,7 +206,8 @@ impl SearchPager<'_> {
         }
 
         let mut ret = model::ResponseItem::<Vec<model::Tweet>>::default();
-        while let Some(tweets) = self.next(client, 100).await? {
+        let max_results = self.head.max_results.unwrap_or(100);
+        while let Some(tweets) = self.next(client, max_results).await? {
             let model::ResponseItem {
                 data,
                 includes,
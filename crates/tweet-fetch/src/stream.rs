@@ -1,3 +1,4 @@
+use std::sync::Arc;
 use std::time::Duration;
 
 use futures_util::{Stream, TryFutureExt};
@@ -12,6 +13,22 @@ use crate::{
     TwitterClient,
 };
 
+/// Lifecycle event of the filtered stream connection.
+#[derive(Debug, Clone, Copy)]
+pub enum StreamEvent {
+    /// Connected to the stream, possibly after backing off.
+    Connected,
+    /// Waiting `duration` before connecting again, after `failures` consecutive failures of
+    /// `kind`.
+    BackingOff {
+        kind: BackoffType,
+        failures: u32,
+        duration: Duration,
+    },
+}
+
+pub(crate) type StreamObserver = Arc<dyn Fn(StreamEvent) + Send + Sync>;
+
 fn create_endpoint_url() -> reqwest::Url {
     const STREAM_ENDPOINT: &str = "https://api.twitter.com/2/tweets/search/stream";
     let mut url = reqwest::Url::parse(STREAM_ENDPOINT).unwrap();
@@ -22,7 +39,8 @@ fn create_endpoint_url() -> reqwest::Url {
                 "author_id",
                 "referenced_tweets.id",
                 "referenced_tweets.id.author_id",
-                "attachments.media_keys"
+                "attachments.media_keys",
+                "attachments.poll_ids"
             ],
         )
         .append_pair(
@@ -31,42 +49,75 @@ fn create_endpoint_url() -> reqwest::Url {
                 "created_at",
                 "entities",
                 "public_metrics",
-                "possibly_sensitive"
+                "possibly_sensitive",
+                "lang",
+                "conversation_id"
             ],
         )
         .append_pair(
             "user.fields",
-            concat_param!["profile_image_url", "public_metrics"],
+            concat_param!["created_at", "profile_image_url", "public_metrics"],
         )
         .append_pair(
             "media.fields",
-            concat_param!["width", "height", "url", "preview_image_url"],
+            concat_param![
+                "width",
+                "height",
+                "url",
+                "preview_image_url",
+                "duration_ms",
+                "variants"
+            ],
+        )
+        .append_pair(
+            "poll.fields",
+            concat_param!["duration_minutes", "end_datetime", "voting_status"],
         )
         .finish();
     url
 }
 
-async fn connect_once(client: &reqwest::Client) -> reqwest::Result<reqwest::Response> {
-    client
-        .get(create_endpoint_url())
-        .send()
-        .await?
-        .error_for_status()
+async fn connect_once(client: &TwitterClient) -> Result<reqwest::Response, Error> {
+    // the stream client has high priority, which never skips
+    client.acquire("stream").await.ok();
+    let resp = client.get(create_endpoint_url()).send().await;
+    util::record_request(client, "stream", &resp);
+    Ok(util::check_auth(resp?).await?.error_for_status()?)
 }
 
-async fn connect_with_backoff(client: &TwitterClient) -> reqwest::Response {
+/// Connects to the filtered stream, retrying until it succeeds or Twitter rejects the
+/// credentials.
+async fn connect_with_backoff(
+    client: &TwitterClient,
+    observer: Option<&StreamObserver>,
+) -> Result<reqwest::Response, Error> {
     let mut backoff = Backoff::new();
     backoff.backoff_fn(|duration| {
         let sleep_msecs = duration.as_millis();
         info!("Waiting {} ms...", sleep_msecs);
         Box::pin(tokio::time::sleep(duration))
     });
+    if let Some(observer) = observer {
+        let observer = observer.clone();
+        backoff.on_backoff(move |kind, failures, duration| {
+            observer(StreamEvent::BackingOff {
+                kind,
+                failures,
+                duration,
+            });
+        });
+    }
 
     backoff
         .run_fn(|| async {
             let err = match connect_once(client).await {
-                Ok(resp) => return Ok(resp),
-                Err(err) => err,
+                Ok(resp) => return Ok(Ok(resp)),
+                Err(err @ Error::Unauthorized { .. }) => return Ok(Err(err)),
+                Err(Error::Http(err)) => err,
+                Err(err) => {
+                    error!("Unknown error: {}", err);
+                    return Err(BackoffType::Server);
+                }
             };
 
             if err.is_connect() {
@@ -91,6 +142,7 @@ async fn connect_with_backoff(client: &TwitterClient) -> reqwest::Response {
 
 pub fn make_stream(
     client: TwitterClient,
+    observer: Option<StreamObserver>,
 ) -> impl Stream<Item = Result<model::ResponseItem<model::Tweet, model::StreamMeta>, Error>> {
     async fn read_single(resp: &mut reqwest::Response) -> Result<Option<bytes::Bytes>, Error> {
         Ok(tokio::time::timeout(Duration::from_secs(30), resp.chunk())
@@ -99,8 +151,11 @@ pub fn make_stream(
     }
 
     async_stream::try_stream! {
-        let mut resp = connect_with_backoff(&client).await;
+        let mut resp = connect_with_backoff(&client, observer.as_ref()).await?;
         info!("Connected to filtered stream");
+        if let Some(observer) = &observer {
+            observer(StreamEvent::Connected);
+        }
 
         let mut s = Vec::new();
         loop {
@@ -115,7 +170,11 @@ pub fn make_stream(
                         let string = String::from_utf8_lossy(&s);
                         let string = string.as_ref().trim();
                         if !string.is_empty() {
-                            let res = serde_json::from_str::<model::TwitterResponse<_, _>>(string);
+                            let res = {
+                                let span = model::span!("stream_parse", bytes = string.len());
+                                let _guard = span.enter();
+                                serde_json::from_str::<model::TwitterResponse<_, _>>(string)
+                            };
                             match res {
                                 Ok(res) => {
                                     let mut item = res.into_result()?;
@@ -1,5 +1,6 @@
 use model::ResponseItem;
 use tweet_model as model;
+use model::trace::TraceExt;
 use crate::{
     util,
     concat_param,
@@ -39,7 +40,8 @@ impl UserTimelineHead {
         client: &TwitterClient,
         catchup: bool,
     ) -> Result<model::ResponseItem<Vec<model::Tweet>>, Error> {
-        let mut res = load_timeline_since(client, self, catchup).await?;
+        let span = model::span!("user_fetch", user_id = %self.id);
+        let mut res = load_timeline_since(client, self, catchup).in_span(span.clone()).await?;
         if let Some(last_tweet) = res.data.last() {
             let updating = self.head.is_some();
 
@@ -53,8 +55,9 @@ impl UserTimelineHead {
 
             // augment
             if updating && (!catchup || res.data.len() <= 5) {
-                let augment_data =
-                    util::load_batch_augment_data(client, &res.data, &res.includes).await?;
+                let augment_data = util::load_batch_augment_data(client, &res.data, &res.includes)
+                    .in_span(span)
+                    .await?;
                 if let Some(model::ResponseItem { includes, .. }) = augment_data {
                     res.includes.augment(includes);
                 }
@@ -75,7 +78,8 @@ fn create_endpoint_url(id: &str, max_results: u32, since: Option<&str>, paginati
                 "author_id",
                 "referenced_tweets.id",
                 "referenced_tweets.id.author_id",
-                "attachments.media_keys"
+                "attachments.media_keys",
+                "attachments.poll_ids"
             ],
         )
         .append_pair(
@@ -84,16 +88,29 @@ fn create_endpoint_url(id: &str, max_results: u32, since: Option<&str>, paginati
                 "created_at",
                 "entities",
                 "public_metrics",
-                "possibly_sensitive"
+                "possibly_sensitive",
+                "lang",
+                "conversation_id"
             ],
         )
         .append_pair(
             "user.fields",
-            concat_param!["profile_image_url", "public_metrics"],
+            concat_param!["created_at", "profile_image_url", "public_metrics"],
         )
         .append_pair(
             "media.fields",
-            concat_param!["width", "height", "url", "preview_image_url"],
+            concat_param![
+                "width",
+                "height",
+                "url",
+                "preview_image_url",
+                "duration_ms",
+                "variants"
+            ],
+        )
+        .append_pair(
+            "poll.fields",
+            concat_param!["duration_minutes", "end_datetime", "voting_status"],
         )
         .extend_pairs(since.map(|since| ("since_id", since)))
         .extend_pairs(pagination_token.map(|token| ("pagination_token", token)))
@@ -117,9 +134,10 @@ async fn load_timeline_since(
     let make_request = |token: Option<String>| {
         let url = create_endpoint_url(list_id, max_results, since_id, token.as_deref());
         async {
-            let base_ret = client
-                .get(url)
-                .send()
+            client.acquire("users").await?;
+            let resp = client.get(url).send().await;
+            util::record_request(client, "users", &resp);
+            let base_ret = util::check_auth(resp?)
                 .await?
                 .json::<model::TwitterResponse<Option<Vec<model::Tweet>>, model::ListMeta>>()
                 .await?;
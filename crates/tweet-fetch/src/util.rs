@@ -1,4 +1,5 @@
 use super::{model, TwitterClient};
+use model::trace::TraceExt;
 
 macro_rules! concat_param {
     ($param1:literal $(, $param:literal)*) => {
@@ -10,7 +11,7 @@ pub fn append_query_param_for_tweet(url: &mut reqwest::Url) {
     url.query_pairs_mut()
         .append_pair(
             "expansions",
-            concat_param!["author_id", "attachments.media_keys"],
+            concat_param!["author_id", "attachments.media_keys", "attachments.poll_ids"],
         )
         .append_pair(
             "tweet.fields",
@@ -18,20 +19,97 @@ pub fn append_query_param_for_tweet(url: &mut reqwest::Url) {
                 "created_at",
                 "entities",
                 "public_metrics",
-                "possibly_sensitive"
+                "possibly_sensitive",
+                "lang",
+                "conversation_id"
             ],
         )
         .append_pair(
             "user.fields",
-            concat_param!["profile_image_url", "public_metrics"],
+            concat_param!["created_at", "profile_image_url", "public_metrics"],
         )
         .append_pair(
             "media.fields",
-            concat_param!["width", "height", "url", "preview_image_url"],
+            concat_param![
+                "width",
+                "height",
+                "url",
+                "preview_image_url",
+                "duration_ms",
+                "variants"
+            ],
+        )
+        .append_pair(
+            "poll.fields",
+            concat_param!["duration_minutes", "end_datetime", "voting_status"],
         )
         .finish();
 }
 
+/// Records the outcome of a request to `endpoint` in metrics, and its rate limit headers in the
+/// budget of `client`.
+pub(crate) fn record_request(
+    client: &TwitterClient,
+    endpoint: &'static str,
+    ret: &reqwest::Result<reqwest::Response>,
+) {
+    if let Ok(resp) = ret {
+        client.budget().update(endpoint, resp.headers());
+    }
+    let status = match ret {
+        Ok(resp) => Some(resp.status()),
+        Err(e) => e.status(),
+    };
+    let status = status
+        .map(|status| model::metrics::status_class(status.as_u16()))
+        .unwrap_or("error");
+    model::metrics::increment_counter(
+        model::metrics::TWITTER_REQUESTS,
+        &[("endpoint", endpoint), ("status", status)],
+    );
+}
+
+/// Problem types of 403 responses which reject the client, rather than access to a resource.
+const CLIENT_PROBLEMS: &[&str] = &[
+    "https://api.twitter.com/2/problems/client-forbidden",
+    "https://api.twitter.com/2/problems/unsupported-authentication",
+];
+
+/// Returns whether a response of `status` with `body` rejects the credentials.
+fn rejects_credentials(status: reqwest::StatusCode, body: &str) -> bool {
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED => true,
+        // also returned for protected or suspended resources, which only fail their request
+        reqwest::StatusCode::FORBIDDEN => {
+            CLIENT_PROBLEMS.iter().any(|problem| body.contains(problem))
+        }
+        _ => false,
+    }
+}
+
+/// Fails with `Error::Unauthorized` if Twitter rejected the credentials of `resp`.
+pub(crate) async fn check_auth(resp: reqwest::Response) -> Result<reqwest::Response, crate::Error> {
+    let status = resp.status();
+    if status != reqwest::StatusCode::UNAUTHORIZED && status != reqwest::StatusCode::FORBIDDEN {
+        return Ok(resp);
+    }
+
+    let mut builder = http::Response::builder().status(status).version(resp.version());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = resp.headers().clone();
+    }
+    let body = resp.bytes().await?;
+    let text = String::from_utf8_lossy(&body);
+    if rejects_credentials(status, &text) {
+        return Err(crate::Error::Unauthorized {
+            status: status.as_u16(),
+            body: text.into_owned(),
+        });
+    }
+    // hand the body back, for the caller to parse the error
+    Ok(builder.body(body).unwrap().into())
+}
+
 fn needs_augment(tweet: &model::Tweet, includes: &model::ResponseIncludes) -> Option<String> {
     let real_tweet = if let Some(rt_id) = tweet.get_retweet_source() {
         includes.get_tweet(rt_id).unwrap()
@@ -68,6 +146,72 @@ pub async fn load_batch_augment_data(
     log::debug!("Media info missing, fetching tweet info: {:?}", ids);
     let resp = client
         .retrieve(&ids.iter().map(|s| &**s).collect::<Vec<_>>())
+        .in_span(model::span!("augment", tweets = ids.len()))
         .await?;
     Ok(Some(resp))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_FORBIDDEN: &str = r#"{
+        "title": "Client Forbidden",
+        "detail": "You must use keys and tokens from an App attached to a Project.",
+        "reason": "client-not-enrolled",
+        "type": "https://api.twitter.com/2/problems/client-forbidden"
+    }"#;
+
+    const NOT_AUTHORIZED_FOR_RESOURCE: &str = r#"{
+        "errors": [{
+            "resource_type": "list",
+            "title": "Not Authorized",
+            "detail": "Sorry, you are not authorized to see the List with id: [1].",
+            "type": "https://api.twitter.com/2/problems/not-authorized-for-resource"
+        }]
+    }"#;
+
+    fn response(status: u16, body: &'static str) -> reqwest::Response {
+        http::Response::builder()
+            .status(status)
+            .header("x-rate-limit-remaining", "10")
+            .body(body)
+            .unwrap()
+            .into()
+    }
+
+    #[tokio::test]
+    async fn unauthorized_rejects_credentials() {
+        let e = check_auth(response(401, "Unauthorized")).await.unwrap_err();
+        assert!(e.is_unauthorized());
+        assert_eq!(e.to_string(), "unauthorized (HTTP 401): Unauthorized");
+    }
+
+    #[tokio::test]
+    async fn forbidden_client_rejects_credentials() {
+        let e = check_auth(response(403, CLIENT_FORBIDDEN)).await.unwrap_err();
+        match e {
+            crate::Error::Unauthorized { status, body } => {
+                assert_eq!(status, 403);
+                assert_eq!(body, CLIENT_FORBIDDEN);
+            }
+            e => panic!("expected an auth failure, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn forbidden_resource_is_passed_on() {
+        let resp = check_auth(response(403, NOT_AUTHORIZED_FOR_RESOURCE)).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(resp.headers()["x-rate-limit-remaining"], "10");
+        assert_eq!(resp.text().await.unwrap(), NOT_AUTHORIZED_FOR_RESOURCE);
+    }
+
+    #[tokio::test]
+    async fn other_responses_are_passed_on() {
+        let resp = check_auth(response(200, "{}")).await.unwrap();
+        assert_eq!(resp.text().await.unwrap(), "{}");
+        let resp = check_auth(response(429, "Too Many Requests")).await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+    }
+}
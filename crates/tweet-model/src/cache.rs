@@ -1,7 +1,11 @@
 use std::future::Future;
 use std::pin::Pin;
 
+// futures of caches on wasm, e.g. backed by JS promises, can't be sent across threads
+#[cfg(not(target_arch = "wasm32"))]
 type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+#[cfg(target_arch = "wasm32")]
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
 
 pub trait CacheItem {
     fn key(&self) -> &str;
@@ -0,0 +1,352 @@
+//! Generators of structurally valid model values, for tests and fuzzing.
+//!
+//! Values are random but consistent with what the API returns: IDs and media keys are numeric,
+//! entity ranges are UTF-16 offsets into the unescaped text, and the author and media of a tweet
+//! are in the includes of its response. The same seed generates the same values.
+
+use chrono::{Duration, TimeZone, Utc};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use url::Url;
+
+use crate::*;
+
+/// Plain words of tweet text, with characters which are escaped in raw text and ones outside
+/// the BMP, which take two UTF-16 code units.
+const WORDS: &[&str] = &[
+    "hello",
+    "art",
+    "new",
+    "drawing",
+    "&",
+    "<3",
+    "a>b",
+    "\u{c548}\u{b155}",
+    "\u{1f3a8}",
+    "\u{1f600}\u{1f600}",
+    "caf\u{e9}",
+    "\"quoted\"",
+];
+
+/// Escapes text like the API does: only `&`, `<` and `>`.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Seeded generator of model values.
+#[derive(Debug)]
+pub struct Fixtures {
+    rng: StdRng,
+    next_id: u64,
+}
+
+impl Fixtures {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            next_id: 1_400_000_000_000_000_000,
+        }
+    }
+
+    /// Returns a new numeric ID, greater than the previous ones like tweet IDs.
+    pub fn id(&mut self) -> String {
+        self.next_id += self.rng.gen_range(1..1 << 22);
+        self.next_id.to_string()
+    }
+
+    fn username(&mut self) -> String {
+        let len = self.rng.gen_range(1..=15);
+        (0..len)
+            .map(|_| {
+                *b"abcdefghijklmnopqrstuvwxyz0123456789_"
+                    .choose(&mut self.rng)
+                    .unwrap() as char
+            })
+            .collect()
+    }
+
+    fn created_at(&mut self) -> chrono::DateTime<Utc> {
+        let days_ago = self.rng.gen_range(0..3650);
+        Utc.ymd(2022, 1, 1).and_hms(0, 0, 0) - Duration::days(days_ago)
+    }
+
+    pub fn user(&mut self) -> User {
+        let id = self.id();
+        let username = self.username();
+        let name = WORDS.choose(&mut self.rng).unwrap().to_string();
+        let image_url = format!("https://pbs.twimg.com/profile_images/{}/a_normal.jpg", id);
+        let metrics = UserPublicMetrics {
+            followers_count: self.rng.gen_range(0..1_000_000),
+            following_count: self.rng.gen_range(0..10_000),
+            tweet_count: self.rng.gen_range(0..100_000),
+            listed_count: self.rng.gen_range(0..1000),
+        };
+        User::new(id, name, username)
+            .with_profile_image_url(image_url.parse().unwrap())
+            .with_metrics(metrics)
+            .with_created_at(self.created_at())
+    }
+
+    pub fn media(&mut self) -> Media {
+        let key = format!("3_{}", self.id());
+        let ty = *[MediaType::Photo, MediaType::Video, MediaType::AnimatedGif]
+            .choose(&mut self.rng)
+            .unwrap();
+        let width = self.rng.gen_range(1..4096);
+        let height = self.rng.gen_range(1..4096);
+        let url: Url = format!("https://pbs.twimg.com/media/{}.jpg", key)
+            .parse()
+            .unwrap();
+        let media = Media::new(key, ty, width, height);
+        match ty {
+            MediaType::Photo => media.with_url(url),
+            MediaType::Video | MediaType::AnimatedGif => media.with_preview_image_url(url),
+        }
+    }
+
+    /// Generates a tweet by `author_id` with entities, linking to `media_keys` at the end of the
+    /// text like the API does.
+    pub fn tweet(&mut self, author_id: &str, media_keys: Vec<String>) -> Tweet {
+        let mut text = String::new();
+        let mut entities = Entities::default();
+        let push_separator = |text: &mut String| {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+        };
+
+        for _ in 0..self.rng.gen_range(0..12) {
+            push_separator(&mut text);
+            let start = utf16_len(&text);
+            match self.rng.gen_range(0..6) {
+                0 => {
+                    let tag = self.username();
+                    text.push('#');
+                    text.push_str(&tag);
+                    let end = utf16_len(&text);
+                    entities.hashtags.push(Hashtag { start, end, tag });
+                }
+                1 => {
+                    let username = self.username();
+                    text.push('@');
+                    text.push_str(&username);
+                    let end = utf16_len(&text);
+                    entities.mentions.push(MentionEntity {
+                        start,
+                        end,
+                        username,
+                    });
+                }
+                2 => {
+                    let url = self.short_url();
+                    text.push_str(url.as_str());
+                    let end = utf16_len(&text);
+                    let expanded = format!("https://example.com/{}", self.username());
+                    entities.urls.push(UrlEntity {
+                        start,
+                        end,
+                        url,
+                        display_url: expanded.trim_start_matches("https://").to_owned(),
+                        expanded_url: expanded.parse().unwrap(),
+                        media_key: None,
+                    });
+                }
+                _ => text.push_str(WORDS.choose(&mut self.rng).unwrap()),
+            }
+        }
+
+        // the link to attached media comes last, and is shared by all of them
+        if let Some(media_key) = media_keys.first() {
+            push_separator(&mut text);
+            let start = utf16_len(&text);
+            let url = self.short_url();
+            text.push_str(url.as_str());
+            let end = utf16_len(&text);
+            let tweet_url = format!("https://twitter.com/i/web/status/{}/photo/1", self.next_id);
+            entities.urls.push(UrlEntity {
+                start,
+                end,
+                url,
+                display_url: String::from("pic.twitter.com/media"),
+                expanded_url: tweet_url.parse().unwrap(),
+                media_key: Some(media_key.clone()),
+            });
+        }
+
+        let metrics = TweetPublicMetrics {
+            reply_count: self.rng.gen_range(0..1000),
+            retweet_count: self.rng.gen_range(0..10_000),
+            quote_count: self.rng.gen_range(0..1000),
+            like_count: self.rng.gen_range(0..100_000),
+        };
+        let mut tweet = Tweet::new(self.id(), escape(&text))
+            .with_created_at(self.created_at())
+            .with_author_id(author_id)
+            .with_metrics(metrics)
+            .with_media_keys(media_keys)
+            .with_possibly_sensitive(self.rng.gen_bool(0.1));
+        tweet.entities = entities;
+        tweet
+    }
+
+    fn short_url(&mut self) -> Url {
+        let code = (0..10)
+            .map(|_| {
+                *b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789"
+                    .choose(&mut self.rng)
+                    .unwrap() as char
+            })
+            .collect::<String>();
+        format!("https://t.co/{}", code).parse().unwrap()
+    }
+
+    /// Generates a tweet with its author and media in the includes. Some tweets are retweets,
+    /// with the retweeted tweet and its author and media in the includes too.
+    pub fn response_item(&mut self) -> ResponseItem<Tweet> {
+        let mut includes = ResponseIncludes::default();
+        let original = if self.rng.gen_bool(0.3) {
+            let tweet = self.tweet_into(&mut includes);
+            let id = tweet.id().to_owned();
+            includes.push_tweet(tweet);
+            Some(id)
+        } else {
+            None
+        };
+
+        let mut tweet = self.tweet_into(&mut includes);
+        if let Some(id) = original {
+            tweet = tweet.with_referenced_tweet(TweetReferenceType::Retweeted, id);
+        }
+        ResponseItem {
+            data: tweet,
+            includes,
+            meta: None,
+        }
+    }
+
+    /// Generates a tweet, pushing its author and media into `includes`.
+    fn tweet_into(&mut self, includes: &mut ResponseIncludes) -> Tweet {
+        let author = self.user();
+        let media = (0..self.rng.gen_range(0..=4))
+            .map(|_| self.media())
+            .collect::<Vec<_>>();
+        let tweet = self.tweet(
+            author.id(),
+            media.iter().map(|media| media.key().to_owned()).collect(),
+        );
+        includes.push_user(author);
+        for media in media {
+            includes.push_media(media);
+        }
+        tweet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEEDS: std::ops::Range<u64> = 0..200;
+
+    /// Slices `text` with a range of UTF-16 offsets, like the API gives for entities.
+    fn slice_utf16(text: &str, range: std::ops::Range<usize>) -> String {
+        let units = text.encode_utf16().collect::<Vec<_>>();
+        String::from_utf16(&units[range]).unwrap()
+    }
+
+    #[test]
+    fn response_item_round_trips() {
+        let mut fixtures = Fixtures::new(0);
+        for _ in SEEDS {
+            let item = fixtures.response_item();
+            let value = serde_json::to_value(&item).unwrap();
+            let parsed: ResponseItem<Tweet> = serde_json::from_value(value.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), value);
+            assert_eq!(parsed.data.id(), item.data.id());
+            assert_eq!(parsed.data.raw_text(), item.data.raw_text());
+            assert_eq!(parsed.includes.len(), item.includes.len());
+        }
+    }
+
+    #[test]
+    fn unescape_inverts_escape() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // pieces of entities, so that escaped and unescaped entities mix in the text
+        let pieces = ["&", "amp;", "lt;", "gt;", "<", ">", ";", "a", "\u{1f3a8}"];
+        for _ in 0..1000 {
+            let text = (0..rng.gen_range(0..16))
+                .map(|_| *pieces.choose(&mut rng).unwrap())
+                .collect::<String>();
+            let tweet = Tweet::new("1", escape(&text));
+            assert_eq!(tweet.unescaped_text(), text, "raw text {:?}", tweet.raw_text());
+        }
+
+        for seed in SEEDS {
+            let tweet = Fixtures::new(seed).response_item().data;
+            assert_eq!(escape(&tweet.unescaped_text()), tweet.raw_text());
+        }
+    }
+
+    #[test]
+    fn entity_ranges_slice_unescaped_text() {
+        for seed in SEEDS {
+            let tweet = Fixtures::new(seed).response_item().data;
+            let text = tweet.unescaped_text();
+            let entities = tweet.entities();
+            for hashtag in entities.hashtags() {
+                assert_eq!(slice_utf16(&text, hashtag.range()), format!("#{}", hashtag.tag()));
+            }
+            for mention in entities.mentions() {
+                let username = mention.username();
+                assert_eq!(slice_utf16(&text, mention.range()), format!("@{}", username));
+            }
+            for url in entities.urls() {
+                assert_eq!(slice_utf16(&text, url.range()), url.url().as_str());
+            }
+        }
+    }
+
+    #[test]
+    fn markdown_replaces_entities() {
+        for seed in SEEDS {
+            let tweet = Fixtures::new(seed).response_item().data;
+            let markdown = tweet.markdown_text();
+            assert!(!markdown.contains("&amp;"), "{:?}", markdown);
+            for hashtag in tweet.entities().hashtags() {
+                assert!(markdown.contains(&format!("[#{}](", hashtag.tag())), "{:?}", markdown);
+            }
+            for url in tweet.entities().urls() {
+                // the trailing media link is removed, and other links are expanded
+                assert!(!markdown.contains(url.url().as_str()), "{:?}", markdown);
+            }
+        }
+    }
+
+    #[test]
+    fn markdown_survives_broken_ranges() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for seed in SEEDS {
+            let mut tweet = Fixtures::new(seed).response_item().data;
+            let len = utf16_len(&tweet.unescaped_text());
+            // ranges past the end, reversed, overlapping or splitting surrogate pairs
+            let mut range = || (rng.gen_range(0..len + 4), rng.gen_range(0..len + 4));
+            for hashtag in &mut tweet.entities.hashtags {
+                (hashtag.start, hashtag.end) = range();
+            }
+            for mention in &mut tweet.entities.mentions {
+                (mention.start, mention.end) = range();
+            }
+            for url in &mut tweet.entities.urls {
+                (url.start, url.end) = range();
+            }
+            tweet.markdown_text();
+        }
+    }
+}
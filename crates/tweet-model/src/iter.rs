@@ -0,0 +1,134 @@
+//! Adapters over iterators of tweets, shared by the filters of the engines.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+
+use crate::Tweet;
+
+/// Compares tweet IDs numerically.
+///
+/// IDs are decimal numbers without leading zeros, so comparing them as strings is wrong when
+/// their lengths differ.
+pub fn compare_ids(a: &str, b: &str) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// Returns whether the tweet with ID `id` was posted after the one with ID `than`.
+pub fn is_newer(id: &str, than: &str) -> bool {
+    compare_ids(id, than) == Ordering::Greater
+}
+
+/// Adapters of iterators over tweets, owned or borrowed.
+pub trait TweetIterExt: Iterator + Sized
+where
+    Self::Item: Borrow<Tweet>,
+{
+    /// Skips retweets, keeping original tweets, replies and quotes.
+    fn originals_only(self) -> std::iter::Filter<Self, fn(&Self::Item) -> bool> {
+        self.filter(|tweet| !tweet.borrow().is_retweet())
+    }
+
+    /// Keeps tweets with media attached, see `Tweet::has_media`.
+    fn with_media(self) -> std::iter::Filter<Self, fn(&Self::Item) -> bool> {
+        self.filter(|tweet| tweet.borrow().has_media())
+    }
+
+    /// Keeps tweets newer than the tweet with ID `id`.
+    fn newer_than(self, id: &str) -> NewerThan<'_, Self> {
+        NewerThan { iter: self, id }
+    }
+}
+
+impl<I> TweetIterExt for I
+where
+    I: Iterator,
+    I::Item: Borrow<Tweet>,
+{
+}
+
+/// Iterator returned by `TweetIterExt::newer_than`.
+#[derive(Debug, Clone)]
+pub struct NewerThan<'a, I> {
+    iter: I,
+    id: &'a str,
+}
+
+impl<I> Iterator for NewerThan<'_, I>
+where
+    I: Iterator,
+    I::Item: Borrow<Tweet>,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let id = self.id;
+        self.iter.find(|tweet| is_newer(tweet.borrow().id(), id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TweetReferenceType;
+
+    fn ids<'a>(tweets: impl Iterator<Item = &'a Tweet>) -> Vec<&'a str> {
+        tweets.map(|tweet| tweet.id()).collect()
+    }
+
+    #[test]
+    fn ids_compare_numerically() {
+        assert_eq!(compare_ids("9", "10"), Ordering::Less);
+        assert_eq!(compare_ids("100", "99"), Ordering::Greater);
+        assert_eq!(compare_ids("20", "3"), Ordering::Greater);
+        assert_eq!(
+            compare_ids("1460323737035677698", "1460323737035677698"),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare_ids("999999999999999999", "1000000000000000000"),
+            Ordering::Less
+        );
+        assert!(is_newer("1460323737035677698", "20"));
+        assert!(!is_newer("20", "20"));
+
+        let tweets = ["8", "9", "10", "11", "100"].map(|id| Tweet::new(id, "text"));
+        assert_eq!(ids(tweets.iter().newer_than("9")), ["10", "11", "100"]);
+        assert_eq!(ids(tweets.iter().newer_than("99")), ["100"]);
+        assert!(tweets.iter().newer_than("1000").next().is_none());
+    }
+
+    #[test]
+    fn originals_keep_replies_and_quotes() {
+        let reply =
+            Tweet::new("1", "reply").with_referenced_tweet(TweetReferenceType::RepliedTo, "10");
+        // as returned for a retweet of a reply
+        let retweeted_reply = Tweet::new("2", "RT reply")
+            .with_referenced_tweet(TweetReferenceType::Retweeted, "1")
+            .with_referenced_tweet(TweetReferenceType::RepliedTo, "10");
+        let quote =
+            Tweet::new("3", "quote").with_referenced_tweet(TweetReferenceType::Quoted, "10");
+        let quote_reply = Tweet::new("4", "quote reply")
+            .with_referenced_tweet(TweetReferenceType::Quoted, "10")
+            .with_referenced_tweet(TweetReferenceType::RepliedTo, "11");
+        let retweeted_quote = Tweet::new("5", "RT quote")
+            .with_referenced_tweet(TweetReferenceType::Retweeted, "3")
+            .with_referenced_tweet(TweetReferenceType::Quoted, "10");
+        let tweets = [reply, retweeted_reply, quote, quote_reply, retweeted_quote];
+
+        assert!(tweets[1].is_retweet() && tweets[4].is_retweet());
+        assert!(tweets[2].is_quote() && !tweets[2].is_retweet());
+        assert!(tweets[3].is_quote() && tweets[3].is_reply());
+        assert_eq!(ids(tweets.iter().originals_only()), ["1", "3", "4"]);
+        // owned items too
+        let originals = tweets.into_iter().originals_only().collect::<Vec<_>>();
+        assert_eq!(ids(originals.iter()), ["1", "3", "4"]);
+    }
+
+    #[test]
+    fn with_media_skips_referenced_media() {
+        let photo = Tweet::new("1", "photo").with_media_keys(vec![String::from("3_1")]);
+        let quote = Tweet::new("2", "quote").with_referenced_tweet(TweetReferenceType::Quoted, "1");
+        let tweets = [photo, quote];
+        assert_eq!(ids(tweets.iter().with_media()), ["1"]);
+    }
+}
@@ -1,8 +1,21 @@
+//! Models of the Twitter API v2 and caches, shared by the crates of the workspace.
+//!
+//! The crate also builds for `wasm32-unknown-unknown`, to use the models and scores in workers
+//! post-processing route payloads; check with
+//! `cargo check --target wasm32-unknown-unknown -p tweet-model` when changing dependencies.
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 pub mod cache;
+#[cfg(any(test, feature = "fixture"))]
+pub mod fixture;
+pub mod iter;
+pub mod metrics;
+pub mod score;
+pub mod text;
+pub mod trace;
 use cache::CacheItem;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -19,6 +32,8 @@ pub struct Tweet {
     possibly_sensitive: Option<bool>,
     #[serde(default)]
     referenced_tweets: Vec<ReferencedTweet>,
+    lang: Option<String>,
+    conversation_id: Option<String>,
 }
 
 impl CacheItem for Tweet {
@@ -27,6 +42,69 @@ impl CacheItem for Tweet {
     }
 }
 
+impl Tweet {
+    pub fn new(id: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            text: text.into(),
+            created_at: None,
+            author_id: None,
+            entities: Default::default(),
+            attachments: Default::default(),
+            public_metrics: None,
+            possibly_sensitive: None,
+            referenced_tweets: Vec::new(),
+            lang: None,
+            conversation_id: None,
+        }
+    }
+
+    pub fn with_created_at(mut self, created_at: DateTime<Utc>) -> Self {
+        self.created_at = Some(created_at);
+        self
+    }
+
+    pub fn with_author_id(mut self, author_id: impl Into<String>) -> Self {
+        self.author_id = Some(author_id.into());
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: TweetPublicMetrics) -> Self {
+        self.public_metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_media_keys(mut self, media_keys: Vec<String>) -> Self {
+        self.attachments.media_keys = media_keys;
+        self
+    }
+
+    pub fn with_poll_ids(mut self, poll_ids: Vec<String>) -> Self {
+        self.attachments.poll_ids = poll_ids;
+        self
+    }
+
+    pub fn with_possibly_sensitive(mut self, possibly_sensitive: bool) -> Self {
+        self.possibly_sensitive = Some(possibly_sensitive);
+        self
+    }
+
+    pub fn with_lang(mut self, lang: impl Into<String>) -> Self {
+        self.lang = Some(lang.into());
+        self
+    }
+
+    pub fn with_conversation_id(mut self, conversation_id: impl Into<String>) -> Self {
+        self.conversation_id = Some(conversation_id.into());
+        self
+    }
+
+    pub fn with_referenced_tweet(mut self, ty: TweetReferenceType, id: impl Into<String>) -> Self {
+        self.referenced_tweets.push(ReferencedTweet { ty, id: id.into() });
+        self
+    }
+}
+
 impl Tweet {
     pub fn id(&self) -> &str {
         &self.id
@@ -43,6 +121,11 @@ impl Tweet {
             .replace("&amp;", "&")
     }
 
+    /// Tweet text as Discord-flavored markdown, with entities turned into links.
+    pub fn markdown_text(&self) -> String {
+        text::render_markdown(self)
+    }
+
     pub fn created_at(&self) -> Option<DateTime<Utc>> {
         self.created_at
     }
@@ -59,6 +142,10 @@ impl Tweet {
         &self.attachments.media_keys
     }
 
+    pub fn poll_ids(&self) -> &[String] {
+        &self.attachments.poll_ids
+    }
+
     pub fn metrics(&self) -> Option<&TweetPublicMetrics> {
         self.public_metrics.as_ref()
     }
@@ -67,6 +154,16 @@ impl Tweet {
         self.possibly_sensitive.unwrap_or(false)
     }
 
+    /// Language detected by Twitter, as a BCP 47 tag. `und` if undetermined.
+    pub fn lang(&self) -> Option<&str> {
+        self.lang.as_deref()
+    }
+
+    /// ID of the tweet starting the conversation, which is the tweet itself if it's not a reply.
+    pub fn conversation_id(&self) -> Option<&str> {
+        self.conversation_id.as_deref()
+    }
+
     pub fn referenced_tweets(&self) -> &[ReferencedTweet] {
         &self.referenced_tweets
     }
@@ -77,12 +174,38 @@ impl Tweet {
             .find(|t| t.ty == TweetReferenceType::Retweeted)
             .map(|t| &*t.id)
     }
+
+    fn references(&self, ty: TweetReferenceType) -> bool {
+        self.referenced_tweets.iter().any(|t| t.ty == ty)
+    }
+
+    pub fn is_retweet(&self) -> bool {
+        self.references(TweetReferenceType::Retweeted)
+    }
+
+    /// Returns whether the tweet replies to another one. Retweets of replies are not replies
+    /// themselves; check the retweeted tweet for them.
+    pub fn is_reply(&self) -> bool {
+        self.references(TweetReferenceType::RepliedTo)
+    }
+
+    /// Returns whether the tweet quotes another one, which may be a reply at the same time.
+    pub fn is_quote(&self) -> bool {
+        self.references(TweetReferenceType::Quoted)
+    }
+
+    /// Returns whether the tweet has media attached, not counting the media of retweeted or
+    /// quoted tweets.
+    pub fn has_media(&self) -> bool {
+        !self.attachments.media_keys.is_empty()
+    }
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(default)]
 pub struct Attachments {
     media_keys: Vec<String>,
+    poll_ids: Vec<String>,
 }
 
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -90,8 +213,24 @@ pub struct Attachments {
 pub struct Entities {
     hashtags: Vec<Hashtag>,
     urls: Vec<UrlEntity>,
+    mentions: Vec<MentionEntity>,
+}
+
+impl Entities {
+    pub fn hashtags(&self) -> &[Hashtag] {
+        &self.hashtags
+    }
+
+    pub fn urls(&self) -> &[UrlEntity] {
+        &self.urls
+    }
+
+    pub fn mentions(&self) -> &[MentionEntity] {
+        &self.mentions
+    }
 }
 
+/// Entity ranges are given in UTF-16 code units of the unescaped text.
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Hashtag {
     start: usize,
@@ -99,6 +238,16 @@ pub struct Hashtag {
     tag: String,
 }
 
+impl Hashtag {
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct UrlEntity {
     start: usize,
@@ -106,6 +255,47 @@ pub struct UrlEntity {
     url: Url,
     display_url: String,
     expanded_url: Url,
+    media_key: Option<String>,
+}
+
+impl UrlEntity {
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub fn display_url(&self) -> &str {
+        &self.display_url
+    }
+
+    pub fn expanded_url(&self) -> &Url {
+        &self.expanded_url
+    }
+
+    /// Key of the attached media this URL links to, if any.
+    pub fn media_key(&self) -> Option<&str> {
+        self.media_key.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MentionEntity {
+    start: usize,
+    end: usize,
+    username: String,
+}
+
+impl MentionEntity {
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -148,6 +338,7 @@ pub struct User {
     username: String,
     profile_image_url: Option<Url>,
     public_metrics: Option<UserPublicMetrics>,
+    created_at: Option<DateTime<Utc>>,
 }
 
 impl CacheItem for User {
@@ -156,6 +347,34 @@ impl CacheItem for User {
     }
 }
 
+impl User {
+    pub fn new(id: impl Into<String>, name: impl Into<String>, username: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            name: name.into(),
+            username: username.into(),
+            profile_image_url: None,
+            public_metrics: None,
+            created_at: None,
+        }
+    }
+
+    pub fn with_profile_image_url(mut self, url: Url) -> Self {
+        self.profile_image_url = Some(url);
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: UserPublicMetrics) -> Self {
+        self.public_metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_created_at(mut self, created_at: DateTime<Utc>) -> Self {
+        self.created_at = Some(created_at);
+        self
+    }
+}
+
 impl User {
     pub fn id(&self) -> &str {
         &self.id
@@ -186,6 +405,11 @@ impl User {
     pub fn metrics(&self) -> Option<&UserPublicMetrics> {
         self.public_metrics.as_ref()
     }
+
+    /// Creation time of the account.
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.created_at
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -205,6 +429,30 @@ pub struct Media {
     ty: MediaType,
     url: Option<Url>,
     preview_image_url: Option<Url>,
+    duration_ms: Option<u64>,
+    #[serde(default)]
+    variants: Vec<MediaVariant>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MediaVariant {
+    bit_rate: Option<u64>,
+    content_type: String,
+    url: Url,
+}
+
+impl MediaVariant {
+    pub fn bit_rate(&self) -> Option<u64> {
+        self.bit_rate
+    }
+
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
 }
 
 impl CacheItem for Media {
@@ -221,6 +469,31 @@ pub enum MediaType {
     AnimatedGif,
 }
 
+impl Media {
+    pub fn new(media_key: impl Into<String>, ty: MediaType, width: u64, height: u64) -> Self {
+        Self {
+            media_key: media_key.into(),
+            width,
+            height,
+            ty,
+            url: None,
+            preview_image_url: None,
+            duration_ms: None,
+            variants: Vec::new(),
+        }
+    }
+
+    pub fn with_url(mut self, url: Url) -> Self {
+        self.url = Some(url);
+        self
+    }
+
+    pub fn with_preview_image_url(mut self, url: Url) -> Self {
+        self.preview_image_url = Some(url);
+        self
+    }
+}
+
 impl Media {
     pub fn key(&self) -> &str {
         &self.media_key
@@ -242,6 +515,22 @@ impl Media {
         self.url.as_ref().or(self.preview_image_url.as_ref())
     }
 
+    pub fn duration(&self) -> Option<std::time::Duration> {
+        self.duration_ms.map(std::time::Duration::from_millis)
+    }
+
+    pub fn variants(&self) -> &[MediaVariant] {
+        &self.variants
+    }
+
+    /// Returns the MP4 variant with the highest bit rate, if any.
+    pub fn best_video_variant(&self) -> Option<&MediaVariant> {
+        self.variants
+            .iter()
+            .filter(|v| v.content_type == "video/mp4")
+            .max_by_key(|v| v.bit_rate.unwrap_or(0))
+    }
+
     pub fn url_orig(&self) -> Option<Url> {
         if let Some(url) = &self.url {
             let mut url = url.clone();
@@ -284,6 +573,14 @@ impl<Data: cache::CacheItem, Meta> ResponseItem<Data, Meta> {
     }
 }
 
+impl<Meta> ResponseItem<Tweet, Meta> {
+    /// Drops includes which the tweet doesn't refer to, returning the number of items removed.
+    /// See `ResponseIncludes::retain_for`.
+    pub fn prune(&mut self) -> usize {
+        self.includes.retain_for(&[&self.data])
+    }
+}
+
 impl<Data, Meta> ResponseItem<Data, Meta> {
     pub fn get_media(&self, media_key: &str) -> Option<&Media> {
         self.includes.get_media(media_key)
@@ -319,12 +616,115 @@ impl<Data, Meta> ResponseItem<Data, Meta> {
     }
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Poll {
+    id: String,
+    options: Vec<PollOption>,
+    duration_minutes: Option<u64>,
+    end_datetime: Option<DateTime<Utc>>,
+    voting_status: Option<PollVotingStatus>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PollOption {
+    position: u32,
+    label: String,
+    votes: u64,
+}
+
+impl PollOption {
+    pub fn new(position: u32, label: impl Into<String>, votes: u64) -> Self {
+        Self {
+            position,
+            label: label.into(),
+            votes,
+        }
+    }
+
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn votes(&self) -> u64 {
+        self.votes
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PollVotingStatus {
+    Open,
+    Closed,
+}
+
+impl Poll {
+    pub fn new(id: impl Into<String>, options: Vec<PollOption>) -> Self {
+        Self {
+            id: id.into(),
+            options,
+            duration_minutes: None,
+            end_datetime: None,
+            voting_status: None,
+        }
+    }
+
+    pub fn with_end_datetime(mut self, end_datetime: DateTime<Utc>) -> Self {
+        self.end_datetime = Some(end_datetime);
+        self
+    }
+
+    pub fn with_voting_status(mut self, voting_status: PollVotingStatus) -> Self {
+        self.voting_status = Some(voting_status);
+        self
+    }
+}
+
+impl Poll {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Returns the options, ordered by position.
+    pub fn options(&self) -> Vec<&PollOption> {
+        let mut options = self.options.iter().collect::<Vec<_>>();
+        options.sort_by_key(|o| o.position);
+        options
+    }
+
+    pub fn total_votes(&self) -> u64 {
+        self.options.iter().map(|o| o.votes).sum()
+    }
+
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        self.duration_minutes
+            .map(|minutes| chrono::Duration::minutes(minutes as i64))
+    }
+
+    pub fn end_datetime(&self) -> Option<DateTime<Utc>> {
+        self.end_datetime
+    }
+
+    /// Returns whether the poll is closed, by voting status or by end time if the status is
+    /// missing.
+    pub fn is_closed(&self, now: DateTime<Utc>) -> bool {
+        match self.voting_status {
+            Some(status) => status == PollVotingStatus::Closed,
+            None => self.end_datetime.map(|end| end <= now).unwrap_or(false),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
 #[serde(default)]
 pub struct ResponseIncludes {
     tweets: Vec<Tweet>,
     users: Vec<User>,
     media: Vec<Media>,
+    polls: Vec<Poll>,
 }
 
 impl ResponseIncludes {
@@ -332,6 +732,10 @@ impl ResponseIncludes {
         self.media.iter().find(|m| m.media_key == media_key)
     }
 
+    pub fn get_poll(&self, id: &str) -> Option<&Poll> {
+        self.polls.iter().find(|p| p.id == id)
+    }
+
     pub fn get_tweet(&self, id: &str) -> Option<&Tweet> {
         self.tweets.iter().find(|t| t.id == id)
     }
@@ -340,16 +744,78 @@ impl ResponseIncludes {
         self.users.iter().find(|u| u.id == id)
     }
 
+    pub fn push_tweet(&mut self, tweet: Tweet) {
+        self.tweets.push(tweet);
+    }
+
+    pub fn push_user(&mut self, user: User) {
+        self.users.push(user);
+    }
+
+    pub fn push_media(&mut self, media: Media) {
+        self.media.push(media);
+    }
+
+    pub fn push_poll(&mut self, poll: Poll) {
+        self.polls.push(poll);
+    }
+
     pub fn augment(&mut self, other: Self) {
         self.tweets.extend(other.tweets);
         self.users.extend(other.users);
         self.media.extend(other.media);
+        self.polls.extend(other.polls);
     }
 
     pub fn take_augment(&mut self, other: &mut Self) {
         let other = std::mem::take(other);
         self.augment(other);
     }
+
+    /// Number of included tweets, users, media and polls.
+    pub fn len(&self) -> usize {
+        self.tweets.len() + self.users.len() + self.media.len() + self.polls.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Keeps only what `tweets` refer to, transitively: referenced tweets, and the authors,
+    /// media and polls of `tweets` and the referenced tweets. Returns the number of items
+    /// removed.
+    ///
+    /// Augmenting includes of several tweets at once leaves items of the other tweets behind,
+    /// which would otherwise be routed and cached with each of them.
+    pub fn retain_for(&mut self, tweets: &[&Tweet]) -> usize {
+        use std::collections::HashSet;
+
+        let mut tweet_ids = HashSet::new();
+        let mut user_ids = HashSet::new();
+        let mut media_keys = HashSet::new();
+        let mut poll_ids = HashSet::new();
+        let mut pending = tweets.to_vec();
+        while let Some(tweet) = pending.pop() {
+            user_ids.extend(tweet.author_id.clone());
+            media_keys.extend(tweet.attachments.media_keys.iter().cloned());
+            poll_ids.extend(tweet.attachments.poll_ids.iter().cloned());
+            for reference in &tweet.referenced_tweets {
+                if !tweet_ids.insert(reference.id.clone()) {
+                    continue;
+                }
+                if let Some(referenced) = self.get_tweet(&reference.id) {
+                    pending.push(referenced);
+                }
+            }
+        }
+
+        let len = self.len();
+        self.tweets.retain(|tweet| tweet_ids.contains(&tweet.id));
+        self.users.retain(|user| user_ids.contains(&user.id));
+        self.media.retain(|media| media_keys.contains(&media.media_key));
+        self.polls.retain(|poll| poll_ids.contains(&poll.id));
+        len - self.len()
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -358,6 +824,10 @@ pub struct StreamMeta {
 }
 
 impl StreamMeta {
+    pub fn new(matching_rules: Vec<MatchingRule>) -> Self {
+        Self { matching_rules }
+    }
+
     pub fn matching_rules(&self) -> &[MatchingRule] {
         &self.matching_rules
     }
@@ -370,6 +840,13 @@ pub struct MatchingRule {
 }
 
 impl MatchingRule {
+    pub fn new(id: impl Into<String>, tag: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            tag: tag.into(),
+        }
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
@@ -379,6 +856,135 @@ impl MatchingRule {
     }
 }
 
+/// Rule of the filtered stream.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StreamRule {
+    id: String,
+    value: String,
+    #[serde(default)]
+    tag: Option<String>,
+}
+
+impl StreamRule {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+}
+
+/// Rule to be added to the filtered stream.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewStreamRule {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+}
+
+impl NewStreamRule {
+    /// Returns whether `rule` has the same value and tag as this one.
+    pub fn matches(&self, rule: &StreamRule) -> bool {
+        self.value == rule.value && self.tag == rule.tag
+    }
+}
+
+/// Response of listing the rules of the filtered stream.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StreamRules {
+    // missing if there are no rules
+    #[serde(default)]
+    data: Vec<StreamRule>,
+}
+
+impl StreamRules {
+    pub fn rules(&self) -> &[StreamRule] {
+        &self.data
+    }
+
+    pub fn into_rules(self) -> Vec<StreamRule> {
+        self.data
+    }
+}
+
+/// Response of adding or deleting rules of the filtered stream.
+///
+/// Rules which couldn't be added or deleted, e.g. invalid or duplicate ones, are listed in
+/// `errors` while the others are applied.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StreamRulesUpdate {
+    #[serde(default)]
+    data: Vec<StreamRule>,
+    meta: StreamRulesUpdateMeta,
+    #[serde(default)]
+    errors: Vec<StreamRuleError>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct StreamRulesUpdateMeta {
+    summary: StreamRulesSummary,
+}
+
+/// Counts of rules affected by an update.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct StreamRulesSummary {
+    pub created: u32,
+    pub not_created: u32,
+    pub valid: u32,
+    pub invalid: u32,
+    pub deleted: u32,
+    pub not_deleted: u32,
+}
+
+impl StreamRulesUpdate {
+    /// Rules which were added, including their new IDs.
+    pub fn rules(&self) -> &[StreamRule] {
+        &self.data
+    }
+
+    pub fn summary(&self) -> &StreamRulesSummary {
+        &self.meta.summary
+    }
+
+    pub fn errors(&self) -> &[StreamRuleError] {
+        &self.errors
+    }
+}
+
+/// Error about a single rule of an update.
+#[derive(Debug, Clone, Deserialize, Serialize, thiserror::Error)]
+#[error("{}{}", .title, .details.iter().map(|d| format!(": {}", d)).collect::<String>())]
+pub struct StreamRuleError {
+    title: String,
+    #[serde(rename = "type")]
+    ty: String,
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    details: Vec<String>,
+}
+
+impl StreamRuleError {
+    /// Value of the rule this error is about, for rules being added.
+    pub fn value(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    /// ID of the rule this error is about, for duplicate rules and rules being deleted.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ListMeta {
     meta: ListMetaInner,
@@ -442,6 +1048,18 @@ pub struct TwitterError {
     detail: String,
     #[serde(rename = "type")]
     ty: String,
+    resource_id: Option<String>,
+}
+
+impl TwitterError {
+    pub fn is_not_found(&self) -> bool {
+        self.ty.ends_with("/resource-not-found")
+    }
+
+    /// ID of the resource this error is about, if any.
+    pub fn resource_id(&self) -> Option<&str> {
+        self.resource_id.as_deref()
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, thiserror::Error)]
@@ -450,6 +1068,20 @@ pub struct ResponseError {
     errors: Vec<TwitterError>,
 }
 
+impl ResponseError {
+    pub fn errors(&self) -> &[TwitterError] {
+        &self.errors
+    }
+
+    /// IDs of the requested resources which don't exist, e.g. deleted tweets.
+    pub fn not_found_ids(&self) -> impl Iterator<Item = &str> {
+        self.errors
+            .iter()
+            .filter(|e| e.is_not_found())
+            .filter_map(|e| e.resource_id())
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum TwitterResponse<Data, Meta = Option<()>> {
@@ -465,3 +1097,118 @@ impl<Data, Meta> TwitterResponse<Data, Meta> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixtures;
+
+    fn poll(id: &str) -> Poll {
+        serde_json::from_value(serde_json::json!({ "id": id, "options": [] })).unwrap()
+    }
+
+    /// Includes of a retweet, augmented with the items of an unrelated tweet.
+    fn retweet_with_unrelated() -> (ResponseItem<Tweet>, Vec<String>) {
+        let mut fixtures = Fixtures::new(0);
+        let mut includes = ResponseIncludes::default();
+        let mut user = || {
+            let user = fixtures.user();
+            let id = user.id().to_owned();
+            includes.push_user(user);
+            id
+        };
+        let (retweeter, author, quoted_author, unrelated_author) = (user(), user(), user(), user());
+
+        let mut fixtures = Fixtures::new(1);
+        let quoted_media = fixtures.media();
+        let source_media = fixtures.media();
+        let unrelated_media = fixtures.media();
+        let quoted = fixtures
+            .tweet(&quoted_author, vec![quoted_media.key().to_owned()])
+            // not included, which must not stop the walk
+            .with_referenced_tweet(TweetReferenceType::RepliedTo, "1")
+            .with_poll_ids(vec![String::from("p1")]);
+        let source = fixtures
+            .tweet(&author, vec![source_media.key().to_owned()])
+            .with_referenced_tweet(TweetReferenceType::Quoted, quoted.id());
+        let retweet = fixtures
+            .tweet(&retweeter, Vec::new())
+            .with_referenced_tweet(TweetReferenceType::Retweeted, source.id());
+        let unrelated = fixtures
+            .tweet(&unrelated_author, vec![unrelated_media.key().to_owned()])
+            .with_poll_ids(vec![String::from("p2")]);
+
+        let unrelated_ids = vec![
+            unrelated.id().to_owned(),
+            unrelated_author,
+            unrelated_media.key().to_owned(),
+            String::from("p2"),
+        ];
+        for media in [quoted_media, source_media, unrelated_media] {
+            includes.push_media(media);
+        }
+        for tweet in [quoted, source, unrelated] {
+            includes.push_tweet(tweet);
+        }
+        includes.polls.extend([poll("p1"), poll("p2")]);
+        let item = ResponseItem {
+            data: retweet,
+            includes,
+            meta: None,
+        };
+        (item, unrelated_ids)
+    }
+
+    #[test]
+    fn retain_for_keeps_referenced_items() {
+        let (mut item, unrelated_ids) = retweet_with_unrelated();
+        let before = item.includes.clone();
+        assert_eq!(item.prune(), unrelated_ids.len());
+
+        let includes = &item.includes;
+        let source = includes.get_tweet(item.data.get_retweet_source().unwrap()).unwrap();
+        let quoted = includes.get_tweet(&source.referenced_tweets()[0].id).unwrap();
+        for tweet in [&item.data, source, quoted] {
+            assert!(includes.get_user(tweet.author_id().unwrap()).is_some());
+            for key in tweet.media_keys() {
+                assert!(includes.get_media(key).is_some(), "media {} of {}", key, tweet.id());
+            }
+        }
+        assert!(includes.get_poll("p1").is_some());
+
+        let [tweet_id, user_id, media_key, poll_id] =
+            <[String; 4]>::try_from(unrelated_ids).unwrap();
+        assert!(before.get_tweet(&tweet_id).is_some());
+        assert!(includes.get_tweet(&tweet_id).is_none());
+        assert!(includes.get_user(&user_id).is_none());
+        assert!(includes.get_media(&media_key).is_none());
+        assert!(includes.get_poll(&poll_id).is_none());
+
+        // pruning is idempotent
+        assert_eq!(item.prune(), 0);
+    }
+
+    #[test]
+    fn retain_for_survives_reference_cycles() {
+        let mut includes = ResponseIncludes::default();
+        let a = Tweet::new("1", "a").with_referenced_tweet(TweetReferenceType::Quoted, "2");
+        let b = Tweet::new("2", "b").with_referenced_tweet(TweetReferenceType::Quoted, "1");
+        includes.push_tweet(a.clone());
+        includes.push_tweet(b);
+        includes.push_tweet(Tweet::new("3", "c"));
+        assert_eq!(includes.retain_for(&[&a]), 1);
+        assert!(includes.get_tweet("1").is_some());
+        assert!(includes.get_tweet("2").is_some());
+    }
+
+    #[test]
+    fn retain_for_several_tweets() {
+        let (item, unrelated_ids) = retweet_with_unrelated();
+        let mut includes = item.includes.clone();
+        let unrelated = includes.get_tweet(&unrelated_ids[0]).unwrap().clone();
+        assert_eq!(includes.retain_for(&[&item.data, &unrelated]), 1);
+        // the unrelated tweet itself is not referenced by either
+        assert!(includes.get_tweet(&unrelated_ids[0]).is_none());
+        assert!(includes.get_media(&unrelated_ids[2]).is_some());
+    }
+}
@@ -0,0 +1,128 @@
+//! Metrics facade shared by the crates of the workspace.
+//!
+//! Instrumentation points call `increment_counter` and `set_gauge`, which do nothing until a
+//! recorder is installed with `set_recorder`. The names below are stable; recorders may prefix
+//! them but shouldn't rename them.
+
+use std::sync::RwLock;
+
+/// Tweets received, labeled by `engine`.
+pub const TWEETS_RECEIVED: &str = "tweets_received_total";
+/// Tweets of the filtered stream with at least one route.
+pub const TWEETS_ROUTED: &str = "tweets_routed_total";
+/// Filtered stream payloads dropped as repeats of a tweet received shortly before.
+pub const STREAM_DUPLICATES: &str = "stream_duplicates_total";
+/// Webhook requests which succeeded, labeled by destination `host`.
+pub const WEBHOOKS_SENT: &str = "webhooks_sent_total";
+/// Webhook requests which failed after retries, labeled by destination `host`.
+pub const WEBHOOKS_FAILED: &str = "webhooks_failed_total";
+/// Twitter API requests, labeled by `endpoint` and `status` class (`2xx`, `4xx`, `5xx`, or
+/// `error` for network errors).
+pub const TWITTER_REQUESTS: &str = "twitter_requests_total";
+/// Requests left in the rate limit window, labeled by `endpoint`.
+pub const TWITTER_BUDGET_REMAINING: &str = "twitter_budget_remaining";
+/// Requests held back by the rate limit budget, labeled by `endpoint`, `priority` and
+/// `outcome` (`wait`, `skip`).
+pub const TWITTER_BUDGET_THROTTLED: &str = "twitter_budget_throttled_total";
+/// Sleeps before retrying, labeled by `kind` (`ratelimit`, `server`, `network`).
+pub const BACKOFF_SLEEPS: &str = "backoff_sleeps_total";
+/// Tweets tracked for trending.
+pub const TRACKER_SIZE: &str = "tracker_size";
+/// Tweets tracked for trending, labeled by search `term`.
+pub const TRACKER_TERM_SIZE: &str = "tracker_term_size";
+/// Webhook deliveries waiting in the persistent queue.
+pub const DELIVERY_QUEUE_SIZE: &str = "delivery_queue_size";
+/// Queued deliveries given up, labeled by `reason` (`expired`, `gone`, `summarized`).
+pub const DELIVERIES_DROPPED: &str = "deliveries_dropped_total";
+/// Used heap of the route script isolate, in bytes.
+pub const ROUTER_HEAP_USED_BYTES: &str = "router_heap_used_bytes";
+
+pub type Labels<'a> = &'a [(&'static str, &'a str)];
+
+/// Destination of metrics.
+pub trait Recorder: Send + Sync {
+    fn increment_counter(&self, name: &'static str, labels: Labels<'_>, value: u64);
+    fn set_gauge(&self, name: &'static str, labels: Labels<'_>, value: f64);
+}
+
+static RECORDER: RwLock<Option<&'static dyn Recorder>> = RwLock::new(None);
+
+/// Installs the global recorder, replacing the previous one.
+pub fn set_recorder(recorder: &'static dyn Recorder) {
+    *RECORDER.write().unwrap() = Some(recorder);
+}
+
+pub fn increment_counter(name: &'static str, labels: Labels<'_>) {
+    increment_counter_by(name, labels, 1);
+}
+
+pub fn increment_counter_by(name: &'static str, labels: Labels<'_>, value: u64) {
+    if let Some(recorder) = *RECORDER.read().unwrap() {
+        recorder.increment_counter(name, labels, value);
+    }
+}
+
+pub fn set_gauge(name: &'static str, labels: Labels<'_>, value: f64) {
+    if let Some(recorder) = *RECORDER.read().unwrap() {
+        recorder.set_gauge(name, labels, value);
+    }
+}
+
+/// Returns the status class label of a response status, e.g. `4xx`.
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct LogRecorder(Mutex<Vec<String>>);
+
+    impl Recorder for LogRecorder {
+        fn increment_counter(&self, name: &'static str, labels: Labels<'_>, value: u64) {
+            let entry = format!("{} {:?} +{}", name, labels, value);
+            self.0.lock().unwrap().push(entry);
+        }
+
+        fn set_gauge(&self, name: &'static str, labels: Labels<'_>, value: f64) {
+            let entry = format!("{} {:?} ={}", name, labels, value);
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn metrics_go_to_the_recorder() {
+        // nothing is recorded without a recorder
+        increment_counter(TWEETS_ROUTED, &[]);
+
+        let recorder = Box::leak(Box::new(LogRecorder::default()));
+        set_recorder(recorder);
+        increment_counter(TWEETS_RECEIVED, &[("engine", "stream")]);
+        increment_counter_by(WEBHOOKS_SENT, &[("host", "discord.com")], 3);
+        set_gauge(TRACKER_SIZE, &[], 4.0);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                r#"tweets_received_total [("engine", "stream")] +1"#,
+                r#"webhooks_sent_total [("host", "discord.com")] +3"#,
+                "tracker_size [] =4",
+            ],
+        );
+    }
+
+    #[test]
+    fn status_classes() {
+        let classes = [101, 204, 304, 429, 503].map(status_class);
+        assert_eq!(classes, ["1xx", "2xx", "3xx", "4xx", "5xx"]);
+    }
+}
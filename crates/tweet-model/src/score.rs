@@ -0,0 +1,794 @@
+//! Scores of tweets, for thresholds and routing.
+//!
+//! Functions without an `_at` suffix read the current time, which isn't available on
+//! `wasm32-unknown-unknown`, so they aren't built there, nor is `DefaultScorer` scoring with
+//! them. Use the `_at` variants there with the time from the host.
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use crate::{ResponseIncludes, Tweet, TweetPublicMetrics, UserPublicMetrics};
+
+/// Scoring formula to use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScoreStrategy {
+    /// `DefaultScorer`.
+    #[default]
+    Default,
+    /// `EngagementScorer`.
+    Engagement,
+}
+
+/// Weights of the score formula.
+///
+/// Every field has a default, so a config only needs the ones it changes.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoreParams {
+    /// Formula the weights are used by.
+    pub strategy: ScoreStrategy,
+    /// Retweets at which the retweet score reaches 1, with no quotes.
+    ///
+    /// Retweets and quotes add up to a single retweet score, each by its divisor. With equal
+    /// divisors, as by default, they count the same.
+    pub retweet_divisor: f64,
+    /// Quotes at which the retweet score reaches 1, with no retweets. Lower than
+    /// `retweet_divisor` to weigh quotes, which carry commentary, over plain retweets.
+    pub quote_divisor: f64,
+    /// Likes at which the like score reaches 1.
+    pub like_divisor: f64,
+    /// Follower adjustment of an author with no followers.
+    pub follower_adjust_min: f64,
+    /// Follower adjustment approached as followers grow.
+    pub follower_adjust_max: f64,
+    /// Followers over which the remaining follower adjustment shrinks to a fifth.
+    pub follower_scale: f64,
+    /// Weight of the followers to following ratio, penalizing authors who follow more accounts
+    /// than follow them.
+    pub follow_rate_weight: f64,
+    /// Followers to following ratio assumed for authors who follow nobody, and the cap of the
+    /// ratio otherwise.
+    pub max_follow_ratio: f64,
+    /// Multiplier of the final score.
+    pub multiplier: f64,
+    /// Factor the score is multiplied by per `freshness_days` the tweet is younger.
+    pub freshness_base: f64,
+    /// Age in days at which a tweet gets no freshness bonus.
+    pub freshness_days: f64,
+    /// Factor the score of a brand-new account is multiplied by. The factor rises linearly to 1
+    /// as the account reaches `account_age_days`.
+    pub new_account_factor: f64,
+    /// Age in days from which an account is no longer dampened.
+    pub account_age_days: f64,
+    /// Factor the score of a tweet with at least one photo, video or GIF is multiplied by.
+    pub media_bonus: f64,
+    /// Cap of the final score, if any.
+    pub max_score: Option<f64>,
+}
+
+impl ScoreParams {
+    const DEFAULT: Self = Self {
+        strategy: ScoreStrategy::Default,
+        retweet_divisor: 500.0,
+        quote_divisor: 500.0,
+        like_divisor: 2000.0,
+        follower_adjust_min: 1.0,
+        follower_adjust_max: 1.5,
+        follower_scale: 1e5,
+        follow_rate_weight: 4.0 / 9.0,
+        max_follow_ratio: 10.0,
+        multiplier: 30.0,
+        freshness_base: 1.5,
+        freshness_days: 10.0,
+        new_account_factor: 1.0,
+        account_age_days: 365.0,
+        media_bonus: 1.0,
+        max_score: None,
+    };
+
+    fn media_multiplier(&self, media_count: usize) -> f64 {
+        if media_count > 0 {
+            self.media_bonus
+        } else {
+            1.0
+        }
+    }
+
+    /// Clamps `score` to be finite, non-negative and at most `max_score`.
+    fn clamp(&self, score: f64) -> f64 {
+        if !score.is_finite() {
+            return 0.0;
+        }
+        let score = score.max(0.0);
+        match self.max_score {
+            Some(max_score) => score.min(max_score),
+            None => score,
+        }
+    }
+}
+
+impl Default for ScoreParams {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Inputs of a score.
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreInput<'a> {
+    pub tweet_metrics: &'a TweetPublicMetrics,
+    pub user_metrics: &'a UserPublicMetrics,
+    pub created_at: DateTime<Utc>,
+    /// Creation time of the author's account, if known.
+    pub author_created_at: Option<DateTime<Utc>>,
+    pub media_count: usize,
+}
+
+impl<'a> ScoreInput<'a> {
+    /// Collects the inputs of `tweet` with its author from `includes`.
+    ///
+    /// Returns `None` if the author, the metrics or the creation time are missing.
+    pub fn from_tweet(tweet: &'a Tweet, includes: &'a ResponseIncludes) -> Option<Self> {
+        let author = includes.get_user(tweet.author_id()?)?;
+        Some(Self {
+            tweet_metrics: tweet.metrics()?,
+            user_metrics: author.metrics()?,
+            created_at: tweet.created_at()?,
+            author_created_at: author.created_at(),
+            media_count: tweet.media_keys().len(),
+        })
+    }
+}
+
+/// Scoring formula, shared by the router, the search tracker and list thresholds.
+pub trait Scorer: std::fmt::Debug + Send + Sync {
+    /// Returns the score, which should be finite and non-negative.
+    fn score(&self, input: &ScoreInput<'_>) -> f64;
+
+    /// Returns the score along with its terms. Scorers without the terms of the default formula
+    /// only fill in `total`.
+    fn breakdown(&self, input: &ScoreInput<'_>) -> ScoreBreakdown {
+        ScoreBreakdown {
+            total: self.score(input),
+            ..Default::default()
+        }
+    }
+}
+
+/// Formula of `compute_score_with`, weighing engagement by followers and freshness.
+#[derive(Debug, Clone, Default)]
+pub struct DefaultScorer(pub ScoreParams);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Scorer for DefaultScorer {
+    fn score(&self, input: &ScoreInput<'_>) -> f64 {
+        self.breakdown(input).total
+    }
+
+    fn breakdown(&self, input: &ScoreInput<'_>) -> ScoreBreakdown {
+        compute_score_breakdown(&self.0, input)
+    }
+}
+
+/// Absolute engagement, regardless of the author and the age of the tweet: the retweet and
+/// like ratios of `ScoreParams` added up and times `multiplier`.
+#[derive(Debug, Clone, Default)]
+pub struct EngagementScorer(pub ScoreParams);
+
+impl Scorer for EngagementScorer {
+    fn score(&self, input: &ScoreInput<'_>) -> f64 {
+        let params = &self.0;
+        let metrics = input.tweet_metrics;
+        let score = (metrics.retweet_count as f64 / params.retweet_divisor
+            + metrics.quote_count as f64 / params.quote_divisor
+            + metrics.like_count as f64 / params.like_divisor)
+            * params.multiplier
+            * params.media_multiplier(input.media_count);
+        params.clamp(score)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ScoreParams {
+    /// Returns the scorer of `strategy` with these params.
+    pub fn into_scorer(self) -> Arc<dyn Scorer> {
+        match self.strategy {
+            ScoreStrategy::Default => Arc::new(DefaultScorer(self)),
+            ScoreStrategy::Engagement => Arc::new(EngagementScorer(self)),
+        }
+    }
+}
+
+/// Computes the score of `tweet` with its author from `includes`.
+///
+/// Returns `None` if the author, the metrics or the creation time are missing.
+pub fn score_tweet(scorer: &dyn Scorer, tweet: &Tweet, includes: &ResponseIncludes) -> Option<f64> {
+    Some(scorer.score(&ScoreInput::from_tweet(tweet, includes)?))
+}
+
+/// Computes the score of `tweet` like `score_tweet`, along with its terms.
+pub fn score_tweet_breakdown(
+    scorer: &dyn Scorer,
+    tweet: &Tweet,
+    includes: &ResponseIncludes,
+) -> Option<ScoreBreakdown> {
+    Some(scorer.breakdown(&ScoreInput::from_tweet(tweet, includes)?))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Computes the score with the default params.
+pub fn compute_score(
+    tweet_metrics: &TweetPublicMetrics,
+    user_metrics: &UserPublicMetrics,
+    created_at: DateTime<Utc>,
+) -> f64 {
+    let input = ScoreInput {
+        tweet_metrics,
+        user_metrics,
+        created_at,
+        author_created_at: None,
+        media_count: 0,
+    };
+    compute_score_with(&ScoreParams::DEFAULT, &input)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Days since `created_at`, with fractions.
+///
+/// Times in the future, from clock skew, are treated as now.
+pub fn age_days(created_at: DateTime<Utc>) -> f64 {
+    age_days_at(created_at, Utc::now())
+}
+
+/// Days from `created_at` to `now`, like `age_days`.
+pub fn age_days_at(created_at: DateTime<Utc>, now: DateTime<Utc>) -> f64 {
+    let days = (now - created_at).num_milliseconds() as f64 / (1000 * 60 * 60 * 24) as f64;
+    days.max(0.0)
+}
+
+/// Terms of a score, to see which of them dominated.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScoreBreakdown {
+    /// Retweets over `retweet_divisor`.
+    pub retweet_ratio: f64,
+    /// Quotes over `quote_divisor`.
+    pub quote_ratio: f64,
+    /// Score of `retweet_ratio` and `quote_ratio` combined.
+    pub rt_score: f64,
+    pub like_score: f64,
+    /// Divisor of the engagement scores, growing with followers.
+    pub follower_adjust: f64,
+    /// Penalty of authors who follow more accounts than follow them.
+    pub follow_rate_adjust: f64,
+    pub freshness_multiplier: f64,
+    /// Dampening of young accounts, 1 if the account is old enough or its age is unknown.
+    pub account_age_adjust: f64,
+    /// `media_bonus` if the tweet has media, 1 otherwise.
+    pub media_multiplier: f64,
+    /// The score, as returned by `compute_score_with`.
+    pub total: f64,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Computes the score with `params`.
+///
+/// The score is always finite and non-negative, even with zero metrics, and at most
+/// `max_score`. Tweets from the future, by clock skew, score as if just created.
+pub fn compute_score_with(params: &ScoreParams, input: &ScoreInput<'_>) -> f64 {
+    compute_score_breakdown(params, input).total
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Computes the score with `params`, along with its terms.
+pub fn compute_score_breakdown(params: &ScoreParams, input: &ScoreInput<'_>) -> ScoreBreakdown {
+    compute_score_breakdown_at(params, input, Utc::now())
+}
+
+/// Computes the score with `params` as of `now`, along with its terms.
+pub fn compute_score_breakdown_at(
+    params: &ScoreParams,
+    input: &ScoreInput<'_>,
+    now: DateTime<Utc>,
+) -> ScoreBreakdown {
+    let &ScoreInput {
+        tweet_metrics,
+        user_metrics,
+        created_at,
+        author_created_at,
+        media_count,
+    } = input;
+    let days_diff = age_days_at(created_at, now);
+
+    let &TweetPublicMetrics {
+        retweet_count: retweets,
+        quote_count: quotes,
+        like_count: likes,
+        ..
+    } = tweet_metrics;
+    let &UserPublicMetrics {
+        followers_count: followers,
+        following_count: following,
+        ..
+    } = user_metrics;
+
+    // log2 of zero is -inf, clamped to zero by `max`
+    let retweet_ratio = retweets as f64 / params.retweet_divisor;
+    let quote_ratio = quotes as f64 / params.quote_divisor;
+    let rtparam = retweet_ratio + quote_ratio;
+    let rt_score = rtparam.log2().max(0.0) + rtparam.powi(2).min(1.0);
+
+    let likeparam = likes as f64 / params.like_divisor;
+    let like_score = likeparam.log2().max(0.0) + likeparam.min(1.0);
+
+    let f_log_y = (params.follower_adjust_max - params.follower_adjust_min).log10()
+        + 0.2f64.log10() * followers as f64 / params.follower_scale;
+    let follower_adjust = params.follower_adjust_max - 10.0f64.powf(f_log_y);
+    let follow_ratio = if following == 0 {
+        params.max_follow_ratio
+    } else {
+        (followers as f64 / following as f64).min(params.max_follow_ratio)
+    };
+    let follow_rate_adjust =
+        (1.0f64 - params.follow_rate_weight * follow_ratio.powi(2)).max(0.0);
+
+    let freshness_multiplier =
+        params.freshness_base.powf((params.freshness_days - days_diff) / params.freshness_days);
+    let account_age_adjust = match author_created_at {
+        Some(author_created_at) => {
+            let account_age = age_days_at(author_created_at, now);
+            let ramp = (account_age / params.account_age_days).clamp(0.0, 1.0);
+            params.new_account_factor + (1.0 - params.new_account_factor) * ramp
+        }
+        None => 1.0,
+    };
+    let media_multiplier = params.media_multiplier(media_count);
+
+    let score = ((rt_score + like_score) / follower_adjust - follow_rate_adjust).max(0.0)
+        * params.multiplier
+        * freshness_multiplier
+        * account_age_adjust
+        * media_multiplier;
+    ScoreBreakdown {
+        retweet_ratio,
+        quote_ratio,
+        rt_score,
+        like_score,
+        follower_adjust,
+        follow_rate_adjust,
+        freshness_multiplier,
+        account_age_adjust,
+        media_multiplier,
+        total: params.clamp(score),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    fn now() -> DateTime<Utc> {
+        Utc.ymd(2022, 1, 1).and_hms(0, 0, 0)
+    }
+
+    fn tweet_metrics(retweets: u64, quotes: u64, likes: u64) -> TweetPublicMetrics {
+        TweetPublicMetrics {
+            reply_count: 0,
+            retweet_count: retweets,
+            quote_count: quotes,
+            like_count: likes,
+        }
+    }
+
+    fn user_metrics(followers: u64, following: u64) -> UserPublicMetrics {
+        UserPublicMetrics {
+            followers_count: followers,
+            following_count: following,
+            tweet_count: 0,
+            listed_count: 0,
+        }
+    }
+
+    fn input<'a>(
+        tweet_metrics: &'a TweetPublicMetrics,
+        user_metrics: &'a UserPublicMetrics,
+        created_at: DateTime<Utc>,
+    ) -> ScoreInput<'a> {
+        ScoreInput {
+            tweet_metrics,
+            user_metrics,
+            created_at,
+            author_created_at: None,
+            media_count: 0,
+        }
+    }
+
+    fn score_at(params: &ScoreParams, input: &ScoreInput<'_>) -> f64 {
+        compute_score_breakdown_at(params, input, now()).total
+    }
+
+    /// Score as computed before the parameters were introduced, with retweets and quotes
+    /// counted together.
+    fn baseline_score(
+        tweet_metrics: &TweetPublicMetrics,
+        user_metrics: &UserPublicMetrics,
+        created_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> f64 {
+        let days_diff = (now - created_at).num_milliseconds() as f64 / 86400000.0;
+
+        let rts = (tweet_metrics.retweet_count + tweet_metrics.quote_count) as f64;
+        let rtparam = rts / 500.0;
+        let rt_score = rtparam.log2().max(0.0) + rtparam.powi(2).min(1.0);
+        let likeparam = tweet_metrics.like_count as f64 / 2000.0;
+        let like_score = likeparam.log2().max(0.0) + likeparam.min(1.0);
+
+        let followers = user_metrics.followers_count as f64;
+        let following = user_metrics.following_count as f64;
+        let f_log_y = -(2.0f64.log10()) + 0.2f64.log10() * 1e-5 * followers;
+        let follower_adjust = 1.5 - 10.0f64.powf(f_log_y);
+        let follow_rate_adjust = (1.0 - (4.0 / 9.0) * (followers / following).powi(2)).max(0.0);
+
+        ((rt_score + like_score) / follower_adjust - follow_rate_adjust).max(0.0)
+            * 30.0
+            * 1.5f64.powf((10.0 - days_diff) / 10.0)
+    }
+
+    #[test]
+    fn future_tweets_are_new() {
+        let now = now();
+        assert_eq!(age_days_at(now + Duration::minutes(1), now), 0.0);
+        assert_eq!(age_days_at(now + Duration::days(1), now), 0.0);
+        assert_eq!(age_days_at(now - Duration::hours(36), now), 1.5);
+
+        let tweet = tweet_metrics(100, 10, 1000);
+        let user = user_metrics(5000, 100);
+        let fresh = score_at(&ScoreParams::DEFAULT, &input(&tweet, &user, now));
+        for skew in [Duration::minutes(1), Duration::days(1)] {
+            let input = input(&tweet, &user, now + skew);
+            assert_eq!(score_at(&ScoreParams::DEFAULT, &input), fresh);
+        }
+    }
+
+    #[test]
+    fn score_decreases_with_age() {
+        let tweet = tweet_metrics(1000, 100, 20000);
+        let user = user_metrics(5000, 100);
+        let mut author_input = input(&tweet, &user, now());
+        author_input.author_created_at = Some(now() - Duration::days(100));
+        let mut prev = f64::INFINITY;
+        for hours in (-24..60 * 24).step_by(6) {
+            let input = ScoreInput {
+                created_at: now() - Duration::hours(hours),
+                ..author_input
+            };
+            let score = score_at(&ScoreParams::DEFAULT, &input);
+            assert!(score.is_finite() && score > 0.0, "{} at {}h", score, hours);
+            assert!(score <= prev, "{} after {} at {}h", score, prev, hours);
+            prev = score;
+        }
+    }
+
+    #[test]
+    fn max_score_caps() {
+        let tweet = tweet_metrics(100_000, 10_000, 1_000_000);
+        let user = user_metrics(5000, 100);
+        let input = input(&tweet, &user, now());
+        assert!(score_at(&ScoreParams::DEFAULT, &input) > 100.0);
+        let params = ScoreParams {
+            max_score: Some(100.0),
+            ..ScoreParams::DEFAULT
+        };
+        assert_eq!(score_at(&params, &input), 100.0);
+    }
+
+    #[test]
+    fn default_divisors_match_combined_formula() {
+        let cases = [
+            (0, 0, 0, 100, 100),
+            (250, 250, 1000, 1000, 300),
+            (500, 0, 2000, 5000, 1000),
+            (0, 500, 2000, 5000, 1000),
+            (300, 900, 50000, 200000, 500),
+            (12000, 3000, 150000, 1000000, 2000),
+            (40, 7, 800, 30, 400),
+        ];
+        let ages = [Duration::zero(), Duration::hours(5), Duration::days(3)];
+        for (retweets, quotes, likes, followers, following) in cases {
+            let tweet = tweet_metrics(retweets, quotes, likes);
+            let user = user_metrics(followers, following);
+            for age in ages {
+                let created_at = now() - age;
+                let expected = baseline_score(&tweet, &user, created_at, now());
+                let score = score_at(&ScoreParams::DEFAULT, &input(&tweet, &user, created_at));
+                assert!(
+                    (score - expected).abs() <= expected.abs() * 1e-12,
+                    "{} != {} with {:?}, {:?}, age {}",
+                    score,
+                    expected,
+                    tweet,
+                    user,
+                    age,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn zero_counts_are_finite() {
+        fn breakdown(
+            params: &ScoreParams,
+            tweet: &TweetPublicMetrics,
+            user: &UserPublicMetrics,
+        ) -> ScoreBreakdown {
+            compute_score_breakdown_at(params, &input(tweet, user, now()), now())
+        }
+
+        let tweet = tweet_metrics(1000, 100, 20000);
+        let cases = [
+            (tweet_metrics(1000, 100, 20000), user_metrics(5000, 0)),
+            (tweet_metrics(1000, 100, 20000), user_metrics(0, 100)),
+            (tweet_metrics(1000, 100, 20000), user_metrics(0, 0)),
+            (tweet_metrics(0, 0, 0), user_metrics(5000, 100)),
+            (tweet_metrics(0, 0, 0), user_metrics(0, 0)),
+        ];
+        for (tweet, user) in &cases {
+            let total = breakdown(&ScoreParams::DEFAULT, tweet, user).total;
+            assert!(
+                total.is_finite() && total >= 0.0,
+                "{} with {:?}, {:?}",
+                total,
+                tweet,
+                user,
+            );
+        }
+
+        // following nobody counts as the highest ratio
+        let params = ScoreParams {
+            follow_rate_weight: 0.001,
+            ..ScoreParams::DEFAULT
+        };
+        let no_following = breakdown(&params, &tweet, &user_metrics(5000, 0));
+        let max_ratio = breakdown(&params, &tweet, &user_metrics(5000, 500));
+        assert_eq!(no_following.follow_rate_adjust, 1.0 - 0.001 * 100.0);
+        assert_eq!(no_following.total, max_ratio.total);
+        let no_followers = breakdown(&params, &tweet, &user_metrics(0, 0));
+        assert_eq!(no_followers.follow_rate_adjust, 1.0 - 0.001 * 100.0);
+    }
+
+    #[test]
+    fn default_params_keep_scores() {
+        assert_eq!(ScoreParams::default(), ScoreParams::DEFAULT);
+        let params: ScoreParams = serde_json::from_str("{}").unwrap();
+        assert_eq!(params, ScoreParams::DEFAULT);
+
+        // 30 day old account, with media
+        let cases = [
+            ((250, 250, 1000), (1000, 300), Duration::zero()),
+            ((500, 100, 4000), (5000, 1000), Duration::hours(12)),
+            ((300, 900, 50000), (200000, 500), Duration::days(3)),
+            ((12000, 3000, 150000), (1000000, 2000), Duration::days(20)),
+            ((40, 7, 800), (30, 400), Duration::hours(1)),
+        ];
+        let scores = [
+            66.96542981876901,
+            138.53399919895543,
+            212.8772005375038,
+            175.14279645959942,
+            0.0,
+        ];
+        for (case, expected) in cases.into_iter().zip(scores) {
+            let ((retweets, quotes, likes), (followers, following), age) = case;
+            let tweet = tweet_metrics(retweets, quotes, likes);
+            let user = user_metrics(followers, following);
+            let mut input = input(&tweet, &user, now() - age);
+            input.author_created_at = Some(now() - Duration::days(30));
+            input.media_count = 2;
+            let score = score_at(&ScoreParams::DEFAULT, &input);
+            assert!(
+                (score - expected).abs() <= 1e-9,
+                "{} != {} with {:?}, {:?}, age {}",
+                score,
+                expected,
+                tweet,
+                user,
+                age,
+            );
+        }
+    }
+
+    #[test]
+    fn new_accounts_are_dampened() {
+        let params = ScoreParams {
+            new_account_factor: 0.5,
+            ..ScoreParams::DEFAULT
+        };
+        let tweet = tweet_metrics(1000, 100, 20000);
+        let user = user_metrics(5000, 100);
+        let account_age = |age: Option<Duration>| {
+            let mut input = input(&tweet, &user, now());
+            input.author_created_at = age.map(|age| now() - age);
+            compute_score_breakdown_at(&params, &input, now())
+        };
+
+        let brand_new = account_age(Some(Duration::zero()));
+        let half_year = account_age(Some(Duration::hours(365 * 12)));
+        let five_years = account_age(Some(Duration::days(5 * 365)));
+        assert_eq!(brand_new.account_age_adjust, 0.5);
+        assert_eq!(half_year.account_age_adjust, 0.75);
+        assert_eq!(five_years.account_age_adjust, 1.0);
+        assert!(five_years.total > 0.0);
+        assert_eq!(brand_new.total, five_years.total * 0.5);
+
+        // accounts created in the future, by clock skew, are brand-new
+        let skewed = account_age(Some(-Duration::days(1)));
+        assert_eq!(skewed.account_age_adjust, 0.5);
+
+        // unknown account age is neutral
+        let unknown = account_age(None);
+        assert_eq!(unknown.account_age_adjust, 1.0);
+        assert_eq!(unknown.total, five_years.total);
+    }
+
+    fn assert_breakdown(a: &ScoreBreakdown, e: &ScoreBreakdown) {
+        let pairs = [
+            (a.retweet_ratio, e.retweet_ratio, "retweet_ratio"),
+            (a.quote_ratio, e.quote_ratio, "quote_ratio"),
+            (a.rt_score, e.rt_score, "rt_score"),
+            (a.like_score, e.like_score, "like_score"),
+            (a.follower_adjust, e.follower_adjust, "follower_adjust"),
+            (
+                a.follow_rate_adjust,
+                e.follow_rate_adjust,
+                "follow_rate_adjust",
+            ),
+            (
+                a.freshness_multiplier,
+                e.freshness_multiplier,
+                "freshness_multiplier",
+            ),
+            (
+                a.account_age_adjust,
+                e.account_age_adjust,
+                "account_age_adjust",
+            ),
+            (a.media_multiplier, e.media_multiplier, "media_multiplier"),
+            (a.total, e.total, "total"),
+        ];
+        for (actual, expected, name) in pairs {
+            assert!(
+                (actual - expected).abs() <= 1e-9 * expected.abs().max(1.0),
+                "{}: {} != {}",
+                name,
+                actual,
+                expected,
+            );
+        }
+    }
+
+    #[test]
+    fn breakdown_of_reference_inputs() {
+        let params = ScoreParams {
+            new_account_factor: 0.5,
+            media_bonus: 1.2,
+            ..ScoreParams::DEFAULT
+        };
+        let cases = [
+            (
+                (250, 250, 1000),
+                (1000, 300),
+                Duration::zero(),
+                1000,
+                ScoreBreakdown {
+                    retweet_ratio: 0.5,
+                    quote_ratio: 0.5,
+                    rt_score: 1.0,
+                    like_score: 0.5,
+                    follower_adjust: 1.0079827783182713,
+                    follow_rate_adjust: 0.0,
+                    freshness_multiplier: 1.5,
+                    account_age_adjust: 1.0,
+                    media_multiplier: 1.2,
+                    total: 80.35851578252282,
+                },
+            ),
+            (
+                (1000, 200, 30000),
+                (50000, 1000),
+                Duration::hours(12),
+                100,
+                ScoreBreakdown {
+                    retweet_ratio: 2.0,
+                    quote_ratio: 0.4,
+                    rt_score: 2.2630344058337934,
+                    like_score: 4.906890595608519,
+                    follower_adjust: 1.276393202250021,
+                    follow_rate_adjust: 0.0,
+                    freshness_multiplier: 1.4698962979688366,
+                    account_age_adjust: 0.636986301369863,
+                    media_multiplier: 1.2,
+                    total: 189.34307239265394,
+                },
+            ),
+            (
+                (5, 0, 40),
+                (300, 600),
+                Duration::days(2),
+                10,
+                ScoreBreakdown {
+                    retweet_ratio: 0.01,
+                    quote_ratio: 0.0,
+                    rt_score: 0.0001,
+                    like_score: 0.02,
+                    follower_adjust: 1.002408338084004,
+                    follow_rate_adjust: 0.8888888888888888,
+                    freshness_multiplier: 1.3831618672225916,
+                    account_age_adjust: 0.5136986301369864,
+                    media_multiplier: 1.2,
+                    total: 0.0,
+                },
+            ),
+        ];
+        for (metrics, followers, age, account_days, expected) in cases {
+            let (retweets, quotes, likes) = metrics;
+            let tweet = tweet_metrics(retweets, quotes, likes);
+            let user = user_metrics(followers.0, followers.1);
+            let mut input = input(&tweet, &user, now() - age);
+            input.author_created_at = Some(now() - Duration::days(account_days));
+            input.media_count = 1;
+            let breakdown = compute_score_breakdown_at(&params, &input, now());
+            assert_breakdown(&breakdown, &expected);
+        }
+    }
+
+    /// Scores every tweet the same, to tell the injected scorer from the default one.
+    #[derive(Debug)]
+    struct FixedScorer(f64);
+
+    impl Scorer for FixedScorer {
+        fn score(&self, _input: &ScoreInput<'_>) -> f64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn tweets_are_scored_by_the_given_scorer() {
+        let mut includes = ResponseIncludes::default();
+        includes.push_user(
+            crate::User::new("1", "Author", "author")
+                .with_metrics(user_metrics(100, 100))
+                .with_created_at(now()),
+        );
+        let tweet = Tweet::new("10", "text")
+            .with_author_id("1")
+            .with_created_at(now())
+            .with_metrics(tweet_metrics(500, 0, 2000));
+
+        assert_eq!(score_tweet(&FixedScorer(7.0), &tweet, &includes), Some(7.0));
+        let breakdown = score_tweet_breakdown(&FixedScorer(7.0), &tweet, &includes).unwrap();
+        assert_eq!(
+            breakdown,
+            ScoreBreakdown {
+                total: 7.0,
+                ..Default::default()
+            },
+        );
+
+        let engagement = ScoreParams {
+            strategy: ScoreStrategy::Engagement,
+            ..ScoreParams::DEFAULT
+        };
+        // one retweet score and one like score, times the multiplier
+        assert_eq!(score_tweet(&*engagement.into_scorer(), &tweet, &includes), Some(60.0));
+        let default = score_tweet(&*ScoreParams::DEFAULT.into_scorer(), &tweet, &includes);
+        assert_ne!(default, Some(60.0));
+
+        let orphan = tweet.clone().with_author_id("2");
+        assert_eq!(score_tweet(&FixedScorer(7.0), &orphan, &includes), None);
+    }
+}
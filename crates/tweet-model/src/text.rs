@@ -0,0 +1,124 @@
+use url::Url;
+
+use crate::{Tweet, UrlEntity};
+
+enum Replacement<'a> {
+    Hashtag(&'a str),
+    Mention(&'a str),
+    Url(&'a UrlEntity),
+}
+
+/// Maps UTF-16 offsets of `text` to byte offsets. Offsets in the middle of a surrogate pair
+/// map to `None`.
+fn utf16_offsets(text: &str) -> Vec<Option<usize>> {
+    let mut ret = Vec::with_capacity(text.len() + 1);
+    for (idx, ch) in text.char_indices() {
+        ret.push(Some(idx));
+        if ch.len_utf16() == 2 {
+            ret.push(None);
+        }
+    }
+    ret.push(Some(text.len()));
+    ret
+}
+
+fn hashtag_url(tag: &str) -> Url {
+    let mut url = Url::parse("https://twitter.com/hashtag").unwrap();
+    url.path_segments_mut().unwrap().push(tag);
+    url
+}
+
+/// Renders tweet text as Discord markdown.
+///
+/// The text is unescaped, hashtags and mentions become links, t.co links are replaced with
+/// their expanded URL, and the trailing link to the tweet's own media is removed.
+pub fn render_markdown(tweet: &Tweet) -> String {
+    let text = tweet.unescaped_text();
+    let entities = tweet.entities();
+
+    let mut replacements = Vec::new();
+    replacements.extend(
+        entities
+            .hashtags()
+            .iter()
+            .map(|e| (e.range(), Replacement::Hashtag(e.tag()))),
+    );
+    replacements.extend(
+        entities
+            .mentions()
+            .iter()
+            .map(|e| (e.range(), Replacement::Mention(e.username()))),
+    );
+    replacements.extend(entities.urls().iter().map(|e| (e.range(), Replacement::Url(e))));
+    replacements.sort_by_key(|(range, _)| range.start);
+
+    let offsets = utf16_offsets(&text);
+    let mut ret = String::with_capacity(text.len());
+    let mut cursor = 0;
+    for (range, replacement) in replacements {
+        let start = offsets.get(range.start).copied().flatten();
+        let end = offsets.get(range.end).copied().flatten();
+        let (start, end) = match (start, end) {
+            // skip entities overlapping the previous one, and ones with invalid ranges
+            (Some(start), Some(end)) if cursor <= start && start <= end => (start, end),
+            _ => {
+                log::debug!("Skipping entity with invalid range {:?}", range);
+                continue;
+            }
+        };
+
+        ret.push_str(&text[cursor..start]);
+        let original = &text[start..end];
+        match replacement {
+            Replacement::Hashtag(tag) => {
+                ret.push_str(&format!("[{}]({})", original, hashtag_url(tag)));
+            }
+            Replacement::Mention(username) => {
+                ret.push_str(&format!("[{}](https://twitter.com/{})", original, username));
+            }
+            Replacement::Url(entity) => {
+                let is_media_link = entity.media_key().is_some()
+                    || entity.display_url().starts_with("pic.twitter.com/");
+                let trailing = text[end..].trim().is_empty();
+                if is_media_link && trailing {
+                    // shown as embed images instead
+                } else if is_media_link {
+                    ret.push_str(original);
+                } else {
+                    ret.push_str(&format!(
+                        "[{}]({})",
+                        entity.display_url(),
+                        entity.expanded_url(),
+                    ));
+                }
+            }
+        }
+        cursor = end;
+    }
+    ret.push_str(&text[cursor..]);
+    ret.truncate(ret.trim_end().len());
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixture::Fixtures;
+
+    #[test]
+    fn utf16_offsets_map_code_units() {
+        for seed in 0..200 {
+            let text = Fixtures::new(seed).response_item().data.unescaped_text();
+            let offsets = utf16_offsets(&text);
+            assert_eq!(offsets.len(), text.encode_utf16().count() + 1);
+            for (unit, offset) in offsets.iter().enumerate() {
+                let prefix_units = match offset {
+                    Some(offset) => text[..*offset].encode_utf16().count(),
+                    // the second unit of a surrogate pair
+                    None => text[..offsets[unit - 1].unwrap()].encode_utf16().count() + 1,
+                };
+                assert_eq!(prefix_units, unit, "{:?}", text);
+            }
+        }
+    }
+}
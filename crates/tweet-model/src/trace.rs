@@ -0,0 +1,90 @@
+//! Tracing facade shared by the crates of the workspace.
+//!
+//! With the `tracing` feature, `span!` creates a `tracing` span, and futures run inside one with
+//! `in_span`, so a subscriber can reconstruct the timeline of each tweet from stream or poll to
+//! delivery. Without a subscriber, spans are written as `log` records at the trace level. Without
+//! the feature, spans are no-ops and compile away.
+//!
+//! Span names and field names are stable: `tweet_id`, `engine`, `list_id`, `user_id`, `term`
+//! and `destination`, the last one redacted.
+
+#[doc(hidden)]
+#[cfg(feature = "tracing")]
+pub use tracing as __tracing;
+
+/// Span of an operation.
+#[cfg(feature = "tracing")]
+pub type Span = tracing::Span;
+
+/// Span of an operation; a no-op without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone, Default)]
+pub struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub fn none() -> Self {
+        Self
+    }
+
+    pub fn current() -> Self {
+        Self
+    }
+
+    pub fn enter(&self) -> Entered {
+        Entered
+    }
+}
+
+/// Guard of an entered span; a no-op without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug)]
+pub struct Entered;
+
+/// Creates a span at the info level, e.g. `span!("route", tweet_id = %id)`.
+///
+/// Fields are not evaluated without the `tracing` feature.
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! span {
+    ($($args:tt)*) => {
+        $crate::trace::__tracing::info_span!($($args)*)
+    };
+}
+
+/// Creates a span at the info level, e.g. `span!("route", tweet_id = %id)`.
+///
+/// Fields are not evaluated without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+#[macro_export]
+macro_rules! span {
+    ($($args:tt)*) => {
+        $crate::trace::Span::none()
+    };
+}
+
+/// Future running inside a span.
+#[cfg(feature = "tracing")]
+pub type InSpan<F> = tracing::instrument::Instrumented<F>;
+
+/// Future running inside a span; the future itself without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+pub type InSpan<F> = F;
+
+/// Runs futures inside spans.
+pub trait TraceExt: std::future::Future + Sized {
+    /// Enters `span` whenever the future is polled.
+    fn in_span(self, span: Span) -> InSpan<Self>;
+}
+
+impl<F: std::future::Future> TraceExt for F {
+    #[cfg(feature = "tracing")]
+    fn in_span(self, span: Span) -> InSpan<Self> {
+        tracing::Instrument::instrument(self, span)
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    fn in_span(self, _span: Span) -> InSpan<Self> {
+        self
+    }
+}
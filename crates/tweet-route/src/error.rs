@@ -2,8 +2,10 @@
 pub enum Error {
     #[error("JS function {0} not found, or is not a function")]
     FunctionNotFound(String),
+    #[error("tweet {0} is missing its author, metrics or retweeted tweet")]
+    IncompleteTweet(String),
     #[error("uncaught exception: {0}")]
-    JsException(String),
+    JsException(Box<JsError>),
     #[error("cannot convert V8 data: {0}")]
     JsInterop(
         #[from]
@@ -11,3 +13,88 @@ pub enum Error {
         serde_v8::Error,
     ),
 }
+
+impl Error {
+    pub fn js_error(&self) -> Option<&JsError> {
+        if let Self::JsException(e) = self {
+            Some(e)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct JsError {
+    pub message: String,
+    pub resource_name: Option<String>,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub stack: Vec<Frame>,
+    /// `stack` property of the thrown value, if it was an `Error` object.
+    pub formatted_stack: Option<String>,
+}
+
+impl JsError {
+    pub(crate) fn from_message(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            resource_name: None,
+            line: None,
+            column: None,
+            stack: Vec::new(),
+            formatted_stack: None,
+        }
+    }
+
+    /// Formats the stack trace for logging, preferring the one V8 formatted itself.
+    pub fn format_stack(&self) -> String {
+        if let Some(stack) = &self.formatted_stack {
+            return stack.clone();
+        }
+
+        let mut ret = self.message.clone();
+        for frame in &self.stack {
+            ret.push_str("\n    at ");
+            ret.push_str(&frame.to_string());
+        }
+        ret
+    }
+}
+
+impl std::fmt::Display for JsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(line) = self.line {
+            write!(
+                f,
+                " ({}:{}",
+                self.resource_name.as_deref().unwrap_or("<anonymous>"),
+                line,
+            )?;
+            if let Some(column) = self.column {
+                write!(f, ":{}", column)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub function_name: Option<String>,
+    pub script_name: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let script_name = self.script_name.as_deref().unwrap_or("<anonymous>");
+        match &self.function_name {
+            Some(name) => write!(f, "{} ({}:{}:{})", name, script_name, self.line, self.column),
+            None => write!(f, "{}:{}:{}", script_name, self.line, self.column),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Copy, Clone, Default, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeapStats {
+    pub used_heap_size: usize,
+    pub total_heap_size: usize,
+    pub heap_size_limit: usize,
+    pub external_memory: usize,
+}
+
+impl HeapStats {
+    pub(crate) fn from_isolate(isolate: &mut v8::Isolate) -> Self {
+        let mut stats = v8::HeapStatistics::default();
+        isolate.get_heap_statistics(&mut stats);
+        Self {
+            used_heap_size: stats.used_heap_size(),
+            total_heap_size: stats.total_heap_size(),
+            heap_size_limit: stats.heap_size_limit(),
+            external_memory: stats.external_memory(),
+        }
+    }
+
+    /// Ratio of used heap to the configured heap limit.
+    pub fn usage_ratio(&self) -> f64 {
+        if self.heap_size_limit == 0 {
+            0.0
+        } else {
+            self.used_heap_size as f64 / self.heap_size_limit as f64
+        }
+    }
+}
+
+/// Adds up the statistics of several isolates, e.g. the members of a pool.
+impl std::iter::Sum for HeapStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Self::default(), |acc, stats| Self {
+            used_heap_size: acc.used_heap_size + stats.used_heap_size,
+            total_heap_size: acc.total_heap_size + stats.total_heap_size,
+            heap_size_limit: acc.heap_size_limit + stats.heap_size_limit,
+            external_memory: acc.external_memory + stats.external_memory,
+        })
+    }
+}
+
+impl std::fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MIB: f64 = 1024.0 * 1024.0;
+        write!(
+            f,
+            "used {:.2} MiB / total {:.2} MiB (limit {:.2} MiB), external {:.2} MiB",
+            self.used_heap_size as f64 / MIB,
+            self.total_heap_size as f64 / MIB,
+            self.heap_size_limit as f64 / MIB,
+            self.external_memory as f64 / MIB,
+        )
+    }
+}
+
+/// Thread-safe view of the latest heap statistics of a `Router`.
+///
+/// `Router` itself is tied to the thread owning the isolate; this handle can be moved to other
+/// tasks and reports whatever was sampled by the last `Router::heap_stats` call.
+#[derive(Debug, Clone, Default)]
+pub struct HeapStatsHandle {
+    inner: Arc<Mutex<Option<HeapStats>>>,
+}
+
+impl HeapStatsHandle {
+    pub fn get(&self) -> Option<HeapStats> {
+        *self.inner.lock().unwrap()
+    }
+
+    pub(crate) fn set(&self, stats: HeapStats) {
+        *self.inner.lock().unwrap() = Some(stats);
+    }
+}
@@ -1,16 +1,88 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use tweet_model::{
     self as model,
     cache::*,
 };
 
 mod error;
-mod score;
+mod heap;
+mod validate;
+
+pub use error::{Error, Frame, JsError};
+pub use heap::{HeapStats, HeapStatsHandle};
+pub use validate::{SamplePayload, ValidationProblem, ValidationReport};
+pub use model::score::{
+    compute_score, compute_score_breakdown, compute_score_with, score_tweet, score_tweet_breakdown,
+    DefaultScorer, EngagementScorer, ScoreBreakdown, ScoreInput, ScoreParams, ScoreStrategy, Scorer,
+};
+
+/// Name of the script when a `Router` is created from a single source.
+pub const DEFAULT_SCRIPT_NAME: &str = "route.js";
+/// Origin of routes added by tag routes instead of a script.
+pub const TAG_ROUTE_ORIGIN: &str = "tag_webhooks";
+
+fn extract_exception(try_catch: &mut v8::TryCatch<'_, v8::HandleScope<'_>>) -> JsError {
+    let formatted_stack = try_catch
+        .stack_trace()
+        .filter(|stack| stack.is_string())
+        .map(|stack| stack.to_rust_string_lossy(try_catch));
+
+    let message = if let Some(message) = try_catch.message() {
+        message
+    } else {
+        let mut ret = JsError::from_message("unknown exception");
+        ret.formatted_stack = formatted_stack;
+        return ret;
+    };
+
+    let text = message.get(try_catch).to_rust_string_lossy(try_catch);
+    let resource_name = message
+        .get_script_resource_name(try_catch)
+        .filter(|name| name.is_string())
+        .map(|name| name.to_rust_string_lossy(try_catch));
+    let line = message.get_line_number(try_catch);
+    // V8 reports 0-based start column for messages, but 1-based for stack frames
+    let column = line.map(|_| message.get_start_column() + 1);
 
-pub use error::Error;
-pub use score::compute_score;
+    let mut stack = Vec::new();
+    if let Some(trace) = message.get_stack_trace(try_catch) {
+        for idx in 0..trace.get_frame_count() {
+            let frame = if let Some(frame) = trace.get_frame(try_catch, idx) {
+                frame
+            } else {
+                continue;
+            };
+            let function_name = frame
+                .get_function_name(try_catch)
+                .map(|name| name.to_rust_string_lossy(try_catch))
+                .filter(|name| !name.is_empty());
+            let script_name = frame
+                .get_script_name(try_catch)
+                .map(|name| name.to_rust_string_lossy(try_catch));
+            stack.push(Frame {
+                function_name,
+                script_name,
+                line: frame.get_line_number(),
+                column: frame.get_column(),
+            });
+        }
+    }
+
+    JsError {
+        message: text,
+        resource_name,
+        line,
+        column,
+        stack,
+        formatted_stack,
+    }
+}
 
 fn load_script(
     isolate: &mut v8::OwnedIsolate,
+    name: &str,
     script: &str,
 ) -> Result<v8::Global<v8::Function>, Error> {
     let mut global_scope = v8::HandleScope::new(isolate);
@@ -19,9 +91,24 @@ fn load_script(
 
     let mut try_catch = v8::TryCatch::new(&mut script_scope);
 
+    let resource_name = v8::String::new(&mut try_catch, name).unwrap();
+    let source_map_url = v8::undefined(&mut try_catch);
+    let origin = v8::ScriptOrigin::new(
+        &mut try_catch,
+        resource_name.into(),
+        0,
+        0,
+        false,
+        0,
+        source_map_url.into(),
+        false,
+        false,
+        false,
+    );
+
     let result = v8::String::new(&mut try_catch, script);
     let s = if let Some(s) = result {
-        v8::Script::compile(&mut try_catch, s, None)
+        v8::Script::compile(&mut try_catch, s, Some(&origin))
     } else {
         None
     };
@@ -31,9 +118,7 @@ fn load_script(
         None
     };
     if try_catch.has_caught() {
-        let msg = try_catch.message().unwrap();
-        let msg = msg.get(&mut try_catch).to_rust_string_lossy(&mut try_catch);
-        return Err(Error::JsException(msg));
+        return Err(Error::JsException(Box::new(extract_exception(&mut try_catch))));
     }
     drop(try_catch);
 
@@ -55,120 +140,421 @@ fn load_script(
     Ok(route_fn)
 }
 
+#[derive(Debug)]
+struct RouteScript {
+    name: String,
+    route_fn: v8::Global<v8::Function>,
+}
+
+/// Error thrown by one of the scripts of a `Router`.
+#[derive(Debug)]
+pub struct ScriptError {
+    pub origin: String,
+    pub error: Error,
+}
+
+/// Webhook receiving tweets which match rules with a tag, without a route script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagRoute {
+    pub url: url::Url,
+    pub thread_id: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct Router {
     isolate: v8::OwnedIsolate,
-    route_fn: v8::Global<v8::Function>,
+    scripts: Vec<RouteScript>,
+    /// Routes added for tweets matching rules with the tag, besides those of the scripts.
+    tag_routes: BTreeMap<String, Vec<TagRoute>>,
+    scorer: Arc<dyn Scorer>,
+    heap_stats: HeapStatsHandle,
 }
 
 impl Router {
     pub fn new(heap_limit: usize, script: &str) -> Result<Self, Error> {
+        Self::with_scripts(heap_limit, [(DEFAULT_SCRIPT_NAME, script)])
+    }
+
+    /// Creates a router running each `(name, source)` script in order, concatenating their routes.
+    pub fn with_scripts<'a>(
+        heap_limit: usize,
+        scripts: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, Error> {
         let mut isolate = v8::Isolate::new(v8::CreateParams::default().heap_limits(0, heap_limit));
-        let route_fn = load_script(&mut isolate, script)?;
-        Ok(Self { isolate, route_fn })
+        isolate.set_capture_stack_trace_for_uncaught_exceptions(true, 32);
+        let mut loaded = Vec::new();
+        for (name, script) in scripts {
+            let route_fn = load_script(&mut isolate, name, script)?;
+            loaded.push(RouteScript {
+                name: name.to_owned(),
+                route_fn,
+            });
+        }
+        let mut router = Self {
+            isolate,
+            scripts: loaded,
+            tag_routes: Default::default(),
+            scorer: Arc::new(DefaultScorer::default()),
+            heap_stats: Default::default(),
+        };
+        router.validate_on_load();
+        Ok(router)
+    }
+
+    pub fn script_names(&self) -> impl Iterator<Item = &str> {
+        self.scripts.iter().map(|s| &*s.name)
     }
 
-    pub fn reload(&mut self, script: &str) -> Result<(), Error> {
-        let route_fn = load_script(&mut self.isolate, script)?;
-        self.route_fn = route_fn;
+    /// Replaces the script named `name`, or appends it if there's no such script.
+    ///
+    /// The previous version stays active if the new one fails to load.
+    pub fn reload(&mut self, name: &str, script: &str) -> Result<(), Error> {
+        let route_fn = load_script(&mut self.isolate, name, script)?;
+        if let Some(entry) = self.scripts.iter_mut().find(|s| s.name == name) {
+            entry.route_fn = route_fn;
+        } else {
+            self.scripts.push(RouteScript {
+                name: name.to_owned(),
+                route_fn,
+            });
+        }
+        self.validate_on_load();
         Ok(())
     }
 
-    pub async fn call<'data, Cache: LoadCache<model::Tweet>>(
+    /// Replaces the tag routes, which send tweets to webhooks by the tags of matching rules with
+    /// the standard rendering.
+    ///
+    /// A router without scripts routes tweets with tag routes only.
+    pub fn set_tag_routes(&mut self, tag_routes: BTreeMap<String, Vec<TagRoute>>) {
+        self.tag_routes = tag_routes;
+    }
+
+    /// Replaces the scorer of the `score` of payloads, `DefaultScorer` with the default params
+    /// unless set.
+    pub fn set_scorer(&mut self, scorer: Arc<dyn Scorer>) {
+        self.scorer = scorer;
+    }
+
+    /// Samples heap statistics of the isolate, also updating the shared handle.
+    pub fn heap_stats(&mut self) -> HeapStats {
+        let stats = HeapStats::from_isolate(&mut self.isolate);
+        self.heap_stats.set(stats);
+        stats
+    }
+
+    pub fn heap_stats_handle(&self) -> HeapStatsHandle {
+        self.heap_stats.clone()
+    }
+
+    /// Asks V8 to free as much memory as possible, running a full GC.
+    pub fn low_memory_notification(&mut self) {
+        self.isolate.low_memory_notification();
+    }
+
+    pub async fn call<'data, Cache>(
         &mut self,
         res: &'data model::ResponseItem<model::Tweet, model::StreamMeta>,
         cache: &Cache,
+    ) -> Result<RouteResult<'data>, Error>
+    where
+        Cache: LoadCache<CacheData>,
+    {
+        let previous = PreviousRoute::load(res, cache).await?;
+        self.call_with(res, previous)
+    }
+
+    /// Routes a tweet of the filtered stream like `call`, with its route data read beforehand,
+    /// e.g. on a thread which has the cache.
+    pub fn call_with<'data>(
+        &mut self,
+        res: &'data model::ResponseItem<model::Tweet, model::StreamMeta>,
+        previous: PreviousRoute,
     ) -> Result<RouteResult<'data>, Error> {
-        let model::ResponseItem {
-            data,
-            includes,
-            meta,
-        } = res;
-        let tweet = data
-            .get_retweet_source()
-            .map(|rt_id| includes.get_tweet(rt_id).unwrap());
-        let original_data = if tweet.is_some() {
-            let author_id = data.author_id().unwrap();
-            let author = includes.get_user(author_id).unwrap();
-            Some((data, author))
-        } else {
-            None
-        };
-        let tweet = tweet.unwrap_or(data);
-        let author_id = tweet.author_id().unwrap();
-        let author = includes.get_user(author_id).unwrap();
+        let data = stream_payload(&*self.scorer, res, previous)?;
+        self.route(data)
+    }
+
+    /// Routes a tweet which didn't come from the filtered stream, e.g. one of a list.
+    ///
+    /// `tags` are passed to the scripts in place of matching rules. The tweet is routed as not
+    /// cached, without previous route data.
+    pub fn call_plain<'data>(
+        &mut self,
+        tweet: &'data model::Tweet,
+        includes: &'data model::ResponseIncludes,
+        tags: &[&'data str],
+    ) -> Result<RouteResult<'data>, Error> {
+        let data = make_payload(&*self.scorer, tweet, includes, tags.to_vec(), false, None)?;
+        self.route(data)
+    }
 
-        let has_cache = cache.has(tweet.id()).await.unwrap_or(false);
+    fn route<'data>(&mut self, mut data: RoutePayload<'data>) -> Result<RouteResult<'data>, Error> {
+        let span = model::span!("route", tweet_id = %data.tweet.id());
+        let _guard = span.enter();
+        let mut routes = Vec::new();
+        let mut errors = Vec::new();
+        let mut score_override = None;
+        for idx in 0..self.scripts.len() {
+            let origin = self.scripts[idx].name.clone();
+            let ret = self
+                .invoke::<RouteReturn>(idx, &data)
+                .and_then(RouteReturn::into_parts);
+            match ret {
+                Ok((items, score)) => {
+                    routes.extend(items.into_iter().map(|mut item| {
+                        item.origin = origin.clone();
+                        item
+                    }));
+                    score_override = score.or(score_override);
+                }
+                Err(error) => {
+                    errors.push(ScriptError { origin, error });
+                }
+            }
+        }
+        if let Some(score) = score_override {
+            data.score = score;
+        }
+        for &tag in &data.tags {
+            for tag_route in self.tag_routes.get(tag).into_iter().flatten() {
+                // a tweet matching several tags is sent to each webhook once
+                let duplicate = routes
+                    .iter()
+                    .any(|r| r.url == tag_route.url && r.thread_id == tag_route.thread_id);
+                if !duplicate {
+                    routes.push(RouteResultItem {
+                        origin: TAG_ROUTE_ORIGIN.to_owned(),
+                        url: tag_route.url.clone(),
+                        thread_id: tag_route.thread_id.clone(),
+                        payload: Default::default(),
+                        render: Some(Default::default()),
+                    });
+                }
+            }
+        }
+        if !errors.is_empty() && errors.len() == self.scripts.len() {
+            let first = errors.remove(0);
+            for ScriptError { origin, error } in errors {
+                log::error!("Route script {} failed: {}", origin, error);
+            }
+            return Err(first.error);
+        }
 
-        let tweet_metrics = tweet.metrics().unwrap();
-        let user_metrics = author.metrics().unwrap();
-        let score = score::compute_score(tweet_metrics, user_metrics, tweet.created_at().unwrap());
+        Ok(RouteResult {
+            payload: data,
+            routes,
+            errors,
+            score_override,
+        })
+    }
 
-        let media = tweet
-            .media_keys()
-            .iter()
-            .filter_map(|k| includes.get_media(k))
-            .collect::<Vec<_>>();
-        let tags = meta
-            .matching_rules()
-            .iter()
-            .map(|x| x.tag())
-            .collect::<Vec<_>>();
+    /// Runs every route function against `sample_payload` and checks the shape of their return
+    /// values.
+    ///
+    /// Exceptions are reported as problems, unless every script threw.
+    pub fn validate(&mut self, sample_payload: &RoutePayload<'_>) -> Result<ValidationReport, Error> {
+        let mut report = ValidationReport::default();
+        let mut errors = Vec::new();
+        for idx in 0..self.scripts.len() {
+            let origin = self.scripts[idx].name.clone();
+            match self.invoke::<serde_json::Value>(idx, sample_payload) {
+                Ok(ret) => report.check_value(&origin, &ret),
+                Err(e) => {
+                    report.add_error(&origin, &e);
+                    errors.push(e);
+                }
+            }
+        }
+        if !errors.is_empty() && errors.len() == self.scripts.len() {
+            return Err(errors.remove(0));
+        }
+        Ok(report)
+    }
 
-        let data = RoutePayload {
-            tweet,
-            author,
-            original_tweet: original_data.as_ref().map(|&(tweet, _)| tweet),
-            original_author: original_data.as_ref().map(|&(_, author)| author),
-            media,
-            score,
-            tags,
-            cached: has_cache,
-        };
+    fn validate_on_load(&mut self) {
+        let sample = SamplePayload::new();
+        match self.validate(&sample.payload()) {
+            Ok(report) => {
+                for problem in &report.problems {
+                    log::warn!("Route script returned invalid data for sample payload: {}", problem);
+                }
+            }
+            Err(e) => {
+                log::warn!("Route script failed on sample payload: {}", e);
+            }
+        }
+    }
 
+    fn invoke<T>(&mut self, script_idx: usize, data: &RoutePayload<'_>) -> Result<T, Error>
+    where
+        T: serde::de::DeserializeOwned,
+    {
         let mut global_scope = v8::HandleScope::new(&mut self.isolate);
         let ctx = v8::Context::new(&mut global_scope);
         let mut script_scope = v8::ContextScope::new(&mut global_scope, ctx);
 
         let mut scope_val = v8::TryCatch::new(&mut script_scope);
         let scope = &mut scope_val;
-        let data_obj = serde_v8::to_v8(scope, &data)?;
+        let data_obj = serde_v8::to_v8(scope, data)?;
 
         let recv = v8::undefined(scope);
-        let route_fn = self.route_fn.open(scope);
+        let route_fn = self.scripts[script_idx].route_fn.open(scope);
         let ret = route_fn.call(scope, recv.into(), &[data_obj]);
         if scope.has_caught() {
-            let msg = scope.message().unwrap();
-            let msg = msg.get(scope).to_rust_string_lossy(scope);
-            return Err(Error::JsException(msg));
+            return Err(Error::JsException(Box::new(extract_exception(scope))));
         }
         let ret = ret.unwrap();
 
-        let routes = serde_v8::from_v8(scope, ret)?;
+        let ret = serde_v8::from_v8(scope, ret)?;
         drop(scope_val);
         script_scope.perform_microtask_checkpoint();
+        Ok(ret)
+    }
+}
 
-        Ok(RouteResult {
-            payload: data,
-            routes,
-        })
+/// Returns the tweet to route for `data`, which is the source tweet of retweets.
+fn routed_tweet<'data>(
+    data: &'data model::Tweet,
+    includes: &'data model::ResponseIncludes,
+) -> Result<&'data model::Tweet, Error> {
+    match data.get_retweet_source() {
+        Some(rt_id) => includes
+            .get_tweet(rt_id)
+            .ok_or_else(|| Error::IncompleteTweet(data.id().to_owned())),
+        None => Ok(data),
     }
 }
 
+fn stream_payload<'data>(
+    scorer: &dyn Scorer,
+    res: &'data model::ResponseItem<model::Tweet, model::StreamMeta>,
+    previous: PreviousRoute,
+) -> Result<RoutePayload<'data>, Error> {
+    let model::ResponseItem {
+        data,
+        includes,
+        meta,
+    } = res;
+    let tags = meta
+        .matching_rules()
+        .iter()
+        .map(|x| x.tag())
+        .collect::<Vec<_>>();
+    make_payload(scorer, data, includes, tags, previous.cached, previous.data)
+}
+
+fn make_payload<'data>(
+    scorer: &dyn Scorer,
+    data: &'data model::Tweet,
+    includes: &'data model::ResponseIncludes,
+    tags: Vec<&'data str>,
+    cached: bool,
+    previous: Option<CacheData>,
+) -> Result<RoutePayload<'data>, Error> {
+    let incomplete = || Error::IncompleteTweet(data.id().to_owned());
+    let author_of = |tweet: &model::Tweet| tweet.author_id().and_then(|id| includes.get_user(id));
+
+    let tweet = routed_tweet(data, includes)?;
+    let original_data = if tweet.id() != data.id() {
+        Some((data, author_of(data).ok_or_else(incomplete)?))
+    } else {
+        None
+    };
+    let author = author_of(tweet).ok_or_else(incomplete)?;
+
+    let media = tweet
+        .media_keys()
+        .iter()
+        .filter_map(|k| includes.get_media(k))
+        .collect::<Vec<_>>();
+
+    let score_input = ScoreInput {
+        tweet_metrics: tweet.metrics().ok_or_else(incomplete)?,
+        user_metrics: author.metrics().ok_or_else(incomplete)?,
+        created_at: tweet.created_at().ok_or_else(incomplete)?,
+        author_created_at: author.created_at(),
+        media_count: tweet.media_keys().len(),
+    };
+    let score_breakdown = scorer.breakdown(&score_input);
+    let score = score_breakdown.total;
+    debug_assert!(score.is_finite(), "score of tweet {} is {}", tweet.id(), score);
+
+    Ok(RoutePayload {
+        tweet,
+        text: tweet.markdown_text(),
+        author,
+        original_tweet: original_data.map(|(tweet, _)| tweet),
+        original_author: original_data.map(|(_, author)| author),
+        media,
+        score,
+        score_breakdown,
+        author_age_days: author.created_at().map(model::score::age_days),
+        tags,
+        cached,
+        previous,
+    })
+}
+
 #[derive(Debug, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RoutePayload<'a> {
     pub tweet: &'a model::Tweet,
+    /// Text of `tweet` as markdown, with entities linked and t.co links expanded.
+    pub text: String,
     pub author: &'a model::User,
     pub original_tweet: Option<&'a model::Tweet>,
     pub original_author: Option<&'a model::User>,
     pub media: Vec<&'a model::Media>,
     pub score: f64,
+    /// Terms of `score`.
+    pub score_breakdown: model::score::ScoreBreakdown,
+    /// Age of the author's account in days, if known.
+    pub author_age_days: Option<f64>,
     pub tags: Vec<&'a str>,
     pub cached: bool,
+    /// Route data recorded when this tweet was last routed, if it was.
+    pub previous: Option<CacheData>,
+}
+
+/// Route data of a tweet of the filtered stream, read from the cache before routing it.
+#[derive(Debug, Clone, Default)]
+pub struct PreviousRoute {
+    /// Whether the tweet is cached, which it may be without being relayed.
+    pub cached: bool,
+    /// Route data recorded when the tweet was last routed, if it was.
+    pub data: Option<CacheData>,
+}
+
+impl PreviousRoute {
+    /// Reads the route data of the tweet of `res` from `cache`.
+    pub async fn load<Cache>(
+        res: &model::ResponseItem<model::Tweet, model::StreamMeta>,
+        cache: &Cache,
+    ) -> Result<Self, Error>
+    where
+        Cache: LoadCache<CacheData>,
+    {
+        let tweet = routed_tweet(&res.data, &res.includes)?;
+
+        // the tweet alone may be cached without being relayed, e.g. as an unrouted tweet
+        let cached = LoadCache::<CacheData>::has(cache, tweet.id()).await.unwrap_or(false);
+        let data = if cached {
+            match LoadCache::<CacheData>::load(cache, tweet.id()).await {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    log::debug!("No previous route data for {}: {}", tweet.id(), e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Ok(Self { cached, data })
+    }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct CacheData {
     tweet_id: String,
@@ -176,7 +562,11 @@ pub struct CacheData {
     target_tweet_id: Option<String>,
     target_author_id: Option<String>,
     media_keys: Vec<String>,
+    /// Score the tweet was routed with, which route scripts may have overridden.
     score: f64,
+    /// Score computed from the metrics, if a route script overrode it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metric_score: Option<f64>,
     tags: Vec<String>,
 }
 
@@ -186,6 +576,18 @@ impl CacheItem for CacheData {
     }
 }
 
+impl CacheData {
+    /// Tags of the rules the tweet matched when it was routed.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// ID of the retweeted tweet, if the routed tweet was a retweet.
+    pub fn target_tweet_id(&self) -> Option<&str> {
+        self.target_tweet_id.as_deref()
+    }
+}
+
 impl From<&'_ RoutePayload<'_>> for CacheData {
     fn from(payload: &'_ RoutePayload<'_>) -> Self {
         let target_tweet_id = payload.original_tweet.and(Some(payload.tweet)).map(|x| x.id().to_owned());
@@ -199,21 +601,90 @@ impl From<&'_ RoutePayload<'_>> for CacheData {
             target_author_id,
             media_keys: payload.media.iter().map(|&x| x.key().to_owned()).collect(),
             score: payload.score,
+            metric_score: Some(payload.score_breakdown.total).filter(|&s| s != payload.score),
             tags: payload.tags.iter().map(|&x| x.to_owned()).collect(),
         }
     }
 }
 
+/// Return value of a route function, either the routes or an object with the routes and a score
+/// to use instead of the metric score.
+///
+/// ```js
+/// return { routes: [{ url }], scoreOverride: 42 };
+/// ```
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum RouteReturn {
+    Routes(Vec<RouteResultItem>),
+    WithScore {
+        routes: Vec<RouteResultItem>,
+        #[serde(default, rename = "scoreOverride")]
+        score_override: Option<f64>,
+    },
+}
+
+impl RouteReturn {
+    fn into_parts(self) -> Result<(Vec<RouteResultItem>, Option<f64>), Error> {
+        match self {
+            Self::Routes(routes) => Ok((routes, None)),
+            Self::WithScore {
+                routes,
+                score_override,
+            } => {
+                if let Some(score) = score_override {
+                    if !score.is_finite() || score < 0.0 {
+                        return Err(Error::JsException(Box::new(JsError::from_message(format!(
+                            "scoreOverride must be a non-negative number, got {}",
+                            score,
+                        )))));
+                    }
+                }
+                Ok((routes, score_override))
+            }
+        }
+    }
+}
+
 #[derive(Debug, serde::Deserialize)]
 pub struct RouteResultItem {
+    /// Name of the script which returned this route.
+    #[serde(skip)]
+    pub origin: String,
     pub url: url::Url,
+    /// Thread in the webhook's channel to post into.
+    #[serde(default, rename = "threadId")]
+    pub thread_id: Option<String>,
+    #[serde(default)]
     pub payload: serde_json::Value,
+    /// When present, the standard tweet rendering is sent with these overrides instead of
+    /// `payload`.
+    #[serde(default)]
+    pub render: Option<RouteRender>,
+}
+
+/// Overrides a route script can apply to the standard tweet rendering.
+///
+/// ```js
+/// return [{ url, render: { contentPrefix: "New art!\n", color: 0xff0000, suppressMedia: false } }];
+/// ```
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteRender {
+    pub content_prefix: Option<String>,
+    pub content_suffix: Option<String>,
+    pub color: Option<u32>,
+    #[serde(default)]
+    pub suppress_media: bool,
+    pub username: Option<String>,
 }
 
 #[derive(Debug)]
 pub struct RouteResult<'a> {
     payload: RoutePayload<'a>,
     routes: Vec<RouteResultItem>,
+    errors: Vec<ScriptError>,
+    score_override: Option<f64>,
 }
 
 impl<'a> RouteResult<'a> {
@@ -221,6 +692,12 @@ impl<'a> RouteResult<'a> {
         &self.payload
     }
 
+    /// Score set by a route script in place of the metric score, which is then the `score` of
+    /// the payload. The last script setting one wins.
+    pub fn score_override(&self) -> Option<f64> {
+        self.score_override
+    }
+
     pub async fn cache_recursive<Cache>(&self, cache: &Cache) -> Result<(), Cache::Error> where
         Cache: StoreCache<model::Tweet> + StoreCache<model::User> + StoreCache<model::Media> + StoreCache<CacheData>,
     {
@@ -252,4 +729,380 @@ impl<'a> RouteResult<'a> {
     pub fn routes(&self) -> &[RouteResultItem] {
         &self.routes
     }
+
+    pub fn into_routes(self) -> Vec<RouteResultItem> {
+        self.routes
+    }
+
+    /// Drops the payload, keeping what's needed to build the result again with
+    /// `RouteOutcome::into_result`.
+    pub fn into_outcome(self) -> RouteOutcome {
+        let previous = PreviousRoute {
+            cached: self.payload.cached,
+            data: self.payload.previous,
+        };
+        RouteOutcome {
+            previous,
+            routes: self.routes,
+            errors: self.errors,
+            score_override: self.score_override,
+        }
+    }
+
+    /// Errors from scripts which failed while others succeeded.
+    pub fn errors(&self) -> &[ScriptError] {
+        &self.errors
+    }
+}
+
+/// `RouteResult` of a tweet of the filtered stream without its payload, which borrows the tweet,
+/// so that it can be sent back from the thread which routed a copy of the tweet.
+#[derive(Debug, Default)]
+pub struct RouteOutcome {
+    previous: PreviousRoute,
+    routes: Vec<RouteResultItem>,
+    errors: Vec<ScriptError>,
+    score_override: Option<f64>,
+}
+
+impl RouteOutcome {
+    /// Builds the result of routing the tweet of `res`, scoring it with `scorer` like the router
+    /// which routed it.
+    pub fn into_result<'data>(
+        self,
+        scorer: &dyn Scorer,
+        res: &'data model::ResponseItem<model::Tweet, model::StreamMeta>,
+    ) -> Result<RouteResult<'data>, Error> {
+        let mut payload = stream_payload(scorer, res, self.previous)?;
+        if let Some(score) = self.score_override {
+            payload.score = score;
+        }
+        Ok(RouteResult {
+            payload,
+            routes: self.routes,
+            errors: self.errors,
+            score_override: self.score_override,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::future::BoxFuture;
+
+    const HEAP_LIMIT: usize = 64 << 20;
+
+    fn init_v8() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            let platform = v8::Platform::new(0, false).make_shared();
+            v8::V8::initialize_platform(platform);
+            v8::V8::initialize();
+        });
+    }
+
+    fn js_error(e: Error) -> JsError {
+        match e {
+            Error::JsException(e) => *e,
+            e => panic!("expected an exception, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn load_error_has_location() {
+        init_v8();
+        let script = "function route() {}\n\n  throw new Error('boom');\n";
+        let e = js_error(Router::new(HEAP_LIMIT, script).unwrap_err());
+        assert_eq!(e.resource_name.as_deref(), Some(DEFAULT_SCRIPT_NAME));
+        // the message points at the throw statement, 1-based like the frames
+        assert_eq!((e.line, e.column), (Some(3), Some(3)));
+        assert_eq!(e.to_string(), "Uncaught Error: boom (route.js:3:3)");
+        // the frame points at the constructed error
+        let stack = e.format_stack();
+        assert!(stack.starts_with("Error: boom\n"), "{}", stack);
+        assert!(stack.contains("route.js:3:9"), "{}", stack);
+    }
+
+    #[test]
+    fn route_error_has_frames() {
+        init_v8();
+        let script = "\
+function route(data) {
+  return check(data);
+}
+function check(data) {
+    throw new TypeError('bad tweet ' + data.tweet.id);
+}
+";
+        let mut router = Router::new(HEAP_LIMIT, script).unwrap();
+        let sample = SamplePayload::new();
+        let e = js_error(router.validate(&sample.payload()).unwrap_err());
+        assert_eq!((e.line, e.column), (Some(5), Some(5)));
+        assert!(e.message.contains("TypeError: bad tweet 1000000000000000000"), "{}", e);
+
+        let frames = e
+            .stack
+            .iter()
+            .map(|frame| (frame.function_name.as_deref(), frame.line, frame.column))
+            .collect::<Vec<_>>();
+        assert_eq!(frames, [(Some("check"), 5, 11), (Some("route"), 2, 10)]);
+        for frame in &e.stack {
+            assert_eq!(frame.script_name.as_deref(), Some(DEFAULT_SCRIPT_NAME));
+        }
+        let stack = e.format_stack();
+        assert!(stack.contains("at check (route.js:5:11)"), "{}", stack);
+        assert!(stack.contains("at route (route.js:2:10)"), "{}", stack);
+    }
+
+    fn route_return(json: &str) -> Result<(Vec<RouteResultItem>, Option<f64>), Error> {
+        serde_json::from_str::<RouteReturn>(json).unwrap().into_parts()
+    }
+
+    fn urls(routes: &[RouteResultItem]) -> Vec<&str> {
+        routes.iter().map(|route| route.url.as_str()).collect()
+    }
+
+    #[test]
+    fn route_return_shapes() {
+        let (routes, score) = route_return(r#"[{"url": "https://a.test/"}]"#).unwrap();
+        assert_eq!(urls(&routes), ["https://a.test/"]);
+        assert_eq!(score, None);
+
+        let json = r#"{"routes": [{"url": "https://a.test/"}], "scoreOverride": 42}"#;
+        let (routes, score) = route_return(json).unwrap();
+        assert_eq!(urls(&routes), ["https://a.test/"]);
+        assert_eq!(score, Some(42.0));
+
+        let (routes, score) = route_return(r#"{"routes": []}"#).unwrap();
+        assert!(routes.is_empty());
+        assert_eq!(score, None);
+
+        assert!(serde_json::from_str::<RouteReturn>(r#"{"scoreOverride": 1}"#).is_err());
+    }
+
+    #[test]
+    fn score_override_must_be_non_negative() {
+        let (_, score) = route_return(r#"{"routes": [], "scoreOverride": 0}"#).unwrap();
+        assert_eq!(score, Some(0.0));
+
+        let e = js_error(route_return(r#"{"routes": [], "scoreOverride": -1}"#).unwrap_err());
+        assert!(e.message.contains("got -1"), "{}", e);
+        // not representable in JSON, but route scripts may return it
+        for score in [f64::NAN, f64::INFINITY] {
+            let ret = RouteReturn::WithScore {
+                routes: Vec::new(),
+                score_override: Some(score),
+            };
+            assert!(ret.into_parts().is_err(), "{}", score);
+        }
+    }
+
+    #[derive(Debug)]
+    struct FixedScorer(f64);
+
+    impl Scorer for FixedScorer {
+        fn score(&self, _input: &ScoreInput<'_>) -> f64 {
+            self.0
+        }
+    }
+
+    fn stream_item() -> model::ResponseItem<model::Tweet, model::StreamMeta> {
+        let metrics = model::TweetPublicMetrics {
+            reply_count: 0,
+            retweet_count: 1,
+            quote_count: 0,
+            like_count: 1,
+        };
+        let tweet = model::Tweet::new("10", "text")
+            .with_author_id("1")
+            .with_created_at(chrono::Utc::now())
+            .with_metrics(metrics);
+        let mut includes = model::ResponseIncludes::default();
+        let author_metrics = model::UserPublicMetrics {
+            followers_count: 0,
+            following_count: 0,
+            tweet_count: 0,
+            listed_count: 0,
+        };
+        includes.push_user(model::User::new("1", "Author", "author").with_metrics(author_metrics));
+        let meta = model::StreamMeta::new(vec![model::MatchingRule::new("1", "art")]);
+        model::ResponseItem {
+            data: tweet,
+            includes,
+            meta,
+        }
+    }
+
+    #[test]
+    fn payload_is_scored_by_the_given_scorer() {
+        let res = stream_item();
+        let payload = stream_payload(&FixedScorer(7.0), &res, PreviousRoute::default()).unwrap();
+        assert_eq!(payload.score, 7.0);
+        assert_eq!(payload.score_breakdown.total, 7.0);
+        assert_eq!(payload.tags, ["art"]);
+
+        let scorer = FixedScorer(3.0);
+        let payload = make_payload(&scorer, &res.data, &res.includes, vec![], false, None).unwrap();
+        assert_eq!(payload.score, 3.0);
+
+        // the outcome is scored again by the scorer it's given, then overridden
+        let scorer = FixedScorer(7.0);
+        let result = RouteOutcome::default().into_result(&scorer, &res).unwrap();
+        assert_eq!(result.payload().score, 7.0);
+        let outcome = RouteOutcome {
+            score_override: Some(1.0),
+            ..Default::default()
+        };
+        let result = outcome.into_result(&scorer, &res).unwrap();
+        assert_eq!(result.payload().score, 1.0);
+        assert_eq!(result.payload().score_breakdown.total, 7.0);
+    }
+
+    #[test]
+    fn router_scores_with_its_scorer() {
+        init_v8();
+        let script =
+            "function route(data) { return { routes: [], scoreOverride: data.score * 2 }; }";
+        let mut router = Router::new(HEAP_LIMIT, script).unwrap();
+        router.set_scorer(Arc::new(FixedScorer(7.0)));
+        let res = stream_item();
+        let result = router.call_with(&res, PreviousRoute::default()).unwrap();
+        assert_eq!(result.score_override(), Some(14.0));
+        let tags = ["art"];
+        let result = router.call_plain(&res.data, &res.includes, &tags).unwrap();
+        assert_eq!(result.score_override(), Some(14.0));
+    }
+
+    fn origins<'a>(result: &'a RouteResult<'_>) -> Vec<(&'a str, &'a str)> {
+        let route = |route: &'a RouteResultItem| (&*route.origin, route.url.as_str());
+        result.routes().iter().map(route).collect()
+    }
+
+    #[test]
+    fn scripts_are_merged_in_order() {
+        init_v8();
+        let first = "\
+function route(data) {
+  const routes = [{ url: 'https://a.test/' }, { url: 'https://shared.test/' }];
+  return { routes, scoreOverride: 1 };
+}
+";
+        let second = "\
+function route(data) {
+  const routes = [{ url: 'https://shared.test/' }, { url: 'https://b.test/' }];
+  return { routes, scoreOverride: 2 };
+}
+";
+        let scripts = [("first.js", first), ("second.js", second)];
+        let mut router = Router::with_scripts(HEAP_LIMIT, scripts).unwrap();
+        let names = router.script_names().collect::<Vec<_>>();
+        assert_eq!(names, ["first.js", "second.js"]);
+
+        let res = stream_item();
+        let result = router.call_with(&res, PreviousRoute::default()).unwrap();
+        // routes to the same webhook from different scripts are all kept
+        let expected = [
+            ("first.js", "https://a.test/"),
+            ("first.js", "https://shared.test/"),
+            ("second.js", "https://shared.test/"),
+            ("second.js", "https://b.test/"),
+        ];
+        assert_eq!(origins(&result), expected);
+        assert!(result.errors().is_empty());
+        // the last script setting a score wins
+        assert_eq!(result.score_override(), Some(2.0));
+        assert_eq!(result.payload().score, 2.0);
+    }
+
+    #[test]
+    fn failing_script_keeps_the_others() {
+        init_v8();
+        let failing = "function route(data) { throw new Error('boom'); }";
+        let working = "function route(data) { return [{ url: 'https://b.test/' }]; }";
+        let scripts = [("failing.js", failing), ("working.js", working)];
+        let mut router = Router::with_scripts(HEAP_LIMIT, scripts).unwrap();
+
+        let res = stream_item();
+        let result = router.call_with(&res, PreviousRoute::default()).unwrap();
+        assert_eq!(origins(&result), [("working.js", "https://b.test/")]);
+        let errors = result.errors();
+        assert_eq!(errors.len(), 1);
+        let ScriptError { origin, error } = &errors[0];
+        assert_eq!(origin, "failing.js");
+        assert!(error.to_string().contains("boom"), "{}", error);
+
+        // the router fails only if every script does
+        router.reload("working.js", failing).unwrap();
+        let e = router.call_with(&res, PreviousRoute::default()).unwrap_err();
+        let e = js_error(e);
+        assert_eq!(e.resource_name.as_deref(), Some("failing.js"));
+    }
+
+    /// Cache of the route data of a single tweet.
+    #[derive(Debug, Default)]
+    struct RouteCache {
+        cached: bool,
+        data: Option<CacheData>,
+    }
+
+    impl Cache for RouteCache {
+        type Error = std::io::Error;
+    }
+
+    impl LoadCache<CacheData> for RouteCache {
+        fn load(&self, key: &str) -> BoxFuture<'_, Result<CacheData, Self::Error>> {
+            let data = self.data.clone().filter(|data| data.key() == key);
+            let ret = data.ok_or_else(|| std::io::ErrorKind::NotFound.into());
+            Box::pin(async { ret })
+        }
+
+        fn has(&self, _key: &str) -> BoxFuture<'_, Result<bool, Self::Error>> {
+            let ret = self.cached;
+            Box::pin(async move { Ok(ret) })
+        }
+    }
+
+    #[test]
+    fn previous_route_data_is_passed() {
+        use futures_util::FutureExt;
+
+        init_v8();
+        let script = "\
+function route(data) {
+  const previous = data.previous ? data.previous.tags.join() : 'none';
+  return [{ url: `https://a.test/${data.cached}/${previous}` }];
+}
+";
+        let mut router = Router::new(HEAP_LIMIT, script).unwrap();
+        let res = stream_item();
+        let mut route = |cache: &RouteCache| {
+            let previous = PreviousRoute::load(&res, cache).now_or_never().unwrap();
+            let result = router.call_with(&res, previous.unwrap()).unwrap();
+            let url = result.routes()[0].url.to_string();
+            (result.cached(), url)
+        };
+
+        let (cached, url) = route(&RouteCache::default());
+        assert!(!cached);
+        assert_eq!(url, "https://a.test/false/none");
+        // the tweet alone may be cached without route data
+        let without_data = RouteCache {
+            cached: true,
+            data: None,
+        };
+        let (cached, url) = route(&without_data);
+        assert!(cached);
+        assert_eq!(url, "https://a.test/true/none");
+
+        let payload = stream_payload(&DefaultScorer::default(), &res, PreviousRoute::default());
+        let routed = RouteCache {
+            cached: true,
+            data: Some(CacheData::from(&payload.unwrap())),
+        };
+        let (cached, url) = route(&routed);
+        assert!(cached);
+        assert_eq!(url, "https://a.test/true/art");
+    }
 }
@@ -0,0 +1,229 @@
+use chrono::Utc;
+
+use tweet_model as model;
+
+use crate::{RoutePayload, RouteRender};
+
+/// Owned synthetic tweet data used to exercise route scripts without a live stream.
+#[derive(Debug, Clone)]
+pub struct SamplePayload {
+    tweet: model::Tweet,
+    author: model::User,
+    media: model::Media,
+}
+
+impl Default for SamplePayload {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SamplePayload {
+    pub fn new() -> Self {
+        let author = model::User::new("12", "Sample User", "sample_user")
+            .with_profile_image_url("https://pbs.twimg.com/profile_images/1/sample_normal.jpg".parse().unwrap())
+            .with_created_at(Utc::now() - chrono::Duration::days(1000))
+            .with_metrics(model::UserPublicMetrics {
+                followers_count: 1000,
+                following_count: 500,
+                tweet_count: 10000,
+                listed_count: 10,
+            });
+        let media = model::Media::new("3_1000000000000000000", model::MediaType::Photo, 1200, 800)
+            .with_url("https://pbs.twimg.com/media/sample.jpg".parse().unwrap());
+        let tweet = model::Tweet::new("1000000000000000000", "Sample tweet #sample https://t.co/sample")
+            .with_created_at(Utc::now())
+            .with_author_id(author.id())
+            .with_media_keys(vec![media.key().to_owned()])
+            .with_metrics(model::TweetPublicMetrics {
+                reply_count: 1,
+                retweet_count: 10,
+                quote_count: 1,
+                like_count: 100,
+            });
+        Self {
+            tweet,
+            author,
+            media,
+        }
+    }
+
+    pub fn payload(&self) -> RoutePayload<'_> {
+        RoutePayload {
+            tweet: &self.tweet,
+            text: self.tweet.markdown_text(),
+            author: &self.author,
+            original_tweet: None,
+            original_author: None,
+            media: vec![&self.media],
+            score: 10.0,
+            score_breakdown: crate::ScoreBreakdown {
+                total: 10.0,
+                ..Default::default()
+            },
+            author_age_days: self.author.created_at().map(model::score::age_days),
+            tags: vec!["sample"],
+            cached: false,
+            previous: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationProblem {
+    /// Name of the script which returned the offending value.
+    pub origin: String,
+    /// Index of the offending route, `None` if the return value itself is malformed.
+    pub index: Option<usize>,
+    pub field: Option<String>,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ValidationProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.index, &self.field) {
+            (Some(index), Some(field)) => {
+                write!(f, "{}: routes[{}].{}: {}", self.origin, index, field, self.reason)
+            }
+            (Some(index), None) => write!(f, "{}: routes[{}]: {}", self.origin, index, self.reason),
+            (None, _) => write!(f, "{}: return value: {}", self.origin, self.reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    pub route_count: usize,
+    pub problems: Vec<ValidationProblem>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub(crate) fn add_error(&mut self, origin: &str, error: &crate::Error) {
+        let mut report = Reporter {
+            origin,
+            report: self,
+        };
+        report.problem(None, None, format!("threw: {}", error));
+    }
+
+    pub(crate) fn check_value(&mut self, origin: &str, value: &serde_json::Value) {
+        let mut report = Reporter {
+            origin,
+            report: self,
+        };
+        let routes = match value {
+            serde_json::Value::Array(routes) => routes,
+            serde_json::Value::Object(ret) => {
+                match ret.get("scoreOverride") {
+                    None | Some(serde_json::Value::Null) => {}
+                    Some(serde_json::Value::Number(n)) if n.as_f64().unwrap_or(-1.0) >= 0.0 => {}
+                    Some(score) => report.problem(
+                        None,
+                        Some("scoreOverride"),
+                        format!("expected a non-negative number, got {}", score),
+                    ),
+                }
+                match ret.get("routes") {
+                    Some(serde_json::Value::Array(routes)) => routes,
+                    Some(routes) => {
+                        let message =
+                            format!("expected an array of routes, got {}", type_name(routes));
+                        report.problem(None, Some("routes"), message);
+                        return;
+                    }
+                    None => {
+                        report.problem(None, Some("routes"), String::from("missing"));
+                        return;
+                    }
+                }
+            }
+            _ => {
+                let message = format!(
+                    "expected an array of routes or {{ routes, scoreOverride }}, got {}",
+                    type_name(value),
+                );
+                report.problem(None, None, message);
+                return;
+            }
+        };
+        report.report.route_count += routes.len();
+
+        for (index, route) in routes.iter().enumerate() {
+            let route = if let Some(route) = route.as_object() {
+                route
+            } else {
+                report.problem(Some(index), None, format!("expected an object, got {}", type_name(route)));
+                continue;
+            };
+
+            match route.get("url") {
+                None => report.problem(Some(index), Some("url"), String::from("missing")),
+                Some(serde_json::Value::String(url)) => match url.parse::<url::Url>() {
+                    Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+                    Ok(url) => report.problem(
+                        Some(index),
+                        Some("url"),
+                        format!("unsupported scheme {}", url.scheme()),
+                    ),
+                    Err(e) => report.problem(Some(index), Some("url"), format!("invalid URL: {}", e)),
+                },
+                Some(url) => report.problem(
+                    Some(index),
+                    Some("url"),
+                    format!("expected a string, got {}", type_name(url)),
+                ),
+            }
+
+            let render = route.get("render").filter(|v| !v.is_null());
+            if let Some(render) = render {
+                if let Err(e) = serde_json::from_value::<RouteRender>(render.clone()) {
+                    report.problem(Some(index), Some("render"), e.to_string());
+                }
+            }
+            match route.get("payload") {
+                None | Some(serde_json::Value::Null) if render.is_none() => {
+                    report.problem(Some(index), Some("payload"), String::from("missing, and no render given"));
+                }
+                Some(payload) if !payload.is_object() && !payload.is_null() => {
+                    report.problem(
+                        Some(index),
+                        Some("payload"),
+                        format!("expected an object, got {}", type_name(payload)),
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+struct Reporter<'a> {
+    origin: &'a str,
+    report: &'a mut ValidationReport,
+}
+
+impl Reporter<'_> {
+    fn problem(&mut self, index: Option<usize>, field: Option<&str>, reason: String) {
+        self.report.problems.push(ValidationProblem {
+            origin: self.origin.to_owned(),
+            index,
+            field: field.map(String::from),
+            reason,
+        });
+    }
+}
+
+fn type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+}